@@ -0,0 +1,83 @@
+use std::collections::VecDeque;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: "Sent" isn't the same as "arrived".
+//
+// write() returning Ok only means the bytes reached our own kernel. The
+// server may still drop the line - flood protection, a mute, a connection
+// that dies before it's read - and the client would never know.
+//
+// A version 3 server answers each chat line with an Ack: "the Nth line you
+// sent is message 17". Both sides count every Text frame since login,
+// commands too, so no line has to carry a number. The server handles lines
+// in order, so an Ack for line 5 also means lines before it that are still
+// waiting will never get one: they were dropped.
+//
+// Only chat lines and /msg become messages; other commands are counted but
+// never waited for.
+// ---------------------------------------------------------------------------
+
+/// What an Ack settled.
+pub struct Settled {
+    /// The line that was acked, if we were waiting for it.
+    pub acked: Option<String>,
+    /// Earlier lines that now never will be.
+    pub dropped: Vec<String>,
+}
+
+/// Lines sent this session that the server hasn't acked yet.
+#[derive(Default)]
+pub struct Unacked {
+    // Whether this server acks at all.
+    enabled: bool,
+    // Text frames sent since login.
+    count: u64,
+    waiting: VecDeque<(u64, String)>,
+}
+
+impl Unacked {
+    /// Start counting afresh for a new session. Returns the lines the last
+    /// one never acked: they may or may not have got through.
+    pub fn restart(&mut self, enabled: bool) -> Vec<String> {
+        self.enabled = enabled;
+        self.count = 0;
+        self.waiting.drain(..).map(|(_, line)| line).collect()
+    }
+
+    /// We sent `line` as a Text frame.
+    pub fn sent(&mut self, line: &str) {
+        self.count += 1;
+        if self.enabled && becomes_message(line) {
+            self.waiting.push_back((self.count, line.to_string()));
+        }
+    }
+
+    /// The server acked line `seq`.
+    pub fn ack(&mut self, seq: u64) -> Settled {
+        let mut settled = Settled {
+            acked: None,
+            dropped: Vec::new(),
+        };
+        while let Some((n, _)) = self.waiting.front() {
+            if *n > seq {
+                break;
+            }
+            let (n, line) = self.waiting.pop_front().unwrap();
+            if n == seq {
+                settled.acked = Some(line);
+            } else {
+                settled.dropped.push(line);
+            }
+        }
+        settled
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+}
+
+// Whether the server turns `line` into a message with an id.
+fn becomes_message(line: &str) -> bool {
+    !line.starts_with('/') || line.starts_with("/msg ")
+}
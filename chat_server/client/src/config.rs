@@ -0,0 +1,157 @@
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde::Deserialize;
+
+use crate::tls::{TlsOptions, Trust};
+
+// Where and how to connect. Like the server (see its config.rs), a flag
+// beats an environment variable, which beats the config file:
+//
+//   server = "[::1]:8080"
+//   nick = "alice"
+//   tls_pin = "tls/cert.pem"
+//   downloads = "received"
+
+/// The port to use when --server doesn't name one.
+pub const DEFAULT_PORT: u16 = 8080;
+
+const DEFAULT_SERVER: &str = "127.0.0.1";
+
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Nickname to ask for, or the account to log in to with --password.
+    pub nick: Option<String>,
+
+    /// Server to connect to: host, host:port, IP or [IPv6]:port
+    /// [default: 127.0.0.1:8080].
+    #[arg(long, env = "CHAT_SERVER")]
+    pub server: Option<String>,
+
+    /// Log in to the account named by the nickname with this password.
+    #[arg(long, env = "CHAT_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// Use TLS, trusting certificates signed by the CA in this PEM file.
+    #[arg(long, env = "CHAT_TLS_CA", conflicts_with = "tls_pin")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Use TLS, accepting only the certificate in this PEM file.
+    #[arg(long, env = "CHAT_TLS_PIN")]
+    pub tls_pin: Option<PathBuf>,
+
+    /// The name the server's certificate must be for [default: the host
+    /// part of --server].
+    #[arg(long, env = "CHAT_TLS_NAME")]
+    pub tls_name: Option<String>,
+
+    /// Print what the server sends as JSON events, one per line, for
+    /// scripts and bots. The client's own messages go to stderr.
+    #[arg(long)]
+    pub raw: bool,
+
+    /// Stick to plain lines and a prompt, even on a terminal, instead of
+    /// the full-screen interface.
+    #[arg(long, env = "CHAT_PLAIN")]
+    pub plain: bool,
+
+    /// Ask the server to say when each message reaches each recipient.
+    /// Needs a protocol version 3 server.
+    #[arg(long, env = "CHAT_RECEIPTS")]
+    pub receipts: bool,
+
+    /// Where files other users send you are saved [default: the current
+    /// directory].
+    #[arg(long, env = "CHAT_DOWNLOADS")]
+    pub downloads: Option<PathBuf>,
+}
+
+/// A server address, split so TLS can check the certificate's name.
+pub struct Endpoint {
+    /// What to hand to TcpStream::connect.
+    pub addr: String,
+    pub host: String,
+}
+
+impl Options {
+    /// Read options from a TOML file.
+    pub fn load(path: &Path) -> io::Result<Options> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        toml::from_str(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// These options, with anything they leave unset taken from `fallback`.
+    pub fn or(self, fallback: Options) -> Options {
+        Options {
+            nick: self.nick.or(fallback.nick),
+            server: self.server.or(fallback.server),
+            password: self.password.or(fallback.password),
+            tls_ca: self.tls_ca.or(fallback.tls_ca),
+            tls_pin: self.tls_pin.or(fallback.tls_pin),
+            tls_name: self.tls_name.or(fallback.tls_name),
+            raw: self.raw || fallback.raw,
+            plain: self.plain || fallback.plain,
+            receipts: self.receipts || fallback.receipts,
+            downloads: self.downloads.or(fallback.downloads),
+        }
+    }
+
+    pub fn endpoint(&self) -> Endpoint {
+        let server = self.server.as_deref().unwrap_or(DEFAULT_SERVER).trim();
+        if let Ok(addr) = server.parse::<SocketAddr>() {
+            return Endpoint {
+                addr: addr.to_string(),
+                host: addr.ip().to_string(),
+            };
+        }
+        // A bare IPv6 address is full of colons, so look for one before
+        // trying to split off a port.
+        let bare = server.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = bare.parse::<IpAddr>() {
+            return Endpoint {
+                addr: SocketAddr::new(ip, DEFAULT_PORT).to_string(),
+                host: ip.to_string(),
+            };
+        }
+        match server.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => Endpoint {
+                addr: server.to_string(),
+                host: host.to_string(),
+            },
+            _ => Endpoint {
+                addr: format!("{}:{}", server, DEFAULT_PORT),
+                host: server.to_string(),
+            },
+        }
+    }
+
+    /// TLS settings, or None for plain TCP.
+    pub fn tls(&self, endpoint: &Endpoint) -> io::Result<Option<TlsOptions>> {
+        let trust = match (&self.tls_ca, &self.tls_pin) {
+            (Some(ca), None) => Trust::Ca(ca.clone()),
+            (None, Some(pin)) => Trust::Pinned(pin.clone()),
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "set tls_ca or tls_pin, not both",
+                ))
+            }
+        };
+        let server_name = self
+            .tls_name
+            .clone()
+            .unwrap_or_else(|| endpoint.host.clone());
+        Ok(Some(TlsOptions { trust, server_name }))
+    }
+}
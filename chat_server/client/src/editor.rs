@@ -0,0 +1,177 @@
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: A line editor is a string and a cursor.
+//
+// In plain mode the terminal edits the line for us ("cooked" mode) and we
+// only see it once Enter is pressed. The full-screen interface puts the
+// terminal in raw mode to get every key as it's pressed, so editing becomes
+// our job: inserting at the cursor, deleting on either side of it, moving
+// by word, and bringing back earlier lines with Up and Down, like a shell.
+//
+// The cursor is a byte index into the String, and always sits on a char
+// boundary. Moving it means stepping over whole chars, never single bytes,
+// or a multibyte character like "é" gets cut in half.
+// ---------------------------------------------------------------------------
+
+// Lines remembered for Up and Down.
+const HISTORY: usize = 500;
+
+/// The line being typed, and the ones typed before it.
+#[derive(Debug, Default)]
+pub struct Editor {
+    line: String,
+    cursor: usize,
+    history: Vec<String>,
+    // The history entry showing, while going through them.
+    browsing: Option<usize>,
+    // What was being typed before that started.
+    draft: String,
+}
+
+impl Editor {
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// The line up to the cursor.
+    pub fn before_cursor(&self) -> &str {
+        &self.line[..self.cursor]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.line.is_empty()
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.line.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// Delete the character before the cursor.
+    pub fn backspace(&mut self) {
+        if let Some(c) = self.before_cursor().chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.line.remove(self.cursor);
+        }
+    }
+
+    /// Delete the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        if let Some(c) = self.before_cursor().chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    pub fn right(&mut self) {
+        if let Some(c) = self.line[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.line.len();
+    }
+
+    /// Back to the start of this word, or the one before.
+    pub fn word_left(&mut self) {
+        self.cursor = self.word_start();
+    }
+
+    /// On to the end of this word, or the next one.
+    pub fn word_right(&mut self) {
+        let rest = &self.line[self.cursor..];
+        let word = rest.len() - rest.trim_start().len();
+        let end = rest[word..].find(' ').map_or(rest.len(), |at| word + at);
+        self.cursor += end;
+    }
+
+    /// Delete back to the start of the word, like Ctrl+W in a shell.
+    pub fn delete_word(&mut self) {
+        let start = self.word_start();
+        self.line.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    /// Delete everything before the cursor.
+    pub fn clear_before(&mut self) {
+        self.line.replace_range(..self.cursor, "");
+        self.cursor = 0;
+    }
+
+    /// Delete everything from the cursor on.
+    pub fn clear_after(&mut self) {
+        self.line.truncate(self.cursor);
+    }
+
+    /// Show the history entry before the one showing.
+    pub fn previous(&mut self) {
+        let at = match self.browsing {
+            Some(0) => return,
+            Some(at) => at - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.line.clone();
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(at);
+        self.set(self.history[at].clone());
+    }
+
+    /// Show the history entry after the one showing, or what was being
+    /// typed before browsing started.
+    pub fn next(&mut self) {
+        let Some(at) = self.browsing else {
+            return;
+        };
+        if at + 1 < self.history.len() {
+            self.browsing = Some(at + 1);
+            self.set(self.history[at + 1].clone());
+        } else {
+            self.browsing = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.set(draft);
+        }
+    }
+
+    /// The line is done: hand it over, remember it, and start a new one.
+    pub fn take(&mut self) -> String {
+        let line = std::mem::take(&mut self.line);
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+        if remember(&line) && self.history.last() != Some(&line) {
+            if self.history.len() == HISTORY {
+                self.history.remove(0);
+            }
+            self.history.push(line.clone());
+        }
+        line
+    }
+
+    fn set(&mut self, line: String) {
+        self.line = line;
+        self.cursor = self.line.len();
+    }
+
+    fn word_start(&self) -> usize {
+        let before = self.before_cursor().trim_end();
+        before.rfind(' ').map_or(0, |at| at + 1)
+    }
+}
+
+// Blank lines aren't worth recalling, and passwords shouldn't be lying
+// around to recall.
+fn remember(line: &str) -> bool {
+    let command = line.split_whitespace().next().unwrap_or_default();
+    !line.trim().is_empty() && command != "/login" && command != "/register"
+}
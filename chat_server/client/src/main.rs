@@ -1,8 +1,29 @@
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::TcpStream;
-use std::sync::mpsc;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use protocol::{Decoder, Event as ChatEvent, Frame, FrameReader, Framing, MessageType};
+
+mod acks;
+mod config;
+mod editor;
+mod reconnect;
+mod tls;
+mod transfer;
+mod tui;
+mod ui;
+
+use acks::Unacked;
+use config::Options;
+use reconnect::{Backoff, Pending};
+use tls::TlsOptions;
+use transfer::{Outcome, Transfers};
+use tui::Update;
+use ui::{raw, Kind};
 
 // ---------------------------------------------------------------------------
 // LEARNING NOTE: The client has a classic concurrency problem.
@@ -14,110 +35,749 @@ use std::time::Duration;
 // These can't happen sequentially (if we wait for user input, we miss server
 // messages; if we wait for server messages, we can't type).
 //
-// Solution: split into two threads.
-//   - Main thread: reads stdin → writes to server
-//   - Spawned thread: reads server → prints to stdout
+// Solution: split into threads.
+//   - Stdin thread: reads stdin → sends each line to main over a channel
+//   - Receiver thread: reads server → prints to stdout
+//   - Main thread: writes lines to the server, and reconnects when the
+//     receiver reports the connection lost
 //
 // TcpStream::try_clone() lets both threads share the same socket without
 // needing Arc<Mutex<>> because we split read/write responsibilities cleanly.
 // One thread only reads, one only writes. No contention = no Mutex needed.
 // This is the "split ownership" pattern and is much cleaner than sharing.
+// (Over TLS the two halves do share the session - see tls.rs. And the
+// receiver writes too, to answer pings, so the writer half sits behind a
+// Mutex after all.)
+//
+// Main learns about typed lines and lost connections from one channel, in
+// the order they happened, so it never has to wait on two things at once.
+// On a terminal the full-screen interface takes the stdin thread's place
+// (see tui.rs), and everything printed goes through ui.rs.
+//
+// The one thing both threads track is which lines the server has acked
+// (see acks.rs): main counts them out, the receiver ticks them off. Files
+// being sent get a thread each (see transfer.rs).
 // ---------------------------------------------------------------------------
 
+// The two halves of a connection, plain or TLS.
+type Reader = Box<dyn Read + Send>;
+type Writer = Box<dyn Write + Send>;
+
+// How long to wait for the server to answer the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+// The server pings us every 15 seconds or so even when nobody is talking,
+// so this much silence means it's gone, not just quiet.
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Reconnect backoff (see reconnect.rs).
+const RETRY_BASE: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+// Lines kept while disconnected.
+const MAX_PENDING: usize = 100;
+
+// println! for the client's own messages, wherever they go (see ui.rs).
+macro_rules! note {
+    ($($arg:tt)*) => {
+        ui::note(&format!($($arg)*))
+    };
+}
+
+// eprintln! for things going wrong, likewise.
+macro_rules! error {
+    ($($arg:tt)*) => {
+        ui::error(&format!($($arg)*))
+    };
+}
+
+// What main hears about.
+enum Event {
+    Line(String),
+    StdinClosed,
+    // The receiver for this session lost the connection.
+    Lost(u64),
+}
+
+// Where to connect and who to be, for reconnecting.
+struct Profile {
+    addr: String,
+    tls: Option<TlsOptions>,
+    // "/login <name> <password>", when logging in to an account.
+    credentials: Option<String>,
+    // Kept up to date by the receiver, so a reconnect asks for the name we
+    // had at the time, even after a /nick.
+    nick: Arc<Mutex<String>>,
+}
+
+// One connection to the server.
+struct Session {
+    // Counts up with every connection, so a late Lost from an old session
+    // isn't mistaken for the current one failing.
+    id: u64,
+    // The protocol version the server agreed to.
+    version: u8,
+    writer: Arc<Mutex<Writer>>,
+    // To hang up from main, which also wakes the receiver.
+    socket: TcpStream,
+}
+
+#[derive(Parser)]
+#[command(about = "A small chat client")]
+struct Cli {
+    /// Read settings from this TOML file. Flags and environment variables
+    /// override it.
+    #[arg(long, env = "CHAT_CLIENT_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: Options,
+}
+
 fn main() -> io::Result<()> {
-    let addr = "127.0.0.1:8080";
-    let stream = TcpStream::connect(addr)?;
+    let cli = Cli::parse();
+    let options = match &cli.config {
+        Some(path) => cli.options.or(Options::load(path)?),
+        None => cli.options,
+    };
+    ui::set_raw(options.raw);
+    let endpoint = options.endpoint();
+    let tls = options.tls(&endpoint)?;
 
-    // Set a read timeout of 15 seconds
-    const TIME_OUT_SECS: u64 = 15;
-    stream.set_read_timeout(Some(Duration::from_secs(TIME_OUT_SECS)))?;
-    println!("[client] Connected to {}", addr);
-    println!("[client] Type a message and press Enter to send. Ctrl+C to quit.");
+    // The first connection has to work; after that, we keep trying.
+    let (mut session, mut reader) = connect(&endpoint.addr, tls.as_ref(), 1)?;
 
-    // Clone the stream. reader_stream is for the background thread,
-    // writer_stream stays in main.
-    let reader_stream = stream.try_clone()?;
+    // Log in before starting the receiver thread: the server answers the
+    // nickname request directly, and nothing else arrives until it accepts.
+    // With a password, the nickname is an account to log in to rather than
+    // a nickname to pick.
+    let credentials = match (&options.nick, &options.password) {
+        (Some(name), Some(password)) => Some(format!("/login {} {}", name, password)),
+        _ => None,
+    };
+    let first_try = credentials.clone().or_else(|| options.nick.clone());
+    let nick = login(&session.writer, &mut reader, first_try)?;
+    note!(
+        "[client] Logged in as {}. Use /nick <name> to change it.",
+        nick
+    );
+    note!("[client] Type a message and press Enter to send. Ctrl+C to quit.");
+    let profile = Profile {
+        addr: endpoint.addr,
+        tls,
+        credentials,
+        nick: Arc::new(Mutex::new(nick)),
+    };
 
-    let (tx, rx) = mpsc::channel();
+    let (events, rx) = mpsc::channel();
+    // On a terminal, the full-screen interface reads the keyboard. Dropping
+    // it gives the terminal back.
+    let full_screen =
+        !options.raw && !options.plain && io::stdin().is_terminal() && io::stdout().is_terminal();
+    let screen = if full_screen {
+        Some(tui::start(events.clone())?)
+    } else {
+        spawn_stdin(events.clone());
+        None
+    };
+
+    let mut pending = Pending::new(MAX_PENDING);
+    let mut backoff = Backoff::new(RETRY_BASE, RETRY_MAX);
+    let unacked = Arc::new(Mutex::new(Unacked::default()));
+    let downloads = options
+        .downloads
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    let transfers = Arc::new(Mutex::new(Transfers::new(downloads)));
+
+    prompt(&pending, &unacked);
+
+    'sessions: loop {
+        ui::update(Update::Connected {
+            server: profile.addr.clone(),
+            nick: profile.nick.lock().unwrap().clone(),
+            version: session.version,
+            secure: profile.tls.is_some(),
+        });
+        // In raw mode the acks go to the script, which can keep its own
+        // count.
+        let acks = session.version >= protocol::ACKS_VERSION && !raw();
+        let lost = unacked.lock().unwrap().restart(acks);
+        if !lost.is_empty() {
+            note!("[client] The connection dropped before these were confirmed:");
+            for line in lost {
+                note!("[unconfirmed] {}", line);
+            }
+        }
+        // The server forgets transfers when a connection drops, so do we.
+        for lost in transfers.lock().unwrap().reset() {
+            note!("[file] {}", lost);
+        }
+
+        // From here on a quiet connection is normal: only silence longer
+        // than the server's heartbeat means trouble.
+        session.socket.set_read_timeout(Some(SILENCE_TIMEOUT))?;
+        spawn_receiver(
+            &session,
+            reader,
+            events.clone(),
+            Arc::clone(&profile.nick),
+            Arc::clone(&unacked),
+            Arc::clone(&transfers),
+        );
+
+        // The user list starts from whoever /who says is here.
+        if ui::has_screen() {
+            ui::update(Update::Who);
+            if let Err(e) = send_line(&session, &unacked, "/who") {
+                error!("[client] Send error: {}", e);
+            }
+        }
+
+        // Receipts are per connection, so ask again every time. If this
+        // fails, so will whatever comes next, which takes care of it.
+        if options.receipts {
+            if session.version >= protocol::ACKS_VERSION {
+                if let Err(e) = send_line(&session, &unacked, "/receipts on") {
+                    error!("[client] Send error: {}", e);
+                }
+            } else {
+                note!("[client] This server can't send delivery receipts");
+            }
+        }
 
-    // Spawn a background thread to handle incoming messages from the server.
-    // 'move' transfers ownership of reader_stream into the closure.
-    let receiver = thread::spawn(move || {
-        let reader = BufReader::new(reader_stream);
-        for line in reader.lines() {
+        // Whatever was typed while we were away goes first.
+        if !pending.is_empty() {
+            note!("[client] Sending {} queued message(s)", pending.len());
+        }
+        let mut connected = true;
+        while let Some(line) = pending.pop() {
+            if let Err(e) = send_input(&session, &unacked, &transfers, &line) {
+                error!("[client] Send error: {}", e);
+                pending.push_front(line);
+                connected = false;
+                break;
+            }
+        }
+
+        while connected {
+            let Ok(event) = rx.recv() else {
+                break 'sessions;
+            };
+            match event {
+                Event::Line(msg) => {
+                    if msg.trim().is_empty() {
+                        prompt(&pending, &unacked);
+                        continue;
+                    }
+
+                    // LEARNING NOTE: TCP is a byte stream, not a message
+                    // stream. You must define your own message framing. We
+                    // used to append \n and let the server read
+                    // line-by-line; now each message goes out as a
+                    // length-prefixed frame (see the protocol crate), so it
+                    // could even contain newlines.
+                    if let Err(e) = send_input(&session, &unacked, &transfers, &msg) {
+                        error!("[client] Send error: {}", e);
+                        queue(&mut pending, &unacked, msg);
+                        connected = false;
+                        continue;
+                    }
+                    prompt(&pending, &unacked);
+                }
+                Event::StdinClosed => break 'sessions,
+                Event::Lost(id) if id == session.id => connected = false,
+                Event::Lost(_) => {}
+            }
+        }
+
+        // Make sure the receiver is gone too, however we got here.
+        let _ = session.socket.shutdown(Shutdown::Both);
+        let next = reconnect(
+            &profile,
+            session.id + 1,
+            &rx,
+            (&mut pending, &unacked),
+            &mut backoff,
+        );
+        match next {
+            Some((next, next_reader)) => {
+                session = next;
+                reader = next_reader;
+            }
+            None => break 'sessions,
+        }
+    }
+
+    // Give the terminal back first, so the goodbye stays on it.
+    drop(screen);
+    note!("[client] Disconnecting...");
+    let _ = session.socket.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+// Read typed lines in plain mode. Stdin gets its own thread now that it's
+// no longer main's only job.
+fn spawn_stdin(events: mpsc::Sender<Event>) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
             match line {
-                Ok(msg) => {
-                    // \r clears the current input line before printing,
-                    // so the server message doesn't appear mid-sentence.
-                    print!("\r{}\n> ", msg);
-                    io::stdout().flush().ok();
+                Ok(line) => {
+                    if events.send(Event::Line(line)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    error!("[client] Stdin error: {}", e);
+                    break;
+                }
+            }
+        }
+        let _ = events.send(Event::StdinClosed);
+    });
+}
+
+// Open a connection and run the protocol handshake.
+fn connect(
+    addr: &str,
+    tls: Option<&TlsOptions>,
+    id: u64,
+) -> io::Result<(Session, FrameReader<Reader>)> {
+    let stream = TcpStream::connect(addr)?;
+
+    // Clones of the socket share its read timeout, so keep one to change
+    // the timeout after the handshake.
+    let socket = stream.try_clone()?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    // Announce that we speak the framed protocol. An old server would not
+    // answer with the magic bytes, and the handshake fails loudly.
+    //
+    // Split the connection. The reader half is for the background thread,
+    // the writer half stays in main.
+    let (version, reader, writer): (u8, Reader, Writer) = match tls {
+        Some(options) => {
+            let (version, reader, writer) = tls::connect(stream, options)?;
+            note!("[client] TLS session established");
+            (version, Box::new(reader), Box::new(writer))
+        }
+        None => {
+            let mut stream = stream;
+            let version = protocol::client_handshake(&mut stream)?;
+            (version, Box::new(stream.try_clone()?), Box::new(stream))
+        }
+    };
+    note!("[client] Connected to {} (protocol v{})", addr, version);
+
+    // FrameReader keeps half-received frames buffered across read
+    // timeouts, so a timeout never corrupts the stream.
+    let reader = FrameReader::new(reader, Decoder::new(Framing::Framed));
+    let session = Session {
+        id,
+        version,
+        // Both threads write: main sends what we type, the receiver
+        // answers the server's pings.
+        writer: Arc::new(Mutex::new(writer)),
+        socket,
+    };
+    Ok((session, reader))
+}
+
+// Try to get back in, waiting longer after each failure. Lines typed
+// meanwhile are queued. Returns None if stdin closed while we waited.
+fn reconnect(
+    profile: &Profile,
+    id: u64,
+    rx: &mpsc::Receiver<Event>,
+    (pending, unacked): (&mut Pending, &Mutex<Unacked>),
+    backoff: &mut Backoff,
+) -> Option<(Session, FrameReader<Reader>)> {
+    let mut what = "Connection lost. Reconnecting";
+    loop {
+        let delay = backoff.next_delay();
+        note!("\n[client] {} in {:.1}s...", what, delay.as_secs_f64());
+        ui::update(Update::Retrying(delay));
+        what = "Trying again";
+        prompt(pending, unacked);
+
+        // Wait out the delay, but keep taking what the user types.
+        let deadline = Instant::now() + delay;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(left) {
+                Ok(Event::Line(line)) => {
+                    if !line.trim().is_empty() {
+                        queue(pending, unacked, line);
+                    } else {
+                        prompt(pending, unacked);
+                    }
+                }
+                Ok(Event::StdinClosed) => return None,
+                Ok(Event::Lost(_)) => {}
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+
+        let (session, mut reader) = match connect(&profile.addr, profile.tls.as_ref(), id) {
+            Ok(connected) => connected,
+            Err(e) => {
+                error!("[client] Reconnect failed: {}", e);
+                continue;
+            }
+        };
+        // Log in again as who we were. No prompting here: if the server
+        // still remembers our old connection, it will let go of the name
+        // soon enough, so just try again later.
+        let wanted = profile
+            .credentials
+            .clone()
+            .unwrap_or_else(|| profile.nick.lock().unwrap().clone());
+        match try_login(&session.writer, &mut reader, &wanted) {
+            Ok(Ok(name)) => {
+                note!("[client] Reconnected as {}", name);
+                *profile.nick.lock().unwrap() = name;
+                backoff.reset();
+                return Some((session, reader));
+            }
+            Ok(Err(refused)) => error!("[client] Server refused the login: {}", refused),
+            Err(e) => error!("[client] Reconnect failed: {}", e),
+        }
+        let _ = session.socket.shutdown(Shutdown::Both);
+    }
+}
+
+// Spawn a background thread to handle incoming messages from the server.
+// 'move' transfers ownership of the reader into the closure.
+fn spawn_receiver(
+    session: &Session,
+    mut reader: FrameReader<Reader>,
+    events: mpsc::Sender<Event>,
+    nick: Arc<Mutex<String>>,
+    unacked: Arc<Mutex<Unacked>>,
+    transfers: Arc<Mutex<Transfers>>,
+) {
+    let id = session.id;
+    let writer = Arc::clone(&session.writer);
+    thread::spawn(move || {
+        loop {
+            match reader.read_frame() {
+                Ok(Some(frame)) if frame.kind == MessageType::Ping => {
+                    if let Err(e) = send(&writer, &Frame::pong(&frame)) {
+                        error!("\n[client] Send error: {}", e);
+                    }
+                }
+                Ok(Some(frame)) if frame.kind == MessageType::Chunk => {
+                    if let Some((id, bytes)) = frame.chunk_parts() {
+                        let outcome = transfers.lock().unwrap().chunk(id, bytes);
+                        settle(outcome, &writer, &unacked);
+                    }
+                }
+                Ok(Some(frame)) => {
+                    if frame.kind == MessageType::Nick {
+                        let name = frame.as_str().unwrap_or_default().to_string();
+                        ui::update(Update::Nick(name.clone()));
+                        *nick.lock().unwrap() = name;
+                    }
+                    let me = nick.lock().unwrap().clone();
+                    if let Some(outcome) = transfer_event(&transfers, &me, &frame) {
+                        settle(outcome, &writer, &unacked);
+                    }
+                    if raw() {
+                        show_raw(&frame);
+                        io::stdout().flush().ok();
+                    } else {
+                        show(&frame, &unacked);
+                    }
+                }
+                Ok(None) => {
+                    note!("\n[client] Server disconnected.");
+                    break;
                 }
                 Err(e) => {
                     match e.kind() {
                         io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
-                            println!("\n[client] Read timeout (no data for 15s)");
-                            // Could retry or keep waiting. For now, just ignore and keep waiting.
-                            break;
+                            // Not even a ping: the server or the network
+                            // between us has gone away.
+                            note!(
+                                "\n[client] No word from the server for {}s; connection lost.",
+                                SILENCE_TIMEOUT.as_secs()
+                            );
                         }
                         _ => {
-                            eprintln!("\n[client] Read error: {}", e);
-                            println!("\n[client] Server disconnected.");
-                            let _ = tx.send(()); // Signal main thread to exit.
-                            break;
+                            error!("\n[client] Read error: {}", e);
+                            note!("\n[client] Server disconnected.");
                         }
                     }
+                    break;
                 }
             }
         }
+        let _ = events.send(Event::Lost(id)); // Signal main thread to reconnect.
     });
+}
+
+// Show a frame from the server to a person.
+fn show(frame: &Frame, unacked: &Mutex<Unacked>) {
+    let msg = frame.as_str().unwrap_or_default();
+    match frame.kind {
+        MessageType::Text => ui::show(Kind::Chat, msg),
+        MessageType::Error => ui::show(Kind::Error, &format!("[server error] {}", msg)),
+        MessageType::Nick => ui::show(Kind::Notice, &format!("*** You are now known as {}", msg)),
+        MessageType::Notice => ui::notice(msg),
+        // Private messages get their own look so they don't blend into
+        // room traffic.
+        MessageType::Direct => {
+            let (from, text) = frame.direct_parts().unwrap_or(("?", msg));
+            ui::show(Kind::Direct, &format!("[dm from {}] {}", from, text))
+        }
+        MessageType::Event => match frame.to_event() {
+            Ok(event @ ChatEvent::Chat { .. }) => ui::show(Kind::Chat, &event.to_string()),
+            Ok(event @ ChatEvent::Direct { .. }) => ui::show(Kind::Direct, &event.to_string()),
+            Ok(ChatEvent::Error { text }) => {
+                ui::show(Kind::Error, &format!("[server error] {}", text))
+            }
+            Ok(ChatEvent::Notice { text }) => ui::notice(&text),
+            // Our own lines, coming back with their ids.
+            Ok(ChatEvent::Ack { seq, id }) => {
+                let mut unacked = unacked.lock().unwrap();
+                let settled = unacked.ack(seq);
+                ui::counts(0, unacked.len());
+                for line in settled.dropped {
+                    ui::show(Kind::Sent, &format!("[not sent] {}", line));
+                }
+                if let Some(line) = settled.acked {
+                    ui::show(Kind::Sent, &format!("[sent #{}] {}", id, line));
+                }
+            }
+            Ok(ChatEvent::Receipt { id, to }) => {
+                ui::show(Kind::Sent, &format!("[#{} delivered to {}]", id, to))
+            }
+            // Already told in words, by settle().
+            Ok(
+                ChatEvent::FileOffer { .. }
+                | ChatEvent::FileAccepted { .. }
+                | ChatEvent::FileProgress { .. }
+                | ChatEvent::FileCancelled { .. },
+            ) => {}
+            Ok(event) => {
+                ui::presence(&event);
+                ui::show(Kind::Notice, &format!("*** {}", event))
+            }
+            Err(e) => ui::show(
+                Kind::Error,
+                &format!("[client] Could not read an event: {}", e),
+            ),
+        },
+        // The server will hang up next; the reconnect loop takes it from
+        // there.
+        MessageType::Shutdown => note!("\r[client] The server is going away: {}", msg),
+        MessageType::Ping | MessageType::Pong | MessageType::Chunk => {}
+    }
+}
+
+// Print a frame from the server as a line of JSON, for --raw. Anything
+// that isn't chat traffic goes to stderr.
+fn show_raw(frame: &Frame) {
+    let msg = frame.as_str().unwrap_or_default();
+    match frame.kind {
+        MessageType::Event => match frame.to_event() {
+            Ok(event) => println!("{}", event.to_json()),
+            Err(e) => error!("[client] Could not read an event: {}", e),
+        },
+        // An older server sends text; make the nearest event of it.
+        MessageType::Text | MessageType::Error | MessageType::Notice | MessageType::Direct => {
+            let event = ChatEvent::from_legacy(frame).unwrap_or_else(|| ChatEvent::notice(msg));
+            println!("{}", event.to_json())
+        }
+        MessageType::Nick => note!("[client] You are now known as {}", msg),
+        MessageType::Shutdown => note!("[client] The server is going away: {}", msg),
+        MessageType::Ping | MessageType::Pong | MessageType::Chunk => {}
+    }
+}
 
-    // Main thread handles sending.
-    let stdin = io::stdin();
-    let mut writer = stream;
+fn send(writer: &Mutex<Writer>, frame: &Frame) -> io::Result<()> {
+    protocol::write_frame(&mut *writer.lock().unwrap(), Framing::Framed, frame)
+}
 
-    print!("> ");
-    io::stdout().flush()?;
+// Send a line we typed, counting it for the acks. The count is held while
+// sending, so the receiver can't see the ack before we've counted the line.
+fn send_line(session: &Session, unacked: &Mutex<Unacked>, line: &str) -> io::Result<()> {
+    send_counted(&session.writer, unacked, line)
+}
+
+// Any Text frame has to be counted, whichever thread sends it.
+fn send_counted(writer: &Mutex<Writer>, unacked: &Mutex<Unacked>, line: &str) -> io::Result<()> {
+    let mut unacked = unacked.lock().unwrap();
+    send(writer, &Frame::text(line))?;
+    unacked.sent(line);
+    Ok(())
+}
 
-    for line in stdin.lock().lines() {
-        // Check if the receiver thread has signaled to exit.
-        if rx.try_recv().is_ok() {
-            println!("\n[client] Exiting due to server disconnect.");
-            break;
+// Send something typed. "/send <nick> <path>" is the client's own command:
+// it turns into an /offer of the file, for the server to pass on.
+fn send_input(
+    session: &Session,
+    unacked: &Mutex<Unacked>,
+    transfers: &Mutex<Transfers>,
+    line: &str,
+) -> io::Result<()> {
+    let args = match line.strip_prefix("/send") {
+        Some(args) if args.is_empty() || args.starts_with(' ') => args.trim(),
+        _ => return send_line(session, unacked, line),
+    };
+    let Some((to, path)) = args.split_once(char::is_whitespace) else {
+        note!("[client] usage: /send <nick> <path>");
+        return Ok(());
+    };
+    if session.version < protocol::FILES_VERSION {
+        note!("[client] This server can't pass files on");
+        return Ok(());
+    }
+    let path = PathBuf::from(path.trim());
+    let info = match transfer::inspect(&path) {
+        Ok(info) => info,
+        Err(e) => {
+            note!("[client] Can't send {}: {}", path.display(), e);
+            return Ok(());
         }
+    };
+    let offer = format!("/offer {} {} {} {}", to, info.size, info.sha256, info.name);
+    transfers.lock().unwrap().offering(to, path, info);
+    send_line(session, unacked, &offer)
+}
+
+// Let the transfers know about a file event, if this frame is one.
+fn transfer_event(transfers: &Mutex<Transfers>, me: &str, frame: &Frame) -> Option<Outcome> {
+    let event = frame.to_event().ok()?;
+    let mut transfers = transfers.lock().unwrap();
+    Some(match event {
+        ChatEvent::FileOffer {
+            id,
+            from,
+            to,
+            name,
+            size,
+            sha256,
+        } => transfers.offer(me, id, (&from, &to), &name, size, &sha256),
+        ChatEvent::FileAccepted { id } => transfers.accepted(id),
+        ChatEvent::FileProgress { id, bytes } => transfers.progress(id, bytes),
+        ChatEvent::FileCancelled { id, reason } => transfers.cancelled(id, &reason),
+        _ => return None,
+    })
+}
 
-        match line {
-            Ok(msg) => {
-                if msg.trim().is_empty() {
-                    print!("> ");
+// Do what a file event calls for: tell the user, call the transfer off, or
+// start sending the file on a thread of its own.
+fn settle(outcome: Outcome, writer: &Arc<Mutex<Writer>>, unacked: &Arc<Mutex<Unacked>>) {
+    for line in outcome.notes {
+        ui::show(Kind::File, &format!("[file] {}", line));
+    }
+    if let Some(id) = outcome.cancel {
+        if let Err(e) = send_counted(writer, unacked, &format!("/cancel {}", id)) {
+            error!("\n[client] Send error: {}", e);
+        }
+    }
+    let Some(upload) = outcome.upload else {
+        return;
+    };
+    let writer = Arc::clone(writer);
+    let unacked = Arc::clone(unacked);
+    thread::spawn(move || match upload.send(|frame| send(&writer, frame)) {
+        Ok(()) => {}
+        // Called off; whoever did it has said so.
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+        Err(e) => {
+            ui::show(
+                Kind::File,
+                &format!("[file] Could not send {}: {}", upload.name, e),
+            );
+            let _ = send_counted(&writer, &unacked, &format!("/cancel {}", upload.id));
+        }
+    });
+}
+
+// Hold on to a line until we're connected again.
+fn queue(pending: &mut Pending, unacked: &Mutex<Unacked>, line: String) {
+    if !pending.push(line) {
+        note!("[client] Queue full; dropped the oldest queued message");
+    }
+    prompt(pending, unacked);
+}
+
+// The input prompt, showing how much is waiting to be sent or acked.
+fn prompt(pending: &Pending, unacked: &Mutex<Unacked>) {
+    ui::prompt(pending.len(), unacked.lock().unwrap().len());
+}
+
+// Ask the server for a nickname until it accepts one. The first attempt comes
+// from the command line if one was given, later ones from stdin. Servers
+// with accounts also take "/login <name> <password>" or "/register <name>
+// <password>" here.
+fn login(
+    writer: &Mutex<Writer>,
+    reader: &mut FrameReader<Reader>,
+    mut candidate: Option<String>,
+) -> io::Result<String> {
+    loop {
+        let wanted = match candidate.take() {
+            Some(nick) => nick,
+            None => {
+                let ask = "Nickname (or /login <name> <password>): ";
+                if raw() {
+                    eprint!("{}", ask);
+                } else {
+                    print!("{}", ask);
                     io::stdout().flush()?;
-                    continue;
                 }
-
-                // Append \n because the server reads line-by-line.
-                // LEARNING NOTE: TCP is a byte stream, not a message stream.
-                // You must define your own message framing. Here we use
-                // newlines. Real protocols use length-prefixed frames or
-                // delimiters like HTTP's \r\n\r\n.
-                let to_send = format!("{}\n", msg);
-                if let Err(e) = writer.write_all(to_send.as_bytes()) {
-                    eprintln!("[client] Send error: {}", e);
-                    break;
+                let mut line = String::new();
+                if io::stdin().read_line(&mut line)? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
                 }
+                line.trim().to_string()
+            }
+        };
+        match try_login(writer, reader, &wanted)? {
+            Ok(nick) => return Ok(nick),
+            Err(refused) => note!("[client] {}", refused),
+        }
+    }
+}
 
-                print!("> ");
-                io::stdout().flush()?;
+// One login attempt. The outer Result is the connection failing, the
+// inner one the server saying no.
+fn try_login(
+    writer: &Mutex<Writer>,
+    reader: &mut FrameReader<Reader>,
+    wanted: &str,
+) -> io::Result<Result<String, String>> {
+    send(writer, &Frame::nick(wanted))?;
+    loop {
+        match reader.read_frame()? {
+            Some(frame) if frame.kind == MessageType::Nick => {
+                return Ok(Ok(frame.as_str().unwrap_or_default().to_string()));
             }
-            Err(e) => {
-                eprintln!("[client] Stdin error: {}", e);
-                break;
+            Some(frame) if frame.kind == MessageType::Error => {
+                return Ok(Err(frame.as_str().unwrap_or_default().to_string()));
+            }
+            // A version 2 server sends the refusal as an event.
+            Some(frame) if frame.kind == MessageType::Event => {
+                if let Ok(ChatEvent::Error { text }) = frame.to_event() {
+                    return Ok(Err(text));
+                }
+            }
+            Some(frame) if frame.kind == MessageType::Ping => {
+                send(writer, &Frame::pong(&frame))?;
+            }
+            Some(_) => {}
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "server closed the connection during login",
+                ))
             }
         }
     }
-
-    println!("[client] Disconnecting...");
-    let _ = receiver.join();
-
-    Ok(())
 }
 
 // ---------------------------------------------------------------------------
@@ -129,13 +789,16 @@ fn main() -> io::Result<()> {
 //    to also exit? Look into std::sync::atomic::AtomicBool as a shared flag,
 //    or a channel (std::sync::mpsc).
 //
-// 2. USERNAME: Send your username as the first line right after connecting,
+// 2. Did this one.
+//    USERNAME: Send your username as the first line right after connecting,
 //    before entering the read loop. The server will use it to label messages.
 //
-// 3. RECONNECT: If the connection drops, try to reconnect with exponential
+// 3. Did this one.
+//    RECONNECT: If the connection drops, try to reconnect with exponential
 //    backoff (wait 1s, then 2s, then 4s, etc.). Use std::thread::sleep.
 //
-// 4. TIMEOUT: Use TcpStream::set_read_timeout() to add a timeout.
+// 4. Did this one.
+//    TIMEOUT: Use TcpStream::set_read_timeout() to add a timeout.
 //    What error do you get when it fires? How do you distinguish a timeout
 //    from a real disconnect?
 // ---------------------------------------------------------------------------
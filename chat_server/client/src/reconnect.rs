@@ -0,0 +1,110 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Exponential backoff with jitter.
+//
+// When the server goes away, retrying in a tight loop just burns CPU and
+// floods the network with connection attempts. Waiting a fixed time is
+// better, but picture the server restarting with a thousand clients
+// attached: they all lost it at the same instant, so they all come back at
+// the same instant, every time. That's a thundering herd.
+//
+// Backoff doubles the wait after each failure (1s, 2s, 4s, ... up to a cap)
+// so a server that stays down costs us little. Jitter then picks a random
+// point in the upper half of that wait, which spreads the herd out.
+//
+// While we're away, whatever the user types waits in a bounded queue and
+// goes out once we're back. Bounded, because someone could leave the
+// client disconnected for hours with something piping into stdin.
+// ---------------------------------------------------------------------------
+
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let full = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt += 1;
+        full / 2 + full.mul_f64(random_fraction() / 2.0)
+    }
+
+    /// We're connected again: the next failure starts from the beginning.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+// A number in [0, 1). Good enough for jitter, and saves a dependency:
+// RandomState is seeded randomly for every process.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.write_u128(now.as_nanos());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Lines typed while disconnected, oldest first.
+pub struct Pending {
+    lines: VecDeque<String>,
+    cap: usize,
+}
+
+impl Pending {
+    pub fn new(cap: usize) -> Self {
+        Pending {
+            lines: VecDeque::new(),
+            cap,
+        }
+    }
+
+    /// Queue a line. Returns false if the queue was full and the oldest
+    /// line had to make room.
+    pub fn push(&mut self, line: String) -> bool {
+        let room = self.lines.len() < self.cap;
+        if !room {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+        room
+    }
+
+    /// Put a line back at the front, e.g. because sending it failed.
+    pub fn push_front(&mut self, line: String) {
+        if self.lines.len() >= self.cap {
+            self.lines.pop_back();
+        }
+        self.lines.push_front(line);
+    }
+
+    pub fn pop(&mut self) -> Option<String> {
+        self.lines.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
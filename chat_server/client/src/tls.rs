@@ -0,0 +1,237 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Two threads, one TLS session.
+//
+// The plain client splits its socket with try_clone(): one thread reads, the
+// other writes, no locking. A TLS session can't be cloned like that - both
+// directions share keys and sequence numbers inside one ClientConnection.
+//
+// So the session lives in an Arc<Mutex<>>, and each side still gets its own
+// clone of the socket. The reader blocks on the *socket* without holding
+// the lock, and only takes it for the moment it takes to decrypt what
+// arrived. The writer takes it to encrypt and send. Neither can starve the
+// other.
+//
+// How do we know we're talking to the real server? Either a CA we trust
+// signed its certificate (--tls-ca), or we were given the server's exact
+// certificate up front and accept nothing else (--tls-pin). Pinning is
+// what you want for a self-signed development certificate.
+// ---------------------------------------------------------------------------
+
+/// How to check the server's certificate.
+pub enum Trust {
+    /// Certificates signed by a CA in this PEM file.
+    Ca(PathBuf),
+    /// Exactly the certificate in this PEM file.
+    Pinned(PathBuf),
+}
+
+pub struct TlsOptions {
+    pub trust: Trust,
+    /// The name the certificate must be for (ignored when pinning).
+    pub server_name: String,
+}
+
+/// Run the TLS handshake and then the protocol handshake over it. Returns
+/// the protocol version and the two halves of the connection.
+pub fn connect(stream: TcpStream, options: &TlsOptions) -> io::Result<(u8, TlsReader, TlsWriter)> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+    let config = match &options.trust {
+        Trust::Ca(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(invalid_data)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        Trust::Pinned(path) => {
+            let cert = load_certs(path)?.remove(0);
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCert { cert, provider }))
+                .with_no_client_auth()
+        }
+    };
+    let name = ServerName::try_from(options.server_name.clone()).map_err(invalid_data)?;
+    let session = ClientConnection::new(Arc::new(config), name).map_err(invalid_data)?;
+
+    // The handshake runs on the first read or write, here the protocol
+    // hello.
+    let mut tls = StreamOwned::new(session, stream);
+    let version = protocol::client_handshake(&mut tls)?;
+
+    let (session, stream) = tls.into_parts();
+    let shared = Arc::new(Mutex::new(session));
+    let reader = TlsReader {
+        session: Arc::clone(&shared),
+        stream: stream.try_clone()?,
+        writer: stream.try_clone()?,
+    };
+    let writer = TlsWriter {
+        session: shared,
+        stream,
+    };
+    Ok((version, reader, writer))
+}
+
+pub struct TlsReader {
+    session: Arc<Mutex<ClientConnection>>,
+    stream: TcpStream,
+    // For anything rustls has to answer by itself (alerts, key updates).
+    writer: TcpStream,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = [0u8; 4096];
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                match session.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+            }
+            // Wait for more ciphertext without holding the lock, so the
+            // writer can carry on meanwhile. Read timeouts surface here.
+            let n = self.stream.read(&mut incoming)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut session = self.session.lock().unwrap();
+            let mut received = &incoming[..n];
+            while !received.is_empty() {
+                session.read_tls(&mut received)?;
+                session
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            while session.wants_write() {
+                session.write_tls(&mut self.writer)?;
+            }
+        }
+    }
+}
+
+pub struct TlsWriter {
+    session: Arc<Mutex<ClientConnection>>,
+    stream: TcpStream,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = session.writer().write(buf)?;
+        while session.wants_write() {
+            session.write_tls(&mut self.stream)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        while session.wants_write() {
+            session.write_tls(&mut self.stream)?;
+        }
+        Ok(())
+    }
+}
+
+// Accepts the one certificate we were given and nothing else. The handshake
+// signatures are still checked, so the server must hold the matching key.
+#[derive(Debug)]
+struct PinnedCert {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use protocol::Frame;
+use sha2::{Digest, Sha256};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Sending a file through a chat server.
+//
+// "/send bob report.csv" never puts the file in a chat message. The client
+// reads the file once to get its size and SHA-256 hash, and offers it:
+// "/offer bob 1234 <hash> report.csv". If bob accepts, the file follows in
+// binary Chunk frames, which the server passes on to him one by one.
+//
+// Two threads on each side are busy with it:
+//
+//   sending   - a thread of its own reads the file and writes chunks, but
+//               never more than WINDOW of them past what the server says
+//               has reached bob. Without that, a big file would land in
+//               bob's outbox all at once, and the server would drop it (or
+//               him) as a client that can't keep up.
+//   receiving - the receiver thread writes each chunk to "report.csv.part"
+//               and feeds it to a hasher as it goes. Once every byte is in,
+//               the hash has to match the offer, or the file is deleted:
+//               whatever went wrong on the way, bob never ends up with a
+//               file that only looks right.
+// ---------------------------------------------------------------------------
+
+// Chunks that may be on their way at once.
+const WINDOW: u64 = 8;
+
+// How long a transfer may go without any progress before we give up on it.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A file as /send offers it.
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    /// SHA-256 of the contents, in hex.
+    pub sha256: String,
+}
+
+/// Read `path` through once for its size and hash.
+pub fn inspect(path: &Path) -> io::Result<FileInfo> {
+    let mut file = File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file"));
+    }
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok(FileInfo {
+        name,
+        size,
+        sha256: hex(&hasher.finalize()),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// "512 bytes", "12.3 KiB", "4.0 MiB".
+pub fn size_text(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} bytes", bytes),
+        1024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// How far a file we're sending has got. The receiver thread hears about
+/// it; the thread sending the file waits on it.
+#[derive(Default)]
+pub struct Progress {
+    state: Mutex<(u64, bool)>,
+    changed: Condvar,
+}
+
+impl Progress {
+    fn update(&self, delivered: u64) {
+        self.state.lock().unwrap().0 = delivered;
+        self.changed.notify_all();
+    }
+
+    fn cancel(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.changed.notify_all();
+    }
+
+    // Wait until there's room for another chunk after `sent` bytes.
+    fn wait_for_room(&self, sent: u64) -> io::Result<()> {
+        let window = WINDOW * protocol::MAX_CHUNK as u64;
+        let state = self.state.lock().unwrap();
+        let (state, timeout) = self
+            .changed
+            .wait_timeout_while(state, STALL_TIMEOUT, |(delivered, cancelled)| {
+                !*cancelled && sent >= *delivered + window
+            })
+            .unwrap();
+        if state.1 {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"))
+        } else if timeout.timed_out() {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the recipient stopped taking it",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A file to send, once the recipient has said yes.
+pub struct Upload {
+    pub id: u64,
+    pub name: String,
+    path: PathBuf,
+    size: u64,
+    progress: Arc<Progress>,
+}
+
+impl Upload {
+    /// Send the file as Chunk frames, paced by the progress reports. Fails
+    /// with Interrupted if the transfer is cancelled meanwhile.
+    pub fn send(&self, mut send: impl FnMut(&Frame) -> io::Result<()>) -> io::Result<()> {
+        let mut file = File::open(&self.path)?;
+        let mut buf = vec![0u8; protocol::MAX_CHUNK];
+        let mut sent = 0;
+        while sent < self.size {
+            self.progress.wait_for_room(sent)?;
+            let want = (self.size - sent).min(buf.len() as u64) as usize;
+            let n = file.read(&mut buf[..want])?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the file got shorter since it was offered",
+                ));
+            }
+            send(&Frame::chunk(self.id, &buf[..n]))?;
+            sent += n as u64;
+        }
+        Ok(())
+    }
+}
+
+// A file we offered.
+struct Outgoing {
+    to: String,
+    path: PathBuf,
+    info: FileInfo,
+    // Known once the server echoes the offer back.
+    id: Option<u64>,
+    progress: Arc<Progress>,
+    // The last quarter reported.
+    shown: u64,
+}
+
+// A file offered to us.
+struct Incoming {
+    from: String,
+    name: String,
+    size: u64,
+    sha256: String,
+    // Set once we've accepted.
+    download: Option<Download>,
+}
+
+struct Download {
+    file: File,
+    part: PathBuf,
+    dest: PathBuf,
+    received: u64,
+    hasher: Sha256,
+    shown: u64,
+}
+
+/// What the receiver should do about a transfer event.
+#[derive(Default)]
+pub struct Outcome {
+    /// Lines for the user.
+    pub notes: Vec<String>,
+    /// Start sending this file.
+    pub upload: Option<Upload>,
+    /// Tell the server to call this transfer off.
+    pub cancel: Option<u64>,
+}
+
+impl Outcome {
+    fn note(note: String) -> Self {
+        Outcome {
+            notes: vec![note],
+            ..Outcome::default()
+        }
+    }
+}
+
+/// Every transfer this client is part of.
+pub struct Transfers {
+    downloads: PathBuf,
+    outgoing: Vec<Outgoing>,
+    incoming: HashMap<u64, Incoming>,
+}
+
+impl Transfers {
+    /// Received files are saved in `downloads`.
+    pub fn new(downloads: PathBuf) -> Self {
+        Transfers {
+            downloads,
+            outgoing: Vec::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    /// We're about to offer `path` to `to`.
+    pub fn offering(&mut self, to: &str, path: PathBuf, info: FileInfo) {
+        self.outgoing.push(Outgoing {
+            to: to.to_string(),
+            path,
+            info,
+            id: None,
+            progress: Arc::default(),
+            shown: 0,
+        });
+    }
+
+    /// The server passed on an offer: ours coming back with its id, or
+    /// someone else's for us. `me` is our nickname.
+    pub fn offer(
+        &mut self,
+        me: &str,
+        id: u64,
+        (from, to): (&str, &str),
+        name: &str,
+        size: u64,
+        sha256: &str,
+    ) -> Outcome {
+        if from.eq_ignore_ascii_case(me) {
+            let ours = self.outgoing.iter_mut().find(|o| {
+                o.id.is_none()
+                    && o.to.eq_ignore_ascii_case(to)
+                    && o.info.name == name
+                    && o.info.sha256 == sha256
+            });
+            return match ours {
+                Some(outgoing) => {
+                    outgoing.id = Some(id);
+                    Outcome::note(format!(
+                        "Offered {} to {} (transfer {}); waiting for an answer",
+                        name, to, id
+                    ))
+                }
+                None => Outcome::default(),
+            };
+        }
+        self.incoming.insert(
+            id,
+            Incoming {
+                from: from.to_string(),
+                name: name.to_string(),
+                size,
+                sha256: sha256.to_string(),
+                download: None,
+            },
+        );
+        Outcome::note(format!(
+            "{} wants to send you {} ({}). /accept {} or /reject {}",
+            from,
+            name,
+            size_text(size),
+            id,
+            id
+        ))
+    }
+
+    /// Transfer `id` was accepted: by them, so start sending, or by us, so
+    /// get ready to receive.
+    pub fn accepted(&mut self, id: u64) -> Outcome {
+        if let Some(outgoing) = self.outgoing.iter().find(|o| o.id == Some(id)) {
+            return Outcome {
+                notes: vec![format!(
+                    "{} accepted {}; sending",
+                    outgoing.to, outgoing.info.name
+                )],
+                upload: Some(Upload {
+                    id,
+                    name: outgoing.info.name.clone(),
+                    path: outgoing.path.clone(),
+                    size: outgoing.info.size,
+                    progress: Arc::clone(&outgoing.progress),
+                }),
+                cancel: None,
+            };
+        }
+        let Some(incoming) = self.incoming.get_mut(&id) else {
+            return Outcome::default();
+        };
+        match open_download(&self.downloads, &incoming.name) {
+            Ok(download) => {
+                let note = format!(
+                    "Receiving {} from {} into {}",
+                    incoming.name,
+                    incoming.from,
+                    download.dest.display()
+                );
+                incoming.download = Some(download);
+                // An empty file is already all here.
+                if incoming.size == 0 {
+                    return self.finish(id, note);
+                }
+                Outcome::note(note)
+            }
+            Err(e) => {
+                self.incoming.remove(&id);
+                Outcome {
+                    notes: vec![format!("Could not save the file: {}", e)],
+                    cancel: Some(id),
+                    ..Outcome::default()
+                }
+            }
+        }
+    }
+
+    /// `bytes` of a file we're sending have reached the recipient.
+    pub fn progress(&mut self, id: u64, bytes: u64) -> Outcome {
+        let Some(at) = self.outgoing.iter().position(|o| o.id == Some(id)) else {
+            return Outcome::default();
+        };
+        let outgoing = &mut self.outgoing[at];
+        outgoing.progress.update(bytes);
+        if bytes >= outgoing.info.size {
+            let outgoing = self.outgoing.remove(at);
+            return Outcome::note(format!(
+                "{} has {} ({})",
+                outgoing.to,
+                outgoing.info.name,
+                size_text(outgoing.info.size)
+            ));
+        }
+        match quarter(bytes, outgoing.info.size, &mut outgoing.shown) {
+            Some(percent) => {
+                Outcome::note(format!("{}: {}% delivered", outgoing.info.name, percent))
+            }
+            None => Outcome::default(),
+        }
+    }
+
+    /// A piece of a file we're receiving.
+    pub fn chunk(&mut self, id: u64, bytes: &[u8]) -> Outcome {
+        let Some(incoming) = self.incoming.get_mut(&id) else {
+            return Outcome::default();
+        };
+        let Some(download) = &mut incoming.download else {
+            return Outcome::default();
+        };
+        if download.received + bytes.len() as u64 > incoming.size {
+            return self.fail(id, "more data than was offered".to_string());
+        }
+        if let Err(e) = download.file.write_all(bytes) {
+            return self.fail(id, format!("could not write it: {}", e));
+        }
+        download.hasher.update(bytes);
+        download.received += bytes.len() as u64;
+        if download.received == incoming.size {
+            return self.finish(id, String::new());
+        }
+        match quarter(download.received, incoming.size, &mut download.shown) {
+            Some(percent) => Outcome::note(format!("{}: {}% received", incoming.name, percent)),
+            None => Outcome::default(),
+        }
+    }
+
+    /// Transfer `id` is off.
+    pub fn cancelled(&mut self, id: u64, reason: &str) -> Outcome {
+        if let Some(at) = self.outgoing.iter().position(|o| o.id == Some(id)) {
+            let outgoing = self.outgoing.remove(at);
+            outgoing.progress.cancel();
+            return Outcome::note(format!("{} not sent: {}", outgoing.info.name, reason));
+        }
+        match self.incoming.remove(&id) {
+            Some(incoming) => {
+                discard(incoming.download);
+                Outcome::note(format!("{} not received: {}", incoming.name, reason))
+            }
+            None => Outcome::default(),
+        }
+    }
+
+    /// The connection dropped, and every transfer with it.
+    pub fn reset(&mut self) -> Vec<String> {
+        let mut notes = Vec::new();
+        for outgoing in self.outgoing.drain(..) {
+            outgoing.progress.cancel();
+            notes.push(format!("{} not sent: connection lost", outgoing.info.name));
+        }
+        for (_, incoming) in self.incoming.drain() {
+            if incoming.download.is_some() {
+                notes.push(format!("{} not received: connection lost", incoming.name));
+            }
+            discard(incoming.download);
+        }
+        notes
+    }
+
+    // Every byte is in: keep the file if the hash matches.
+    fn finish(&mut self, id: u64, mut note: String) -> Outcome {
+        let Some(incoming) = self.incoming.remove(&id) else {
+            return Outcome::default();
+        };
+        let Some(download) = incoming.download else {
+            return Outcome::default();
+        };
+        let Download {
+            file,
+            part,
+            dest,
+            hasher,
+            ..
+        } = download;
+        drop(file);
+        if !note.is_empty() {
+            note.push_str(". ");
+        }
+        if hex(&hasher.finalize()) != incoming.sha256 {
+            let _ = fs::remove_file(&part);
+            note.push_str(&format!(
+                "{} from {} failed its SHA-256 check and was deleted",
+                incoming.name, incoming.from
+            ));
+            return Outcome::note(note);
+        }
+        match fs::rename(&part, &dest) {
+            Ok(()) => note.push_str(&format!(
+                "Saved {} ({}, SHA-256 checked)",
+                dest.display(),
+                size_text(incoming.size)
+            )),
+            Err(e) => note.push_str(&format!("Could not save {}: {}", dest.display(), e)),
+        }
+        Outcome::note(note)
+    }
+
+    // Give up on receiving `id`, and ask the server to call it off.
+    fn fail(&mut self, id: u64, why: String) -> Outcome {
+        let name = self.incoming.remove(&id).map(|incoming| {
+            discard(incoming.download);
+            incoming.name
+        });
+        Outcome {
+            notes: vec![format!(
+                "{} not received: {}",
+                name.unwrap_or_default(),
+                why
+            )],
+            cancel: Some(id),
+            ..Outcome::default()
+        }
+    }
+}
+
+// A percentage worth reporting, once per quarter of the file.
+fn quarter(done: u64, size: u64, shown: &mut u64) -> Option<u64> {
+    let now = done * 4 / size.max(1);
+    if now <= *shown || now >= 4 {
+        return None;
+    }
+    *shown = now;
+    Some(now * 25)
+}
+
+// Where to save `name`: in `dir`, under a name nothing has yet. The bytes
+// go to a ".part" file next to it until the hash checks out.
+fn open_download(dir: &Path, name: &str) -> io::Result<Download> {
+    fs::create_dir_all(dir)?;
+    // Only the last component, in case the server let a path through.
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "download".to_string());
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+        _ => (name.clone(), String::new()),
+    };
+    for n in 0.. {
+        let dest = match n {
+            0 => dir.join(&name),
+            n => dir.join(format!("{} ({}){}", stem, n, ext)),
+        };
+        let part = PathBuf::from(format!("{}.part", dest.display()));
+        if dest.exists() {
+            continue;
+        }
+        match OpenOptions::new().write(true).create_new(true).open(&part) {
+            Ok(file) => {
+                return Ok(Download {
+                    file,
+                    part,
+                    dest,
+                    received: 0,
+                    hasher: Sha256::new(),
+                    shown: 0,
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("some name is always free")
+}
+
+// Throw away a half-received file.
+fn discard(download: Option<Download>) {
+    if let Some(download) = download {
+        drop(download.file);
+        let _ = fs::remove_file(download.part);
+    }
+}
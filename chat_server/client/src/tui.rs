@@ -0,0 +1,563 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use protocol::{Event as ChatEvent, Status};
+use ratatui::crossterm::event::{
+    self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use ratatui::layout::{Alignment, Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use unicode_width::UnicodeWidthChar;
+
+use crate::editor::Editor;
+use crate::ui::{self, Kind};
+use crate::Event;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Owning the whole screen.
+//
+// The plain client prints a line and lets the terminal scroll. A
+// full-screen interface instead draws every cell itself, so it can keep
+// regions apart:
+//
+//   +--------------------------------------+-----------+
+//   | messages, scrolled with PgUp/PgDn     | who's     |
+//   |                                      | online    |
+//   +--------------------------------------+-----------+
+//   | status: server, nick, queued lines               |
+//   | > the line being typed                           |
+//   +--------------------------------------------------+
+//
+// ratatui does the drawing: each frame we describe the whole screen, and it
+// works out which cells changed and sends only those. crossterm puts the
+// terminal into raw mode (every key as it's pressed, no echo) and on the
+// "alternate screen", so the shell's own scrollback comes back untouched
+// when we quit.
+//
+// One thread owns the terminal. Everyone else sends it Updates (see ui.rs),
+// and it hands finished lines to main the way the stdin thread does in
+// plain mode. Between keys it wakes every TICK to take in what arrived.
+//
+// The user list starts from a /who sent on every connect, whose reply is
+// read rather than shown, and follows presence events from there.
+// ---------------------------------------------------------------------------
+
+/// Something for the screen to show.
+pub enum Update {
+    Line(Kind, String),
+    Notice(String),
+    /// Presence and nickname changes, for the user list.
+    Event(ChatEvent),
+    /// Lines queued while disconnected, and lines not yet acked.
+    Counts(usize, usize),
+    Connected {
+        server: String,
+        nick: String,
+        version: u8,
+        secure: bool,
+    },
+    /// We have a new nickname.
+    Nick(String),
+    /// The connection is gone; the next try is this far off.
+    Retrying(Duration),
+    /// We've asked for /who: read the reply into the user list.
+    Who,
+    Quit,
+}
+
+// How often to look for updates while no key is pressed.
+const TICK: Duration = Duration::from_millis(50);
+
+// Lines kept for scrolling back.
+const SCROLLBACK: usize = 5000;
+
+// The user list's width, and the narrowest screen that still gets one.
+const SIDEBAR: u16 = 24;
+const SIDEBAR_MIN_WIDTH: u16 = 60;
+
+/// The running interface. Dropping it puts the terminal back.
+pub struct Screen {
+    updates: mpsc::Sender<Update>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Take over the terminal and start the UI thread. Typed lines go to main
+/// as Event::Line; quitting sends Event::StdinClosed.
+pub fn start(events: mpsc::Sender<Event>) -> io::Result<Screen> {
+    let mut terminal = ratatui::try_init()?;
+    let (updates, rx) = mpsc::channel();
+    ui::attach(updates.clone());
+    let thread = thread::spawn(move || {
+        let result = App::new(events.clone()).run(&mut terminal, rx);
+        ratatui::restore();
+        if let Err(e) = result {
+            eprintln!("[client] Screen error: {}", e);
+        }
+        // However we got here, nobody is typing any more.
+        let _ = events.send(Event::StdinClosed);
+    });
+    Ok(Screen {
+        updates,
+        thread: Some(thread),
+    })
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = self.updates.send(Update::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Where the connection stands, for the status bar.
+enum Link {
+    Connecting,
+    Connected {
+        server: String,
+        version: u8,
+        secure: bool,
+    },
+    Retrying(Duration),
+}
+
+// Reading a /who reply.
+enum Who {
+    Idle,
+    // We asked; the reply is for the user list only.
+    Asked,
+    // This many lines to go.
+    Reading { left: usize, shown: bool },
+}
+
+// Someone in the user list.
+struct User {
+    nick: String,
+    away: bool,
+}
+
+// What a key press means for the loop.
+enum Flow {
+    Go,
+    Redraw,
+    Quit,
+}
+
+struct App {
+    events: mpsc::Sender<Event>,
+    editor: Editor,
+    messages: VecDeque<(Kind, String)>,
+    // How many rows up from the newest line we've scrolled.
+    scroll: usize,
+    // The message pane's size at the last draw.
+    pane: (usize, usize),
+    // By lowercased nickname.
+    users: BTreeMap<String, User>,
+    who: Who,
+    link: Link,
+    nick: String,
+    counts: (usize, usize),
+}
+
+impl App {
+    fn new(events: mpsc::Sender<Event>) -> Self {
+        App {
+            events,
+            editor: Editor::default(),
+            messages: VecDeque::new(),
+            scroll: 0,
+            pane: (80, 20),
+            users: BTreeMap::new(),
+            who: Who::Idle,
+            link: Link::Connecting,
+            nick: String::new(),
+            counts: (0, 0),
+        }
+    }
+
+    fn run(
+        mut self,
+        terminal: &mut DefaultTerminal,
+        updates: mpsc::Receiver<Update>,
+    ) -> io::Result<()> {
+        let mut dirty = true;
+        loop {
+            if dirty {
+                terminal.draw(|frame| self.draw(frame))?;
+                dirty = false;
+            }
+            if event::poll(TICK)? {
+                if let TermEvent::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Release {
+                        match self.key(key) {
+                            Flow::Go => {}
+                            Flow::Redraw => terminal.clear()?,
+                            Flow::Quit => return Ok(()),
+                        }
+                    }
+                }
+                // Resizes and the like need a redraw too.
+                dirty = true;
+            }
+            loop {
+                match updates.try_recv() {
+                    Ok(Update::Quit) | Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                    Ok(update) => {
+                        self.apply(update);
+                        dirty = true;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                }
+            }
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent) -> Flow {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let editor = &mut self.editor;
+        match key.code {
+            KeyCode::Char('c') if ctrl => return Flow::Quit,
+            KeyCode::Char('d') if ctrl && editor.is_empty() => return Flow::Quit,
+            KeyCode::Char('l') if ctrl => return Flow::Redraw,
+            KeyCode::Char('a') if ctrl => editor.home(),
+            KeyCode::Char('e') if ctrl => editor.end(),
+            KeyCode::Char('b') if ctrl => editor.left(),
+            KeyCode::Char('f') if ctrl => editor.right(),
+            KeyCode::Char('d') if ctrl => editor.delete(),
+            KeyCode::Char('u') if ctrl => editor.clear_before(),
+            KeyCode::Char('k') if ctrl => editor.clear_after(),
+            KeyCode::Char('w') if ctrl => editor.delete_word(),
+            KeyCode::Char('p') if ctrl => editor.previous(),
+            KeyCode::Char('n') if ctrl => editor.next(),
+            KeyCode::Char(_) if ctrl => {}
+            KeyCode::Char(c) => editor.insert(c),
+            KeyCode::Backspace => editor.backspace(),
+            KeyCode::Delete => editor.delete(),
+            KeyCode::Left if ctrl => editor.word_left(),
+            KeyCode::Right if ctrl => editor.word_right(),
+            KeyCode::Left => editor.left(),
+            KeyCode::Right => editor.right(),
+            KeyCode::Home => editor.home(),
+            KeyCode::End => editor.end(),
+            KeyCode::Up => editor.previous(),
+            KeyCode::Down => editor.next(),
+            KeyCode::PageUp => self.scroll += self.page(),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page()),
+            KeyCode::Enter => {
+                let line = editor.take();
+                if !line.trim().is_empty() {
+                    self.scroll = 0;
+                    if self.events.send(Event::Line(line)).is_err() {
+                        return Flow::Quit;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Flow::Go
+    }
+
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Line(kind, text) => self.push(kind, text),
+            Update::Notice(text) => self.notice(text),
+            Update::Event(event) => self.presence(event),
+            Update::Counts(queued, unacked) => self.counts = (queued, unacked),
+            Update::Connected {
+                server,
+                nick,
+                version,
+                secure,
+            } => {
+                self.link = Link::Connected {
+                    server,
+                    version,
+                    secure,
+                };
+                self.nick = nick;
+                // Whoever was around last time, /who will tell us again.
+                self.users.clear();
+            }
+            Update::Nick(nick) => self.nick = nick,
+            Update::Retrying(delay) => self.link = Link::Retrying(delay),
+            Update::Who => self.who = Who::Asked,
+            Update::Quit => {}
+        }
+    }
+
+    fn push(&mut self, kind: Kind, text: String) {
+        // Keep what's on screen in place while scrolled back.
+        if self.scroll > 0 {
+            self.scroll += wrap(&text, self.pane.0).len();
+        }
+        if self.messages.len() == SCROLLBACK {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((kind, text));
+    }
+
+    // A /who reply is "N users online:" and then a line for each of them,
+    // like "bob - idle 2m, connected 1h, away: lunch".
+    fn notice(&mut self, text: String) {
+        match self.who {
+            Who::Reading { left, shown } => {
+                if let Some((nick, about)) = text.split_once(" - ") {
+                    let user = User {
+                        nick: nick.to_string(),
+                        away: about.contains(", away: "),
+                    };
+                    self.users.insert(nick.to_ascii_lowercase(), user);
+                }
+                self.who = match left {
+                    1 => Who::Idle,
+                    left => Who::Reading {
+                        left: left - 1,
+                        shown,
+                    },
+                };
+                if !shown {
+                    return;
+                }
+            }
+            _ => {
+                if let Some(count) = who_count(&text) {
+                    let shown = !matches!(self.who, Who::Asked);
+                    self.users.clear();
+                    self.who = match count {
+                        0 => Who::Idle,
+                        left => Who::Reading { left, shown },
+                    };
+                    if !shown {
+                        return;
+                    }
+                }
+            }
+        }
+        self.push(Kind::Notice, format!("*** {}", text));
+    }
+
+    fn presence(&mut self, event: ChatEvent) {
+        match event {
+            ChatEvent::Presence { nick, status, .. } => {
+                let key = nick.to_ascii_lowercase();
+                match status {
+                    Status::Offline => {
+                        self.users.remove(&key);
+                    }
+                    status => {
+                        let away = status == Status::Away;
+                        self.users.insert(key, User { nick, away });
+                    }
+                }
+            }
+            ChatEvent::Nick { old, new } => {
+                if let Some(mut user) = self.users.remove(&old.to_ascii_lowercase()) {
+                    user.nick = new.clone();
+                    self.users.insert(new.to_ascii_lowercase(), user);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn page(&self) -> usize {
+        self.pane.1.saturating_sub(1).max(1)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [body, status, input] = Layout::vertical([
+            Constraint::Min(1),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let pane = if body.width >= SIDEBAR_MIN_WIDTH {
+            let [pane, sidebar] =
+                Layout::horizontal([Constraint::Min(1), Constraint::Length(SIDEBAR)]).areas(body);
+            frame.render_widget(self.user_list(), sidebar);
+            pane
+        } else {
+            body
+        };
+        self.pane = (pane.width as usize, pane.height as usize);
+        frame.render_widget(Paragraph::new(self.rows()), pane);
+        self.status_bar(frame, status);
+
+        let (line, cursor) = self.input_line(input.width as usize);
+        frame.render_widget(Paragraph::new(line), input);
+        frame.set_cursor_position((input.x + cursor as u16, input.y));
+    }
+
+    // The rows that fit in the message pane, newest at the bottom.
+    fn rows(&mut self) -> Vec<Line<'static>> {
+        let (width, height) = self.pane;
+        let mut rows = Vec::new();
+        for (kind, text) in self.messages.iter().rev() {
+            let style = style(*kind);
+            for row in wrap(text, width).into_iter().rev() {
+                rows.push(Line::styled(row, style));
+            }
+            if rows.len() >= height + self.scroll {
+                break;
+            }
+        }
+        // Can't scroll past the oldest line.
+        self.scroll = self.scroll.min(rows.len().saturating_sub(height));
+        let mut shown: Vec<Line> = rows.into_iter().skip(self.scroll).take(height).collect();
+        // Short of a full pane, the lines sit at the bottom.
+        shown.resize(height, Line::default());
+        shown.reverse();
+        shown
+    }
+
+    fn user_list(&self) -> Paragraph<'static> {
+        let lines: Vec<Line> = self
+            .users
+            .values()
+            .map(|user| {
+                let mut style = Style::new();
+                if user.nick == self.nick {
+                    style = style.add_modifier(Modifier::BOLD);
+                }
+                if user.away {
+                    Line::styled(format!("{} (away)", user.nick), style.fg(Color::DarkGray))
+                } else {
+                    Line::styled(user.nick.clone(), style)
+                }
+            })
+            .collect();
+        let title = format!(" Online ({}) ", self.users.len());
+        Paragraph::new(lines).block(Block::new().borders(Borders::LEFT).title(title))
+    }
+
+    fn status_bar(&self, frame: &mut Frame, area: Rect) {
+        let (mut text, colour) = match &self.link {
+            Link::Connecting => ("connecting".to_string(), Color::Yellow),
+            Link::Connected {
+                server,
+                version,
+                secure,
+            } => (
+                format!(
+                    "{} on {} (protocol v{}{})",
+                    self.nick,
+                    server,
+                    version,
+                    if *secure { ", TLS" } else { "" }
+                ),
+                Color::Blue,
+            ),
+            Link::Retrying(delay) => (
+                format!("disconnected; next try in {:.1}s", delay.as_secs_f64()),
+                Color::Red,
+            ),
+        };
+        match self.counts {
+            (0, 0) => {}
+            (queued, 0) => text.push_str(&format!(" | {} queued", queued)),
+            (0, unacked) => text.push_str(&format!(" | {} pending", unacked)),
+            (queued, unacked) => {
+                text.push_str(&format!(" | {} queued, {} pending", queued, unacked))
+            }
+        }
+        if self.scroll > 0 {
+            text.push_str(" | more below");
+        }
+        let style = Style::new().bg(colour).fg(Color::White);
+        frame.render_widget(Paragraph::new(format!(" {}", text)).style(style), area);
+        let help = "PgUp/PgDn scroll, Ctrl+C quit ";
+        if area.width as usize > text.len() + help.len() + 4 {
+            let help = Paragraph::new(help).alignment(Alignment::Right);
+            frame.render_widget(help.style(style), area);
+        }
+    }
+
+    // The input line, scrolled sideways to keep the cursor in view, and
+    // the column the cursor is in.
+    fn input_line(&self, width: usize) -> (Line<'static>, usize) {
+        let prompt = "> ";
+        let room = width.saturating_sub(prompt.len() + 1).max(1);
+        let before = columns(self.editor.before_cursor());
+        let skip = before.saturating_sub(room);
+        let mut skipped = 0;
+        let mut shown = String::new();
+        for c in self.editor.line().chars() {
+            if skipped < skip {
+                skipped += c.width().unwrap_or(0);
+            } else {
+                shown.push(c);
+            }
+        }
+        let line = Line::from(vec![
+            Span::styled(prompt, Style::new().fg(Color::Cyan)),
+            shown.into(),
+        ]);
+        (line, prompt.len() + before - skipped)
+    }
+}
+
+// "3 users online:" -> 3.
+fn who_count(text: &str) -> Option<usize> {
+    let (count, word) = text.strip_suffix(" online:")?.split_once(' ')?;
+    match word {
+        "user" | "users" => count.parse().ok(),
+        _ => None,
+    }
+}
+
+fn style(kind: Kind) -> Style {
+    let style = Style::new();
+    match kind {
+        Kind::Chat => style,
+        Kind::Direct => style.fg(Color::Magenta),
+        Kind::Notice => style.fg(Color::Cyan),
+        Kind::Error => style.fg(Color::Red),
+        Kind::Client => style.fg(Color::Yellow),
+        Kind::Sent => style.fg(Color::DarkGray),
+        Kind::File => style.fg(Color::Green),
+    }
+}
+
+fn columns(text: &str) -> usize {
+    text.chars().map(|c| c.width().unwrap_or(0)).sum()
+}
+
+// Split `text` into rows at most `width` columns wide, breaking after a
+// space where there is one.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(2);
+    let mut rows = Vec::new();
+    for line in text.split('\n') {
+        let mut row = String::new();
+        let mut used = 0;
+        for c in line.chars() {
+            // Tabs and other control characters would throw the columns
+            // off.
+            let c = if c.is_control() { ' ' } else { c };
+            let w = c.width().unwrap_or(0);
+            if used + w > width {
+                let rest = match row.rfind(' ') {
+                    Some(at) if at + 1 < row.len() => row.split_off(at + 1),
+                    _ => String::new(),
+                };
+                rows.push(std::mem::replace(&mut row, rest));
+                used = columns(&row);
+                if used + w > width {
+                    rows.push(std::mem::take(&mut row));
+                    used = 0;
+                }
+            }
+            row.push(c);
+            used += w;
+        }
+        rows.push(row);
+    }
+    rows
+}
@@ -0,0 +1,152 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex, OnceLock};
+
+use protocol::Event as ChatEvent;
+
+use crate::tui::Update;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Three ways to show a chat.
+//
+//   plain  - lines on stdout, under a "> " prompt. Each message from the
+//            server is printed over the prompt (the \r trick), which works
+//            anywhere but splits whatever you were halfway through typing.
+//   raw    - JSON events on stdout, one per line, for scripts (--raw).
+//            Everything meant for a person goes to stderr.
+//   screen - a full-screen interface (see tui.rs) that keeps the messages
+//            and the line being typed apart. The default on a terminal.
+//
+// Main, the receiver and the file uploads all have things to say, and none
+// of them should have to care which mode we're in. So they say it here. In
+// screen mode it goes over a channel to the UI thread, the only one allowed
+// to touch the terminal; once that's gone, we're back to plain lines.
+// ---------------------------------------------------------------------------
+
+/// What a line is, so the screen can colour it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Someone talking in a room.
+    Chat,
+    /// A private message, which shouldn't blend into room traffic.
+    Direct,
+    /// Presence, joins and other news from the server.
+    Notice,
+    /// The server refusing something.
+    Error,
+    /// The client's own messages.
+    Client,
+    /// What happened to our own lines: sent, dropped, delivered.
+    Sent,
+    /// File transfers.
+    File,
+}
+
+static RAW: AtomicBool = AtomicBool::new(false);
+
+static SCREEN: OnceLock<mpsc::Sender<Update>> = OnceLock::new();
+
+// Lines queued while disconnected, and lines sent but not yet acked.
+static COUNTS: Mutex<(usize, usize)> = Mutex::new((0, 0));
+
+pub fn set_raw(on: bool) {
+    RAW.store(on, Ordering::Relaxed);
+}
+
+pub fn raw() -> bool {
+    RAW.load(Ordering::Relaxed)
+}
+
+/// From now on, everything goes to the screen.
+pub fn attach(updates: mpsc::Sender<Update>) {
+    let _ = SCREEN.set(updates);
+}
+
+/// Whether we started the full-screen interface.
+pub fn has_screen() -> bool {
+    SCREEN.get().is_some()
+}
+
+/// Tell the screen something only it shows, like the connection state.
+/// Other modes have nothing to do with it.
+pub fn update(update: Update) {
+    to_screen(update);
+}
+
+/// A line for a person to read, printed over the prompt.
+pub fn show(kind: Kind, text: &str) {
+    if to_screen(Update::Line(kind, text.to_string())) {
+        return;
+    }
+    if raw() {
+        eprintln!("{}", text);
+    } else {
+        print!("\r{}\n{}", text, prompt_text());
+        io::stdout().flush().ok();
+    }
+}
+
+/// A notice from the server. The screen reads /who replies from these.
+pub fn notice(text: &str) {
+    if !to_screen(Update::Notice(text.to_string())) {
+        show(Kind::Notice, &format!("*** {}", text));
+    }
+}
+
+/// Someone's presence or name changed. Only the screen keeps a user list.
+pub fn presence(event: &ChatEvent) {
+    if matches!(event, ChatEvent::Presence { .. } | ChatEvent::Nick { .. }) {
+        to_screen(Update::Event(event.clone()));
+    }
+}
+
+/// The client's own news, on a line of its own.
+pub fn note(text: &str) {
+    if to_screen(Update::Line(Kind::Client, text.trim_start().to_string())) {
+        return;
+    }
+    if raw() {
+        eprintln!("{}", text);
+    } else {
+        println!("{}", text);
+    }
+}
+
+/// Something going wrong on our side.
+pub fn error(text: &str) {
+    if !to_screen(Update::Line(Kind::Error, text.trim_start().to_string())) {
+        eprintln!("{}", text);
+    }
+}
+
+/// Note how many lines are queued and unacked, without redrawing anything.
+pub fn counts(queued: usize, unacked: usize) {
+    *COUNTS.lock().unwrap() = (queued, unacked);
+    to_screen(Update::Counts(queued, unacked));
+}
+
+/// Show the prompt, with how much is waiting to be sent or acked.
+pub fn prompt(queued: usize, unacked: usize) {
+    counts(queued, unacked);
+    if has_screen() || raw() {
+        return;
+    }
+    print!("{}", prompt_text());
+    io::stdout().flush().ok();
+}
+
+fn prompt_text() -> String {
+    match *COUNTS.lock().unwrap() {
+        (0, 0) => "> ".to_string(),
+        (queued, 0) => format!("[{} queued] > ", queued),
+        (0, unacked) => format!("[{} pending] > ", unacked),
+        (queued, unacked) => format!("[{} queued, {} pending] > ", queued, unacked),
+    }
+}
+
+// False if there's no screen (any more), so the caller prints it instead.
+fn to_screen(update: Update) -> bool {
+    SCREEN
+        .get()
+        .is_some_and(|screen| screen.send(update).is_ok())
+}
@@ -1 +1,96 @@
+use protocol::{Decoder, Event, Frame, FrameReader, Framing, MessageType};
+use server::server::Server;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+// The real client, scripted through --raw: lines in on stdin, JSON events
+// out on stdout, against a server embedded in the test on a free port.
+#[test]
+pub fn test_raw_client_against_a_live_server() {
+    let server = Server::builder()
+        .workers(1)
+        .build()
+        .unwrap()
+        .spawn()
+        .unwrap();
+    let addr = server.local_addr();
+
+    let mut client = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--raw", "--server", &addr.to_string(), "alice"])
+        .env_remove("CHAT_CLIENT_CONFIG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = client.stdin.take().unwrap();
+    let stdout = BufReader::new(client.stdout.take().unwrap());
+    // Read the client's output on a thread, so a missing event times out
+    // instead of hanging the test.
+    let (tx, events) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines().map_while(Result::ok) {
+            if tx.send(Event::from_json(&line).unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    let next_chat = || loop {
+        let event = events.recv_timeout(Duration::from_secs(10)).unwrap();
+        if let Event::Chat { .. } | Event::Direct { .. } = event {
+            return event;
+        }
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while server.hub().find("alice").is_none() {
+        assert!(Instant::now() < deadline, "the client never logged in");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    protocol::client_handshake(&mut stream).unwrap();
+    protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick("bob")).unwrap();
+    let mut bob = FrameReader::new(stream, Decoder::new(Framing::Framed));
+    assert_eq!(bob.read_frame().unwrap().unwrap().kind, MessageType::Nick);
+    let mut bob_hears = || loop {
+        let event = bob.read_frame().unwrap().unwrap().to_event().unwrap();
+        if let Event::Chat { .. } | Event::Direct { .. } = event {
+            return event;
+        }
+    };
+
+    writeln!(stdin, "hi bob").unwrap();
+    assert!(matches!(
+        bob_hears(),
+        Event::Chat { from, text, .. } if from == "alice" && text == "hi bob"
+    ));
+    writeln!(stdin, "/msg bob psst").unwrap();
+    assert!(matches!(
+        bob_hears(),
+        Event::Direct { from, text, .. } if from == "alice" && text == "psst"
+    ));
+
+    protocol::write_frame(bob.get_mut(), Framing::Framed, &Frame::text("hi alice")).unwrap();
+    assert!(matches!(
+        next_chat(),
+        Event::Chat { from, text, .. } if from == "bob" && text == "hi alice"
+    ));
+
+    // End of input is the end of the session.
+    drop(stdin);
+    assert!(client.wait().unwrap().success());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while server.hub().find("alice").is_some() {
+        assert!(Instant::now() < deadline, "the server never noticed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.stop().unwrap();
+}
@@ -0,0 +1,281 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Frame, FrameError, MessageType};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Say what happened, not how to show it.
+//
+// Version 1 of the protocol sends chat as text the server has already
+// formatted for humans: "lobby [alice]:hi". That's fine for a person
+// reading a terminal, but a bot that wants the sender has to pick the string
+// apart again - and breaks the day someone changes the format or picks a
+// nickname with a bracket in it.
+//
+// Version 2 sends typed events instead. Each one is a JSON object with a
+// "type" field saying what happened and a field for each detail:
+//
+//   {"type":"chat","id":17,"room":"lobby","from":"alice","text":"hi"}
+//   {"type":"nick","old":"alice","new":"alicia"}
+//
+// JSON because every language can read it, and because one object per
+// line ("JSON lines") is something tools like jq already understand. Turning
+// an event into text for a human is now the client's job (see Display
+// below), and older clients still get the version 1 text, made from the
+// same event by to_frame().
+//
+// Since version 3 every chat line gets an id from the server, counting up,
+// and the sender is told which: an Ack says "the Nth line you sent is
+// message 17". The client numbers its lines the same way (every Text frame
+// since it logged in counts, commands too), so it can tell which of its
+// lines made it. A Receipt goes further, for clients that ask for them:
+// message 17 was written out to bob's connection.
+//
+// Version 4 passes files between users. The sender offers one with its
+// size and SHA-256 hash, the recipient accepts or rejects, and the server
+// relays the file in binary Chunk frames - events would have to encode the
+// bytes as text. The server reports how much has reached the recipient, so
+// the sender never has more than a few chunks waiting anywhere, and the
+// recipient checks the hash once it has the lot.
+//
+// Federated servers (see federation.rs in the server) talk to each other with
+// the same events, each wrapped in a Relay that says which server it started
+// on and that server's number for it. That pair is how a server that hears
+// the same event twice, by two routes, knows to drop the second copy.
+// ---------------------------------------------------------------------------
+
+/// Something that happened in the chat, as version 2 clients receive it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Someone said something in a room.
+    Chat {
+        /// The server's id for the message (version 3). Replayed history
+        /// has none.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        room: String,
+        from: String,
+        text: String,
+        /// When it was said, in seconds since the Unix epoch. Only set on
+        /// messages replayed from history; live ones are happening now.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        time: Option<u64>,
+    },
+    /// A private message to us.
+    Direct {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        from: String,
+        text: String,
+    },
+    /// Someone joined a room we're in.
+    Join { room: String, nick: String },
+    /// Someone left a room we're in.
+    Leave {
+        room: String,
+        nick: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Someone we can see changed their nickname.
+    Nick { old: String, new: String },
+    /// Something the server wants us to know.
+    Notice { text: String },
+    /// The server rejected something we sent.
+    Error { text: String },
+    /// Someone came, went, or changed their status.
+    Presence {
+        nick: String,
+        status: Status,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// The server took the `seq`th line we sent since logging in (counting
+    /// from 1) and gave it the id `id`.
+    Ack { seq: u64, id: u64 },
+    /// Our message `id` was delivered to `to`. Only sent after asking with
+    /// /receipts on.
+    Receipt { id: u64, to: String },
+    /// `from` wants to send `to` a file (version 4). Both of them get it;
+    /// the sender learns the transfer's id this way.
+    FileOffer {
+        id: u64,
+        from: String,
+        to: String,
+        name: String,
+        size: u64,
+        /// The SHA-256 hash of the file, in hex.
+        sha256: String,
+    },
+    /// The recipient accepted transfer `id`: the sender may start sending.
+    FileAccepted { id: u64 },
+    /// `bytes` of transfer `id` have reached the recipient so far. Only the
+    /// sender gets these.
+    FileProgress { id: u64, bytes: u64 },
+    /// Transfer `id` is off: rejected, cancelled, or one side left.
+    FileCancelled { id: u64, reason: String },
+    /// Between federated servers only: `event` happened on the server named
+    /// `origin`, which numbered it `id`.
+    Relay {
+        origin: String,
+        id: u64,
+        event: Box<Event>,
+    },
+}
+
+/// What a Presence event says about someone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    Away,
+    Offline,
+}
+
+impl Event {
+    pub fn notice(text: &str) -> Self {
+        Event::Notice {
+            text: text.to_string(),
+        }
+    }
+
+    pub fn error(text: &str) -> Self {
+        Event::Error {
+            text: text.to_string(),
+        }
+    }
+
+    /// The event as one line of JSON, without the newline.
+    pub fn to_json(&self) -> String {
+        // Every field is a string, number or enum, so this can't fail.
+        serde_json::to_string(self).expect("events always serialize")
+    }
+
+    pub fn from_json(json: &str) -> Result<Event, FrameError> {
+        serde_json::from_str(json).map_err(|e| FrameError::InvalidEvent(e.to_string()))
+    }
+
+    /// The event as a version 1 client gets it: preformatted text.
+    pub fn to_frame(&self) -> Frame {
+        match self {
+            Event::Chat { .. } => Frame::text(&self.to_string()),
+            Event::Direct { from, text, .. } => Frame::direct(from, text),
+            Event::Error { text } => Frame::error(text),
+            _ => Frame::notice(&self.to_string()),
+        }
+    }
+
+    /// The event a version 1 frame stands for, for the kinds that map onto
+    /// one without guessing. Text frames don't: their structure is lost.
+    pub fn from_legacy(frame: &Frame) -> Option<Event> {
+        let text = frame.as_str().ok()?;
+        match frame.kind {
+            MessageType::Notice => Some(Event::notice(text)),
+            MessageType::Error => Some(Event::error(text)),
+            MessageType::Direct => {
+                let (from, text) = frame.direct_parts()?;
+                Some(Event::Direct {
+                    id: None,
+                    from: from.to_string(),
+                    text: text.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How a person reads the event; the same text version 1 sends.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Chat {
+                room,
+                from,
+                text,
+                time,
+                ..
+            } => {
+                // Replayed messages say when they were sent.
+                if let Some(time) = time {
+                    write!(f, "[{}] ", utc_timestamp(*time))?;
+                }
+                write!(f, "{} [{}]:{}", room, from, text)
+            }
+            Event::Direct { from, text, .. } => write!(f, "[dm from {}] {}", from, text),
+            Event::Join { room, nick } => write!(f, "{} has joined {}", nick, room),
+            Event::Leave { room, nick, reason } => {
+                write!(f, "{} has left {}", nick, room)?;
+                match reason {
+                    Some(reason) => write!(f, " ({})", reason),
+                    None => Ok(()),
+                }
+            }
+            Event::Nick { old, new } => write!(f, "{} is now known as {}", old, new),
+            Event::Notice { text } | Event::Error { text } => f.write_str(text),
+            Event::Presence {
+                nick,
+                status,
+                message,
+            } => {
+                match status {
+                    Status::Online => write!(f, "{} is online", nick)?,
+                    Status::Away => write!(f, "{} is away", nick)?,
+                    Status::Offline => write!(f, "{} went offline", nick)?,
+                }
+                match message {
+                    Some(message) => write!(f, ": {}", message),
+                    None => Ok(()),
+                }
+            }
+            Event::Ack { seq, id } => write!(f, "line {} sent as message {}", seq, id),
+            Event::Receipt { id, to } => write!(f, "message {} delivered to {}", id, to),
+            Event::FileOffer {
+                id,
+                from,
+                to,
+                name,
+                size,
+                ..
+            } => write!(
+                f,
+                "{} offers {} the file {} ({} bytes) as transfer {}",
+                from, to, name, size, id
+            ),
+            Event::FileAccepted { id } => write!(f, "transfer {} accepted", id),
+            Event::FileProgress { id, bytes } => {
+                write!(f, "transfer {}: {} bytes delivered", id, bytes)
+            }
+            Event::FileCancelled { id, reason } => {
+                write!(f, "transfer {} cancelled: {}", id, reason)
+            }
+            Event::Relay { event, .. } => event.fmt(f),
+        }
+    }
+}
+
+/// "YYYY-MM-DD HH:MM" in UTC, without pulling in a date crate. The date
+/// part is Howard Hinnant's days-to-civil algorithm.
+pub fn utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let minutes = secs % 86_400 / 60;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
@@ -0,0 +1,632 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub mod event;
+
+pub use event::{utc_timestamp, Event, Status};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Why a framing protocol?
+//
+// TCP is a byte stream, not a message stream. The first version of the chat
+// used newlines as the message boundary, which has three problems:
+//
+//   1. A message can never contain a newline (or arbitrary binary data).
+//   2. A peer that never sends '\n' makes BufReader grow its buffer forever.
+//   3. There is no room to say *what kind* of message this is.
+//
+// A length-prefixed frame fixes all three. Every frame on the wire is:
+//
+//   +----------------+-----------+------------------------+
+//   | length: u32 BE | type: u8  | payload: length-1 bytes |
+//   +----------------+-----------+------------------------+
+//
+// The length covers the type byte plus the payload, so the reader always
+// knows exactly how many bytes to wait for, and can refuse a frame that is
+// too big *before* buffering it.
+//
+// This crate is shared by both the server and the client so the two sides
+// can never disagree about the format.
+// ---------------------------------------------------------------------------
+
+/// Bytes a framed client sends right after connecting. The leading NUL can
+/// never start a line typed by a human, so the server can tell a framed
+/// client from an old newline client by looking at the first byte.
+pub const MAGIC: [u8; 4] = [0x00, b'C', b'H', b'T'];
+
+/// Highest protocol version this build understands. Version 2 added Event
+/// frames: the server sends chat traffic as typed events rather than text
+/// (see event.rs). Version 3 added message ids, acknowledgements and
+/// delivery receipts. Version 4 added file transfer: offers as events, the
+/// file itself in Chunk frames.
+pub const VERSION: u8 = 4;
+
+/// The first version with Event frames.
+pub const EVENTS_VERSION: u8 = 2;
+
+/// The first version with Ack and Receipt events.
+pub const ACKS_VERSION: u8 = 3;
+
+/// The first version that can send and receive files.
+pub const FILES_VERSION: u8 = 4;
+
+/// Most file bytes one Chunk frame carries. Well under MAX_FRAME_SIZE, so a
+/// file never holds up chat for long.
+pub const MAX_CHUNK: usize = 32 * 1024;
+
+/// Largest frame (type byte + payload) either side will accept.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+const HEADER_LEN: usize = 4;
+
+/// What a frame carries. The numeric values are part of the wire format, so
+/// never renumber an existing variant - only add new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    /// A chat line (client -> server) or a rendered chat line (server -> client).
+    Text = 1,
+    /// The server rejected something the client sent.
+    Error = 2,
+    /// Client -> server: the nickname it wants (first frame, or a rename).
+    /// Server -> client: the nickname it now has.
+    Nick = 3,
+    /// Server -> client: something happened that nobody "said", like a
+    /// rename. Rendered differently from chat.
+    Notice = 4,
+    /// Server -> client: a private message. The payload is the sender's
+    /// nickname, a space, then the text (nicknames can't contain spaces).
+    Direct = 5,
+    /// Either way: are you still there? The payload is an opaque token.
+    Ping = 6,
+    /// The answer to a Ping, carrying the same token back.
+    Pong = 7,
+    /// Server -> client: the server is going away and will close the
+    /// connection once this has been delivered. The payload says why.
+    Shutdown = 8,
+    /// Server -> client, version 2 and up: something happened in the chat.
+    /// The payload is one `Event` as JSON.
+    Event = 9,
+    /// Either way, version 4 and up: a piece of a file being sent. The
+    /// payload is the transfer id (u64, big-endian), then the file bytes.
+    /// The only frame that isn't text.
+    Chunk = 10,
+}
+
+impl MessageType {
+    /// Whether the payload of this type must be valid UTF-8.
+    pub fn is_text(self) -> bool {
+        matches!(
+            self,
+            MessageType::Text
+                | MessageType::Error
+                | MessageType::Nick
+                | MessageType::Notice
+                | MessageType::Direct
+                | MessageType::Ping
+                | MessageType::Pong
+                | MessageType::Shutdown
+                | MessageType::Event
+        )
+    }
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = FrameError;
+
+    fn try_from(value: u8) -> Result<Self, FrameError> {
+        match value {
+            1 => Ok(MessageType::Text),
+            2 => Ok(MessageType::Error),
+            3 => Ok(MessageType::Nick),
+            4 => Ok(MessageType::Notice),
+            5 => Ok(MessageType::Direct),
+            6 => Ok(MessageType::Ping),
+            7 => Ok(MessageType::Pong),
+            8 => Ok(MessageType::Shutdown),
+            9 => Ok(MessageType::Event),
+            10 => Ok(MessageType::Chunk),
+            other => Err(FrameError::UnknownType(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: MessageType,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: MessageType, payload: impl Into<Vec<u8>>) -> Self {
+        Frame {
+            kind,
+            payload: payload.into(),
+        }
+    }
+
+    pub fn text(msg: &str) -> Self {
+        Frame::new(MessageType::Text, msg)
+    }
+
+    pub fn error(msg: &str) -> Self {
+        Frame::new(MessageType::Error, msg)
+    }
+
+    pub fn nick(nick: &str) -> Self {
+        Frame::new(MessageType::Nick, nick)
+    }
+
+    pub fn notice(msg: &str) -> Self {
+        Frame::new(MessageType::Notice, msg)
+    }
+
+    pub fn direct(from: &str, msg: &str) -> Self {
+        Frame::new(MessageType::Direct, format!("{} {}", from, msg))
+    }
+
+    pub fn ping(token: &str) -> Self {
+        Frame::new(MessageType::Ping, token)
+    }
+
+    /// The answer to `ping`.
+    pub fn pong(ping: &Frame) -> Self {
+        Frame::new(MessageType::Pong, ping.payload.clone())
+    }
+
+    pub fn shutdown(reason: &str) -> Self {
+        Frame::new(MessageType::Shutdown, reason)
+    }
+
+    pub fn event(event: &Event) -> Self {
+        Frame::new(MessageType::Event, event.to_json())
+    }
+
+    /// A piece of file transfer `id`.
+    pub fn chunk(id: u64, bytes: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(8 + bytes.len());
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(bytes);
+        Frame::new(MessageType::Chunk, payload)
+    }
+
+    /// Split a Chunk frame into (transfer id, file bytes).
+    pub fn chunk_parts(&self) -> Option<(u64, &[u8])> {
+        if self.kind != MessageType::Chunk || self.payload.len() < 8 {
+            return None;
+        }
+        let (id, bytes) = self.payload.split_at(8);
+        Some((u64::from_be_bytes(id.try_into().ok()?), bytes))
+    }
+
+    /// The event an Event frame carries.
+    pub fn to_event(&self) -> Result<Event, FrameError> {
+        if self.kind != MessageType::Event {
+            return Err(FrameError::InvalidEvent(format!(
+                "{:?} frame is not an event",
+                self.kind
+            )));
+        }
+        Event::from_json(self.as_str()?)
+    }
+
+    /// Split a Direct frame into (sender, text).
+    pub fn direct_parts(&self) -> Option<(&str, &str)> {
+        if self.kind != MessageType::Direct {
+            return None;
+        }
+        self.as_str().ok()?.split_once(' ')
+    }
+
+    /// The payload as a string. Frames coming out of a `Decoder` have already
+    /// been checked, but a hand-built frame might not have been.
+    pub fn as_str(&self) -> Result<&str, FrameError> {
+        std::str::from_utf8(&self.payload).map_err(|_| FrameError::InvalidUtf8)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The peer announced (or, in line mode, sent) more than the limit.
+    /// The stream can't be resynchronised after this, so close it.
+    TooLarge { len: usize, max: usize },
+    /// A text frame whose payload isn't UTF-8. The frame was consumed, so
+    /// the connection can keep going.
+    InvalidUtf8,
+    /// A frame type this version doesn't know. Also consumed.
+    UnknownType(u8),
+    /// A zero-length frame has no room for the type byte.
+    Empty,
+    /// An Event frame whose payload isn't an event we understand.
+    InvalidEvent(String),
+}
+
+impl FrameError {
+    /// Whether the decoder is still in sync with the stream after this error.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            FrameError::InvalidUtf8 | FrameError::UnknownType(_) | FrameError::InvalidEvent(_)
+        )
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the {} byte limit", len, max)
+            }
+            FrameError::InvalidUtf8 => write!(f, "message is not valid UTF-8"),
+            FrameError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            FrameError::Empty => write!(f, "empty frame"),
+            FrameError::InvalidEvent(e) => write!(f, "malformed event: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// How a connection splits its byte stream into messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Length-prefixed frames, negotiated with `MAGIC`.
+    Framed,
+    /// One UTF-8 line per message. Kept so old clients can still connect.
+    Lines,
+}
+
+/// Serialise a frame for the given framing mode.
+///
+/// In line mode only the payload is sent, since an old client has no way to
+/// understand the type byte. Non-chat frames get a prefix so they still
+/// stand out.
+pub fn encode(framing: Framing, frame: &Frame) -> Vec<u8> {
+    match framing {
+        Framing::Framed => {
+            let len = (frame.payload.len() + 1) as u32;
+            let mut out = Vec::with_capacity(HEADER_LEN + len as usize);
+            out.extend_from_slice(&len.to_be_bytes());
+            out.push(frame.kind as u8);
+            out.extend_from_slice(&frame.payload);
+            out
+        }
+        Framing::Lines => {
+            let mut out = Vec::with_capacity(frame.payload.len() + 32);
+            if let Some((from, msg)) = frame.direct_parts() {
+                out.extend_from_slice(format!("[dm from {}] {}", from, msg).as_bytes());
+            } else {
+                let prefix: &[u8] = match frame.kind {
+                    MessageType::Text | MessageType::Direct => b"",
+                    MessageType::Error => b"[error] ",
+                    MessageType::Nick => b"*** You are now known as ",
+                    MessageType::Notice => b"*** ",
+                    // A line client can't answer, so the server never
+                    // pings one; this is only so nothing is silently lost.
+                    MessageType::Ping => b"[ping] ",
+                    MessageType::Pong => b"[pong] ",
+                    MessageType::Shutdown => b"*** ",
+                    // Already a line of JSON.
+                    MessageType::Event => b"",
+                    // Line clients can't take files, so they never get
+                    // these either.
+                    MessageType::Chunk => b"[file data] ",
+                };
+                out.extend_from_slice(prefix);
+                out.extend_from_slice(&frame.payload);
+            }
+            out.push(b'\n');
+            out
+        }
+    }
+}
+
+pub fn write_frame<W: Write>(w: &mut W, framing: Framing, frame: &Frame) -> io::Result<()> {
+    w.write_all(&encode(framing, frame))
+}
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: An *incremental* decoder.
+//
+// read_exact() looks like the obvious way to read a frame, but it throws away
+// whatever it already read if the socket times out half way through. Instead
+// the decoder owns a buffer: you feed() it whatever bytes arrived and ask for
+// the next complete frame. Partial frames just wait in the buffer. The same
+// decoder works for blocking sockets, sockets with timeouts, and non-blocking
+// event loops.
+// ---------------------------------------------------------------------------
+
+#[derive(Debug)]
+pub struct Decoder {
+    framing: Framing,
+    buf: Vec<u8>,
+    max: usize,
+}
+
+impl Decoder {
+    pub fn new(framing: Framing) -> Self {
+        Decoder::with_limit(framing, MAX_FRAME_SIZE)
+    }
+
+    pub fn with_limit(framing: Framing, max: usize) -> Self {
+        Decoder {
+            framing,
+            buf: Vec::new(),
+            max,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Pop the next complete message, or `Ok(None)` if more bytes are needed.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        match self.framing {
+            Framing::Framed => self.next_framed(),
+            Framing::Lines => self.next_line(),
+        }
+    }
+
+    fn next_framed(&mut self) -> Result<Option<Frame>, FrameError> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&self.buf[..HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+
+        // Check the size before waiting for the body, so a hostile length
+        // can't make us buffer gigabytes.
+        if len == 0 {
+            return Err(FrameError::Empty);
+        }
+        if len > self.max {
+            return Err(FrameError::TooLarge { len, max: self.max });
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let body: Vec<u8> = self
+            .buf
+            .drain(..HEADER_LEN + len)
+            .skip(HEADER_LEN)
+            .collect();
+        let kind = MessageType::try_from(body[0])?;
+        let payload = body[1..].to_vec();
+        if kind.is_text() && std::str::from_utf8(&payload).is_err() {
+            return Err(FrameError::InvalidUtf8);
+        }
+        Ok(Some(Frame { kind, payload }))
+    }
+
+    fn next_line(&mut self) -> Result<Option<Frame>, FrameError> {
+        let Some(pos) = self.buf.iter().position(|&b| b == b'\n') else {
+            // No newline yet. If the buffer is already past the limit the
+            // peer is never going to send one in time.
+            if self.buf.len() > self.max {
+                return Err(FrameError::TooLarge {
+                    len: self.buf.len(),
+                    max: self.max,
+                });
+            }
+            return Ok(None);
+        };
+        if pos > self.max {
+            return Err(FrameError::TooLarge {
+                len: pos,
+                max: self.max,
+            });
+        }
+
+        let mut line: Vec<u8> = self.buf.drain(..=pos).collect();
+        line.pop(); // '\n'
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        match String::from_utf8(line) {
+            Ok(text) => Ok(Some(Frame::new(MessageType::Text, text))),
+            Err(_) => Err(FrameError::InvalidUtf8),
+        }
+    }
+}
+
+/// A blocking reader built on `Decoder`. Read timeouts are safe: a frame
+/// that was half received stays buffered for the next call.
+pub struct FrameReader<R> {
+    inner: R,
+    decoder: Decoder,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R, decoder: Decoder) -> Self {
+        FrameReader { inner, decoder }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The underlying stream, e.g. to write replies on a TLS session that
+    /// can't be split into separate read and write halves.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Read until one full message is available. `Ok(None)` means the peer
+    /// closed the connection cleanly between messages.
+    ///
+    /// Protocol violations come back as `io::ErrorKind::InvalidData` wrapping
+    /// a `FrameError`; use `frame_error()` to get at it.
+    pub fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(Some(frame));
+            }
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.decoder.feed(&chunk[..n]);
+        }
+    }
+}
+
+/// Pull the `FrameError` back out of an error returned by `FrameReader`.
+pub fn frame_error(e: &io::Error) -> Option<&FrameError> {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<FrameError>())
+}
+
+// ---------------------------------------------------------------------------
+// Negotiation.
+//
+// A framed client opens with MAGIC followed by the highest version it speaks.
+// The server answers with MAGIC and the version both sides will use. An old
+// client just starts typing, so as soon as the first byte isn't MAGIC[0] the
+// server knows it is talking line mode and hands back what it already read.
+// ---------------------------------------------------------------------------
+
+/// The bytes a client sends to open a framed session.
+pub fn client_hello() -> [u8; 5] {
+    let mut hello = [0u8; 5];
+    hello[..4].copy_from_slice(&MAGIC);
+    hello[4] = VERSION;
+    hello
+}
+
+/// The result of the server side of the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Negotiated {
+    /// The client sent the magic; both sides now use this version.
+    Framed(u8),
+    /// The client is a newline client. The bytes are the start of its first
+    /// line and must be fed to the line decoder.
+    Lines(Vec<u8>),
+}
+
+/// The bytes a server answers a framed client with.
+pub fn server_hello(version: u8) -> [u8; 5] {
+    let mut reply = [0u8; 5];
+    reply[..4].copy_from_slice(&MAGIC);
+    reply[4] = version;
+    reply
+}
+
+/// What the first bytes of a connection say about the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hello {
+    /// Everything so far matches MAGIC; wait for more.
+    Incomplete,
+    /// A full hello. `consumed` bytes belong to it; anything after that is
+    /// already the client's first frame.
+    Framed { version: u8, consumed: usize },
+    /// Not a framed client. All the bytes are line-mode chat.
+    Lines,
+}
+
+/// Look at the bytes received so far without doing any I/O. This is the
+/// building block for both `negotiate` and non-blocking servers, which can't
+/// sit in a read loop waiting for the rest of the hello.
+pub fn parse_hello(bytes: &[u8]) -> io::Result<Hello> {
+    let prefix = bytes.len().min(MAGIC.len());
+    if bytes[..prefix] != MAGIC[..prefix] {
+        return Ok(Hello::Lines);
+    }
+    if bytes.len() <= MAGIC.len() {
+        return Ok(Hello::Incomplete);
+    }
+    let version = bytes[MAGIC.len()].min(VERSION);
+    if version == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "client offered protocol version 0",
+        ));
+    }
+    Ok(Hello::Framed {
+        version,
+        consumed: MAGIC.len() + 1,
+    })
+}
+
+/// Server side of the handshake for blocking sockets. Writes the reply
+/// itself when the client turns out to be framed.
+///
+/// A newline client may sit silently waiting for a prompt. Give the stream a
+/// short read timeout before calling this: framed clients send the magic
+/// immediately, so a timeout on the very first byte means line mode.
+pub fn negotiate<S: Read + Write>(stream: &mut S) -> io::Result<Negotiated> {
+    let mut seen = Vec::with_capacity(5);
+    let mut byte = [0u8; 1];
+
+    // Read one byte at a time so a newline client that sent only "hi\n"
+    // isn't left waiting for bytes it will never send.
+    loop {
+        match stream.read(&mut byte) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => seen.push(byte[0]),
+            Err(e)
+                if seen.is_empty()
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                return Ok(Negotiated::Lines(seen));
+            }
+            Err(e) => return Err(e),
+        }
+        match parse_hello(&seen)? {
+            Hello::Incomplete => continue,
+            Hello::Lines => return Ok(Negotiated::Lines(seen)),
+            Hello::Framed { version, .. } => {
+                stream.write_all(&server_hello(version))?;
+                return Ok(Negotiated::Framed(version));
+            }
+        }
+    }
+}
+
+/// Client side of the handshake. Returns the version the server picked.
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> io::Result<u8> {
+    client_handshake_with_version(stream, VERSION)
+}
+
+/// Client side of the handshake, offering `version` rather than the
+/// newest. Useful for talking to a server the way an older client would.
+pub fn client_handshake_with_version<S: Read + Write>(
+    stream: &mut S,
+    version: u8,
+) -> io::Result<u8> {
+    let mut hello = client_hello();
+    hello[4] = version;
+    stream.write_all(&hello)?;
+
+    let mut reply = [0u8; 5];
+    stream.read_exact(&mut reply)?;
+    if reply[..4] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "server does not speak the framed protocol",
+        ));
+    }
+    if reply[4] == 0 || reply[4] > version {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("server picked unsupported version {}", reply[4]),
+        ));
+    }
+    Ok(reply[4])
+}
@@ -0,0 +1,243 @@
+use std::io::{self, Cursor, Read, Write};
+
+use protocol::{
+    Decoder, Event, Frame, FrameError, Framing, Hello, MessageType, Negotiated, Status,
+};
+
+// An in-memory "socket": reads come from `input`, writes land in `output`.
+struct Duplex {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+pub fn test_frame_round_trip_across_partial_reads() {
+    let bytes = protocol::encode(Framing::Framed, &Frame::text("multi\nline"));
+    let mut decoder = Decoder::new(Framing::Framed);
+
+    // Feed one byte at a time; nothing comes out until the last one.
+    for b in &bytes[..bytes.len() - 1] {
+        decoder.feed(&[*b]);
+        assert_eq!(decoder.next_frame(), Ok(None));
+    }
+    decoder.feed(&bytes[bytes.len() - 1..]);
+    assert_eq!(decoder.next_frame(), Ok(Some(Frame::text("multi\nline"))));
+}
+
+#[test]
+pub fn test_oversized_frame_rejected_from_header() {
+    let mut decoder = Decoder::with_limit(Framing::Framed, 16);
+    decoder.feed(&100u32.to_be_bytes());
+    assert_eq!(
+        decoder.next_frame(),
+        Err(FrameError::TooLarge { len: 100, max: 16 })
+    );
+}
+
+#[test]
+pub fn test_invalid_utf8_is_recoverable() {
+    let mut decoder = Decoder::new(Framing::Framed);
+    decoder.feed(&protocol::encode(
+        Framing::Framed,
+        &Frame::new(MessageType::Text, vec![0xff, 0xfe]),
+    ));
+    decoder.feed(&protocol::encode(Framing::Framed, &Frame::text("ok")));
+
+    let err = decoder.next_frame().unwrap_err();
+    assert_eq!(err, FrameError::InvalidUtf8);
+    assert!(err.is_recoverable());
+    assert_eq!(decoder.next_frame(), Ok(Some(Frame::text("ok"))));
+}
+
+#[test]
+pub fn test_line_mode_limits_unterminated_lines() {
+    let mut decoder = Decoder::with_limit(Framing::Lines, 8);
+    decoder.feed(b"hello\r\nthis never ends");
+    assert_eq!(decoder.next_frame(), Ok(Some(Frame::text("hello"))));
+    assert!(matches!(
+        decoder.next_frame(),
+        Err(FrameError::TooLarge { .. })
+    ));
+}
+
+#[test]
+pub fn test_negotiate_framed_client() {
+    let mut client = Duplex {
+        input: Cursor::new(protocol::client_hello().to_vec()),
+        output: Vec::new(),
+    };
+    assert_eq!(
+        protocol::negotiate(&mut client).unwrap(),
+        Negotiated::Framed(protocol::VERSION)
+    );
+    assert_eq!(&client.output[..4], &protocol::MAGIC);
+}
+
+#[test]
+pub fn test_negotiate_falls_back_to_lines() {
+    let mut client = Duplex {
+        input: Cursor::new(b"hi\n".to_vec()),
+        output: Vec::new(),
+    };
+    assert_eq!(
+        protocol::negotiate(&mut client).unwrap(),
+        Negotiated::Lines(b"h".to_vec())
+    );
+    assert!(client.output.is_empty());
+}
+
+#[test]
+pub fn test_direct_frames() {
+    let frame = Frame::direct("alice", "psst, over here");
+    assert_eq!(frame.direct_parts(), Some(("alice", "psst, over here")));
+    assert_eq!(
+        protocol::encode(Framing::Lines, &frame),
+        b"[dm from alice] psst, over here\n".to_vec()
+    );
+    assert_eq!(Frame::text("alice hi").direct_parts(), None);
+}
+
+#[test]
+pub fn test_pong_echoes_ping() {
+    let ping = Frame::ping("42");
+    let bytes = protocol::encode(Framing::Framed, &ping);
+    let mut decoder = Decoder::new(Framing::Framed);
+    decoder.feed(&bytes);
+    let received = decoder.next_frame().unwrap().unwrap();
+    assert_eq!(received.kind, MessageType::Ping);
+
+    let pong = Frame::pong(&received);
+    assert_eq!(pong.kind, MessageType::Pong);
+    assert_eq!(pong.as_str(), Ok("42"));
+}
+
+#[test]
+pub fn test_parse_hello_without_io() {
+    let mut bytes = protocol::client_hello().to_vec();
+    assert_eq!(
+        protocol::parse_hello(&bytes[..3]).unwrap(),
+        Hello::Incomplete
+    );
+
+    // A frame pipelined right behind the hello is left for the decoder.
+    bytes.extend_from_slice(&protocol::encode(Framing::Framed, &Frame::nick("alice")));
+    assert_eq!(
+        protocol::parse_hello(&bytes).unwrap(),
+        Hello::Framed {
+            version: protocol::VERSION,
+            consumed: 5
+        }
+    );
+    assert_eq!(protocol::parse_hello(b"hi").unwrap(), Hello::Lines);
+}
+
+#[test]
+pub fn test_events_as_json_and_as_text() {
+    let chat = Event::Chat {
+        id: None,
+        room: "#lobby".to_string(),
+        from: "alice".to_string(),
+        text: "hi".to_string(),
+        time: None,
+    };
+    let frame = Frame::event(&chat);
+    assert_eq!(
+        frame.as_str(),
+        Ok(r##"{"type":"chat","room":"#lobby","from":"alice","text":"hi"}"##)
+    );
+    assert_eq!(frame.to_event(), Ok(chat.clone()));
+    // Older clients get the text they always did.
+    assert_eq!(chat.to_frame(), Frame::text("#lobby [alice]:hi"));
+
+    let away =
+        Event::from_json(r#"{"type":"presence","nick":"bob","status":"away","message":"lunch"}"#);
+    assert_eq!(
+        away,
+        Ok(Event::Presence {
+            nick: "bob".to_string(),
+            status: Status::Away,
+            message: Some("lunch".to_string()),
+        })
+    );
+    assert_eq!(
+        away.unwrap().to_frame(),
+        Frame::notice("bob is away: lunch")
+    );
+
+    let bad = Frame::new(MessageType::Event, r#"{"type":"shout"}"#);
+    assert!(matches!(bad.to_event(), Err(FrameError::InvalidEvent(_))));
+    assert_eq!(
+        Event::from_legacy(&Frame::direct("bob", "psst")),
+        Some(Event::Direct {
+            id: None,
+            from: "bob".to_string(),
+            text: "psst".to_string()
+        })
+    );
+}
+
+#[test]
+pub fn test_relay_wraps_an_event() {
+    let relay = Event::Relay {
+        origin: "office1".to_string(),
+        id: 7,
+        event: Box::new(Event::Presence {
+            nick: "alice@office1".to_string(),
+            status: Status::Online,
+            message: None,
+        }),
+    };
+    let json = relay.to_json();
+    assert_eq!(
+        json,
+        r#"{"type":"relay","origin":"office1","id":7,"event":{"type":"presence","nick":"alice@office1","status":"online"}}"#
+    );
+    assert_eq!(Event::from_json(&json), Ok(relay.clone()));
+    assert_eq!(relay.to_string(), "alice@office1 is online");
+}
+
+#[test]
+pub fn test_chunk_frames_carry_binary() {
+    let bytes = [0u8, 0xff, b'\n', 0x80];
+    let chunk = Frame::chunk(42, &bytes);
+    let mut decoder = Decoder::new(Framing::Framed);
+    decoder.feed(&protocol::encode(Framing::Framed, &chunk));
+    // Not UTF-8, and that's fine for a chunk.
+    let decoded = decoder.next_frame().unwrap().unwrap();
+    assert_eq!(decoded.kind, MessageType::Chunk);
+    assert_eq!(decoded.chunk_parts(), Some((42, &bytes[..])));
+    assert_eq!(Frame::text("hi").chunk_parts(), None);
+    assert_eq!(
+        Frame::new(MessageType::Chunk, vec![1, 2]).chunk_parts(),
+        None
+    );
+
+    let offer = Event::FileOffer {
+        id: 3,
+        from: "alice".to_string(),
+        to: "bob".to_string(),
+        name: "report.csv".to_string(),
+        size: 1234,
+        sha256: "ab".repeat(32),
+    };
+    assert_eq!(Event::from_json(&offer.to_json()), Ok(offer.clone()));
+    assert_eq!(
+        offer.to_string(),
+        "alice offers bob the file report.csv (1234 bytes) as transfer 3"
+    );
+}
@@ -0,0 +1,219 @@
+// ---------------------------------------------------------------------------
+// Benchmark: thread-per-connection vs. the event loop.
+//
+//   cargo bench -p server
+//   BENCH_CLIENTS=500 BENCH_MESSAGES=20 cargo bench -p server
+//
+// Both servers speak the same framed protocol and do the same work: log a
+// client in, then broadcast every chat line to everyone else. For each one
+// we connect BENCH_CLIENTS clients, have each of them send BENCH_MESSAGES
+// lines, and wait until every line has reached every other client.
+//
+// The thread-per-connection server below is a trimmed copy of the design the
+// chat server used before the event loop: one blocking thread per socket and
+// a broadcast that calls write_all() on every client while holding the
+// client list lock. It is kept here, rather than in the library, purely as
+// the baseline to measure against.
+// ---------------------------------------------------------------------------
+
+use std::env;
+use std::fs;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use protocol::{Decoder, Frame, FrameReader, Framing, MessageType, Negotiated};
+use server::event_loop;
+use server::hub::Hub;
+
+// Give up on a run that hasn't delivered everything by then.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn main() {
+    let clients = env_or("BENCH_CLIENTS", 200);
+    let messages = env_or("BENCH_MESSAGES", 10);
+    let workers = thread::available_parallelism().map_or(2, |n| n.get());
+    println!(
+        "{} clients x {} messages, each broadcast to {} others ({} deliveries)",
+        clients,
+        messages,
+        clients - 1,
+        clients * messages * (clients - 1)
+    );
+
+    let addr = spawn_server(threaded::serve);
+    run("thread-per-connection", addr, clients, messages);
+
+    let addr = spawn_server(move |listener| {
+        let _ = event_loop::serve(vec![listener], Arc::new(Hub::new()), workers);
+    });
+    run(
+        &format!("event loop ({} workers)", workers),
+        addr,
+        clients,
+        messages,
+    );
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+        .max(2)
+}
+
+fn spawn_server(serve: impl FnOnce(TcpListener) + Send + 'static) -> SocketAddr {
+    // Port 0 lets the OS pick a free port, so the two runs never collide.
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local_addr");
+    thread::spawn(move || serve(listener));
+    addr
+}
+
+fn run(label: &str, addr: SocketAddr, clients: usize, messages: usize) {
+    let threads_before = thread_count();
+    let received = Arc::new(AtomicUsize::new(0));
+
+    let start = Instant::now();
+    let mut writers = Vec::with_capacity(clients);
+    for n in 0..clients {
+        writers.push(connect(addr, &format!("user{}", n), &received));
+    }
+    let connected = start.elapsed();
+    // Every client in the bench has one reader thread, in both runs; what
+    // differs is how many threads the server adds on top.
+    let server_threads = thread_count().saturating_sub(threads_before + clients);
+
+    let expected = clients * messages * (clients - 1);
+    let start = Instant::now();
+    for m in 0..messages {
+        for writer in writers.iter_mut() {
+            let frame = Frame::text(&format!("message {}", m));
+            protocol::write_frame(writer, Framing::Framed, &frame).expect("send");
+        }
+    }
+    while received.load(Ordering::Relaxed) < expected {
+        if start.elapsed() > DELIVERY_TIMEOUT {
+            println!(
+                "{:<28} timed out: {} of {} delivered",
+                label,
+                received.load(Ordering::Relaxed),
+                expected
+            );
+            return;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    let delivered = start.elapsed();
+
+    println!(
+        "{:<28} connect+login {:>8.1?}  deliver {:>8.1?}  {:>10.0} msgs/s  ~{} server threads",
+        label,
+        connected,
+        delivered,
+        expected as f64 / delivered.as_secs_f64(),
+        server_threads
+    );
+    // Dropping the writers closes the sockets, which lets the reader
+    // threads (and the threaded server's client threads) finish.
+}
+
+// Connect, log in, and start a thread that counts chat lines received.
+fn connect(addr: SocketAddr, nick: &str, received: &Arc<AtomicUsize>) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).expect("connect");
+    stream.set_nodelay(true).ok();
+    protocol::client_handshake(&mut stream).expect("handshake");
+    protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).expect("login");
+
+    let mut reader = FrameReader::new(
+        stream.try_clone().expect("clone"),
+        Decoder::new(Framing::Framed),
+    );
+    loop {
+        match reader.read_frame().expect("login reply") {
+            Some(frame) if frame.kind == MessageType::Nick => break,
+            Some(_) => continue,
+            None => panic!("server closed during login"),
+        }
+    }
+
+    let received = Arc::clone(received);
+    thread::spawn(move || {
+        while let Ok(Some(frame)) = reader.read_frame() {
+            if frame.kind == MessageType::Text {
+                received.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+    stream
+}
+
+// Threads in this process, from /proc (Linux only; 0 elsewhere).
+fn thread_count() -> usize {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Threads:"))
+                .and_then(|n| n.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+mod threaded {
+    use super::*;
+
+    type ClientHandle = Arc<Mutex<TcpStream>>;
+    type ClientList = Arc<Mutex<Vec<ClientHandle>>>;
+
+    pub fn serve(listener: TcpListener) {
+        let clients: ClientList = Arc::new(Mutex::new(Vec::new()));
+        for stream in listener.incoming().flatten() {
+            let clients = Arc::clone(&clients);
+            thread::spawn(move || handle_client(stream, clients));
+        }
+    }
+
+    fn handle_client(mut stream: TcpStream, clients: ClientList) {
+        stream.set_nodelay(true).ok();
+        if !matches!(protocol::negotiate(&mut stream), Ok(Negotiated::Framed(_))) {
+            return;
+        }
+        let my_handle: ClientHandle = Arc::new(Mutex::new(stream.try_clone().unwrap()));
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+
+        let mut nick = String::new();
+        while let Ok(Some(frame)) = reader.read_frame() {
+            let text = frame.as_str().unwrap_or_default().to_string();
+            if nick.is_empty() {
+                nick = text;
+                let reply = Frame::nick(&nick);
+                let _ =
+                    protocol::write_frame(&mut *my_handle.lock().unwrap(), Framing::Framed, &reply);
+                clients.lock().unwrap().push(Arc::clone(&my_handle));
+                continue;
+            }
+            let outgoing = Frame::text(&format!("[{}]:{}", nick, text));
+            broadcast(&clients, &outgoing, &my_handle);
+        }
+        clients
+            .lock()
+            .unwrap()
+            .retain(|c| !Arc::ptr_eq(c, &my_handle));
+    }
+
+    fn broadcast(clients: &ClientList, message: &Frame, sender: &ClientHandle) {
+        let bytes = protocol::encode(Framing::Framed, message);
+        let list = clients.lock().unwrap();
+        for client in list.iter() {
+            if Arc::ptr_eq(client, sender) {
+                continue;
+            }
+            let _ = std::io::Write::write_all(&mut *client.lock().unwrap(), &bytes);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::net::SocketAddr;
+use std::thread;
+
+use server::command;
+use server::plugin::{Bot, Message, Plugin};
+use server::server::Server;
+
+// A server with two bots built in (see plugin.rs):
+//
+//   echo   - repeats whatever you /msg it
+//   remind - "/msg remind 10m tea" messages you about tea in ten minutes,
+//            and "!remind 10m tea" in a room tells the whole room
+//
+//   cargo run --example bots
+//   cargo run --bin client -- alice
+
+struct Echo;
+
+impl Plugin for Echo {
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    fn on_message(&self, bot: &Bot, message: &Message) {
+        if message.room.is_none() {
+            let _ = bot.tell(&message.from, &message.text);
+        }
+    }
+}
+
+struct Remind;
+
+impl Plugin for Remind {
+    fn name(&self) -> &str {
+        "remind"
+    }
+
+    fn on_connect(&self, bot: &Bot, nick: &str) {
+        let _ = bot.tell(
+            nick,
+            "Need reminding? /msg remind <time> <what>, e.g. 10m tea",
+        );
+    }
+
+    fn on_message(&self, bot: &Bot, message: &Message) {
+        let request = match &message.room {
+            None => message.text.as_str(),
+            Some(_) => match message.text.strip_prefix("!remind ") {
+                Some(request) => request,
+                None => return,
+            },
+        };
+        let parsed = request
+            .split_once(' ')
+            .and_then(|(time, what)| Some((command::parse_duration(time)?, what.trim())));
+        let Some((delay, what)) = parsed else {
+            let _ = bot.tell(&message.from, "usage: <time> <what>, e.g. 10m tea");
+            return;
+        };
+        let _ = bot.tell(
+            &message.from,
+            &format!("OK, in {}", command::format_duration(delay)),
+        );
+        // Callbacks mustn't block the worker, so the waiting happens on a
+        // thread of its own.
+        let bot = bot.clone();
+        let (from, room, what) = (message.from.clone(), message.room.clone(), what.to_string());
+        thread::spawn(move || {
+            thread::sleep(delay);
+            let text = format!("{}: {}", from, what);
+            let _ = match room {
+                Some(room) => bot.say(&room, &text),
+                None => bot.tell(&from, &what),
+            };
+        });
+    }
+}
+
+fn main() -> io::Result<()> {
+    let server = Server::builder()
+        .bind(SocketAddr::from(([127, 0, 0, 1], 8080)))
+        .plugin(Echo)
+        .plugin(Remind)
+        .build()?;
+    println!("Chat with the bots on {}", server.local_addr());
+    server.run()
+}
@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use log::{error, warn};
+use rand_core::OsRng;
+
+use crate::nick::{self, NickError};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Storing passwords.
+//
+// Never store a password, and never store a plain hash of one either: a
+// fast hash like SHA-256 lets an attacker who steals the file try billions
+// of guesses a second. Argon2 is a *password* hash - deliberately slow and
+// memory-hungry, so each guess costs real time and RAM. Every hash also gets
+// its own random salt, so two users with the same password get different
+// hashes and precomputed tables are useless.
+//
+// The result is a self-describing "PHC string":
+//
+//   $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+//
+// which records the algorithm and its cost settings, so we can raise the
+// cost later without breaking old accounts.
+//
+// The user store is a plain text file, one "name:phc-string" per line. It
+// is rewritten whole (to a temporary file, then renamed over the old one) so
+// a crash never leaves it half written, and re-read whenever it changes on
+// disk, so `server user add` takes effect on a running server.
+//
+// Slow hashing has a cost for us too: it must never run on an event loop
+// worker, or every client on that worker would freeze for the duration. The
+// Hub runs logins on their own thread (see Hub::authenticate).
+//
+// A slow hash doesn't stop someone guessing online, one /login at a time.
+// That's what the lockout is for: after `max_failures` wrong passwords in a
+// row, the account refuses logins for `lockout`.
+// ---------------------------------------------------------------------------
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountsConfig {
+    /// The user store. `None` turns accounts off: anyone may pick any free
+    /// nickname, as before.
+    pub file: Option<PathBuf>,
+    /// Whether people without an account may chat, under any nickname that
+    /// isn't registered.
+    pub guests: bool,
+    /// Whether clients may create their own accounts with /register.
+    /// Otherwise only `server user add` can.
+    pub registration: bool,
+    /// Wrong passwords in a row before an account is locked.
+    pub max_failures: u32,
+    /// How long a locked account stays locked.
+    pub lockout: Duration,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        AccountsConfig {
+            file: None,
+            guests: false,
+            registration: true,
+            max_failures: 5,
+            lockout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    /// Deliberately vague: we don't tell a stranger which names exist.
+    BadCredentials,
+    Locked(Duration),
+    Exists,
+    NotFound,
+    WeakPassword,
+    RegistrationClosed,
+    InvalidName(NickError),
+    Io(io::Error),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::BadCredentials => write!(f, "wrong name or password"),
+            AccountError::Locked(left) => write!(
+                f,
+                "too many failed logins; account locked for another {}s",
+                left.as_secs().max(1)
+            ),
+            AccountError::Exists => write!(f, "that name is already registered"),
+            AccountError::NotFound => write!(f, "no such account"),
+            AccountError::WeakPassword => write!(
+                f,
+                "password must be at least {} characters",
+                MIN_PASSWORD_LEN
+            ),
+            AccountError::RegistrationClosed => {
+                write!(f, "registration is closed; ask an admin for an account")
+            }
+            AccountError::InvalidName(e) => write!(f, "{}", e),
+            AccountError::Io(e) => write!(f, "user store error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl From<io::Error> for AccountError {
+    fn from(e: io::Error) -> Self {
+        AccountError::Io(e)
+    }
+}
+
+pub struct Accounts {
+    path: PathBuf,
+    config: AccountsConfig,
+    store: Mutex<Store>,
+    // Failed logins per account (lowercased name). Kept in memory only: a
+    // restart clears lockouts, which is fine for a rate limit.
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+#[derive(Default)]
+struct Store {
+    // Keyed by lowercased name, since names are case-insensitive.
+    users: BTreeMap<String, User>,
+    // The file's modification time when we last read it.
+    modified: Option<SystemTime>,
+}
+
+struct User {
+    name: String,
+    hash: String,
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+impl Accounts {
+    /// Open the user store at `path`. A missing file is an empty store.
+    pub fn open(path: &Path, config: AccountsConfig) -> io::Result<Accounts> {
+        let accounts = Accounts {
+            path: path.to_path_buf(),
+            config,
+            store: Mutex::new(Store::default()),
+            failures: Mutex::new(HashMap::new()),
+        };
+        accounts.refresh(&mut accounts.store.lock().unwrap())?;
+        Ok(accounts)
+    }
+
+    pub fn config(&self) -> &AccountsConfig {
+        &self.config
+    }
+
+    /// Whether `name` belongs to an account.
+    pub fn exists(&self, name: &str) -> io::Result<bool> {
+        let mut store = self.store.lock().unwrap();
+        self.refresh(&mut store)?;
+        Ok(store.users.contains_key(&name.to_ascii_lowercase()))
+    }
+
+    /// Every account name, sorted.
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        let mut store = self.store.lock().unwrap();
+        self.refresh(&mut store)?;
+        Ok(store.users.values().map(|u| u.name.clone()).collect())
+    }
+
+    /// Create an account. Slow: hashes the password.
+    pub fn add(&self, name: &str, password: &str) -> Result<(), AccountError> {
+        nick::validate(name).map_err(AccountError::InvalidName)?;
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AccountError::WeakPassword);
+        }
+        if self.exists(name)? {
+            return Err(AccountError::Exists);
+        }
+        // Hash without holding the lock...
+        let hash = hash_password(password)?;
+        // ...then check again, in case someone took the name meanwhile.
+        let mut store = self.store.lock().unwrap();
+        self.refresh(&mut store)?;
+        let key = name.to_ascii_lowercase();
+        if store.users.contains_key(&key) {
+            return Err(AccountError::Exists);
+        }
+        store.users.insert(
+            key,
+            User {
+                name: name.to_string(),
+                hash,
+            },
+        );
+        self.save(&mut store)?;
+        Ok(())
+    }
+
+    /// Create an account from a client's /register, if registration is open.
+    pub fn register(&self, name: &str, password: &str) -> Result<(), AccountError> {
+        if !self.config.registration {
+            return Err(AccountError::RegistrationClosed);
+        }
+        self.add(name, password)
+    }
+
+    /// Delete an account.
+    pub fn remove(&self, name: &str) -> Result<(), AccountError> {
+        let mut store = self.store.lock().unwrap();
+        self.refresh(&mut store)?;
+        if store.users.remove(&name.to_ascii_lowercase()).is_none() {
+            return Err(AccountError::NotFound);
+        }
+        self.save(&mut store)?;
+        Ok(())
+    }
+
+    /// Check a password. Returns the account's name as registered (with its
+    /// original capitalisation). Slow: hashes the password.
+    pub fn verify(&self, name: &str, password: &str) -> Result<String, AccountError> {
+        let key = name.to_ascii_lowercase();
+        if let Some(left) = self.locked_for(&key) {
+            return Err(AccountError::Locked(left));
+        }
+        let user = {
+            let mut store = self.store.lock().unwrap();
+            self.refresh(&mut store)?;
+            store
+                .users
+                .get(&key)
+                .map(|u| (u.name.clone(), u.hash.clone()))
+        };
+
+        let verified = match &user {
+            Some((_, hash)) => check_password(password, hash),
+            None => {
+                // Spend the same time as a real check, so response times
+                // don't reveal which names have accounts.
+                let _ = hash_password(password);
+                false
+            }
+        };
+        match user {
+            Some((name, _)) if verified => {
+                self.failures.lock().unwrap().remove(&key);
+                Ok(name)
+            }
+            Some(_) => {
+                self.record_failure(&key);
+                Err(AccountError::BadCredentials)
+            }
+            None => Err(AccountError::BadCredentials),
+        }
+    }
+
+    // How much longer `key` is locked out, if it is.
+    fn locked_for(&self, key: &str) -> Option<Duration> {
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.get_mut(key)?;
+        let until = entry.locked_until?;
+        let now = Instant::now();
+        if until > now {
+            return Some(until - now);
+        }
+        // The lockout is over: start counting afresh.
+        failures.remove(key);
+        None
+    }
+
+    fn record_failure(&self, key: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(key.to_string()).or_default();
+        entry.count += 1;
+        if entry.count >= self.config.max_failures {
+            entry.locked_until = Some(Instant::now() + self.config.lockout);
+            warn!("Account {} locked after {} failed logins", key, entry.count);
+        }
+    }
+
+    // Re-read the file if it changed since we last looked.
+    fn refresh(&self, store: &mut Store) -> io::Result<()> {
+        let modified = match fs::metadata(&self.path) {
+            Ok(meta) => meta.modified().ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                store.users.clear();
+                store.modified = None;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if modified.is_some() && modified == store.modified {
+            return Ok(());
+        }
+        let contents = fs::read_to_string(&self.path)?;
+        store.users.clear();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((name, hash)) = line.split_once(':') else {
+                warn!("Skipping malformed line in {}", self.path.display());
+                continue;
+            };
+            store.users.insert(
+                name.to_ascii_lowercase(),
+                User {
+                    name: name.to_string(),
+                    hash: hash.to_string(),
+                },
+            );
+        }
+        store.modified = modified;
+        Ok(())
+    }
+
+    // Write the whole store out: to a temporary file, then rename it into
+    // place, which replaces the old file in one step.
+    fn save(&self, store: &mut Store) -> io::Result<()> {
+        let mut contents = String::new();
+        for user in store.users.values() {
+            contents.push_str(&format!("{}:{}\n", user.name, user.hash));
+        }
+        let tmp = self.path.with_extension("tmp");
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &self.path)?;
+        store.modified = fs::metadata(&self.path)?.modified().ok();
+        Ok(())
+    }
+}
+
+fn hash_password(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+fn check_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(e) => {
+            error!("Unreadable password hash in the user store: {}", e);
+            false
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use protocol::{Event, Frame, Framing};
+
+use crate::event_loop::Mailbox;
+use crate::outbox::{Outbox, Pushed, Receipt};
+use crate::ratelimit::{Limiter, Verdict};
+use crate::ClientId;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Sending without touching the socket.
+//
+// In the thread-per-connection server, send() locked the client's TcpStream
+// and called write_all() right there, on whichever thread wanted to talk.
+// One client with a full socket buffer would block that thread - and since
+// broadcast() held the client list lock at the time, everyone else with it.
+//
+// Now only the event loop worker that owns a connection ever writes to its
+// socket. Everyone else encodes the frame, drops the bytes into the client's
+// outbox and pokes the worker through its Mailbox. The worker is the one
+// dedicated writer for that socket: it writes as much as the socket will
+// take without blocking and comes back for the rest when the socket is
+// writable again. The outbox is bounded (see outbox.rs), so a client that
+// stops reading can't make it grow forever.
+// ---------------------------------------------------------------------------
+
+pub struct Client {
+    pub id: ClientId,
+    pub peer: SocketAddr,
+    /// Fixed at handshake time, so it needs no lock.
+    pub framing: Framing,
+    /// The protocol version agreed at handshake time. Line clients count as
+    /// version 1: they get text.
+    pub version: u8,
+    // The nickname can change with /nick, so it gets its own small Mutex, as
+    // does the room the client is currently talking in. An empty nickname
+    // means the client hasn't logged in yet.
+    nick: Mutex<String>,
+    room: Mutex<Option<String>>,
+    login_attempts: AtomicUsize,
+    // Set while a /login or /register is being checked, so a client can't
+    // pile up password checks.
+    authenticating: AtomicBool,
+    // Logged in to an account, rather than as a guest.
+    authenticated: AtomicBool,
+    // May use the moderation commands.
+    operator: AtomicBool,
+    // For /who: when the connection was made, when the client last said
+    // anything, and why it's away, if it is.
+    connected: Instant,
+    active: Mutex<Instant>,
+    away: Mutex<Option<String>>,
+    // Text frames received since logging in, for acks (see event.rs in the
+    // protocol crate).
+    lines: AtomicU64,
+    // Wants a receipt for every delivery of its messages.
+    receipts: AtomicBool,
+    // Set when this is another server rather than a person (see
+    // federation.rs): the name it goes by.
+    server_name: Mutex<Option<String>>,
+    // Flood protection for what this client sends us, if turned on.
+    limiter: Option<Mutex<Limiter>>,
+    outbox: Outbox,
+    // Set while the client is on its worker's ready list, so a burst of
+    // sends only wakes the worker once.
+    scheduled: AtomicBool,
+    closing: AtomicBool,
+    mailbox: Arc<Mailbox>,
+}
+
+// Each connected client gets a handle so other connections can reach it.
+pub type ClientHandle = Arc<Client>;
+
+impl Client {
+    pub(crate) fn new(
+        id: ClientId,
+        peer: SocketAddr,
+        framing: Framing,
+        version: u8,
+        outbox: Outbox,
+        limiter: Option<Limiter>,
+        mailbox: Arc<Mailbox>,
+    ) -> Self {
+        Client {
+            id,
+            peer,
+            framing,
+            version,
+            nick: Mutex::new(String::new()),
+            room: Mutex::new(None),
+            login_attempts: AtomicUsize::new(0),
+            authenticating: AtomicBool::new(false),
+            authenticated: AtomicBool::new(false),
+            operator: AtomicBool::new(false),
+            connected: Instant::now(),
+            active: Mutex::new(Instant::now()),
+            away: Mutex::new(None),
+            lines: AtomicU64::new(0),
+            receipts: AtomicBool::new(false),
+            server_name: Mutex::new(None),
+            limiter: limiter.map(Mutex::new),
+            outbox,
+            scheduled: AtomicBool::new(false),
+            closing: AtomicBool::new(false),
+            mailbox,
+        }
+    }
+
+    /// Gets typed events rather than preformatted text.
+    pub fn takes_events(&self) -> bool {
+        self.version >= protocol::EVENTS_VERSION
+    }
+
+    /// Gets an Ack for each chat line, and receipts if it asks.
+    pub fn takes_acks(&self) -> bool {
+        self.version >= protocol::ACKS_VERSION
+    }
+
+    /// Can send and receive files.
+    pub fn takes_files(&self) -> bool {
+        self.version >= protocol::FILES_VERSION
+    }
+
+    pub fn nick(&self) -> String {
+        self.nick.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_nick(&self, nick: &str) -> String {
+        std::mem::replace(&mut *self.nick.lock().unwrap(), nick.to_string())
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        !self.nick.lock().unwrap().is_empty()
+    }
+
+    /// The nickname if logged in, otherwise the socket address.
+    pub fn label(&self) -> String {
+        if let Some(name) = self.peer_name() {
+            return format!("peer {} ({})", name, self.peer);
+        }
+        let nick = self.nick();
+        if nick.is_empty() {
+            self.peer.to_string()
+        } else {
+            format!("{} ({})", nick, self.peer)
+        }
+    }
+
+    pub fn room(&self) -> Option<String> {
+        self.room.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_room(&self, room: Option<String>) {
+        *self.room.lock().unwrap() = room;
+    }
+
+    /// Count a failed login and return how many there have been.
+    pub(crate) fn failed_login(&self) -> usize {
+        self.login_attempts.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Start checking a password. False if a check is already under way.
+    pub(crate) fn begin_auth(&self) -> bool {
+        !self.authenticating.swap(true, Ordering::AcqRel)
+    }
+
+    pub(crate) fn end_auth(&self) {
+        self.authenticating.store(false, Ordering::Release);
+    }
+
+    /// Whether the client logged in to an account rather than as a guest.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_authenticated(&self, authenticated: bool) {
+        self.authenticated.store(authenticated, Ordering::Release);
+    }
+
+    /// Whether the client may use the moderation commands.
+    pub fn is_operator(&self) -> bool {
+        self.operator.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_operator(&self, operator: bool) {
+        self.operator.store(operator, Ordering::Release);
+    }
+
+    /// How long ago the connection was made.
+    pub fn connected_for(&self) -> Duration {
+        self.connected.elapsed()
+    }
+
+    /// How long since the client last said anything. Heartbeats don't
+    /// count: they come from the program, not the person.
+    pub fn idle_for(&self) -> Duration {
+        self.active.lock().unwrap().elapsed()
+    }
+
+    pub(crate) fn touch(&self) {
+        *self.active.lock().unwrap() = Instant::now();
+    }
+
+    /// Why the client is away, if it is.
+    pub fn away(&self) -> Option<String> {
+        self.away.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_away(&self, reason: Option<String>) {
+        *self.away.lock().unwrap() = reason;
+    }
+
+    /// Count a Text frame from the client. Returns its number, from 1.
+    pub(crate) fn next_line(&self) -> u64 {
+        self.lines.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Whether the client asked for delivery receipts.
+    pub fn wants_receipts(&self) -> bool {
+        self.receipts.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_receipts(&self, on: bool) {
+        self.receipts.store(on, Ordering::Relaxed);
+    }
+
+    /// The server's name, if this is a federation link.
+    pub fn peer_name(&self) -> Option<String> {
+        self.server_name.lock().unwrap().clone()
+    }
+
+    pub fn is_peer(&self) -> bool {
+        self.server_name.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn set_peer(&self, name: &str) {
+        *self.server_name.lock().unwrap() = Some(name.to_string());
+    }
+
+    /// Charge one incoming message to this client's rate limit.
+    pub(crate) fn rate_check(&self) -> Verdict {
+        match &self.limiter {
+            Some(limiter) => limiter.lock().unwrap().check(Instant::now()),
+            None => Verdict::Allow,
+        }
+    }
+
+    /// Messages from this client dropped by the rate limit.
+    pub fn flood_dropped(&self) -> u64 {
+        self.limiter
+            .as_ref()
+            .map_or(0, |limiter| limiter.lock().unwrap().dropped())
+    }
+
+    /// Queue a frame for this client, encoded for its framing mode. Never
+    /// blocks on the network. Fails if the client is closing, or if its
+    /// outbox is full and the overflow policy is to disconnect it.
+    ///
+    /// A client that takes events gets notices, errors and private messages
+    /// as the matching event.
+    pub fn send(&self, frame: &Frame) -> io::Result<()> {
+        match Event::from_legacy(frame).filter(|_| self.takes_events()) {
+            Some(event) => self.push(&Frame::event(&event), None),
+            None => self.push(frame, None),
+        }
+    }
+
+    /// Queue an event, or its text for a client that doesn't take events.
+    pub fn send_event(&self, event: &Event) -> io::Result<()> {
+        self.deliver(event, None)
+    }
+
+    /// Like send_event(), with a receipt for the sender once it's written.
+    pub(crate) fn deliver(&self, event: &Event, receipt: Option<Receipt>) -> io::Result<()> {
+        if self.takes_events() {
+            self.push(&Frame::event(event), receipt)
+        } else {
+            self.push(&event.to_frame(), receipt)
+        }
+    }
+
+    /// Queue a frame as it is, with a receipt for once it's written. For
+    /// file chunks, which are passed on untouched.
+    pub(crate) fn forward(&self, frame: &Frame, receipt: Receipt) -> io::Result<()> {
+        self.push(frame, Some(receipt))
+    }
+
+    fn push(&self, frame: &Frame, receipt: Option<Receipt>) -> io::Result<()> {
+        if self.is_closing() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let bytes = protocol::encode(self.framing, frame);
+        match self.outbox.push_with_receipt(bytes, receipt) {
+            Pushed::Queued => {}
+            Pushed::DroppedOldest => {
+                // Log the first loss only; the total is logged on disconnect.
+                if self.outbox.dropped() == 1 {
+                    warn!(
+                        "{} is not keeping up; dropping its oldest messages",
+                        self.label()
+                    );
+                }
+            }
+            Pushed::Overflowed => {
+                warn!("{} is not keeping up; disconnecting it", self.label());
+                let notice = Event::error("disconnected: too many messages waiting for you");
+                let notice = if self.takes_events() {
+                    Frame::event(&notice)
+                } else {
+                    notice.to_frame()
+                };
+                self.outbox
+                    .push_unbounded(protocol::encode(self.framing, &notice));
+                self.close();
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "outbox full"));
+            }
+        }
+        self.schedule();
+        Ok(())
+    }
+
+    /// Messages this client lost because its outbox was full.
+    pub fn dropped(&self) -> u64 {
+        self.outbox.dropped()
+    }
+
+    /// Ask the worker to flush what's queued and then hang up.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Release);
+        // Always wake the worker, even if we're already scheduled: a client
+        // whose socket is stuck may never become writable again, and the
+        // worker must still get round to closing it.
+        self.mailbox.ready(self.id);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Acquire)
+    }
+
+    fn schedule(&self) {
+        // swap() returns the old value: only the first sender since the last
+        // flush has to put us on the ready list and wake the worker.
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            self.mailbox.ready(self.id);
+        }
+    }
+
+    /// Take everything queued so far. Called by the owning worker only, and
+    /// only once it has written out what it took last time - frames wait
+    /// here, where the capacity applies, until the socket can take them.
+    pub(crate) fn take_outbox(&self) -> (VecDeque<Vec<u8>>, Vec<Receipt>) {
+        // Clear the flag *before* draining: a send that lands after this
+        // will schedule us again instead of being stranded in the queue.
+        self.scheduled.store(false, Ordering::Release);
+        self.outbox.take_with_receipts()
+    }
+}
@@ -0,0 +1,285 @@
+use std::time::Duration;
+
+use crate::transfer;
+
+// ---------------------------------------------------------------------------
+// Slash commands.
+//
+// Any chat line starting with '/' is a command for the server rather than a
+// message for other users. Parsing is kept separate from executing so the
+// rules are easy to test without a socket.
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `/nick <name>` - change nickname.
+    Nick(String),
+    /// `/join <#room>` - join (or switch to) a room.
+    Join(String),
+    /// `/part [#room]` - leave a room, the current one if none is given.
+    Part(Option<String>),
+    /// `/list` - show every room and how many people are in it.
+    List,
+    /// `/msg <nick> <text>` - send a private message to one user.
+    Msg {
+        to: String,
+        text: String,
+    },
+    /// `/who` - list everyone online, how long they've been idle and
+    /// connected, and who is away.
+    Who,
+    /// `/away [reason]` - mark yourself away; bare `/away` means you're
+    /// back.
+    Away(Option<String>),
+    /// `/receipts on|off` - hear when each of your messages reaches each
+    /// recipient. Needs a protocol version 3 client.
+    Receipts(bool),
+    /// `/offer <nick> <size> <sha256> <name>` - offer a user a file. The
+    /// client sends this for you when you type /send (see transfer.rs).
+    Offer {
+        to: String,
+        size: u64,
+        sha256: String,
+        name: String,
+    },
+    /// `/accept <id>` - take a file you were offered.
+    Accept(u64),
+    /// `/cancel <id>` (or `/reject <id>`) - call off a transfer, either
+    /// side, before or during.
+    Cancel(u64),
+    /// `/history <n>` - show the last n messages in the current room.
+    History(usize),
+    /// `/register <name> <password>` - create an account and log in.
+    Register {
+        name: String,
+        password: String,
+    },
+    /// `/login <name> <password>` - log in to an account.
+    Login {
+        name: String,
+        password: String,
+    },
+    /// `/peer <server-name> [secret]` - log in as another server rather
+    /// than a person (see federation.rs).
+    Peer {
+        name: String,
+        secret: Option<String>,
+    },
+    /// `/kick <nick> [reason]` - disconnect a user. Operators only.
+    Kick {
+        nick: String,
+        reason: String,
+    },
+    /// `/ban <nick|ip> [reason]` - keep a user or address out for good, and
+    /// kick them if they are here. Bare `/ban` lists the bans. Operators
+    /// only.
+    Ban {
+        target: String,
+        reason: String,
+    },
+    Bans,
+    /// `/unban <nick|ip>` - lift a ban. Operators only.
+    Unban(String),
+    /// `/mute <nick> <duration>` - silence a user for a while, e.g. 30s,
+    /// 10m or 2h. Operators only.
+    Mute {
+        nick: String,
+        time: Duration,
+    },
+    /// `/unmute <nick>` - lift a mute early. Operators only.
+    Unmute(String),
+    /// `/op <nick>` - make a user an operator until they disconnect.
+    /// Operators only.
+    Op(String),
+    /// `/deop <nick>` - take operator status away. Operators only.
+    Deop(String),
+}
+
+/// Parse a chat line. Returns `None` for ordinary chat, `Some(Err(usage))`
+/// for a command typed wrong.
+pub fn parse(line: &str) -> Option<Result<Command, String>> {
+    let rest = line.strip_prefix('/')?;
+    let (name, args) = match rest.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (rest, ""),
+    };
+
+    Some(match name {
+        "nick" => match args {
+            "" => Err("usage: /nick <name>".to_string()),
+            nick => Ok(Command::Nick(nick.to_string())),
+        },
+        "join" => match args {
+            "" => Err("usage: /join <#room>".to_string()),
+            room => Ok(Command::Join(room.to_string())),
+        },
+        "part" => match args {
+            "" => Ok(Command::Part(None)),
+            room => Ok(Command::Part(Some(room.to_string()))),
+        },
+        "list" => Ok(Command::List),
+        "msg" => match args.split_once(char::is_whitespace) {
+            Some((to, text)) if !text.trim().is_empty() => Ok(Command::Msg {
+                to: to.to_string(),
+                text: text.trim().to_string(),
+            }),
+            _ => Err("usage: /msg <nick> <text>".to_string()),
+        },
+        "who" => Ok(Command::Who),
+        "away" => match args {
+            "" => Ok(Command::Away(None)),
+            reason => Ok(Command::Away(Some(reason.to_string()))),
+        },
+        "receipts" => match args {
+            "on" => Ok(Command::Receipts(true)),
+            "off" => Ok(Command::Receipts(false)),
+            _ => Err("usage: /receipts on|off".to_string()),
+        },
+        "offer" => offer(args).ok_or_else(|| {
+            "usage: /offer <nick> <size> <sha256> <name>, or /send <nick> <path> in the client"
+                .to_string()
+        }),
+        "accept" => match args.parse() {
+            Ok(id) => Ok(Command::Accept(id)),
+            Err(_) => Err("usage: /accept <transfer id>".to_string()),
+        },
+        "cancel" | "reject" => match args.parse() {
+            Ok(id) => Ok(Command::Cancel(id)),
+            Err(_) => Err(format!("usage: /{} <transfer id>", name)),
+        },
+        "history" => match args.parse() {
+            Ok(n) if n > 0 => Ok(Command::History(n)),
+            _ => Err("usage: /history <n>".to_string()),
+        },
+        "register" => credentials(args)
+            .map(|(name, password)| Command::Register { name, password })
+            .ok_or_else(|| "usage: /register <name> <password>".to_string()),
+        "login" => credentials(args)
+            .map(|(name, password)| Command::Login { name, password })
+            .ok_or_else(|| "usage: /login <name> <password>".to_string()),
+        "peer" => match args.split_once(char::is_whitespace) {
+            _ if args.is_empty() => Err("usage: /peer <server-name> [secret]".to_string()),
+            Some((name, secret)) => Ok(Command::Peer {
+                name: name.to_string(),
+                secret: Some(secret.trim().to_string()),
+            }),
+            None => Ok(Command::Peer {
+                name: args.to_string(),
+                secret: None,
+            }),
+        },
+        "kick" => match split_reason(args) {
+            Some((nick, reason)) => Ok(Command::Kick { nick, reason }),
+            None => Err("usage: /kick <nick> [reason]".to_string()),
+        },
+        "ban" => match split_reason(args) {
+            Some((target, reason)) => Ok(Command::Ban { target, reason }),
+            None => Ok(Command::Bans),
+        },
+        "unban" => match args {
+            "" => Err("usage: /unban <nick|ip>".to_string()),
+            target => Ok(Command::Unban(target.to_string())),
+        },
+        "mute" => match args.split_once(char::is_whitespace) {
+            Some((nick, time)) => match parse_duration(time.trim()) {
+                Some(time) => Ok(Command::Mute {
+                    nick: nick.to_string(),
+                    time,
+                }),
+                None => Err("usage: /mute <nick> <duration>, e.g. 30s, 10m or 2h".to_string()),
+            },
+            None => Err("usage: /mute <nick> <duration>, e.g. 30s, 10m or 2h".to_string()),
+        },
+        "unmute" => match args {
+            "" => Err("usage: /unmute <nick>".to_string()),
+            nick => Ok(Command::Unmute(nick.to_string())),
+        },
+        "op" => match args {
+            "" => Err("usage: /op <nick>".to_string()),
+            nick => Ok(Command::Op(nick.to_string())),
+        },
+        "deop" => match args {
+            "" => Err("usage: /deop <nick>".to_string()),
+            nick => Ok(Command::Deop(nick.to_string())),
+        },
+        other => Err(format!("unknown command /{}", other)),
+    })
+}
+
+/// A duration like "90", "90s", "15m", "2h" or "1d". A bare number is
+/// seconds. Zero is not a duration.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => text.split_at(at),
+        None => (text, "s"),
+    };
+    let n: u64 = number.parse().ok().filter(|&n| n > 0)?;
+    let secs = match unit {
+        "s" => n,
+        "m" => n.checked_mul(60)?,
+        "h" => n.checked_mul(60 * 60)?,
+        "d" => n.checked_mul(24 * 60 * 60)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs))
+}
+
+/// A duration the way people say it: "45s", "12m", "3h 5m", "2d 4h". Only
+/// the two largest units, since nobody cares about the seconds of a day.
+pub fn format_duration(time: Duration) -> String {
+    let secs = time.as_secs();
+    let (days, hours, minutes) = (secs / 86_400, secs % 86_400 / 3600, secs % 3600 / 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, m) => format!("{}m", m),
+        (0, h, 0) => format!("{}h", h),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, 0, _) => format!("{}d", d),
+        (d, h, _) => format!("{}d {}h", d, h),
+    }
+}
+
+// "<target> [reason]". The reason is everything after the target, and may
+// be empty.
+fn split_reason(args: &str) -> Option<(String, String)> {
+    match args.split_once(char::is_whitespace) {
+        Some((target, reason)) => Some((target.to_string(), reason.trim().to_string())),
+        None if !args.is_empty() => Some((args.to_string(), String::new())),
+        None => None,
+    }
+}
+
+// "<nick> <size> <sha256> <name>". The name is everything after the hash,
+// so it may contain spaces, but it's a file name, not a path.
+fn offer(args: &str) -> Option<Command> {
+    let mut parts = args.splitn(4, char::is_whitespace);
+    let to = parts.next()?.to_string();
+    let size = parts.next()?.parse().ok()?;
+    let sha256 = parts.next()?.to_ascii_lowercase();
+    let name = parts.next()?.trim().to_string();
+    let plain = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\'])
+        && !name.chars().any(char::is_control);
+    if !plain || !transfer::is_sha256(&sha256) {
+        return None;
+    }
+    Some(Command::Offer {
+        to,
+        size,
+        sha256,
+        name,
+    })
+}
+
+// "<name> <password>". The password is everything after the name, so it may
+// contain spaces.
+fn credentials(args: &str) -> Option<(String, String)> {
+    let (name, password) = args.split_once(char::is_whitespace)?;
+    let password = password.trim();
+    if password.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), password.to_string()))
+}
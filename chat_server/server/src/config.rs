@@ -0,0 +1,439 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::Args;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+
+use crate::federation::FederationConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::logging::LogConfig;
+use crate::nick;
+use crate::outbox::OverflowPolicy;
+use crate::ratelimit::RateLimitConfig;
+use crate::settings::Settings;
+use crate::tls::TlsSettings;
+use crate::transfer::TransferConfig;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Layered configuration.
+//
+// Every setting can come from three places, and the more specific one wins:
+//
+//   command-line flag  >  environment variable  >  config file  >  default
+//
+// A config file holds what a deployment always wants, the environment is
+// how containers and service managers pass settings in, and a flag is for
+// "just this once". clap already handles the first two for us (each flag
+// names its environment variable), so `Options` is one struct that clap
+// fills from the command line and serde fills from the TOML file, with every
+// field optional. Merging is then just "take mine, else theirs", field by
+// field, and the defaults are applied last, when turning Options into
+// Settings.
+//
+// The file uses the same names as the flags, with underscores:
+//
+//   bind = ["127.0.0.1", "::1"]
+//   port = 8080
+//   motd = "Be nice."
+//   outbox_capacity = 512
+// ---------------------------------------------------------------------------
+
+/// Port used for bind addresses that don't name one.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Port used for WebSocket addresses that don't name one.
+pub const DEFAULT_WEBSOCKET_PORT: u16 = 8081;
+
+/// Port used for metrics addresses that don't name one.
+pub const DEFAULT_METRICS_PORT: u16 = 9898;
+
+// A few event loop threads are plenty; more than the machine has cores
+// would just be the thread-per-connection problem again on a smaller scale.
+pub(crate) const MAX_WORKERS: usize = 8;
+
+// Where --self-signed puts its certificate unless told otherwise.
+const DEV_CERT: &str = "tls/cert.pem";
+const DEV_KEY: &str = "tls/key.pem";
+
+// Where history and accounts live unless told otherwise.
+const DEFAULT_HISTORY: &str = "history";
+const DEFAULT_ACCOUNTS: &str = "accounts.txt";
+const DEFAULT_BANS: &str = "bans.txt";
+const DEFAULT_MODERATION_LOG: &str = "moderation.log";
+
+/// Everything the server can be told, from any source. `None` means "not
+/// set here".
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Address to listen on: an IP, IP:port or host name. Repeat (or
+    /// separate with commas) to listen on several.
+    #[arg(long, env = "CHAT_BIND", value_delimiter = ',')]
+    pub bind: Option<Vec<String>>,
+
+    /// Port for bind addresses that don't include one [default: 8080].
+    #[arg(long, env = "CHAT_PORT")]
+    pub port: Option<u16>,
+
+    /// Also accept WebSocket clients (browsers) on this address: an IP,
+    /// IP:port or host name, port 8081 if none is given. Repeat (or separate
+    /// with commas) for several [default: off].
+    #[arg(long, env = "CHAT_WEBSOCKET", value_delimiter = ',')]
+    pub websocket: Option<Vec<String>>,
+
+    /// Serve Prometheus metrics over HTTP, at /metrics on this address: an
+    /// IP, IP:port or host name, port 9898 if none is given. Repeat (or
+    /// separate with commas) for several [default: off].
+    #[arg(long, env = "CHAT_METRICS", value_delimiter = ',')]
+    pub metrics: Option<Vec<String>>,
+
+    /// Event loop threads [default: one per core, at most 8].
+    #[arg(long, env = "CHAT_WORKERS")]
+    pub workers: Option<usize>,
+
+    /// Message of the day, shown to everyone who logs in.
+    #[arg(long, env = "CHAT_MOTD")]
+    pub motd: Option<String>,
+
+    /// Least severe log level to record: error, warn, info, debug, trace or
+    /// off [default: info].
+    #[arg(long, env = "CHAT_LOG_LEVEL")]
+    #[serde(deserialize_with = "parsed")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Append the log to this file instead of printing it.
+    #[arg(long, env = "CHAT_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Frames each client may have waiting to be sent [default: 1024].
+    #[arg(long, env = "CHAT_OUTBOX_CAPACITY")]
+    pub outbox_capacity: Option<usize>,
+
+    /// What to do when a client's outbox is full: drop-oldest or disconnect
+    /// [default: disconnect].
+    #[arg(long, env = "CHAT_OVERFLOW")]
+    #[serde(deserialize_with = "parsed")]
+    pub overflow: Option<OverflowPolicy>,
+
+    /// Directory for the message history, or "off" [default: history].
+    #[arg(long, env = "CHAT_HISTORY_DIR")]
+    pub history_dir: Option<PathBuf>,
+
+    /// Messages replayed to someone joining a room [default: 20].
+    #[arg(long, env = "CHAT_HISTORY_REPLAY")]
+    pub history_replay: Option<usize>,
+
+    /// Certificate chain (PEM) to serve TLS with. Needs --tls-key.
+    #[arg(long, env = "CHAT_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) for --tls-cert.
+    #[arg(long, env = "CHAT_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve TLS with a generated development certificate (in tls/) when no
+    /// certificate is configured.
+    #[arg(long)]
+    pub self_signed: bool,
+
+    /// The user store, or "off" for no accounts [default: accounts.txt].
+    #[arg(long, env = "CHAT_ACCOUNTS")]
+    pub accounts: Option<PathBuf>,
+
+    /// Whether people without an account may chat [default: false].
+    #[arg(long, env = "CHAT_GUESTS")]
+    pub guests: Option<bool>,
+
+    /// Whether clients may /register accounts [default: true].
+    #[arg(long, env = "CHAT_REGISTRATION")]
+    pub registration: Option<bool>,
+
+    /// Messages per second each client may send; 0 turns rate limiting off
+    /// [default: 5].
+    #[arg(long, env = "CHAT_RATE")]
+    pub rate: Option<f64>,
+
+    /// Messages a client may send in one burst [default: 10].
+    #[arg(long, env = "CHAT_BURST")]
+    pub burst: Option<u32>,
+
+    /// The ban list, or "off" to keep bans in memory only [default:
+    /// bans.txt].
+    #[arg(long, env = "CHAT_BANS")]
+    pub bans: Option<PathBuf>,
+
+    /// Where moderation actions are recorded, or "off" [default:
+    /// moderation.log].
+    #[arg(long, env = "CHAT_MODERATION_LOG")]
+    pub moderation_log: Option<PathBuf>,
+
+    /// Accounts that are operators whenever they log in. Repeat (or separate
+    /// with commas) for several.
+    #[arg(long, env = "CHAT_OPERATORS", value_delimiter = ',')]
+    pub operators: Option<Vec<String>>,
+
+    /// Seconds between heartbeat pings; 0 turns them off [default: 15].
+    #[arg(long, env = "CHAT_HEARTBEAT")]
+    pub heartbeat: Option<u64>,
+
+    /// Unanswered pings before a client is dropped [default: 2].
+    #[arg(long, env = "CHAT_HEARTBEAT_MISSED")]
+    pub heartbeat_missed: Option<u32>,
+
+    /// This server's name among federated servers; its users appear
+    /// elsewhere as nick@name. Needed to peer [default: off].
+    #[arg(long, env = "CHAT_SERVER_NAME")]
+    pub server_name: Option<String>,
+
+    /// Another server to link with: IP:port or host:port, port 8080 if none
+    /// is given. Repeat (or separate with commas) for several.
+    #[arg(long, env = "CHAT_PEERS", value_delimiter = ',')]
+    pub peer: Option<Vec<String>>,
+
+    /// What linking servers must present, on both sides of a link.
+    #[arg(long, env = "CHAT_PEER_SECRET", hide_env_values = true)]
+    pub peer_secret: Option<String>,
+
+    /// Largest file clients may send each other, in MiB; 0 turns file
+    /// transfer off [default: 10].
+    #[arg(long, env = "CHAT_MAX_FILE_MB")]
+    pub max_file_mb: Option<u64>,
+}
+
+impl Options {
+    /// Read options from a TOML file.
+    pub fn load(path: &Path) -> io::Result<Options> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Options::from_toml(&text)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn from_toml(text: &str) -> io::Result<Options> {
+        toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// These options, with anything they leave unset taken from `fallback`.
+    pub fn or(self, fallback: Options) -> Options {
+        Options {
+            bind: self.bind.or(fallback.bind),
+            port: self.port.or(fallback.port),
+            websocket: self.websocket.or(fallback.websocket),
+            metrics: self.metrics.or(fallback.metrics),
+            workers: self.workers.or(fallback.workers),
+            motd: self.motd.or(fallback.motd),
+            log_level: self.log_level.or(fallback.log_level),
+            log_file: self.log_file.or(fallback.log_file),
+            outbox_capacity: self.outbox_capacity.or(fallback.outbox_capacity),
+            overflow: self.overflow.or(fallback.overflow),
+            history_dir: self.history_dir.or(fallback.history_dir),
+            history_replay: self.history_replay.or(fallback.history_replay),
+            tls_cert: self.tls_cert.or(fallback.tls_cert),
+            tls_key: self.tls_key.or(fallback.tls_key),
+            self_signed: self.self_signed || fallback.self_signed,
+            accounts: self.accounts.or(fallback.accounts),
+            guests: self.guests.or(fallback.guests),
+            registration: self.registration.or(fallback.registration),
+            bans: self.bans.or(fallback.bans),
+            moderation_log: self.moderation_log.or(fallback.moderation_log),
+            operators: self.operators.or(fallback.operators),
+            rate: self.rate.or(fallback.rate),
+            burst: self.burst.or(fallback.burst),
+            heartbeat: self.heartbeat.or(fallback.heartbeat),
+            heartbeat_missed: self.heartbeat_missed.or(fallback.heartbeat_missed),
+            server_name: self.server_name.or(fallback.server_name),
+            peer: self.peer.or(fallback.peer),
+            peer_secret: self.peer_secret.or(fallback.peer_secret),
+            max_file_mb: self.max_file_mb.or(fallback.max_file_mb),
+        }
+    }
+
+    /// Every address to listen on. Host names may resolve to several.
+    pub fn listen_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+        let default = ["127.0.0.1".to_string()];
+        let addrs = resolve(self.bind.as_deref().unwrap_or(&default), port)?;
+        if addrs.is_empty() {
+            return Err(invalid_input("nothing to listen on"));
+        }
+        Ok(addrs)
+    }
+
+    /// Every address to accept WebSocket clients on; none unless asked for.
+    pub fn websocket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        match &self.websocket {
+            Some(binds) => resolve(binds, DEFAULT_WEBSOCKET_PORT),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Every address to serve metrics on; none unless asked for.
+    pub fn metrics_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        match &self.metrics {
+            Some(binds) => resolve(binds, DEFAULT_METRICS_PORT),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(2)
+                .min(MAX_WORKERS)
+        })
+    }
+
+    pub fn log_config(&self) -> LogConfig {
+        LogConfig {
+            level: self.log_level.unwrap_or(LevelFilter::Info),
+            file: self.log_file.clone(),
+        }
+    }
+
+    /// The user store, unless accounts are turned off.
+    pub fn accounts_file(&self) -> Option<PathBuf> {
+        switchable(&self.accounts, DEFAULT_ACCOUNTS)
+    }
+
+    /// Turn the options into server settings, filling in defaults.
+    pub fn settings(&self) -> io::Result<Settings> {
+        let mut settings = Settings::default();
+        if let Some(capacity) = self.outbox_capacity {
+            settings.outbox.capacity = capacity;
+        }
+        if let Some(policy) = self.overflow {
+            settings.outbox.policy = policy;
+        }
+
+        settings.history.dir = switchable(&self.history_dir, DEFAULT_HISTORY);
+        if let Some(replay) = self.history_replay {
+            settings.history.replay = replay;
+        }
+
+        settings.tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsSettings {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            (None, None) if self.self_signed => Some(TlsSettings {
+                cert: DEV_CERT.into(),
+                key: DEV_KEY.into(),
+            }),
+            (None, None) => None,
+            _ => return Err(invalid_input("set both tls_cert and tls_key, or neither")),
+        };
+
+        settings.accounts.file = self.accounts_file();
+        if let Some(guests) = self.guests {
+            settings.accounts.guests = guests;
+        }
+        if let Some(registration) = self.registration {
+            settings.accounts.registration = registration;
+        }
+
+        settings.moderation.bans = switchable(&self.bans, DEFAULT_BANS);
+        settings.moderation.log = switchable(&self.moderation_log, DEFAULT_MODERATION_LOG);
+        settings.moderation.operators = self.operators.clone().unwrap_or_default();
+
+        let mut rate_limit = RateLimitConfig::default();
+        if let Some(rate) = self.rate {
+            rate_limit.rate = rate;
+        }
+        if let Some(burst) = self.burst {
+            rate_limit.burst = burst;
+        }
+        settings.rate_limit = (rate_limit.rate > 0.0 && rate_limit.burst > 0).then_some(rate_limit);
+
+        let mut heartbeat = HeartbeatConfig::default();
+        if let Some(secs) = self.heartbeat {
+            heartbeat.interval = Duration::from_secs(secs);
+        }
+        if let Some(missed) = self.heartbeat_missed {
+            heartbeat.missed = missed.max(1);
+        }
+        settings.heartbeat = (!heartbeat.interval.is_zero()).then_some(heartbeat);
+
+        settings.motd = self.motd.clone().filter(|motd| !motd.trim().is_empty());
+
+        let mut transfers = TransferConfig::default();
+        if let Some(mb) = self.max_file_mb {
+            transfers.max_size = mb.saturating_mul(1024 * 1024);
+        }
+        settings.transfers = (transfers.max_size > 0).then_some(transfers);
+
+        settings.federation = match &self.server_name {
+            Some(name) => {
+                nick::validate(name)
+                    .map_err(|e| invalid_input(format!("bad server_name {:?}: {}", name, e)))?;
+                Some(FederationConfig {
+                    name: name.clone(),
+                    peers: resolve(self.peer.as_deref().unwrap_or_default(), DEFAULT_PORT)?,
+                    secret: self.peer_secret.clone(),
+                })
+            }
+            None if self.peer.is_some() => {
+                return Err(invalid_input("set server_name to link with peers"));
+            }
+            None => None,
+        };
+        Ok(settings)
+    }
+}
+
+// A path setting that defaults to on, and that "off" (or an empty value)
+// turns off.
+fn switchable(value: &Option<PathBuf>, default: &str) -> Option<PathBuf> {
+    match value {
+        None => Some(default.into()),
+        Some(path) if path.as_os_str().is_empty() || path == Path::new("off") => None,
+        Some(path) => Some(path.clone()),
+    }
+}
+
+// Read a config file value with the same FromStr the flags use, so both
+// accept exactly the same spellings.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+// Turn bind addresses into socket addresses, using `port` for those that
+// don't name one.
+fn resolve(binds: &[String], port: u16) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    for bind in binds {
+        let bind = bind.trim();
+        if let Ok(addr) = bind.parse::<SocketAddr>() {
+            addrs.push(addr);
+        } else if let Ok(ip) = bind
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            addrs.push(SocketAddr::new(ip, port));
+        } else {
+            let resolved = (bind, port)
+                .to_socket_addrs()
+                .map_err(|e| invalid_input(format!("bad bind address {:?}: {}", bind, e)))?;
+            addrs.extend(resolved);
+        }
+    }
+    addrs.dedup();
+    Ok(addrs)
+}
+
+fn invalid_input(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
@@ -0,0 +1,1213 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use protocol::{Decoder, Frame, Framing, Hello};
+use rustls::{ServerConfig, ServerConnection};
+use socket2::{Domain, Socket, Type};
+
+use crate::client::{Client, ClientHandle};
+use crate::federation::{self, FederationConfig, DIAL_RETRY};
+use crate::heartbeat::{Beat, Heartbeat};
+use crate::hub::Hub;
+use crate::metrics;
+use crate::outbox::Receipt;
+use crate::shutdown::Shutdown;
+use crate::tls;
+use crate::websocket::{self, Handshake, Message};
+use crate::ClientId;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Why an event loop?
+//
+// The first server spawned one OS thread per connection. Each thread spends
+// almost all its life blocked in read(), but still costs a stack (8 MB of
+// address space by default), a kernel task and a context switch every time
+// it wakes up. A few thousand users and the machine is mostly busy juggling
+// threads.
+//
+// A readiness-based server flips it around. Every socket is non-blocking,
+// and we ask the OS (epoll on Linux, kqueue on macOS - mio hides the
+// difference) "tell me which of these sockets have something for me". One
+// thread can then serve thousands of connections, doing work only when a
+// socket is actually ready.
+//
+// We run a small fixed pool of such loops ("workers"), one per core, so we
+// still use every CPU. The accept loop hands each new connection to a worker
+// round-robin, and it stays on that worker for its whole life. Links to
+// other servers (see federation.rs) are dialed on threads of their own,
+// since connecting and logging in block, then handed over the same way.
+//
+// Browser clients come in through their own listener and speak WebSocket
+// (see websocket.rs). Once the upgrade is done, a worker unwraps each text
+// message into a line and feeds it to the same line decoder an old TCP
+// client uses, and wraps each line going out into a text message. The Hub
+// can't tell them apart, so they share rooms with everyone else.
+//
+// The catch: you must never block inside the loop. No blocking reads, no
+// write_all(), no sleeping. If a write can't finish, remember what's left
+// and ask to be told when the socket is writable again.
+// ---------------------------------------------------------------------------
+
+// How long a new connection has to send the framed-protocol magic before we
+// assume it's an old newline client waiting for a prompt.
+const HELLO_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How long a browser has to send its WebSocket upgrade request.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Token 0 is the worker's waker. Client ids start at 1, so a client's token
+// is just its id. In the accept loop, each listener's token is its index,
+// and the shutdown waker gets one no listener will ever have.
+const WAKER: Token = Token(0);
+const SHUTDOWN: Token = Token(usize::MAX);
+
+// How many frames one connection may have handled per turn of the loop.
+// Without a limit, a client pasting thousands of lines would be served to
+// the last one before anyone else got a look in - and before a single
+// broadcast was flushed, so every other client's outbox would fill up while
+// nothing was being written.
+const FRAME_BUDGET: usize = 32;
+
+// How long the accept loop backs off after an accept error (e.g. out of
+// file descriptors) before trying again.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+// How long a shutdown waits for clients to take what's still queued for
+// them before hanging up anyway.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+// What clients are told when the server shuts down.
+const SHUTDOWN_MESSAGE: &str = "server shutting down";
+
+/// How other threads reach a worker. Anyone may put work in; only the worker
+/// takes it out.
+pub struct Mailbox {
+    waker: Waker,
+    // Clients with something new in their outbox.
+    ready: Mutex<Vec<ClientId>>,
+    // Freshly accepted (or dialed) connections for this worker to adopt.
+    incoming: Mutex<Vec<(TcpStream, SocketAddr, ClientId, Kind)>>,
+}
+
+impl Mailbox {
+    /// Tell the worker a client has output waiting.
+    pub(crate) fn ready(&self, id: ClientId) {
+        self.ready.lock().unwrap().push(id);
+        let _ = self.waker.wake();
+    }
+
+    fn hand_over(&self, stream: TcpStream, peer: SocketAddr, id: ClientId, kind: Kind) {
+        self.incoming.lock().unwrap().push((stream, peer, id, kind));
+        let _ = self.waker.wake();
+    }
+
+    // Just get the worker to look around, e.g. at the shutdown switch.
+    fn wake(&self) {
+        let _ = self.waker.wake();
+    }
+}
+
+/// A listening socket, and what it expects to be spoken on it.
+pub enum Listener {
+    /// Our own protocol, framed or line mode.
+    Chat(std::net::TcpListener),
+    /// WebSocket, for browsers.
+    WebSocket(std::net::TcpListener),
+    /// HTTP, for whatever collects the metrics (see metrics.rs).
+    Metrics(std::net::TcpListener),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Chat,
+    WebSocket,
+    /// A link we dialed to the server `name`, already logged in.
+    Dialed {
+        name: String,
+        version: u8,
+    },
+}
+
+/// Bind a listening socket. An IPv6 address listens for IPv6 only, so the
+/// same port can be bound on an IPv4 address too.
+pub fn listen(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // Let a restarted server bind straight away, even while connections
+    // from its previous life are still in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Run the server on already-bound listeners with `workers` event loop
+/// threads. Fails early if the TLS certificate can't be loaded; otherwise
+/// only returns if the accept loop fails.
+pub fn serve(
+    listeners: Vec<std::net::TcpListener>,
+    hub: Arc<Hub>,
+    workers: usize,
+) -> io::Result<()> {
+    let listeners = listeners.into_iter().map(Listener::Chat).collect();
+    serve_until(listeners, hub, workers, Shutdown::new())
+}
+
+/// Like `serve`, but stops gracefully once `shutdown` is triggered: no new
+/// connections, a Shutdown frame to every client, a bounded wait for their
+/// outboxes to drain, then the workers are joined and the history synced
+/// before it returns.
+pub fn serve_until(
+    listeners: Vec<Listener>,
+    hub: Arc<Hub>,
+    workers: usize,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let tls = match &hub.settings().tls {
+        Some(settings) => Some(tls::server_config(settings)?),
+        None => None,
+    };
+
+    let mut mailboxes = Vec::with_capacity(workers.max(1));
+    let mut threads = Vec::with_capacity(workers.max(1));
+    for n in 0..workers.max(1) {
+        let poll = Poll::new()?;
+        let mailbox = Arc::new(Mailbox {
+            waker: Waker::new(poll.registry(), WAKER)?,
+            ready: Mutex::new(Vec::new()),
+            incoming: Mutex::new(Vec::new()),
+        });
+        let worker = Worker {
+            poll,
+            mailbox: Arc::clone(&mailbox),
+            hub: Arc::clone(&hub),
+            tls: tls.clone(),
+            conns: HashMap::new(),
+            hellos: VecDeque::new(),
+            upgrades: VecDeque::new(),
+            backlog: Vec::new(),
+            next_beat: hub
+                .settings()
+                .heartbeat
+                .map(|config| Instant::now() + config.interval),
+            shutdown: shutdown.clone(),
+            draining: None,
+        };
+        let thread = thread::Builder::new()
+            .name(format!("worker-{}", n))
+            .spawn(move || worker.run())?;
+        mailboxes.push(mailbox);
+        threads.push(thread);
+    }
+
+    // Scrapes are answered off the event loops, each metrics listener on a
+    // thread of its own.
+    let mut scrapers = Vec::new();
+    let mut chat_listeners = Vec::new();
+    for listener in listeners {
+        match listener {
+            Listener::Chat(listener) => chat_listeners.push((listener, Kind::Chat)),
+            Listener::WebSocket(listener) => chat_listeners.push((listener, Kind::WebSocket)),
+            Listener::Metrics(listener) => {
+                let hub = Arc::clone(&hub);
+                let shutdown = shutdown.clone();
+                let thread = thread::Builder::new()
+                    .name("metrics".to_string())
+                    .spawn(move || metrics::serve(listener, hub, shutdown))?;
+                scrapers.push(thread);
+            }
+        }
+    }
+
+    let mut poll = Poll::new()?;
+    shutdown.wake_on_trigger(Arc::new(Waker::new(poll.registry(), SHUTDOWN)?));
+    let mut listeners = chat_listeners
+        .into_iter()
+        .map(|(listener, kind)| {
+            listener.set_nonblocking(true)?;
+            Ok((TcpListener::from_std(listener), kind))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    for (n, (listener, _)) in listeners.iter_mut().enumerate() {
+        poll.registry()
+            .register(listener, Token(n), Interest::READABLE)?;
+    }
+    let mut events = Events::with_capacity(64);
+    let ids = Arc::new(AtomicU64::new(0));
+    let mut timeout = None;
+
+    if let Some(federation) = hub.federation() {
+        for &addr in &federation.config().peers {
+            let dialer = Dialer {
+                addr,
+                hub: Arc::clone(&hub),
+                mailboxes: mailboxes.clone(),
+                ids: Arc::clone(&ids),
+                shutdown: shutdown.clone(),
+            };
+            thread::Builder::new()
+                .name(format!("dial-{}", addr))
+                .spawn(move || dialer.run())?;
+        }
+    }
+
+    loop {
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        timeout = None;
+
+        if shutdown.is_requested() {
+            break;
+        }
+
+        // mio is edge-triggered: we only hear about a listener once per
+        // batch of connections, so keep accepting until it says WouldBlock.
+        // Retrying after an error means trying all of them.
+        let ready: Vec<usize> = if events.is_empty() {
+            (0..listeners.len()).collect()
+        } else {
+            events.iter().map(|event| event.token().0).collect()
+        };
+        for (listener, kind) in ready.into_iter().filter_map(|n| listeners.get(n)) {
+            loop {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        info!("New connection from {}", peer);
+                        let _ = stream.set_nodelay(true);
+                        let id: ClientId = ids.fetch_add(1, Ordering::Relaxed) + 1;
+                        let mailbox = &mailboxes[id as usize % mailboxes.len()];
+                        mailbox.hand_over(stream, peer, id, kind.clone());
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Accept error: {}", e);
+                        // There may still be connections waiting, but we won't
+                        // get another edge for them. Come back shortly.
+                        timeout = Some(ACCEPT_RETRY);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Closing the listeners turns new clients away at the door.
+    drop(listeners);
+    info!("No longer accepting connections");
+    for mailbox in &mailboxes {
+        mailbox.wake();
+    }
+    for thread in threads {
+        if thread.join().is_err() {
+            error!("A worker panicked during shutdown");
+        }
+    }
+    for thread in scrapers {
+        match thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("The metrics endpoint failed: {}", e),
+            Err(_) => error!("The metrics endpoint panicked"),
+        }
+    }
+    hub.sync_history()?;
+    info!("Shutdown complete");
+    Ok(())
+}
+
+// Keeps one configured peer linked: dials it, and dials again whenever the
+// link is down. Dialers aren't joined on shutdown; they notice within one
+// step of their sleep, or one dial timeout.
+struct Dialer {
+    addr: SocketAddr,
+    hub: Arc<Hub>,
+    mailboxes: Vec<Arc<Mailbox>>,
+    ids: Arc<AtomicU64>,
+    shutdown: Shutdown,
+}
+
+impl Dialer {
+    fn run(self) {
+        let Some(federation) = self.hub.federation() else {
+            return;
+        };
+        // The name the peer gave last time, and the last error, so a peer
+        // that stays down is only complained about once.
+        let mut name: Option<String> = None;
+        let mut failed: Option<String> = None;
+        while !self.shutdown.is_requested() {
+            let up = name
+                .as_deref()
+                .is_some_and(|name| federation.is_linked(name));
+            if !up {
+                match self.dial(federation.config()) {
+                    Ok(linked) => {
+                        info!("Dialed {} at {}", linked, self.addr);
+                        name = Some(linked);
+                        failed = None;
+                    }
+                    Err(e) => {
+                        let e = e.to_string();
+                        if failed.as_ref() != Some(&e) {
+                            warn!("Could not link with {}: {}", self.addr, e);
+                            failed = Some(e);
+                        }
+                    }
+                }
+            }
+            // Sleep in short steps, so shutting down isn't held up.
+            let wake = Instant::now() + DIAL_RETRY;
+            while Instant::now() < wake && !self.shutdown.is_requested() {
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    // Dial and log in, then hand the link to a worker. Returns the name the
+    // peer gave.
+    fn dial(&self, config: &FederationConfig) -> io::Result<String> {
+        let link = federation::dial(self.addr, config)?;
+        if self.shutdown.is_requested() {
+            return Err(io::Error::other("shutting down"));
+        }
+        link.stream.set_nonblocking(true)?;
+        let _ = link.stream.set_nodelay(true);
+        let stream = TcpStream::from_std(link.stream);
+        let id: ClientId = self.ids.fetch_add(1, Ordering::Relaxed) + 1;
+        let mailbox = &self.mailboxes[id as usize % self.mailboxes.len()];
+        let kind = Kind::Dialed {
+            name: link.name.clone(),
+            version: link.version,
+        };
+        mailbox.hand_over(stream, self.addr, id, kind);
+        Ok(link.name)
+    }
+}
+
+struct Worker {
+    poll: Poll,
+    mailbox: Arc<Mailbox>,
+    hub: Arc<Hub>,
+    tls: Option<Arc<ServerConfig>>,
+    conns: HashMap<Token, Conn>,
+    // Connections still waiting for their hello, oldest first. Every one
+    // gets the same timeout, so the front always expires first.
+    hellos: VecDeque<(Instant, Token)>,
+    // The same for WebSocket connections waiting for their upgrade request.
+    upgrades: VecDeque<(Instant, Token)>,
+    // Connections that used up their frame budget with data still waiting.
+    // No new edge will come for that data, so we go back to them ourselves.
+    backlog: Vec<Token>,
+    // When to next ping every client, if heartbeats are on.
+    next_beat: Option<Instant>,
+    shutdown: Shutdown,
+    // Once shutting down, when we stop waiting for clients to drain.
+    draining: Option<Instant>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            // With a backlog, just check for new events and get straight
+            // back to work.
+            let timeout = if self.backlog.is_empty() {
+                let hello = self.hellos.front().map(|&(deadline, _)| deadline);
+                let upgrade = self.upgrades.front().map(|&(deadline, _)| deadline);
+                [hello, upgrade, self.next_beat, self.draining]
+                    .into_iter()
+                    .flatten()
+                    .min()
+                    .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            } else {
+                Some(Duration::ZERO)
+            };
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Worker poll failed: {}", e);
+                return;
+            }
+
+            let mut backlog = std::mem::take(&mut self.backlog);
+            for event in events.iter() {
+                let token = event.token();
+                // The waker just gets us out of poll(); the work it signals
+                // is picked up below.
+                if token == WAKER {
+                    continue;
+                }
+                if event.is_readable() || event.is_read_closed() || event.is_error() {
+                    // One turn, one budget: don't serve it again from the
+                    // backlog below.
+                    backlog.retain(|&t| t != token);
+                    self.on_readable(token);
+                }
+                if event.is_writable() {
+                    self.flush(token);
+                }
+            }
+            for token in backlog {
+                self.on_readable(token);
+            }
+
+            self.adopt_incoming();
+            self.expire_hellos();
+            self.expire_upgrades();
+            self.heartbeat();
+            self.flush_ready();
+
+            if self.shutdown.is_requested() && self.drain() {
+                return;
+            }
+        }
+    }
+
+    // Shutting down: tell everyone, then wait for their outboxes to empty.
+    // flush() hangs up on each client once it has nothing left to send.
+    // Returns true once everyone is gone, or we stopped waiting.
+    fn drain(&mut self) -> bool {
+        let deadline = match self.draining {
+            Some(deadline) => deadline,
+            None => {
+                let deadline = Instant::now() + SHUTDOWN_GRACE;
+                self.draining = Some(deadline);
+                let tokens: Vec<Token> = self.conns.keys().copied().collect();
+                for token in tokens {
+                    let conn = &self.conns[&token];
+                    if let State::Open { client, .. } = &conn.state {
+                        let _ = client.send(&Frame::shutdown(SHUTDOWN_MESSAGE));
+                    }
+                    self.flush(token);
+                }
+                deadline
+            }
+        };
+        if self.conns.is_empty() {
+            return true;
+        }
+        if Instant::now() < deadline {
+            return false;
+        }
+        warn!(
+            "Gave up waiting for {} client(s) to take their last messages",
+            self.conns.len()
+        );
+        let tokens: Vec<Token> = self.conns.keys().copied().collect();
+        for token in tokens {
+            self.close(token);
+        }
+        true
+    }
+
+    fn adopt_incoming(&mut self) {
+        let incoming = std::mem::take(&mut *self.mailbox.incoming.lock().unwrap());
+        for (mut stream, peer, id, kind) in incoming {
+            if self.shutdown.is_requested() {
+                info!("Turning away {}: shutting down", peer);
+                continue;
+            }
+            let token = Token(id as usize);
+            let tls = match &self.tls {
+                // Links between servers are plain TCP (see federation.rs).
+                Some(_) if matches!(kind, Kind::Dialed { .. }) => None,
+                Some(config) => match ServerConnection::new(Arc::clone(config)) {
+                    Ok(tls) => Some(Box::new(tls)),
+                    Err(e) => {
+                        warn!("Could not start TLS for {}: {}", peer, e);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            if let Err(e) = self
+                .poll
+                .registry()
+                .register(&mut stream, token, Interest::READABLE)
+            {
+                warn!("Could not register {}: {}", peer, e);
+                continue;
+            }
+            let state = match kind {
+                Kind::Chat => {
+                    let deadline = Instant::now() + HELLO_TIMEOUT;
+                    self.hellos.push_back((deadline, token));
+                    State::Hello { seen: Vec::new() }
+                }
+                Kind::WebSocket => {
+                    let deadline = Instant::now() + UPGRADE_TIMEOUT;
+                    self.upgrades.push_back((deadline, token));
+                    State::Upgrade { seen: Vec::new() }
+                }
+                // Opened as soon as it's in place, below.
+                Kind::Dialed { .. } => State::Hello { seen: Vec::new() },
+            };
+            self.conns.insert(
+                token,
+                Conn {
+                    stream,
+                    tls,
+                    peer,
+                    id,
+                    state,
+                    write_buf: Vec::new(),
+                    receipts: Vec::new(),
+                    want_write: false,
+                    budget: FRAME_BUDGET,
+                    heartbeat: None,
+                    websocket: None,
+                    hang_up: false,
+                },
+            );
+            self.hub
+                .metrics()
+                .connections
+                .fetch_add(1, Ordering::Relaxed);
+            if let Kind::Dialed { name, version } = kind {
+                if let Some(conn) = self.conns.get_mut(&token) {
+                    conn.open_link(&name, version, &self.hub, &self.mailbox);
+                }
+            }
+        }
+    }
+
+    // A connection that stayed silent is an old line client waiting to be
+    // prompted for a nickname.
+    fn expire_hellos(&mut self) {
+        let now = Instant::now();
+        while let Some(&(deadline, token)) = self.hellos.front() {
+            if deadline > now {
+                break;
+            }
+            self.hellos.pop_front();
+            let Some(conn) = self.conns.get_mut(&token) else {
+                continue;
+            };
+            if let State::Hello { seen } = &mut conn.state {
+                let seen = std::mem::take(seen);
+                info!("{} is a line-mode client", conn.peer);
+                conn.open(Framing::Lines, 1, &seen, &self.hub, &self.mailbox);
+            }
+        }
+    }
+
+    // A browser that connected but never asked to upgrade isn't going to.
+    fn expire_upgrades(&mut self) {
+        let now = Instant::now();
+        while let Some(&(deadline, token)) = self.upgrades.front() {
+            if deadline > now {
+                break;
+            }
+            self.upgrades.pop_front();
+            let stalled = self
+                .conns
+                .get(&token)
+                .is_some_and(|conn| matches!(conn.state, State::Upgrade { .. }));
+            if stalled {
+                info!(
+                    "{} never sent a WebSocket upgrade request",
+                    self.conns[&token].peer
+                );
+                self.close(token);
+            }
+        }
+    }
+
+    // Ping every framed client, and drop the ones that have stopped
+    // answering (see heartbeat.rs). WebSocket clients get WebSocket pings,
+    // which browsers answer by themselves.
+    fn heartbeat(&mut self) {
+        let Some(config) = self.hub.settings().heartbeat else {
+            return;
+        };
+        // Nobody gets dropped for being slow to answer while we're leaving.
+        if self.draining.is_some() {
+            return;
+        }
+        let now = Instant::now();
+        match self.next_beat {
+            Some(due) if due <= now => {}
+            _ => return,
+        }
+        self.next_beat = Some(now + config.interval);
+
+        let mut dead = Vec::new();
+        let mut pinged = Vec::new();
+        for (&token, conn) in &mut self.conns {
+            let (State::Open { client, .. }, Some(heartbeat)) = (&conn.state, &mut conn.heartbeat)
+            else {
+                continue;
+            };
+            match heartbeat.beat() {
+                Beat::Ping(n) if conn.websocket.is_some() => {
+                    conn.write_buf
+                        .extend_from_slice(&websocket::ping(n.to_string().as_bytes()));
+                    pinged.push(token);
+                }
+                Beat::Ping(n) => {
+                    let _ = client.send(&Frame::ping(&n.to_string()));
+                }
+                Beat::Dead => {
+                    info!(
+                        "{} missed {} heartbeats; disconnecting",
+                        client.label(),
+                        heartbeat.unanswered()
+                    );
+                    dead.push(token);
+                }
+            }
+        }
+        for token in dead {
+            self.close(token);
+        }
+        for token in pinged {
+            self.flush(token);
+        }
+    }
+
+    fn flush_ready(&mut self) {
+        let ready = std::mem::take(&mut *self.mailbox.ready.lock().unwrap());
+        for id in ready {
+            self.flush(Token(id as usize));
+        }
+    }
+
+    fn on_readable(&mut self, token: Token) {
+        // Once shutting down, we only write.
+        if self.draining.is_some() {
+            return;
+        }
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        conn.budget = FRAME_BUDGET;
+        // Frames left over from a turn that ran out of budget go first.
+        conn.process(&self.hub);
+        let mut buf = [0u8; 4096];
+        let mut open = true;
+        // Edge-triggered again: drain the socket until WouldBlock or we'd
+        // never hear about the rest of the data - unless the budget runs
+        // out first, in which case the backlog brings us back next turn.
+        loop {
+            // Stop reading from a client we've decided to drop; flush()
+            // below sends its last words and closes it.
+            if conn.is_closing() {
+                break;
+            }
+            if conn.budget == 0 {
+                self.backlog.push(token);
+                break;
+            }
+            match conn.read_some(&mut buf) {
+                Ok(0) => {
+                    open = false;
+                    break;
+                }
+                Ok(n) => {
+                    self.hub
+                        .metrics()
+                        .bytes_in
+                        .fetch_add(n as u64, Ordering::Relaxed);
+                    if let Some(heartbeat) = &mut conn.heartbeat {
+                        heartbeat.heard();
+                    }
+                    if !conn.received(&buf[..n], &self.hub, &self.mailbox) {
+                        open = false;
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Error reading from {}: {}", conn.peer, e);
+                    open = false;
+                    break;
+                }
+            }
+        }
+        if open {
+            // Answers to whatever we just handled (e.g. the server hello).
+            self.flush(token);
+        } else {
+            self.close(token);
+        }
+    }
+
+    // Write as much as the socket will take: first whatever is left in the
+    // write buffer, then the next batch from the client's outbox. With TLS,
+    // "the socket" includes whatever rustls still has to send.
+    fn flush(&mut self, token: Token) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        let closing = conn.is_closing();
+
+        loop {
+            // Only pull from the outbox once the last batch is fully
+            // written. If the socket is stuck, frames pile up in the
+            // outbox, where its capacity and overflow policy apply.
+            if conn.write_buf.is_empty() {
+                if let State::Open { client, .. } = &conn.state {
+                    let (frames, receipts) = client.take_outbox();
+                    conn.receipts.extend(receipts);
+                    self.hub
+                        .metrics()
+                        .messages_out
+                        .fetch_add(frames.len() as u64, Ordering::Relaxed);
+                    for bytes in frames {
+                        match &conn.websocket {
+                            // One line, one text message.
+                            Some(_) => {
+                                let line = String::from_utf8_lossy(&bytes);
+                                let line = line.strip_suffix('\n').unwrap_or(&line);
+                                conn.write_buf.extend_from_slice(&websocket::text(line));
+                            }
+                            None => conn.write_buf.extend_from_slice(&bytes),
+                        }
+                    }
+                }
+            }
+            if conn.write_buf.is_empty() && !conn.tls_wants_write() {
+                break;
+            }
+            match conn.write_some() {
+                Ok(0) => {
+                    self.close(token);
+                    return;
+                }
+                Ok(n) => {
+                    self.hub
+                        .metrics()
+                        .bytes_out
+                        .fetch_add(n as u64, Ordering::Relaxed);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.hub
+                        .metrics()
+                        .write_errors
+                        .fetch_add(1, Ordering::Relaxed);
+                    warn!("Error writing to {}: {}", conn.peer, e);
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+
+        // A closing client gets one last chance to receive its error frame;
+        // we don't wait around for a peer that isn't reading. When the whole
+        // server is shutting down, everyone is waited for until everything
+        // is out (or drain() runs out of patience), however long that is.
+        let unsent = !conn.write_buf.is_empty() || conn.tls_wants_write();
+        // Everything taken from the outbox so far is out, so whoever asked
+        // can hear it was delivered.
+        if !unsent && !conn.receipts.is_empty() {
+            if let State::Open { client, .. } = &conn.state {
+                self.hub.delivered(client, conn.receipts.drain(..));
+            }
+        }
+        if closing || (self.draining.is_some() && !unsent) {
+            self.close(token);
+            return;
+        }
+
+        // Only ask for writable events while there's something left to write,
+        // otherwise poll would wake us constantly.
+        let want_write = unsent;
+        if want_write != conn.want_write {
+            let interest = if want_write {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            if let Err(e) = self
+                .poll
+                .registry()
+                .reregister(&mut conn.stream, token, interest)
+            {
+                warn!("Could not update {}: {}", conn.peer, e);
+            }
+            conn.want_write = want_write;
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        let Some(mut conn) = self.conns.remove(&token) else {
+            return;
+        };
+        self.hub
+            .metrics()
+            .disconnections
+            .fetch_add(1, Ordering::Relaxed);
+        let _ = self.poll.registry().deregister(&mut conn.stream);
+        // A browser wants a close frame before the connection goes. Like
+        // close_notify below, it's sent only if the socket takes it now.
+        let code = if self.draining.is_some() {
+            websocket::CLOSE_GOING_AWAY
+        } else {
+            websocket::CLOSE_NORMAL
+        };
+        if conn.ws_close(code, "") {
+            let _ = conn.write_some();
+        }
+        if let Some(tls) = &mut conn.tls {
+            // Say goodbye properly, if the socket will take it, so the peer
+            // can tell a hang-up from a truncation attack.
+            tls.send_close_notify();
+            let _ = tls.write_tls(&mut conn.stream);
+        }
+        match conn.state {
+            State::Open { client, .. } => {
+                // Mark it first so nobody queues more output for a socket
+                // that's about to be dropped.
+                client.close();
+                self.hub.disconnected(&client);
+            }
+            State::Hello { .. } | State::Upgrade { .. } => {
+                info!("{} left during the handshake.", conn.peer);
+            }
+        }
+        // Dropping conn closes the socket.
+    }
+}
+
+struct Conn {
+    stream: TcpStream,
+    // The TLS session layered on the stream, when serving TLS.
+    tls: Option<Box<ServerConnection>>,
+    peer: SocketAddr,
+    id: ClientId,
+    state: State,
+    // Bytes taken from the outbox that the socket hasn't accepted yet.
+    write_buf: Vec<u8>,
+    // Receipts for frames in write_buf, sent once it's all written.
+    receipts: Vec<Receipt>,
+    want_write: bool,
+    // Frames it may still have handled this turn.
+    budget: usize,
+    // Set once the connection turns out to be a framed client, if
+    // heartbeats are on.
+    heartbeat: Option<Heartbeat>,
+    // Set once a browser's upgrade request has been accepted.
+    websocket: Option<WebSocket>,
+    // We answered with something we can't follow up on (a refused upgrade),
+    // so hang up once that's written.
+    hang_up: bool,
+}
+
+// A WebSocket connection's own state.
+struct WebSocket {
+    decoder: websocket::Decoder,
+    // Only one close frame per connection.
+    close_sent: bool,
+}
+
+enum State {
+    // Waiting to find out whether this is a framed or a line client.
+    Hello {
+        seen: Vec<u8>,
+    },
+    // A browser that hasn't finished its upgrade request yet.
+    Upgrade {
+        seen: Vec<u8>,
+    },
+    Open {
+        client: ClientHandle,
+        decoder: Decoder,
+    },
+}
+
+impl Conn {
+    fn is_closing(&self) -> bool {
+        self.hang_up || matches!(&self.state, State::Open { client, .. } if client.is_closing())
+    }
+
+    // Queue a WebSocket close frame, unless one was already sent. False if
+    // there was nothing to send (not a WebSocket, or already closed).
+    fn ws_close(&mut self, code: u16, reason: &str) -> bool {
+        let Some(ws) = &mut self.websocket else {
+            return false;
+        };
+        if ws.close_sent {
+            return false;
+        }
+        ws.close_sent = true;
+        self.write_buf
+            .extend_from_slice(&websocket::close(code, reason));
+        true
+    }
+
+    // Hang up once everything queued so far has been written.
+    fn finish(&mut self) {
+        match &self.state {
+            State::Open { client, .. } => client.close(),
+            _ => self.hang_up = true,
+        }
+    }
+
+    // Read plaintext: straight from the socket, or through TLS. Same
+    // contract as Read::read - Ok(0) is end of stream, WouldBlock means
+    // come back when the socket is readable.
+    fn read_some(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(tls) = &mut self.tls else {
+            return self.stream.read(buf);
+        };
+        loop {
+            // Anything already decrypted comes first. Ok(0) here means the
+            // peer sent close_notify; UnexpectedEof that it just hung up,
+            // which for a chat client is as good as saying goodbye.
+            match tls.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                result => return result,
+            }
+            if tls.read_tls(&mut self.stream)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = tls.process_new_packets() {
+                // Let the peer know why, if we can.
+                let _ = tls.write_tls(&mut self.stream);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+    }
+
+    // Push some of the write buffer towards the socket. Ok(0) means the
+    // socket is closed.
+    fn write_some(&mut self) -> io::Result<usize> {
+        let Some(tls) = &mut self.tls else {
+            let n = self.stream.write(&self.write_buf)?;
+            self.write_buf.drain(..n);
+            return Ok(n);
+        };
+        if !self.write_buf.is_empty() {
+            let n = tls.writer().write(&self.write_buf)?;
+            self.write_buf.drain(..n);
+        }
+        if tls.wants_write() {
+            tls.write_tls(&mut self.stream)
+        } else {
+            // rustls holds on to plaintext until the handshake is done,
+            // which needs the client to talk first.
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    fn tls_wants_write(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+
+    // Handle bytes fresh off the socket. Returns false if the connection
+    // should be closed.
+    fn received(&mut self, bytes: &[u8], hub: &Arc<Hub>, mailbox: &Arc<Mailbox>) -> bool {
+        if let State::Hello { seen } = &mut self.state {
+            seen.extend_from_slice(bytes);
+            let seen = std::mem::take(seen);
+            match protocol::parse_hello(&seen) {
+                Ok(Hello::Incomplete) => {
+                    self.state = State::Hello { seen };
+                }
+                Ok(Hello::Framed { version, consumed }) => {
+                    info!("{} speaks framed protocol v{}", self.peer, version);
+                    self.write_buf
+                        .extend_from_slice(&protocol::server_hello(version));
+                    // A quick client may have sent its first frame right
+                    // behind the hello; don't lose it.
+                    self.open(Framing::Framed, version, &seen[consumed..], hub, mailbox);
+                }
+                Ok(Hello::Lines) => {
+                    info!("{} is a line-mode client", self.peer);
+                    self.open(Framing::Lines, 1, &seen, hub, mailbox);
+                }
+                Err(e) => {
+                    warn!("Handshake with {} failed: {}", self.peer, e);
+                    return false;
+                }
+            }
+            return true;
+        }
+
+        if let State::Upgrade { seen } = &mut self.state {
+            seen.extend_from_slice(bytes);
+            let seen = std::mem::take(seen);
+            match websocket::parse_handshake(&seen) {
+                Handshake::Incomplete => self.state = State::Upgrade { seen },
+                Handshake::Accepted { response, consumed } => {
+                    info!("{} upgraded to WebSocket", self.peer);
+                    self.write_buf.extend_from_slice(&response);
+                    self.websocket = Some(WebSocket {
+                        decoder: websocket::Decoder::new(),
+                        close_sent: false,
+                    });
+                    // To the Hub, a browser is a line client.
+                    self.open(Framing::Lines, 1, &[], hub, mailbox);
+                    self.received_ws(&seen[consumed..], hub);
+                }
+                Handshake::Rejected(response) => {
+                    warn!("Refused a WebSocket upgrade from {}", self.peer);
+                    self.write_buf.extend_from_slice(&response);
+                    self.hang_up = true;
+                }
+            }
+            return true;
+        }
+
+        if self.websocket.is_some() {
+            self.received_ws(bytes, hub);
+            return true;
+        }
+
+        if let State::Open { decoder, .. } = &mut self.state {
+            decoder.feed(bytes);
+        }
+        self.process(hub);
+        true
+    }
+
+    // Unwrap WebSocket frames. Text messages go to the line decoder, one
+    // line each; control frames are answered here.
+    fn received_ws(&mut self, bytes: &[u8], hub: &Arc<Hub>) {
+        let Some(ws) = &mut self.websocket else {
+            return;
+        };
+        ws.decoder.feed(bytes);
+        let mut messages = Vec::new();
+        let mut failed = None;
+        loop {
+            match ws.decoder.next_message() {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            }
+        }
+
+        for message in messages {
+            match message {
+                Message::Text(text) => {
+                    if let State::Open { decoder, .. } = &mut self.state {
+                        decoder.feed(text.as_bytes());
+                        decoder.feed(b"\n");
+                    }
+                }
+                Message::Ping(payload) => {
+                    self.write_buf.extend_from_slice(&websocket::pong(&payload));
+                }
+                // Any traffic already counts for the heartbeat.
+                Message::Pong(_) => {}
+                Message::Binary(_) => {
+                    self.ws_close(websocket::CLOSE_UNSUPPORTED, "text messages only");
+                    self.finish();
+                    break;
+                }
+                Message::Close(_) => {
+                    self.ws_close(websocket::CLOSE_NORMAL, "");
+                    self.finish();
+                    break;
+                }
+            }
+        }
+        if let Some(e) = failed {
+            warn!("WebSocket error from {}: {}", self.peer, e);
+            self.ws_close(e.close_code(), &e.to_string());
+            self.finish();
+        }
+        self.process(hub);
+    }
+
+    fn open(
+        &mut self,
+        framing: Framing,
+        version: u8,
+        leftover: &[u8],
+        hub: &Arc<Hub>,
+        mailbox: &Arc<Mailbox>,
+    ) {
+        let client = self.new_client(framing, version, hub, mailbox);
+        hub.connected(&client);
+        self.start(client, framing, leftover, hub);
+    }
+
+    // A link we dialed to another server. It logged in while being dialed,
+    // so it goes straight to the hub as a peer.
+    fn open_link(&mut self, name: &str, version: u8, hub: &Arc<Hub>, mailbox: &Arc<Mailbox>) {
+        let client = self.new_client(Framing::Framed, version, hub, mailbox);
+        hub.dialed(&client, name);
+        self.start(client, Framing::Framed, &[], hub);
+    }
+
+    fn new_client(
+        &self,
+        framing: Framing,
+        version: u8,
+        hub: &Arc<Hub>,
+        mailbox: &Arc<Mailbox>,
+    ) -> ClientHandle {
+        Arc::new(Client::new(
+            self.id,
+            self.peer,
+            framing,
+            version,
+            hub.new_outbox(),
+            hub.new_limiter(),
+            Arc::clone(mailbox),
+        ))
+    }
+
+    fn start(&mut self, client: ClientHandle, framing: Framing, leftover: &[u8], hub: &Arc<Hub>) {
+        if framing == Framing::Framed || self.websocket.is_some() {
+            self.heartbeat = hub.settings().heartbeat.as_ref().map(Heartbeat::new);
+        }
+
+        // The decoder buffers partial messages and enforces MAX_FRAME_SIZE,
+        // so a peer that never finishes a line can't grow our memory forever.
+        let mut decoder = Decoder::new(framing);
+        decoder.feed(leftover);
+        self.state = State::Open { client, decoder };
+        self.process(hub);
+    }
+
+    // Hand complete frames in the decoder to the hub, as many as the budget
+    // allows. The rest wait in the decoder for the next turn.
+    fn process(&mut self, hub: &Arc<Hub>) {
+        let State::Open { client, decoder } = &mut self.state else {
+            return;
+        };
+        while self.budget > 0 && !client.is_closing() {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => {
+                    self.budget -= 1;
+                    hub.metrics().messages_in.fetch_add(1, Ordering::Relaxed);
+                    hub.handle_frame(client, frame);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    self.budget -= 1;
+                    hub.handle_frame_error(client, &e);
+                    if !e.is_recoverable() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use protocol::{Decoder, Event, Frame, Framing, MessageType, Status};
+
+use crate::client::ClientHandle;
+use crate::ClientId;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Federation - many servers, one chat.
+//
+// Two offices, two servers, and people on each who want to talk. Rather than
+// invent a second protocol, servers link up the way clients do: one dials
+// the other's chat port, says hello, and logs in with "/peer <name>" instead
+// of a nickname. From then on each side sends the other what happens locally
+// - room messages and who comes and goes - as events, and delivers what it
+// hears to its own users. Someone on the far side shows up as "bob@office2",
+// so names never clash across servers.
+//
+// With more than two servers, an event has to be passed along: A tells B,
+// and B tells C. That's flooding, and flooding has one big trap: loops. In a
+// triangle A-B-C, A's message reaches C directly *and* through B, and C then
+// tells A and B about it, and round it goes. Two things stop that:
+//
+//   origin id  - every event is wrapped in a Relay carrying the name of the
+//                server it started on and that server's number for it.
+//   seen cache - each server remembers the origin ids it has handled lately
+//                and drops any copy it has seen before, instead of passing
+//                it on again. Its own events coming back are dropped too.
+//
+// The cache is bounded: it only has to outlast the time an event takes to
+// go round the loop, not forever. Numbers start from the clock rather than
+// from 1, so a server that restarts doesn't reuse ids its peers still
+// remember.
+//
+// Presence is state rather than news, so a fresh link starts with a
+// snapshot: everyone each side knows to be online. When a link drops, the
+// users learned through it are announced as gone.
+//
+// Private messages stay on their own server for now, and links are plain
+// TCP: federate servers that don't serve TLS, over a network you trust.
+// ---------------------------------------------------------------------------
+
+/// How often a dialer retries a peer it isn't linked to.
+pub const DIAL_RETRY: Duration = Duration::from_secs(5);
+
+// How long dialing a peer may take, up to and including its answer.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Origin ids remembered for loop prevention.
+const SEEN_CAPACITY: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FederationConfig {
+    /// This server's name, as its users appear elsewhere: "alice@name".
+    pub name: String,
+    /// Servers to dial and stay linked to. A link carries traffic both ways,
+    /// so list each pair on one side only.
+    pub peers: Vec<SocketAddr>,
+    /// What peers must say to link, if set.
+    pub secret: Option<String>,
+}
+
+/// `nick` as users on other servers see it.
+pub fn qualify(nick: &str, server: &str) -> String {
+    format!("{}@{}", nick, server)
+}
+
+/// The server a qualified nickname belongs to.
+pub fn home(nick: &str) -> Option<&str> {
+    nick.rsplit_once('@').map(|(_, server)| server)
+}
+
+/// The origin ids handled lately, oldest first.
+#[derive(Debug, Default)]
+pub struct Seen {
+    order: VecDeque<(String, u64)>,
+    set: HashSet<(String, u64)>,
+    capacity: usize,
+}
+
+impl Seen {
+    pub fn new(capacity: usize) -> Self {
+        Seen {
+            capacity: capacity.max(1),
+            ..Seen::default()
+        }
+    }
+
+    /// Note an origin id. False if it was already there.
+    pub fn insert(&mut self, origin: &str, id: u64) -> bool {
+        let key = (origin.to_string(), id);
+        if !self.set.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.set.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+// Someone on another server.
+struct RemoteUser {
+    // As qualified as it came, e.g. "bob@office2".
+    nick: String,
+    // The link we heard about them on.
+    via: ClientId,
+    away: Option<String>,
+}
+
+/// The server's side of every link: who its peers are, and what it knows
+/// about their users.
+pub struct Federation {
+    config: FederationConfig,
+    // Linked servers. Like clients, but never in the client list.
+    peers: Mutex<Vec<ClientHandle>>,
+    seen: Mutex<Seen>,
+    // Remote users by lowercased qualified nickname.
+    roster: Mutex<BTreeMap<String, RemoteUser>>,
+    last_id: AtomicU64,
+}
+
+impl Federation {
+    pub fn new(config: FederationConfig) -> Self {
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        Federation {
+            config,
+            peers: Mutex::new(Vec::new()),
+            seen: Mutex::new(Seen::new(SEEN_CAPACITY)),
+            roster: Mutex::new(BTreeMap::new()),
+            last_id: AtomicU64::new(start),
+        }
+    }
+
+    pub fn config(&self) -> &FederationConfig {
+        &self.config
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Wrap an event that happened here for our peers.
+    pub fn wrap(&self, event: Event) -> Event {
+        Event::Relay {
+            origin: self.config.name.clone(),
+            id: self.last_id.fetch_add(1, Ordering::Relaxed) + 1,
+            event: Box::new(event),
+        }
+    }
+
+    /// Whether a relayed event is news: not ours, and not seen before.
+    pub fn is_new(&self, origin: &str, id: u64) -> bool {
+        origin != self.config.name && self.seen.lock().unwrap().insert(origin, id)
+    }
+
+    /// Add a link to the server `name`. Fails if we already have one.
+    pub(crate) fn link(&self, link: &ClientHandle, name: &str) -> Result<(), String> {
+        if name.eq_ignore_ascii_case(&self.config.name) {
+            return Err("that is this server's own name".to_string());
+        }
+        let mut peers = self.peers.lock().unwrap();
+        let taken = peers
+            .iter()
+            .any(|p| p.peer_name().is_some_and(|n| n.eq_ignore_ascii_case(name)));
+        if taken {
+            return Err(format!("already linked with {}", name));
+        }
+        link.set_peer(name);
+        peers.push(ClientHandle::clone(link));
+        Ok(())
+    }
+
+    /// Drop a link. Returns the users we knew through it, or None if it
+    /// wasn't a link.
+    pub(crate) fn unlink(&self, link: &ClientHandle) -> Option<Vec<String>> {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            let before = peers.len();
+            peers.retain(|p| p.id != link.id);
+            if peers.len() == before {
+                return None;
+            }
+        }
+        let mut roster = self.roster.lock().unwrap();
+        let gone: Vec<String> = roster
+            .values()
+            .filter(|user| user.via == link.id)
+            .map(|user| user.nick.clone())
+            .collect();
+        roster.retain(|_, user| user.via != link.id);
+        Some(gone)
+    }
+
+    /// Every link, for sending.
+    pub fn peers(&self) -> Vec<ClientHandle> {
+        self.peers.lock().unwrap().clone()
+    }
+
+    /// Whether we have a link to the server `name`.
+    pub fn is_linked(&self, name: &str) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|p| p.peer_name().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+    }
+
+    /// Take in a relayed presence change heard on `via`. True if it changed
+    /// anything our users should hear about.
+    pub(crate) fn update(
+        &self,
+        via: ClientId,
+        nick: &str,
+        status: Status,
+        message: Option<String>,
+    ) -> bool {
+        let key = nick.to_ascii_lowercase();
+        let mut roster = self.roster.lock().unwrap();
+        let away = match status {
+            Status::Online => Some(None),
+            Status::Away => Some(message),
+            Status::Offline => None,
+        };
+        match away {
+            Some(away) => match roster.get_mut(&key) {
+                Some(user) if user.away == away => false,
+                Some(user) => {
+                    user.away = away;
+                    true
+                }
+                None => {
+                    let nick = nick.to_string();
+                    roster.insert(key, RemoteUser { nick, via, away });
+                    true
+                }
+            },
+            // Only the route we learned of someone by may say they left.
+            // Another one may just have lost its own way to them.
+            None => match roster.get(&key) {
+                Some(user) if user.via == via => {
+                    roster.remove(&key);
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// Everyone on other servers, with why they're away if they are.
+    pub fn remote_users(&self) -> Vec<(String, Option<String>)> {
+        let roster = self.roster.lock().unwrap();
+        roster
+            .values()
+            .map(|user| (user.nick.clone(), user.away.clone()))
+            .collect()
+    }
+}
+
+/// A link we dialed, ready to hand to the event loop.
+pub struct Link {
+    pub stream: TcpStream,
+    /// The name the other server gave.
+    pub name: String,
+    pub version: u8,
+}
+
+/// Dial a peer and log in to it as a server. Blocking: run it on a thread
+/// of its own.
+pub fn dial(addr: SocketAddr, config: &FederationConfig) -> io::Result<Link> {
+    let mut stream = TcpStream::connect_timeout(&addr, DIAL_TIMEOUT)?;
+    stream.set_read_timeout(Some(DIAL_TIMEOUT))?;
+    let version = protocol::client_handshake(&mut stream)?;
+    if version < protocol::EVENTS_VERSION {
+        return Err(refused("it is too old to federate"));
+    }
+    let login = match &config.secret {
+        Some(secret) => format!("/peer {} {}", config.name, secret),
+        None => format!("/peer {}", config.name),
+    };
+    protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(&login))?;
+
+    // Read one frame at a time, so nothing meant for the event loop is
+    // left behind in a buffer here.
+    loop {
+        let frame = read_frame(&mut stream)?;
+        match frame.kind {
+            MessageType::Nick => {
+                let name = frame.as_str()?.to_string();
+                stream.set_read_timeout(None)?;
+                return Ok(Link {
+                    stream,
+                    name,
+                    version,
+                });
+            }
+            MessageType::Ping => {
+                protocol::write_frame(&mut stream, Framing::Framed, &Frame::pong(&frame))?;
+            }
+            MessageType::Error => return Err(refused(frame.as_str()?)),
+            MessageType::Event => {
+                if let Ok(Event::Error { text }) = frame.to_event() {
+                    return Err(refused(&text));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Exactly one frame off a blocking stream.
+fn read_frame(stream: &mut TcpStream) -> io::Result<Frame> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len == 0 || len > protocol::MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad frame length {}", len),
+        ));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    let mut decoder = Decoder::new(Framing::Framed);
+    decoder.feed(&header);
+    decoder.feed(&body);
+    match decoder.next_frame()? {
+        Some(frame) => Ok(frame),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+fn refused(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("refused: {}", why))
+}
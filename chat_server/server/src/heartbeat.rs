@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Half-open connections.
+//
+// When a client's machine loses power or its Wi-Fi drops, no FIN or RST
+// ever reaches us. As far as our socket is concerned the connection is fine
+// - it's just quiet. A read-only server waits on it forever, and the client
+// keeps its nickname, its rooms and a slot in every broadcast.
+//
+// The only way to find out is to say something and see if an answer comes
+// back. Every `interval` the server sends each framed client a Ping, and
+// the client answers with a Pong. Anything at all we hear from a client
+// counts as an answer; a client that stays silent through `missed` pings in
+// a row is gone, and we close it like any other disconnect.
+//
+// It works the other way too: since the server pings every client
+// regardless, a client hears from the server at least once per interval. A
+// silence much longer than that means the *server* is gone, and ordinary
+// silence - nobody chatting - is nothing to worry about.
+//
+// Old line-mode clients can't answer a Ping, so they aren't pinged and are
+// never evicted for being idle.
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// How often the server pings each client.
+    pub interval: Duration,
+    /// Pings a client may leave unanswered before it is disconnected.
+    pub missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(15),
+            missed: 2,
+        }
+    }
+}
+
+/// What to do for a client when the heartbeat interval comes round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beat {
+    /// Send it a Ping with this token.
+    Ping(u64),
+    /// It hasn't answered `missed` pings: disconnect it.
+    Dead,
+}
+
+/// One client's side of the heartbeat.
+#[derive(Debug)]
+pub struct Heartbeat {
+    missed: u32,
+    unanswered: u32,
+    sent: u64,
+}
+
+impl Heartbeat {
+    pub fn new(config: &HeartbeatConfig) -> Self {
+        Heartbeat {
+            missed: config.missed,
+            unanswered: 0,
+            sent: 0,
+        }
+    }
+
+    /// We heard from the client.
+    pub fn heard(&mut self) {
+        self.unanswered = 0;
+    }
+
+    /// The interval has passed.
+    pub fn beat(&mut self) -> Beat {
+        if self.unanswered >= self.missed {
+            return Beat::Dead;
+        }
+        self.unanswered += 1;
+        self.sent += 1;
+        Beat::Ping(self.sent)
+    }
+
+    /// Pings sent since the client last said anything.
+    pub fn unanswered(&self) -> u32 {
+        self.unanswered
+    }
+}
@@ -0,0 +1,272 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use protocol::Event;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: An append-only log.
+//
+// Until now a message existed only for as long as it took to broadcast it.
+// To let people catch up we keep every room message on disk, in the simplest
+// durable structure there is: a file we only ever append to. One record per
+// line:
+//
+//   <unix seconds> TAB <room> TAB <sender> TAB <text> NEWLINE
+//
+// Tabs, newlines and backslashes inside a field are escaped, so a record is
+// always exactly one line and a half-written last line (the server died
+// mid-write) is simply skipped when reading back.
+//
+// A single file would grow forever, so the log is split into numbered
+// segments (00000001.log, 00000002.log, ...). When the current one reaches
+// `segment_bytes` we start the next, and once there are more than
+// `max_segments` the oldest is deleted. Old history costs nothing to expire:
+// no rewriting, just unlinking a file.
+//
+// Reading scans segments newest first until it has enough messages for the
+// room. That's a linear scan, but the log is bounded at
+// segment_bytes * max_segments, and only /history and joins ever read it.
+// Private messages are deliberately not recorded.
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Where to keep the log. `None` turns history off.
+    pub dir: Option<PathBuf>,
+    /// Start a new segment once the current one is this big.
+    pub segment_bytes: u64,
+    /// Segments kept on disk; older ones are deleted.
+    pub max_segments: usize,
+    /// Messages replayed to a client when it enters a room.
+    pub replay: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            dir: None,
+            segment_bytes: 1024 * 1024,
+            max_segments: 8,
+            replay: 20,
+        }
+    }
+}
+
+/// One chat message as stored in the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub room: String,
+    pub sender: String,
+    pub text: String,
+}
+
+impl Record {
+    /// A record stamped with the current time.
+    pub fn now(room: &str, sender: &str, text: &str) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Record {
+            time,
+            room: room.to_string(),
+            sender: sender.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    /// The record as one line of the log, newline included.
+    pub fn encode(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\n",
+            self.time,
+            escape(&self.room),
+            escape(&self.sender),
+            escape(&self.text)
+        )
+    }
+
+    /// Parse one line of the log (without its newline). `None` if it's
+    /// damaged.
+    pub fn decode(line: &str) -> Option<Record> {
+        let mut fields = line.splitn(4, '\t');
+        let time = fields.next()?.parse().ok()?;
+        let room = unescape(fields.next()?)?;
+        let sender = unescape(fields.next()?)?;
+        let text = unescape(fields.next()?)?;
+        Some(Record {
+            time,
+            room,
+            sender,
+            text,
+        })
+    }
+
+    /// The record as a version 2 client gets it.
+    pub fn event(&self) -> Event {
+        Event::Chat {
+            id: None,
+            room: self.room.clone(),
+            from: self.sender.clone(),
+            text: self.text.clone(),
+            time: Some(self.time),
+        }
+    }
+
+    /// How a replayed message is shown: the live chat format with the time
+    /// it was sent in front.
+    pub fn render(&self) -> String {
+        self.event().to_string()
+    }
+}
+
+pub struct History {
+    dir: PathBuf,
+    config: HistoryConfig,
+    // The segment we're appending to.
+    file: File,
+    index: u64,
+    len: u64,
+}
+
+impl History {
+    /// Open (or create) the log in `dir`, carrying on in its newest segment.
+    pub fn open(dir: &Path, config: HistoryConfig) -> io::Result<History> {
+        fs::create_dir_all(dir)?;
+        let index = segments(dir)?.last().copied().unwrap_or(1);
+        let file = open_segment(dir, index)?;
+        let len = file.metadata()?.len();
+        Ok(History {
+            dir: dir.to_path_buf(),
+            config,
+            file,
+            index,
+            len,
+        })
+    }
+
+    /// Add a message to the end of the log.
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let line = record.encode();
+        if self.len > 0 && self.len + line.len() as u64 > self.config.segment_bytes {
+            self.rotate()?;
+        }
+        // One write per record: a crash can cut off the last line, but never
+        // interleave two of them.
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    /// Make sure everything appended so far is on disk, not just in the
+    /// OS's buffers.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// The last `n` messages in `room`, oldest first.
+    pub fn recent(&self, room: &str, n: usize) -> io::Result<Vec<Record>> {
+        let mut found = Vec::new();
+        if n == 0 {
+            return Ok(found);
+        }
+        for index in segments(&self.dir)?.into_iter().rev() {
+            let contents = match fs::read_to_string(segment_path(&self.dir, index)) {
+                Ok(contents) => contents,
+                // Rotated away while we were looking; nothing older remains.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            };
+            // Walk each segment backwards too, so we can stop as soon as we
+            // have enough.
+            for record in contents.lines().rev().filter_map(Record::decode) {
+                if record.room == room {
+                    found.push(record);
+                    if found.len() == n {
+                        found.reverse();
+                        return Ok(found);
+                    }
+                }
+            }
+        }
+        found.reverse();
+        Ok(found)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.index += 1;
+        self.file = open_segment(&self.dir, self.index)?;
+        self.len = 0;
+        let keep = self.config.max_segments.max(1) as u64;
+        for old in segments(&self.dir)? {
+            if old + keep <= self.index {
+                fs::remove_file(segment_path(&self.dir, old))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:08}.log", index))
+}
+
+fn open_segment(dir: &Path, index: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, index))
+}
+
+// The segment numbers present in `dir`, in order.
+fn segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let index = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|stem| stem.parse().ok());
+        if let Some(index) = index {
+            found.push(index);
+        }
+    }
+    found.sort_unstable();
+    Ok(found)
+}
+
+fn escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(field: &str) -> Option<String> {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
@@ -0,0 +1,1687 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+use log::{error, info, warn};
+use protocol::{Event, Frame, FrameError, Framing, MessageType, Status};
+
+use crate::accounts::{AccountError, Accounts};
+use crate::client::ClientHandle;
+use crate::command::{self, Command};
+use crate::federation::{self, Federation};
+use crate::history::{History, Record};
+use crate::metrics::{self, Metrics};
+use crate::moderation::{Ban, Moderation};
+use crate::nick::{self, NickError};
+use crate::outbox::{Outbox, OutboxStats, Receipt};
+use crate::plugin::{Bot, Message, Plugin};
+use crate::ratelimit::{Limiter, Verdict};
+use crate::room::{self, Rooms};
+use crate::settings::Settings;
+use crate::transfer::{Transfer, Transfers};
+use crate::ClientId;
+
+// How many bad nicknames or passwords a client may try before we give up
+// on it.
+pub const MAX_LOGIN_ATTEMPTS: usize = 5;
+
+// The most messages one /history can ask for.
+pub const MAX_HISTORY_PAGE: usize = 500;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Why Arc<Mutex<T>>?
+//
+// We need to share the client list across multiple threads. In Rust, you
+// cannot share a plain Vec across threads because the compiler enforces that
+// only one owner exists at a time (ownership rules).
+//
+//   Arc  = Atomically Reference Counted. Lets multiple threads hold a pointer
+//          to the same data. Cloning an Arc just bumps a counter - it does NOT
+//          copy the underlying data.
+//
+//   Mutex = Mutual Exclusion. Only one thread can "lock" it at a time.
+//           Trying to lock when another thread holds it → your thread sleeps
+//           until it's released. This prevents data races.
+//
+// Together: Arc<Mutex<T>> is the "safe shared mutable state" pattern in Rust.
+// You will use this constantly. Learn to love it and fear it equally.
+//
+// The Hub is the chat itself: who is connected, which rooms exist, and what
+// happens when a message arrives. It knows nothing about sockets - the event
+// loop hands it whole frames and it answers by calling Client::send(). The
+// whole Hub lives in one Arc shared by every worker thread.
+// ---------------------------------------------------------------------------
+
+#[derive(Default)]
+pub struct Hub {
+    // The shared list of all logged-in clients.
+    clients: Mutex<Vec<ClientHandle>>,
+    // Room membership. Membership is by ClientId, so to reach the members
+    // we still go through the client list.
+    //
+    // LEARNING NOTE: With two locks there are two possible orders to take
+    // them in, and mixing them up is how deadlocks happen. Rule for this
+    // file: never hold the rooms lock while taking the clients lock. We
+    // always copy the member ids out of Rooms first, release it, and only
+    // then touch clients.
+    rooms: Mutex<Rooms>,
+    settings: Settings,
+    outbox_stats: Arc<OutboxStats>,
+    // Counters for the metrics endpoint (see metrics.rs), kept whether or
+    // not anyone reads them.
+    metrics: Metrics,
+    // The on-disk message log, if history is turned on. Taken on its own,
+    // never while holding either of the locks above.
+    history: Option<Mutex<History>>,
+    // The user store, if accounts are turned on. In an Arc because logins
+    // are checked on their own threads.
+    accounts: Option<Arc<Accounts>>,
+    // Bans, mutes and operators. Has locks of its own, which are never held
+    // while taking any of the above.
+    moderation: Moderation,
+    // The last message id handed out.
+    last_id: AtomicU64,
+    // Links to other servers, if this one federates. Its locks are taken
+    // on their own, like the moderation ones.
+    federation: Option<Federation>,
+    // Files on their way between clients. Also taken on its own.
+    transfers: Mutex<Transfers>,
+    // In-process bots (see plugin.rs), each with the Bot it speaks through.
+    // Called with no locks held.
+    plugins: Vec<(Box<dyn Plugin>, Bot)>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Hub::default()
+    }
+
+    /// A hub using `settings`. Fails if the history log or the user store
+    /// can't be opened.
+    pub fn with_settings(settings: Settings) -> io::Result<Self> {
+        let history = match &settings.history.dir {
+            Some(dir) => Some(Mutex::new(History::open(dir, settings.history.clone())?)),
+            None => None,
+        };
+        let accounts = match &settings.accounts.file {
+            Some(file) => Some(Arc::new(Accounts::open(file, settings.accounts.clone())?)),
+            None => None,
+        };
+        let moderation = Moderation::open(settings.moderation.clone())?;
+        let federation = settings.federation.clone().map(Federation::new);
+        Ok(Hub {
+            settings,
+            history,
+            accounts,
+            moderation,
+            federation,
+            ..Hub::default()
+        })
+    }
+
+    /// Like with_settings(), with plugins. They need a handle on the Hub
+    /// to speak through, so this one comes ready-wrapped in its Arc.
+    pub fn with_plugins(
+        settings: Settings,
+        plugins: Vec<Box<dyn Plugin>>,
+    ) -> io::Result<Arc<Self>> {
+        let mut hub = Hub::with_settings(settings)?;
+        Ok(Arc::new_cyclic(|weak| {
+            hub.plugins = plugins
+                .into_iter()
+                .map(|plugin| {
+                    let bot = Bot::new(plugin.name(), weak.clone());
+                    (plugin, bot)
+                })
+                .collect();
+            hub
+        }))
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Dropped-message counters across every client.
+    pub fn outbox_stats(&self) -> &OutboxStats {
+        &self.outbox_stats
+    }
+
+    /// Traffic and timing counters.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// A fresh outbox for a new client, sized and counted per the settings.
+    pub fn new_outbox(&self) -> Outbox {
+        Outbox::new(self.settings.outbox, Arc::clone(&self.outbox_stats))
+    }
+
+    /// A rate limiter for a new client, if flood protection is on.
+    pub fn new_limiter(&self) -> Option<Limiter> {
+        self.settings
+            .rate_limit
+            .map(|config| Limiter::new(config, Instant::now()))
+    }
+
+    // The two Hub locks, timing how long we wait for them. Same rule as
+    // ever: never take the clients while holding the rooms.
+    fn lock_clients(&self) -> MutexGuard<'_, Vec<ClientHandle>> {
+        metrics::lock(&self.clients, &self.metrics.clients_lock_wait)
+    }
+
+    fn lock_rooms(&self) -> MutexGuard<'_, Rooms> {
+        metrics::lock(&self.rooms, &self.metrics.rooms_lock_wait)
+    }
+
+    /// Number of logged-in clients.
+    pub fn active_connections(&self) -> usize {
+        self.lock_clients().len()
+    }
+
+    /// Flush the history log to disk, if history is turned on.
+    pub fn sync_history(&self) -> io::Result<()> {
+        match &self.history {
+            Some(history) => history.lock().unwrap().sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// The user store, if accounts are turned on.
+    pub fn accounts(&self) -> Option<&Accounts> {
+        self.accounts.as_deref()
+    }
+
+    /// Bans, mutes and operators.
+    pub fn moderation(&self) -> &Moderation {
+        &self.moderation
+    }
+
+    /// Links to other servers, if this one federates.
+    pub fn federation(&self) -> Option<&Federation> {
+        self.federation.as_ref()
+    }
+
+    /// A connection has finished the protocol handshake.
+    pub fn connected(&self, me: &ClientHandle) {
+        if let Some(reason) = self.moderation.ip_ban(me.peer.ip()) {
+            info!("Turning away {}: the address is banned", me.peer);
+            let _ = me.send(&Frame::error(&banned(&reason)));
+            me.close();
+            return;
+        }
+        // Old line clients don't know they're expected to send a nickname
+        // first, so ask them.
+        if me.framing != Framing::Lines {
+            return;
+        }
+        let prompt = match &self.accounts {
+            None => "Welcome! Please enter a nickname.",
+            Some(accounts) => match (accounts.config().guests, accounts.config().registration) {
+                (true, _) => "Welcome! Please enter a nickname, or /login <name> <password>.",
+                (false, true) => {
+                    "Welcome! Please /login <name> <password>, or /register <name> <password> for a new account."
+                }
+                (false, false) => "Welcome! Please /login <name> <password>.",
+            },
+        };
+        let _ = me.send(&Frame::notice(prompt));
+    }
+
+    /// A complete, well-formed frame arrived from `me`.
+    pub fn handle_frame(self: &Arc<Self>, me: &ClientHandle, frame: Frame) {
+        // A Pong only matters to the event loop, which already noted that
+        // the client is alive; it isn't a message and costs no tokens.
+        if frame.kind == MessageType::Pong {
+            return;
+        }
+        // Another server speaks for many people at once, so it has no rate
+        // limit, and nothing to say but relayed events.
+        if me.is_peer() {
+            self.peer_frame(me, frame);
+            return;
+        }
+        // A file is many frames but one thing the user did, and the
+        // recipient sets its pace (see transfer.rs), so chunks don't count
+        // against the rate limit.
+        if frame.kind == MessageType::Chunk {
+            if me.is_logged_in() {
+                self.chunk(me, &frame);
+            }
+            return;
+        }
+        // Number every line a logged-in client sends, even the ones dropped
+        // below, so its count and ours agree (see event.rs in the protocol
+        // crate).
+        let line = match frame.kind == MessageType::Text && me.is_logged_in() {
+            true => me.next_line(),
+            false => 0,
+        };
+        if !self.admit(me) {
+            return;
+        }
+        if frame.kind == MessageType::Ping {
+            let _ = me.send(&Frame::pong(&frame));
+            return;
+        }
+        me.touch();
+
+        // Nobody joins the client list until they have a nickname, so nobody
+        // ever sees a message from an anonymous socket address.
+        if !me.is_logged_in() {
+            self.login(me, frame);
+            return;
+        }
+
+        if frame.kind != MessageType::Text {
+            warn!("Ignoring {:?} frame from {}", frame.kind, me.label());
+            return;
+        }
+        // The decoder already checked the payload is UTF-8.
+        let msg = frame.as_str().unwrap_or_default();
+
+        match command::parse(msg) {
+            Some(Ok(cmd)) => {
+                if let Err(e) = self.run_command(cmd, me, line) {
+                    let _ = me.send(&Frame::error(&e));
+                }
+            }
+            Some(Err(usage)) => {
+                let _ = me.send(&Frame::error(&usage));
+            }
+            None => self.chat(me, msg, line),
+        }
+    }
+
+    // Charge a message to the sender's rate limit and deal with any abuse.
+    // False if the message should be dropped.
+    fn admit(&self, me: &ClientHandle) -> bool {
+        match me.rate_check() {
+            Verdict::Allow => true,
+            Verdict::Drop { warn: true } => {
+                let rate = self.settings.rate_limit.map_or(0.0, |c| c.rate);
+                warn!("{} is flooding; dropping messages", me.label());
+                let _ = me.send(&Frame::error(&format!(
+                    "slow down: at most {} messages a second; message dropped",
+                    rate
+                )));
+                false
+            }
+            Verdict::Drop { warn: false } | Verdict::Muted => false,
+            Verdict::Mute(time) => {
+                warn!("{} muted for {}s for flooding", me.label(), time.as_secs());
+                let _ = me.send(&Frame::error(&format!(
+                    "you are muted for {}s for flooding",
+                    time.as_secs()
+                )));
+                false
+            }
+            Verdict::Disconnect => {
+                warn!(
+                    "Disconnecting {} for flooding ({} messages dropped)",
+                    me.label(),
+                    me.flood_dropped()
+                );
+                let _ = me.send(&Frame::error("disconnected for flooding"));
+                me.close();
+                false
+            }
+        }
+    }
+
+    /// The decoder rejected something `me` sent.
+    pub fn handle_frame_error(&self, me: &ClientHandle, e: &FrameError) {
+        let _ = me.send(&Frame::error(&e.to_string()));
+        if e.is_recoverable() {
+            // Bad UTF-8 or an unknown type: the frame was consumed, so
+            // tell the client and carry on.
+            warn!("Rejected frame from {}: {}", me.label(), e);
+        } else {
+            // Oversized or malformed length: we've lost track of where the
+            // next frame starts, so the only safe move is to hang up.
+            warn!("Dropping {}: {}", me.label(), e);
+            me.close();
+        }
+    }
+
+    // The login handshake: the first message a client sends is the nickname
+    // it wants, or /login or /register when the server has accounts. Framed
+    // clients send a Nick frame; old line clients just type it.
+    fn login(self: &Arc<Self>, me: &ClientHandle, frame: Frame) {
+        if !matches!(frame.kind, MessageType::Nick | MessageType::Text) {
+            return;
+        }
+        let text = frame.as_str().unwrap_or_default().trim();
+
+        match command::parse(text) {
+            Some(Ok(Command::Login { name, password })) => {
+                self.authenticate(me, name, password, false);
+            }
+            Some(Ok(Command::Register { name, password })) => {
+                self.authenticate(me, name, password, true);
+            }
+            Some(Ok(Command::Peer { name, secret })) => {
+                self.peer_login(me, &name, secret);
+            }
+            Some(Ok(_)) => {
+                let _ = me.send(&Frame::error("log in first"));
+            }
+            Some(Err(usage)) => {
+                let _ = me.send(&Frame::error(&usage));
+            }
+            None => {
+                if self.refuse_banned(me, text) {
+                    return;
+                }
+                let result = self.check_guest(text).and_then(|()| {
+                    self.enter(me, text, false)
+                        .map_err(|e| format!("{}; try another", e))
+                });
+                if let Err(e) = result {
+                    self.login_failed(me, &e);
+                }
+            }
+        }
+    }
+
+    // /login and /register. Password hashing is slow on purpose, so it runs
+    // on a thread of its own instead of stalling every client on this
+    // worker; the login finishes from there.
+    fn authenticate(
+        self: &Arc<Self>,
+        me: &ClientHandle,
+        name: String,
+        password: String,
+        register: bool,
+    ) {
+        let Some(accounts) = self.accounts.clone() else {
+            let _ = me.send(&Frame::error(
+                "this server has no accounts; just send a nickname",
+            ));
+            return;
+        };
+        if self.refuse_banned(me, &name) {
+            return;
+        }
+        // Don't start an account for a name someone is using right now.
+        if register && self.find(&name).is_some() {
+            self.login_failed(me, &NickError::Taken.to_string());
+            return;
+        }
+        if !me.begin_auth() {
+            let _ = me.send(&Frame::error("still checking your last attempt"));
+            return;
+        }
+
+        let hub = Arc::clone(self);
+        let client = Arc::clone(me);
+        let spawned = thread::Builder::new()
+            .name("auth".to_string())
+            .spawn(move || {
+                let checked = if register {
+                    accounts.register(&name, &password).map(|()| name)
+                } else {
+                    accounts.verify(&name, &password)
+                };
+                let result = match checked {
+                    Ok(name) => hub.enter(&client, &name, true).map_err(|e| e.to_string()),
+                    Err(AccountError::Io(e)) => {
+                        error!("Could not use the user store: {}", e);
+                        Err("could not check the user store".to_string())
+                    }
+                    Err(e) => Err(e.to_string()),
+                };
+                client.end_auth();
+                if let Err(e) = result {
+                    hub.login_failed(&client, &e);
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("Could not start a login thread: {}", e);
+            let _ = me.send(&Frame::error("could not check your password; try again"));
+            me.end_auth();
+        }
+    }
+
+    // Can someone without an account chat under this name?
+    fn check_guest(&self, name: &str) -> Result<(), String> {
+        match &self.accounts {
+            Some(accounts) if !accounts.config().guests => {
+                Err("this server needs an account: /login <name> <password>".to_string())
+            }
+            _ => self.check_unregistered(name),
+        }
+    }
+
+    // Registered names are only for whoever can log in to them.
+    fn check_unregistered(&self, name: &str) -> Result<(), String> {
+        let Some(accounts) = &self.accounts else {
+            return Ok(());
+        };
+        match accounts.exists(name) {
+            Ok(false) => Ok(()),
+            Ok(true) => Err(format!(
+                "{} is registered; use /login <name> <password>",
+                name
+            )),
+            Err(e) => {
+                error!("Could not read the user store: {}", e);
+                Err("could not check the user store".to_string())
+            }
+        }
+    }
+
+    // Keep a banned name out. There's no point letting them try another
+    // one, so this hangs up. True if `name` was banned.
+    fn refuse_banned(&self, me: &ClientHandle, name: &str) -> bool {
+        let Some(reason) = self.moderation.nick_ban(name) else {
+            return false;
+        };
+        info!("Turning away {}: {} is banned", me.peer, name);
+        let _ = me.send(&Frame::error(&banned(&reason)));
+        me.close();
+        true
+    }
+
+    fn login_failed(&self, me: &ClientHandle, reason: &str) {
+        let _ = me.send(&Frame::error(reason));
+        if me.failed_login() >= MAX_LOGIN_ATTEMPTS {
+            let _ = me.send(&Frame::error("too many failed login attempts"));
+            me.close();
+        }
+    }
+
+    // Put a client into the chat under `name`, now that we know it may use
+    // it. May run on a login thread.
+    fn enter(&self, me: &ClientHandle, name: &str, authenticated: bool) -> Result<(), NickError> {
+        // Check and register under one lock, so two clients asking for the
+        // same name at the same moment can't both get it.
+        {
+            let mut list = self.lock_clients();
+            // It hung up while its password was being checked. Checking
+            // under the list lock means disconnected() either already ran
+            // (and we stop here) or will find it in the list.
+            if me.is_closing() {
+                return Ok(());
+            }
+            claim_nick(&list, me, name)?;
+            self.check_reserved(name)?;
+            me.set_nick(name);
+            me.set_authenticated(authenticated);
+            me.set_operator(authenticated && self.moderation.is_operator_account(name));
+            list.push(Arc::clone(me));
+            // Start everyone off in the lobby so plain chat works without
+            // /join. (Taking rooms while holding clients is the allowed
+            // order.)
+            self.lock_rooms().join(room::DEFAULT_ROOM, me.id);
+        }
+        me.set_room(Some(room::DEFAULT_ROOM.to_string()));
+
+        let how = if authenticated { " (account)" } else { "" };
+        info!("{} logged in as {}{}", me.peer, name, how);
+        let _ = me.send(&Frame::nick(name));
+        if me.is_operator() {
+            let _ = me.send(&Frame::notice("You are an operator"));
+        }
+        if let Some(motd) = &self.settings.motd {
+            for line in motd.lines() {
+                let _ = me.send(&Frame::notice(line));
+            }
+        }
+        // Catch them up on what was said before they arrived.
+        self.replay(me, room::DEFAULT_ROOM, self.settings.history.replay);
+        self.announce(me, Status::Online, None);
+        self.each_plugin(|plugin, bot| plugin.on_connect(bot, name));
+        Ok(())
+    }
+
+    fn chat(&self, me: &ClientHandle, msg: &str, line: u64) {
+        if let Err(e) = self.check_muted(me) {
+            let _ = me.send(&Frame::error(&e));
+            return;
+        }
+        // Chat goes to the room the sender is currently talking in, and only
+        // to the people in it.
+        let Some(current) = me.room() else {
+            let _ = me.send(&Frame::error("you are not in a room; /join one to talk"));
+            return;
+        };
+        let id = self.next_id();
+        let event = Event::Chat {
+            id: Some(id),
+            room: current.clone(),
+            from: me.nick(),
+            text: msg.to_string(),
+            time: None,
+        };
+        info!("{}", event);
+        let members = self.lock_rooms().members(&current);
+        self.fan_out(&members, &event, me, self.receipt(me, id));
+        self.ack(me, line, id);
+
+        self.record(&current, &me.nick(), msg);
+        if let Some(federation) = &self.federation {
+            self.relay(Event::Chat {
+                id: None,
+                room: current.clone(),
+                from: federation::qualify(&me.nick(), federation.name()),
+                text: msg.to_string(),
+                time: None,
+            });
+        }
+        if !self.plugins.is_empty() {
+            let message = Message {
+                id,
+                room: Some(current),
+                from: me.nick(),
+                text: msg.to_string(),
+            };
+            self.each_plugin(|plugin, bot| plugin.on_message(bot, &message));
+        }
+    }
+
+    // Append a room message to the history log, if history is on.
+    fn record(&self, room: &str, sender: &str, text: &str) {
+        if let Some(history) = &self.history {
+            let record = Record::now(room, sender, text);
+            if let Err(e) = history.lock().unwrap().append(&record) {
+                error!("Could not write to the history log: {}", e);
+            }
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Tell `me` its `line`th line became message `id`.
+    fn ack(&self, me: &ClientHandle, line: u64, id: u64) {
+        if me.takes_acks() {
+            let _ = me.send_event(&Event::Ack { seq: line, id });
+        }
+    }
+
+    // What to attach to each copy of message `id`, if `me` wants receipts.
+    fn receipt(&self, me: &ClientHandle, id: u64) -> Option<Receipt> {
+        me.wants_receipts()
+            .then_some(Receipt::Message { id, sender: me.id })
+    }
+
+    /// Frames carrying `receipts` have been written to `recipient`'s
+    /// socket. Tell each sender that is still here.
+    pub fn delivered(&self, recipient: &ClientHandle, receipts: impl IntoIterator<Item = Receipt>) {
+        let to = recipient.nick();
+        let mut news = Vec::new();
+        for receipt in receipts {
+            match receipt {
+                Receipt::Message { id, sender } => {
+                    news.push((sender, Event::Receipt { id, to: to.clone() }))
+                }
+                // How far a file has got. The transfers lock is let go of
+                // before the clients lock is taken.
+                Receipt::Chunk { id, bytes } => {
+                    let transfer = self.transfers.lock().unwrap().delivered(id, bytes);
+                    if let Some(transfer) = transfer {
+                        let bytes = transfer.delivered;
+                        news.push((transfer.from, Event::FileProgress { id, bytes }));
+                    }
+                }
+            }
+        }
+        let list = self.lock_clients();
+        for (sender, event) in news {
+            if let Some(sender) = list.iter().find(|c| c.id == sender) {
+                let _ = sender.send_event(&event);
+            }
+        }
+    }
+
+    // Send `me` the last `n` messages of `room`. Returns how many there were.
+    fn replay(&self, me: &ClientHandle, room: &str, n: usize) -> usize {
+        let Some(history) = &self.history else {
+            return 0;
+        };
+        let records = match history.lock().unwrap().recent(room, n) {
+            Ok(records) => records,
+            Err(e) => {
+                error!("Could not read the history log: {}", e);
+                return 0;
+            }
+        };
+        if records.is_empty() {
+            return 0;
+        }
+        let plural = if records.len() == 1 { "" } else { "s" };
+        let _ = me.send(&Frame::notice(&format!(
+            "Last {} message{} in {}:",
+            records.len(),
+            plural,
+            room
+        )));
+        for record in &records {
+            let _ = me.send_event(&record.event());
+        }
+        records.len()
+    }
+
+    // Carry out a parsed slash command. An Err is sent back to the client as
+    // an error frame.
+    fn run_command(&self, cmd: Command, me: &ClientHandle, line: u64) -> Result<(), String> {
+        match cmd {
+            Command::Nick(wanted) => {
+                if me.is_authenticated() {
+                    return Err("your nickname is your account name".to_string());
+                }
+                // A new name shouldn't be a way out of a mute or into a ban.
+                self.check_muted(me)?;
+                if self.moderation.nick_ban(&wanted).is_some() {
+                    return Err("that nickname is banned".to_string());
+                }
+                self.check_unregistered(&wanted)?;
+                let old = self.rename(me, &wanted).map_err(|e| e.to_string())?;
+                let name = me.nick();
+                info!("{} is now {}", old, name);
+                let _ = me.send(&Frame::nick(&name));
+                // Everyone who can see us in some room should hear about it.
+                let audience = self.lock_rooms().neighbours(me.id);
+                let event = Event::Nick {
+                    old: old.clone(),
+                    new: name,
+                };
+                self.broadcast_event(&audience, &event, me);
+                // Other servers only see people come and go.
+                if let Some(federation) = &self.federation {
+                    self.relay(Event::Presence {
+                        nick: federation::qualify(&old, federation.name()),
+                        status: Status::Offline,
+                        message: None,
+                    });
+                    self.relay(self.presence(me, federation));
+                }
+            }
+            Command::Join(name) => {
+                let room = room::normalize(&name).map_err(|e| e.to_string())?;
+                me.set_room(Some(room.clone()));
+
+                let (joined, created, members) = {
+                    let mut rooms = self.lock_rooms();
+                    let joined = !rooms.is_member(&room, me.id);
+                    let created = rooms.join(&room, me.id);
+                    (joined, created, rooms.members(&room))
+                };
+
+                // /join on a room we're already in just switches to it.
+                if !joined {
+                    let _ = me.send(&Frame::notice(&format!("Now talking in {}", room)));
+                    return Ok(());
+                }
+                if created {
+                    info!("Room {} created", room);
+                }
+                let _ = me.send(&Frame::notice(&format!(
+                    "You joined {} ({} here). Now talking in {}",
+                    room,
+                    members.len(),
+                    room
+                )));
+                let event = Event::Join {
+                    room: room.clone(),
+                    nick: me.nick(),
+                };
+                self.broadcast_event(&members, &event, me);
+                self.replay(me, &room, self.settings.history.replay);
+            }
+            Command::Part(name) => {
+                let room = match name {
+                    Some(name) => room::normalize(&name).map_err(|e| e.to_string())?,
+                    None => me.room().ok_or("you are not in a room")?,
+                };
+                let (removed, members, remaining) = {
+                    let mut rooms = self.lock_rooms();
+                    let removed = rooms.part(&room, me.id).map_err(|e| e.to_string())?;
+                    (removed, rooms.members(&room), rooms.rooms_of(me.id))
+                };
+                if removed {
+                    info!("Room {} is empty, removing it", room);
+                }
+
+                // If we left the room we were talking in, fall back to
+                // another one we're still in (if any).
+                if me.room().as_deref() == Some(room.as_str()) {
+                    me.set_room(remaining.first().cloned());
+                }
+                let next = match me.room() {
+                    Some(current) => format!("Now talking in {}", current),
+                    None => "You are not in any room; /join one to talk".to_string(),
+                };
+                let _ = me.send(&Frame::notice(&format!("You left {}. {}", room, next)));
+                let event = Event::Leave {
+                    room,
+                    nick: me.nick(),
+                    reason: None,
+                };
+                self.broadcast_event(&members, &event, me);
+            }
+            Command::List => {
+                let list = self.lock_rooms().list();
+                let text = if list.is_empty() {
+                    "No rooms".to_string()
+                } else {
+                    let rooms: Vec<String> = list
+                        .iter()
+                        .map(|(name, count)| format!("{} ({})", name, count))
+                        .collect();
+                    format!("Rooms: {}", rooms.join(", "))
+                };
+                let _ = me.send(&Frame::notice(&text));
+            }
+            Command::Msg { to, text } => {
+                self.check_muted(me)?;
+                if let Some((plugin, bot)) = self.plugin(&to) {
+                    let id = self.next_id();
+                    self.ack(me, line, id);
+                    let message = Message {
+                        id,
+                        room: None,
+                        from: me.nick(),
+                        text,
+                    };
+                    call_plugin(plugin, bot, |plugin, bot| plugin.on_message(bot, &message));
+                    return Ok(());
+                }
+                let recipient = self.find(&to).ok_or_else(|| no_such_user(&to))?;
+                info!("dm {} -> {}", me.nick(), recipient.nick());
+                let id = self.next_id();
+                let event = Event::Direct {
+                    id: Some(id),
+                    from: me.nick(),
+                    text,
+                };
+                if let Err(e) = recipient.deliver(&event, self.receipt(me, id)) {
+                    warn!("Error writing to client: {}", e);
+                    return Err(format!("could not deliver to {}", to));
+                }
+                self.ack(me, line, id);
+                // Delivered, but don't expect an answer soon.
+                if let Some(reason) = recipient.away() {
+                    let _ = me.send_event(&Event::Presence {
+                        nick: recipient.nick(),
+                        status: Status::Away,
+                        message: Some(reason),
+                    });
+                }
+            }
+            Command::Offer {
+                to,
+                size,
+                sha256,
+                name,
+            } => {
+                let Some(config) = self.settings.transfers else {
+                    return Err("file transfer is off on this server".to_string());
+                };
+                if !me.takes_files() {
+                    return Err(format!(
+                        "sending files needs a protocol version {} client",
+                        protocol::FILES_VERSION
+                    ));
+                }
+                self.check_muted(me)?;
+                if size > config.max_size {
+                    return Err(format!(
+                        "{} is {} bytes; the limit here is {}",
+                        name, size, config.max_size
+                    ));
+                }
+                let recipient = self.find_other(me, &to)?;
+                if !recipient.takes_files() {
+                    return Err(format!("{}'s client can't receive files", recipient.nick()));
+                }
+                let id = self
+                    .transfers
+                    .lock()
+                    .unwrap()
+                    .offer(me.id, recipient.id, &name, size);
+                info!(
+                    "{} offers {} {} ({} bytes) as transfer {}",
+                    me.nick(),
+                    recipient.nick(),
+                    name,
+                    size,
+                    id
+                );
+                let event = Event::FileOffer {
+                    id,
+                    from: me.nick(),
+                    to: recipient.nick(),
+                    name,
+                    size,
+                    sha256,
+                };
+                let _ = recipient.send_event(&event);
+                let _ = me.send_event(&event);
+            }
+            Command::Accept(id) => {
+                let transfer = self.transfers.lock().unwrap().accept(id, me.id)?;
+                let sender = self.find_id(transfer.from);
+                let accepted = Event::FileAccepted { id };
+                let _ = me.send_event(&accepted);
+                if let Some(sender) = &sender {
+                    let _ = sender.send_event(&accepted);
+                }
+                // An empty file has no chunks to wait for.
+                if transfer.size == 0 {
+                    self.transfers.lock().unwrap().delivered(id, 0);
+                    if let Some(sender) = &sender {
+                        let _ = sender.send_event(&Event::FileProgress { id, bytes: 0 });
+                    }
+                }
+            }
+            Command::Cancel(id) => {
+                let transfer = self.transfers.lock().unwrap().cancel(id, me.id)?;
+                let reason = if transfer.to == me.id && !transfer.accepted {
+                    format!("{} said no", me.nick())
+                } else {
+                    format!("cancelled by {}", me.nick())
+                };
+                self.cancelled(&transfer, &reason);
+            }
+            Command::Receipts(on) => {
+                if !me.takes_acks() {
+                    return Err(format!(
+                        "receipts need a client that speaks protocol version {}",
+                        protocol::ACKS_VERSION
+                    ));
+                }
+                me.set_receipts(on);
+                let state = if on { "on" } else { "off" };
+                let _ = me.send(&Frame::notice(&format!("Delivery receipts {}", state)));
+            }
+            Command::Who => {
+                let list: Vec<ClientHandle> = self.lock_clients().clone();
+                let remote = self
+                    .federation
+                    .as_ref()
+                    .map(|federation| federation.remote_users())
+                    .unwrap_or_default();
+                let count = list.len() + remote.len();
+                let plural = if count == 1 { "" } else { "s" };
+                let _ = me.send(&Frame::notice(&format!("{} user{} online:", count, plural)));
+                for client in list {
+                    let mut line = format!(
+                        "{} - idle {}, connected {}",
+                        client.nick(),
+                        command::format_duration(client.idle_for()),
+                        command::format_duration(client.connected_for())
+                    );
+                    if let Some(reason) = client.away() {
+                        line.push_str(&format!(", away: {}", reason));
+                    }
+                    let _ = me.send(&Frame::notice(&line));
+                }
+                for (nick, away) in remote {
+                    let mut line = format!("{} - on another server", nick);
+                    if let Some(reason) = away {
+                        line.push_str(&format!(", away: {}", reason));
+                    }
+                    let _ = me.send(&Frame::notice(&line));
+                }
+            }
+            Command::Away(reason) => {
+                let was_away = me.away().is_some();
+                me.set_away(reason.clone());
+                match reason {
+                    Some(reason) => {
+                        let _ = me.send(&Frame::notice("You are marked as away"));
+                        self.announce(me, Status::Away, Some(reason));
+                    }
+                    None if was_away => {
+                        let _ = me.send(&Frame::notice("You are no longer away"));
+                        self.announce(me, Status::Online, None);
+                    }
+                    None => return Err("you are not away; /away <reason> to be".to_string()),
+                }
+            }
+            Command::History(n) => {
+                if self.history.is_none() {
+                    return Err("message history is turned off on this server".to_string());
+                }
+                let room = me.room().ok_or("you are not in a room")?;
+                if self.replay(me, &room, n.min(MAX_HISTORY_PAGE)) == 0 {
+                    let _ = me.send(&Frame::notice(&format!("No messages in {} yet", room)));
+                }
+            }
+            Command::Register { .. } | Command::Login { .. } | Command::Peer { .. } => {
+                return Err("you are already logged in".to_string());
+            }
+            cmd @ (Command::Kick { .. }
+            | Command::Ban { .. }
+            | Command::Bans
+            | Command::Unban(_)
+            | Command::Mute { .. }
+            | Command::Unmute(_)
+            | Command::Op(_)
+            | Command::Deop(_)) => {
+                if !me.is_operator() {
+                    return Err("only operators can do that".to_string());
+                }
+                self.moderate(cmd, me)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The operator commands. The caller has checked `me` is an operator.
+    // Every action goes in the audit log (see moderation.rs).
+    fn moderate(&self, cmd: Command, me: &ClientHandle) -> Result<(), String> {
+        let actor = me.nick();
+        match cmd {
+            Command::Kick { nick, reason } => {
+                let target = self.find_other(me, &nick)?;
+                let why = format!("kicked by {}{}", actor, because(&reason));
+                self.moderation.audit(
+                    &actor,
+                    &format!("kicked {}{}", target.label(), because(&reason)),
+                );
+                self.remove(&target, &why, me);
+            }
+            Command::Ban { target, reason } => {
+                let ban = Ban::parse(&target);
+                let applies = |client: &ClientHandle| match &ban {
+                    Ban::Ip(ip) => client.peer.ip() == *ip,
+                    Ban::Nick(nick) => nick::same(&client.nick(), nick),
+                };
+                if applies(me) {
+                    return Err("you can't ban yourself".to_string());
+                }
+                let added = self.moderation.ban(ban.clone(), &reason).map_err(|e| {
+                    error!("Could not save the ban list: {}", e);
+                    "could not save the ban list".to_string()
+                })?;
+                if !added {
+                    return Err(format!("{} is already banned", ban));
+                }
+                self.moderation
+                    .audit(&actor, &format!("banned {}{}", ban, because(&reason)));
+                let _ = me.send(&Frame::notice(&format!("Banned {}", ban)));
+
+                // Whoever it applies to leaves now.
+                let targets: Vec<ClientHandle> = self
+                    .clients
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|c| applies(c))
+                    .cloned()
+                    .collect();
+                let why = format!("banned by {}{}", actor, because(&reason));
+                for target in targets {
+                    self.remove(&target, &why, me);
+                }
+            }
+            Command::Bans => {
+                let bans = self.moderation.bans();
+                if bans.is_empty() {
+                    let _ = me.send(&Frame::notice("Nobody is banned"));
+                }
+                for (ban, reason) in bans {
+                    let _ = me.send(&Frame::notice(&format!(
+                        "Banned: {}{}",
+                        ban,
+                        because(&reason)
+                    )));
+                }
+            }
+            Command::Unban(target) => {
+                let ban = Ban::parse(&target);
+                let removed = self.moderation.unban(&ban).map_err(|e| {
+                    error!("Could not save the ban list: {}", e);
+                    "could not save the ban list".to_string()
+                })?;
+                if !removed {
+                    return Err(format!("{} is not banned", ban));
+                }
+                self.moderation.audit(&actor, &format!("unbanned {}", ban));
+                let _ = me.send(&Frame::notice(&format!("Unbanned {}", ban)));
+            }
+            Command::Mute { nick, time } => {
+                // Mutes are by name, so someone who just left can be muted
+                // before they come back.
+                let target = self.find(&nick);
+                let name = target.as_ref().map_or(nick, |c| c.nick());
+                if nick::same(&name, &actor) {
+                    return Err("you can't mute yourself".to_string());
+                }
+                self.moderation.mute(&name, time);
+                self.moderation
+                    .audit(&actor, &format!("muted {} for {}s", name, time.as_secs()));
+                if let Some(target) = target {
+                    let _ = target.send(&Frame::error(&format!(
+                        "you are muted for {}s by {}",
+                        time.as_secs(),
+                        actor
+                    )));
+                }
+                let _ = me.send(&Frame::notice(&format!(
+                    "Muted {} for {}s",
+                    name,
+                    time.as_secs()
+                )));
+            }
+            Command::Unmute(nick) => {
+                if !self.moderation.unmute(&nick) {
+                    return Err(format!("{} is not muted", nick));
+                }
+                self.moderation.audit(&actor, &format!("unmuted {}", nick));
+                if let Some(target) = self.find(&nick) {
+                    let _ = target.send(&Frame::notice(&format!("{} lifted your mute", actor)));
+                }
+                let _ = me.send(&Frame::notice(&format!("Unmuted {}", nick)));
+            }
+            Command::Op(nick) => {
+                let target = self.find(&nick).ok_or_else(|| no_such_user(&nick))?;
+                if target.is_operator() {
+                    return Err(format!("{} is already an operator", target.nick()));
+                }
+                target.set_operator(true);
+                self.moderation
+                    .audit(&actor, &format!("made {} an operator", target.label()));
+                let _ = target.send(&Frame::notice(&format!("{} made you an operator", actor)));
+                let _ = me.send(&Frame::notice(&format!(
+                    "{} is now an operator",
+                    target.nick()
+                )));
+            }
+            Command::Deop(nick) => {
+                let target = self.find(&nick).ok_or_else(|| no_such_user(&nick))?;
+                if !target.is_operator() {
+                    return Err(format!("{} is not an operator", target.nick()));
+                }
+                target.set_operator(false);
+                self.moderation.audit(
+                    &actor,
+                    &format!("took operator status from {}", target.label()),
+                );
+                let _ = target.send(&Frame::notice(&format!(
+                    "{} took away your operator status",
+                    actor
+                )));
+                let _ = me.send(&Frame::notice(&format!(
+                    "{} is no longer an operator",
+                    target.nick()
+                )));
+            }
+            _ => unreachable!("not a moderation command"),
+        }
+        Ok(())
+    }
+
+    // Someone else who is online, for commands that act on another user.
+    fn find_other(&self, me: &ClientHandle, nick: &str) -> Result<ClientHandle, String> {
+        let target = self.find(nick).ok_or_else(|| no_such_user(nick))?;
+        if Arc::ptr_eq(&target, me) {
+            return Err("you can't do that to yourself".to_string());
+        }
+        Ok(target)
+    }
+
+    // Throw `target` out, telling them and everyone who could see them why.
+    // `why` reads like "kicked by bob (spamming)".
+    fn remove(&self, target: &ClientHandle, why: &str, by: &ClientHandle) {
+        info!("{} was {}", target.label(), why);
+        let _ = target.send(&Frame::error(&format!("you were {}", why)));
+        target.close();
+        let audience = self.lock_rooms().neighbours(target.id);
+        let notice = Frame::notice(&format!("{} was {}", target.nick(), why));
+        self.broadcast(&audience, &notice, target);
+        if !audience.contains(&by.id) {
+            let _ = by.send(&notice);
+        }
+    }
+
+    // A piece of a file from `me`, for whoever is receiving it.
+    fn chunk(&self, me: &ClientHandle, frame: &Frame) {
+        let Some((id, bytes)) = frame.chunk_parts() else {
+            let _ = me.send(&Frame::error("malformed file chunk"));
+            return;
+        };
+        let checked = {
+            let mut transfers = self.transfers.lock().unwrap();
+            // A few chunks may have been on their way when the transfer was
+            // called off; they're dropped quietly.
+            if transfers.get(id).is_none() {
+                return;
+            }
+            transfers
+                .chunk(id, me.id, bytes.len())
+                .map_err(|e| (e, transfers.cancel(id, me.id).ok()))
+        };
+        let to = match checked {
+            Ok(to) => to,
+            Err((e, transfer)) => {
+                warn!("Stopping transfer {} from {}: {}", id, me.label(), e);
+                let _ = me.send(&Frame::error(&e));
+                if let Some(transfer) = transfer {
+                    self.cancelled(&transfer, &e);
+                }
+                return;
+            }
+        };
+        // If they've gone, disconnected() is about to cancel the transfer.
+        if let Some(recipient) = self.find_id(to) {
+            let receipt = Receipt::Chunk {
+                id,
+                bytes: bytes.len(),
+            };
+            let _ = recipient.forward(frame, receipt);
+        }
+    }
+
+    // Tell both sides a transfer is off.
+    fn cancelled(&self, transfer: &Transfer, reason: &str) {
+        info!("Transfer {} cancelled: {}", transfer.id, reason);
+        let event = Event::FileCancelled {
+            id: transfer.id,
+            reason: reason.to_string(),
+        };
+        for side in [transfer.from, transfer.to] {
+            if let Some(client) = self.find_id(side) {
+                let _ = client.send_event(&event);
+            }
+        }
+    }
+
+    // Muted clients may read, but not talk.
+    fn check_muted(&self, me: &ClientHandle) -> Result<(), String> {
+        match self.moderation.muted_for(&me.nick()) {
+            Some(left) => Err(format!(
+                "you are muted for another {}s",
+                left.as_secs().max(1)
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Look up a logged-in client by id.
+    fn find_id(&self, id: ClientId) -> Option<ClientHandle> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|c| c.id == id)
+            .cloned()
+    }
+
+    /// Look up a logged-in client by nickname.
+    pub fn find(&self, nick: &str) -> Option<ClientHandle> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|c| nick::same(&c.nick(), nick))
+            .cloned()
+    }
+
+    // Handle /nick. Returns the old nickname on success.
+    fn rename(&self, me: &ClientHandle, wanted: &str) -> Result<String, NickError> {
+        let list = self.lock_clients();
+        claim_nick(&list, me, wanted)?;
+        self.check_reserved(wanted)?;
+        Ok(me.set_nick(wanted))
+    }
+
+    /// Everyone logged in, by nickname.
+    pub fn nicks(&self) -> Vec<String> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c.nick())
+            .collect()
+    }
+
+    // Plugins speak under their names, so nobody else may use them.
+    fn check_reserved(&self, wanted: &str) -> Result<(), NickError> {
+        match self.plugin(wanted) {
+            Some(_) => Err(NickError::Taken),
+            None => Ok(()),
+        }
+    }
+
+    // The plugin called `name`, if there is one.
+    fn plugin(&self, name: &str) -> Option<(&dyn Plugin, &Bot)> {
+        self.plugins
+            .iter()
+            .find(|(plugin, _)| nick::same(plugin.name(), name))
+            .map(|(plugin, bot)| (plugin.as_ref(), bot))
+    }
+
+    // Let every plugin know about something.
+    fn each_plugin(&self, call: impl Fn(&dyn Plugin, &Bot)) {
+        for (plugin, bot) in &self.plugins {
+            call_plugin(plugin.as_ref(), bot, &call);
+        }
+    }
+
+    /// A plugin says `text` in `room` (see plugin.rs).
+    pub(crate) fn bot_say(&self, from: &str, room: &str, text: &str) -> Result<(), String> {
+        let members = self.lock_rooms().members(room);
+        if members.is_empty() {
+            return Err(format!("nobody is in {}", room));
+        }
+        let event = Event::Chat {
+            id: Some(self.next_id()),
+            room: room.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+            time: None,
+        };
+        info!("{}", event);
+        for client in self.lock_clients().iter() {
+            if members.contains(&client.id) && !client.is_closing() {
+                let _ = client.send_event(&event);
+            }
+        }
+        self.record(room, from, text);
+        if let Some(federation) = &self.federation {
+            self.relay(Event::Chat {
+                id: None,
+                room: room.to_string(),
+                from: federation::qualify(from, federation.name()),
+                text: text.to_string(),
+                time: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// A plugin sends `nick` a private message.
+    pub(crate) fn bot_tell(&self, from: &str, nick: &str, text: &str) -> Result<(), String> {
+        let recipient = self.find(nick).ok_or_else(|| no_such_user(nick))?;
+        let event = Event::Direct {
+            id: Some(self.next_id()),
+            from: from.to_string(),
+            text: text.to_string(),
+        };
+        info!("dm {} -> {}", from, recipient.nick());
+        recipient
+            .send_event(&event)
+            .map_err(|_| format!("could not deliver to {}", nick))
+    }
+
+    // Tell everyone else that `me` came, went or changed status, here and
+    // on other servers.
+    fn announce(&self, me: &ClientHandle, status: Status, message: Option<String>) {
+        let event = Event::Presence {
+            nick: me.nick(),
+            status,
+            message: message.clone(),
+        };
+        let everyone = self.lock_clients().iter().map(|c| c.id).collect();
+        self.broadcast_event(&everyone, &event, me);
+        if let Some(federation) = &self.federation {
+            self.relay(Event::Presence {
+                nick: federation::qualify(&me.nick(), federation.name()),
+                status,
+                message,
+            });
+        }
+    }
+
+    // How `me` looks to other servers right now.
+    fn presence(&self, me: &ClientHandle, federation: &Federation) -> Event {
+        let away = me.away();
+        Event::Presence {
+            nick: federation::qualify(&me.nick(), federation.name()),
+            status: if away.is_some() {
+                Status::Away
+            } else {
+                Status::Online
+            },
+            message: away,
+        }
+    }
+
+    // "/peer <name> [secret]": another server wants to link with us.
+    fn peer_login(&self, me: &ClientHandle, name: &str, secret: Option<String>) {
+        let refusal = match &self.federation {
+            None => Some("this server does not federate".to_string()),
+            Some(federation)
+                if federation.config().secret.is_some() && federation.config().secret != secret =>
+            {
+                Some("wrong peer secret".to_string())
+            }
+            Some(_) if !me.takes_events() => Some(format!(
+                "peers must speak protocol version {} or later",
+                protocol::EVENTS_VERSION
+            )),
+            Some(_) => nick::validate(name)
+                .err()
+                .map(|e| format!("bad server name: {}", e)),
+        };
+        if let Some(refusal) = refusal {
+            warn!("Refusing to link with {} ({}): {}", name, me.peer, refusal);
+            let _ = me.send(&Frame::error(&refusal));
+            me.close();
+            return;
+        }
+        self.link(me, name, true);
+    }
+
+    /// A link we dialed to the server `name` is up (see federation.rs).
+    pub(crate) fn dialed(&self, link: &ClientHandle, name: &str) {
+        self.link(link, name, false);
+    }
+
+    // Start relaying over `link`. The side that was dialed answers with its
+    // own name, the way a client hears its nickname.
+    fn link(&self, link: &ClientHandle, name: &str, answer: bool) {
+        let Some(federation) = &self.federation else {
+            link.close();
+            return;
+        };
+        if let Err(e) = federation.link(link, name) {
+            warn!("Refusing to link with {} ({}): {}", name, link.peer, e);
+            let _ = link.send(&Frame::error(&e));
+            link.close();
+            return;
+        }
+        info!("Linked with {} ({})", name, link.peer);
+        if answer {
+            let _ = link.send(&Frame::nick(federation.name()));
+        }
+        // Tell the new peer who we know to be online: our own users, and
+        // those we know of elsewhere.
+        let locals: Vec<ClientHandle> = self.lock_clients().clone();
+        let mut everyone: Vec<Event> = locals
+            .iter()
+            .map(|client| self.presence(client, federation))
+            .collect();
+        for (nick, away) in federation.remote_users() {
+            everyone.push(Event::Presence {
+                nick,
+                status: if away.is_some() {
+                    Status::Away
+                } else {
+                    Status::Online
+                },
+                message: away,
+            });
+        }
+        for event in everyone {
+            let _ = link.send_event(&federation.wrap(event));
+        }
+    }
+
+    // Send an event that happened here to every linked server.
+    fn relay(&self, event: Event) {
+        let Some(federation) = &self.federation else {
+            return;
+        };
+        let peers = federation.peers();
+        if peers.is_empty() {
+            return;
+        }
+        let relay = federation.wrap(event);
+        for peer in peers {
+            let _ = peer.send_event(&relay);
+        }
+    }
+
+    // A frame from another server.
+    fn peer_frame(&self, link: &ClientHandle, frame: Frame) {
+        match frame.kind {
+            MessageType::Ping => {
+                let _ = link.send(&Frame::pong(&frame));
+            }
+            MessageType::Event => match frame.to_event() {
+                Ok(Event::Relay { origin, id, event }) => self.relayed(link, &origin, id, *event),
+                Ok(Event::Error { text }) => warn!("{} says: {}", link.label(), text),
+                Ok(other) => warn!("Ignoring {:?} from {}", other, link.label()),
+                Err(e) => warn!("Bad event from {}: {}", link.label(), e),
+            },
+            MessageType::Shutdown => info!("{} is shutting down", link.label()),
+            kind => warn!("Ignoring {:?} frame from {}", kind, link.label()),
+        }
+    }
+
+    // An event relayed by `link`: hand it to our users if it's news, and
+    // pass it on to our other peers.
+    fn relayed(&self, link: &ClientHandle, origin: &str, id: u64, event: Event) {
+        let Some(federation) = &self.federation else {
+            return;
+        };
+        if !federation.is_new(origin, id) {
+            return;
+        }
+        match &event {
+            Event::Chat {
+                room, from, text, ..
+            } => {
+                if federation::home(from) == Some(federation.name()) {
+                    return;
+                }
+                let members = self.lock_rooms().members(room);
+                let local = Event::Chat {
+                    id: None,
+                    room: room.clone(),
+                    from: from.clone(),
+                    text: text.clone(),
+                    time: None,
+                };
+                info!("{} (from {})", local, origin);
+                // The link isn't in the client list, so nobody is skipped.
+                self.broadcast_event(&members, &local, link);
+                self.record(room, from, text);
+            }
+            Event::Presence {
+                nick,
+                status,
+                message,
+            } => {
+                if federation::home(nick) == Some(federation.name()) {
+                    return;
+                }
+                // Old news (say, from a snapshot) goes no further.
+                if !federation.update(link.id, nick, *status, message.clone()) {
+                    return;
+                }
+                let everyone = self.lock_clients().iter().map(|c| c.id).collect();
+                self.broadcast_event(&everyone, &event, link);
+            }
+            other => {
+                warn!("Ignoring relayed {:?} from {}", other, link.label());
+                return;
+            }
+        }
+        let relay = Event::Relay {
+            origin: origin.to_string(),
+            id,
+            event: Box::new(event),
+        };
+        for peer in federation.peers() {
+            if peer.id != link.id {
+                let _ = peer.send_event(&relay);
+            }
+        }
+    }
+
+    // A link closed. Everyone we knew through it is gone as far as our users
+    // can tell, and as far as whoever heard of them from us can tell.
+    fn unlinked(&self, link: &ClientHandle) {
+        let Some(federation) = &self.federation else {
+            return;
+        };
+        let Some(gone) = federation.unlink(link) else {
+            return;
+        };
+        info!("Link with {} closed", link.label());
+        let everyone: BTreeSet<ClientId> = self.lock_clients().iter().map(|c| c.id).collect();
+        for nick in gone {
+            let event = Event::Presence {
+                nick,
+                status: Status::Offline,
+                message: None,
+            };
+            self.broadcast_event(&everyone, &event, link);
+            self.relay(event);
+        }
+    }
+
+    /// Send a message to every client whose id is in `audience`, except the
+    /// sender.
+    pub fn broadcast(&self, audience: &BTreeSet<ClientId>, message: &Frame, sender: &ClientHandle) {
+        // Lock the list for the duration of the iteration. This used to be
+        // the expensive part - we wrote to every socket with the lock held.
+        // Now send() only queues bytes, so the lock is held for microseconds
+        // no matter how slow any one client is.
+        let start = Instant::now();
+        let list = self.lock_clients();
+
+        for client in list.iter() {
+            // Skip sending the message back to the sender.
+            // Arc::ptr_eq checks if two Arcs point to the exact same allocation.
+            if Arc::ptr_eq(client, sender) || !audience.contains(&client.id) {
+                continue;
+            }
+            // If the client is already on its way out, we just skip them.
+            // They'll be cleaned up when their worker closes the socket.
+            if client.is_closing() {
+                continue;
+            }
+            // send() logs an overflow itself; nothing else can fail here.
+            let _ = client.send(message);
+        }
+        // Dropping the guard releases the list lock (Drop trait); do it
+        // before the bookkeeping rather than at the end of the scope.
+        drop(list);
+        self.metrics.broadcast.observe(start.elapsed());
+    }
+
+    /// Like broadcast(), with an event: each client gets it in the form it
+    /// understands.
+    pub fn broadcast_event(
+        &self,
+        audience: &BTreeSet<ClientId>,
+        event: &Event,
+        sender: &ClientHandle,
+    ) {
+        self.fan_out(audience, event, sender, None);
+    }
+
+    // broadcast_event(), with a receipt riding along with every copy.
+    fn fan_out(
+        &self,
+        audience: &BTreeSet<ClientId>,
+        event: &Event,
+        sender: &ClientHandle,
+        receipt: Option<Receipt>,
+    ) {
+        let start = Instant::now();
+        let list = self.lock_clients();
+        for client in list.iter() {
+            if Arc::ptr_eq(client, sender) || !audience.contains(&client.id) || client.is_closing()
+            {
+                continue;
+            }
+            let _ = client.deliver(event, receipt);
+        }
+        drop(list);
+        self.metrics.broadcast.observe(start.elapsed());
+    }
+
+    /// The connection is gone. Remove the client from the shared list and
+    /// its rooms.
+    pub fn disconnected(&self, me: &ClientHandle) {
+        if me.is_peer() {
+            self.unlinked(me);
+            return;
+        }
+        // LEARNING NOTE: If you don't do this, the list grows forever with
+        // dead handles, and every broadcast will try (and fail) to write to
+        // them. This is a classic "stale handle" / resource leak bug in chat
+        // servers.
+        //
+        // Take ourself off the list first, under its lock: a login thread
+        // finishing right now either got in before us (and we remove it
+        // here) or will see we're closing and stay out.
+        let remaining = {
+            let mut list = self.lock_clients();
+            let before = list.len();
+            // retain() keeps only elements for which the closure returns true.
+            // We remove ourself by pointer comparison.
+            list.retain(|c| !Arc::ptr_eq(c, me));
+            (list.len() < before).then_some(list.len())
+        };
+        let Some(remaining) = remaining else {
+            info!("{} left before logging in.", me.peer);
+            return;
+        };
+        info!("{} disconnected. Cleaning up.", me.label());
+        if me.dropped() > 0 {
+            info!(
+                "{} lost {} messages to a full outbox",
+                me.label(),
+                me.dropped()
+            );
+        }
+
+        let (_, emptied) = self.lock_rooms().remove_client(me.id);
+        for room in emptied {
+            info!("Room {} is empty, removing it", room);
+        }
+        let transfers = self.transfers.lock().unwrap().remove_client(me.id);
+        for transfer in transfers {
+            self.cancelled(&transfer, &format!("{} left", me.nick()));
+        }
+        info!("Active connections: {}", remaining);
+        // Everyone still here hears that we went.
+        self.announce(me, Status::Offline, None);
+        let nick = me.nick();
+        self.each_plugin(|plugin, bot| plugin.on_disconnect(bot, &nick));
+    }
+}
+
+// Call one plugin. One that panics is logged and carries on, rather than
+// taking this worker, and everyone on it, down with it.
+fn call_plugin(plugin: &dyn Plugin, bot: &Bot, call: impl Fn(&dyn Plugin, &Bot)) {
+    if panic::catch_unwind(AssertUnwindSafe(|| call(plugin, bot))).is_err() {
+        error!("Plugin {} panicked", plugin.name());
+    }
+}
+
+// What a banned client is told.
+fn banned(reason: &str) -> String {
+    format!("you are banned from this server{}", because(reason))
+}
+
+// " (reason)", or nothing if no reason was given.
+fn because(reason: &str) -> String {
+    if reason.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", reason)
+    }
+}
+
+fn no_such_user(nick: &str) -> String {
+    format!("no such user: {} (unknown or offline)", nick)
+}
+
+// Check a nickname against the rules and against everyone else in the list.
+// The caller must hold the list lock and keep holding it until the nickname
+// is stored, or another thread could claim the same name in between.
+fn claim_nick(list: &[ClientHandle], me: &ClientHandle, wanted: &str) -> Result<(), NickError> {
+    nick::validate(wanted)?;
+    let taken = list
+        .iter()
+        .any(|c| !Arc::ptr_eq(c, me) && nick::same(&c.nick(), wanted));
+    if taken {
+        return Err(NickError::Taken);
+    }
+    Ok(())
+}
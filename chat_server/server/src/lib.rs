@@ -1 +1,27 @@
+pub mod accounts;
+pub mod client;
+pub mod command;
+pub mod config;
+pub mod event_loop;
+pub mod federation;
+pub mod heartbeat;
+pub mod history;
+pub mod hub;
+pub mod logging;
+pub mod metrics;
+pub mod moderation;
+pub mod nick;
+pub mod outbox;
+pub mod plugin;
+pub mod ratelimit;
+pub mod room;
+pub mod server;
+pub mod settings;
+pub mod shutdown;
+pub mod tls;
+pub mod transfer;
+pub mod websocket;
 
+/// Every connection gets a unique id when it is accepted. Ids are never
+/// reused, so a stale id can't accidentally point at a new client.
+pub type ClientId = u64;
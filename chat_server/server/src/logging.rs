@@ -0,0 +1,104 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Logging through a facade.
+//
+// The library used to println! its log lines, which gave whoever runs the
+// server no say in where they go or how chatty they are. The `log` crate
+// separates the two: code anywhere calls info!/warn!/error!, and the
+// program installs one logger at startup that decides what to do with
+// them. Until it does, log calls cost almost nothing and print nothing,
+// which is also why the tests are quiet.
+//
+// Ours is deliberately small: lines at or above the configured level go to
+// the terminal (warnings and errors on stderr) or, if a file is configured,
+// to that file with a timestamp in front.
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    /// The least severe level that still gets logged.
+    pub level: LevelFilter,
+    /// Append to this file instead of writing to the terminal.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LevelFilter::Info,
+            file: None,
+        }
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+
+/// Install the server's logger. Can only be done once per process.
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let file = match &config.file {
+        Some(path) => Some(Mutex::new(open(path)?)),
+        None => None,
+    };
+    let logger = Logger {
+        level: config.level,
+        file,
+    };
+    log::set_boxed_logger(Box::new(logger)).map_err(io::Error::other)?;
+    log::set_max_level(config.level);
+    Ok(())
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match &self.file {
+            Some(file) => {
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let _ = writeln!(
+                    file.lock().unwrap(),
+                    "{}:{:02} {:<5} {}",
+                    protocol::utc_timestamp(secs),
+                    secs % 60,
+                    record.level(),
+                    record.args()
+                );
+            }
+            None if record.level() <= Level::Warn => {
+                eprintln!("[server] {}", record.args());
+            }
+            None => println!("[server] {}", record.args()),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
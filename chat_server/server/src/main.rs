@@ -1,160 +1,282 @@
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use std::thread;
-
-// ---------------------------------------------------------------------------
-// LEARNING NOTE: Why Arc<Mutex<T>>?
-//
-// We need to share the client list across multiple threads. In Rust, you
-// cannot share a plain Vec across threads because the compiler enforces that
-// only one owner exists at a time (ownership rules).
-//
-//   Arc  = Atomically Reference Counted. Lets multiple threads hold a pointer
-//          to the same data. Cloning an Arc just bumps a counter - it does NOT
-//          copy the underlying data.
-//
-//   Mutex = Mutual Exclusion. Only one thread can "lock" it at a time.
-//           Trying to lock when another thread holds it → your thread sleeps
-//           until it's released. This prevents data races.
-//
-// Together: Arc<Mutex<T>> is the "safe shared mutable state" pattern in Rust.
-// You will use this constantly. Learn to love it and fear it equally.
-// ---------------------------------------------------------------------------
-
-// Each connected client gets a handle so we can write back to them.
-// We wrap TcpStream in Arc<Mutex<>> so multiple threads can write to it.
-type ClientHandle = Arc<Mutex<TcpStream>>;
-
-// The shared list of all connected clients.
-// Arc lets every client thread hold a reference to this same list.
-type ClientList = Arc<Mutex<Vec<ClientHandle>>>;
-
-fn main() -> std::io::Result<()> {
-    // Create a TCP listener on localhost:8080
-    let addr = "127.0.0.1:8080";
-    let listener = TcpListener::bind(addr)?;
-    println!("Server listening on {}", addr);
-
-    // Create the shared client list. This single instance will be shared
-    // (via Arc clones) with every client thread we spawn.
-    let clients: ClientList = Arc::new(Mutex::new(Vec::new()));
-
-    for incoming in listener.incoming() {
-        match incoming {
-            Ok(stream) => {
-                // Get the peer address before we move the stream.
-                let peer = stream.peer_addr()?;
-                println!("[server] New connection from {}", peer);
-
-                // Wrap the stream so it can be shared across threads.
-                let client_handle = Arc::new(Mutex::new(
-                    // TcpStream::try_clone gives us a second OS-level handle
-                    //to the same socket. We keep one for writing (in the
-                    // client list) and use the original for reading below.
-                    //
-                    // LEARNING NOTE: We can't just clone TcpStream directly
-                    // (it doesn't implement Clone). try_clone() is how you
-                    // get a second handle to the same socket. This is an
-                    // important TCP/OS concept - the OS socket itself is
-                    // reference counted at the kernel level.
-                    stream.try_clone().expect("Failed to clone stream"),
-                ));
-
-                // Register this client in the shared list.
-                // Lock → push → drop the lock immediately.
-                // LEARNING NOTE: Hold locks for the shortest time possible.
-                // Holding a lock while doing I/O is a classic mistake that
-                // causes all other threads to stall waiting.
-                {
-                    let mut list = clients.lock().unwrap();
-                    list.push(Arc::clone(&client_handle));
-                }
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
 
-                // Clone the Arc (not the data) so the new thread gets its
-                // own referene to the shared client list.
-                let clients_clone = Arc::clone(&clients);
+use clap::{Parser, Subcommand};
+use log::{info, warn};
+use server::accounts::{AccountError, Accounts, AccountsConfig};
+use server::config::Options;
+use server::logging;
+use server::server::Server;
+use server::settings::Settings;
+use server::tls;
 
-                thread::spawn(move || {
-                    handle_client(stream, peer.to_string(), clients_clone, client_handle);
-                });
-            }
-            Err(e) => {
-                eprintln!("[server] Accept error: {}", e);
+// The chat logic lives in the library (see hub.rs), the networking in
+// event_loop.rs and the settings in config.rs; server.rs puts them
+// together. main just reads the settings and runs it - or, as `server user
+// ...`, manages the user store.
+
+#[derive(Parser)]
+#[command(about = "A small chat server")]
+struct Cli {
+    /// Read settings from this TOML file. Flags and environment variables
+    /// override it.
+    #[arg(long, env = "CHAT_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: Options,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the user store. A running server notices the file changed and
+    /// picks it up at the next login.
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserAction {
+    /// Create an account; the password is read from stdin.
+    Add { name: String },
+    /// Delete an account.
+    Remove { name: String },
+    /// List every account.
+    List,
+}
+
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+    let options = match &cli.config {
+        Some(path) => cli.options.or(Options::load(path)?),
+        None => cli.options,
+    };
+    if let Some(Command::User { action }) = cli.command {
+        return user_admin(&options, action);
+    }
+
+    logging::init(&options.log_config())?;
+    if let Some(path) = &cli.config {
+        info!("Read settings from {}", path.display());
+    }
+    let settings = options.settings()?;
+    describe(&settings);
+
+    if options.self_signed {
+        if let Some(tls) = &settings.tls {
+            if tls::ensure_self_signed(tls, &["localhost", "127.0.0.1", "::1"])? {
+                info!(
+                    "Generated a self-signed certificate in {}",
+                    tls.cert.display()
+                );
             }
         }
     }
-    Ok(())
+
+    // The builder binds every listener and creates the shared chat state,
+    // one Hub that every worker thread gets an Arc clone of (see
+    // server.rs).
+    let mut builder = Server::builder()
+        .settings(settings)
+        .workers(options.workers());
+    for addr in options.listen_addrs()? {
+        builder = builder.bind(addr);
+    }
+    // Browsers connect here (see websocket.rs).
+    for addr in options.websocket_addrs()? {
+        builder = builder.websocket(addr);
+    }
+    // Prometheus scrapes these (see metrics.rs).
+    for addr in options.metrics_addrs()? {
+        builder = builder.metrics(addr);
+    }
+    let server = builder.build()?;
+    for addr in server.local_addrs() {
+        info!("Listening on {}", addr);
+    }
+    for addr in server.websocket_addrs() {
+        info!("WebSocket gateway on {}", addr);
+    }
+    for addr in server.metrics_addrs() {
+        info!("Metrics on http://{}/metrics", addr);
+    }
+    info!("Running {} event loop workers", server.workers());
+
+    // Ctrl+C or a SIGTERM from a service manager starts a graceful shutdown
+    // (see shutdown.rs). Asking twice means "now".
+    let switch = server.shutdown();
+    ctrlc::set_handler(move || {
+        if switch.is_requested() {
+            warn!("Stopping immediately");
+            std::process::exit(130);
+        }
+        info!("Shutting down; interrupt again to stop immediately");
+        switch.trigger();
+    })
+    .map_err(io::Error::other)?;
+
+    server.run()
 }
 
-fn handle_client(
-    stream: TcpStream,
-    peer: String,
-    clients: ClientList,
-    // We need our own handle so we can remove ourselves from the list on exit.
-    my_handle: ClientHandle,
-) {
-    // BufReader wraps the stream so we can read line-by-line efficiently.
-    // Without buffering, we'd read one byte at a time - very slow.
-    let reader = BufReader::new(stream);
-
-    for line in reader.lines() {
-        match line {
-            Ok(msg) => {
-                let outgoing = format!("[{}]:{}\n", peer, msg);
-                print!("{}", outgoing);
-                // Broadcast to all connected clients.
-                // LEARNING NOTE: We lock the list to iterate it, but we
-                // release the individual client lock after each write.
-                // If we held the list lock AND tried to lock each client,
-                // and another thread was doing the same in the other order,
-                // we'd have a DEADLOCK. Always acquire locks in a consistent
-                // order to avoid this.
-                broadcast(&clients, &outgoing, &my_handle);
-            }
-            Err(e) => {
-                eprintln!("[server] Error reading from {}: {}", peer, e);
-                break;
+// Say what we're about to do, so whoever started the server can check it
+// picked up the settings they meant.
+fn describe(settings: &Settings) {
+    // How much each slow client may have waiting, and what happens when
+    // that fills up (see outbox.rs).
+    info!(
+        "Outbox capacity {} frames, on overflow: {}",
+        settings.outbox.capacity, settings.outbox.policy
+    );
+
+    // Where room messages are logged for replay (see history.rs).
+    match &settings.history.dir {
+        Some(dir) => info!(
+            "Logging history to {}, replaying {} messages on join",
+            dir.display(),
+            settings.history.replay
+        ),
+        None => info!("Message history is off"),
+    }
+
+    // TLS is on when a certificate and key are configured (see tls.rs).
+    // With --self-signed, clients must pin that certificate (--tls-pin
+    // on the client).
+    if let Some(tls) = &settings.tls {
+        info!("Serving TLS with {}", tls.cert.display());
+    }
+
+    // Accounts (see accounts.rs). With them off, anyone may chat under any
+    // free nickname.
+    match &settings.accounts.file {
+        Some(file) => {
+            info!(
+                "Accounts in {} (guests {}, registration {})",
+                file.display(),
+                if settings.accounts.guests {
+                    "allowed"
+                } else {
+                    "not allowed"
+                },
+                if settings.accounts.registration {
+                    "open"
+                } else {
+                    "closed"
+                }
+            );
+            if settings.tls.is_none() {
+                warn!("TLS is off, so passwords cross the network in the clear");
             }
         }
+        None => info!("Accounts are off"),
+    }
+
+    // Moderation (see moderation.rs). Only accounts can be operators from
+    // the start; they can /op others from there.
+    let moderation = &settings.moderation;
+    match &moderation.bans {
+        Some(file) => info!("Ban list in {}", file.display()),
+        None => info!("Bans are kept in memory only"),
+    }
+    if let Some(file) = &moderation.log {
+        info!("Recording moderation in {}", file.display());
+    }
+    if !moderation.operators.is_empty() {
+        info!("Operators: {}", moderation.operators.join(", "));
+        if settings.accounts.file.is_none() {
+            warn!("Accounts are off, so nobody can log in as an operator");
+        }
     }
-    // Client disconnected. Remove them from the shared list.
-    // LEARNING NOTE: If you don't do this, the list grows forever with dead
-    // handles, and every broadcast will try (and fail) to write to them.
-    // This is a classic "stale handle" / resource leak bug in chat servers.
-    cleanup(&clients, &my_handle, &peer);
-}
 
-fn broadcast(clients: &ClientList, message: &str, sender: &ClientHandle) {
-    // Lock the list for the duration of the iteration.
-    let list = clients.lock().unwrap();
+    // Heartbeats (see heartbeat.rs).
+    match &settings.heartbeat {
+        Some(heartbeat) => info!(
+            "Pinging clients every {}s, dropping them after {} missed",
+            heartbeat.interval.as_secs(),
+            heartbeat.missed
+        ),
+        None => info!("Heartbeats are off"),
+    }
+
+    // Flood protection (see ratelimit.rs).
+    match &settings.rate_limit {
+        Some(limit) => info!(
+            "Rate limit {} messages a second, bursts of {}",
+            limit.rate, limit.burst
+        ),
+        None => info!("Rate limiting is off"),
+    }
 
-    for client in list.iter() {
-        // Skip sending the message back to the sender.
-        // Arc::ptr_eq checks if two Arcs point to the exact same allocation.
-        if Arc::ptr_eq(client, sender) {
-            continue;
+    // File transfers (see transfer.rs). The server relays them without
+    // keeping any of the bytes.
+    match &settings.transfers {
+        Some(transfers) => info!("File transfers up to {} bytes", transfers.max_size),
+        None => info!("File transfers are off"),
+    }
+
+    if settings.motd.is_some() {
+        info!("Message of the day is set");
+    }
+
+    // Federation (see federation.rs). Links are plain TCP, so a server that
+    // only speaks TLS can't be dialed by its peers.
+    if let Some(federation) = &settings.federation {
+        let peers: Vec<String> = federation.peers.iter().map(|p| p.to_string()).collect();
+        if peers.is_empty() {
+            info!("Federating as {}, waiting for peers", federation.name);
+        } else {
+            info!(
+                "Federating as {} with {}",
+                federation.name,
+                peers.join(", ")
+            );
         }
-        // Lock this specific client's stream and write to it.
-        // if the write fails (client disconnected), we just skip them.
-        // They'll be cleaned up when their own read loop exists
-        match client.lock().unwrap().write_all(message.as_bytes()) {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("[server] Error writing to client: {}", e);
-            }
+        if settings.tls.is_some() {
+            warn!("TLS is on, so peers will not be able to link with this server");
         }
     }
-    // list lock is released here automatically (Drop trait).
 }
 
-fn cleanup(clients: &ClientList, my_handle: &ClientHandle, peer: &str) {
-    println!("[server] {} disconnected. Cleaning up.", peer);
+// `server user add <name>`, `server user remove <name>`, `server user list`:
+// manage the user store from the shell.
+fn user_admin(options: &Options, action: UserAction) -> io::Result<()> {
+    let Some(file) = options.accounts_file() else {
+        return Err(invalid_input("accounts are turned off"));
+    };
+    let accounts = Accounts::open(&file, AccountsConfig::default())?;
+    let failed = |e: AccountError| invalid_input(&e.to_string());
+    match action {
+        UserAction::Add { name } => {
+            let password = read_password(&name)?;
+            accounts.add(&name, &password).map_err(failed)?;
+            println!("Added {} to {}", name, file.display());
+        }
+        UserAction::Remove { name } => {
+            accounts.remove(&name).map_err(failed)?;
+            println!("Removed {} from {}", name, file.display());
+        }
+        UserAction::List => {
+            for name in accounts.names()? {
+                println!("{}", name);
+            }
+        }
+    }
+    Ok(())
+}
 
-    let mut list = clients.lock().unwrap();
+// One line from stdin. It echoes as you type; pipe it in if that matters.
+fn read_password(name: &str) -> io::Result<String> {
+    print!("Password for {}: ", name);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
 
-    // retain() keeps only elements for which the closure returns true.
-    // We remove ourself by pointer comparison.
-    list.retain(|c| !Arc::ptr_eq(c, my_handle));
-    println!("[server] Active connections: {}", list.len());
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
 }
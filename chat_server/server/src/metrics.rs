@@ -0,0 +1,337 @@
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use crate::hub::Hub;
+use crate::shutdown::Shutdown;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Counting instead of printing.
+//
+// The log says what happened to whom, one line at a time. It can't say how
+// busy the server is: how many people are connected, how many messages a
+// second go through, whether broadcasts are getting slower. For that a
+// server keeps counters, and something like Prometheus comes by every few
+// seconds to read them and draw graphs.
+//
+// Counting has to be cheap, because it happens on every message. So each
+// number is an atomic that any thread can bump without a lock, and nothing
+// is added up or formatted until someone asks. Prometheus works out rates
+// itself from two readings of an ever-growing counter, so we never reset
+// anything.
+//
+// Averages hide the slow cases that matter, so broadcast times go in a
+// histogram instead: a count for each of a few buckets ("under 10us", "under
+// 50us", ...), which is enough to tell the 99th percentile from the median.
+//
+// Reading them is a plain HTTP GET, answered on a thread of its own so a slow
+// scraper never holds up an event loop:
+//
+//   $ curl http://127.0.0.1:9898/metrics
+//   chat_users_online 12
+//   chat_messages_received_total 30127
+//   chat_broadcast_duration_seconds_bucket{le="0.0001"} 29311
+//   ...
+// ---------------------------------------------------------------------------
+
+/// Upper bounds of the broadcast histogram's buckets, in seconds.
+pub const BROADCAST_BUCKETS: [f64; 10] = [
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5,
+];
+
+// How long a scraper has to send its request and take the answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Largest request we'll read.
+const MAX_REQUEST: usize = 8 * 1024;
+
+// How long to back off after an accept error, like the main accept loop.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+const LISTENER: Token = Token(0);
+const SHUTDOWN: Token = Token(1);
+
+/// Server-wide counters. The Hub owns one; the event loop and the Hub count
+/// into it.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Connections accepted or dialed, ever.
+    pub connections: AtomicU64,
+    /// Connections closed, ever. The ones still open are the difference.
+    pub disconnections: AtomicU64,
+    /// Frames read from clients and handed to the Hub.
+    pub messages_in: AtomicU64,
+    /// Frames taken from outboxes to be written.
+    pub messages_out: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    /// Writes that failed outright, losing the connection.
+    pub write_errors: AtomicU64,
+    /// How long each broadcast took, lock wait included.
+    pub broadcast: Histogram,
+    /// Time spent waiting for the Hub's client list, in nanoseconds.
+    pub clients_lock_wait: AtomicU64,
+    /// Time spent waiting for the Hub's rooms, in nanoseconds.
+    pub rooms_lock_wait: AtomicU64,
+}
+
+impl Metrics {
+    /// Connections open right now, logged in or not.
+    pub fn open_connections(&self) -> u64 {
+        let opened = self.connections.load(Ordering::Relaxed);
+        opened.saturating_sub(self.disconnections.load(Ordering::Relaxed))
+    }
+}
+
+/// Durations sorted into BROADCAST_BUCKETS.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    // counts[i] is how many fell in bucket i and no lower one; the extra one
+    // at the end is everything slower than the last bound.
+    counts: [AtomicU64; BROADCAST_BUCKETS.len() + 1],
+    // In nanoseconds.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, time: Duration) {
+        let secs = time.as_secs_f64();
+        let bucket = BROADCAST_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(BROADCAST_BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Take `mutex`, adding however long we had to wait for it to `waited`.
+pub(crate) fn lock<'a, T>(mutex: &'a Mutex<T>, waited: &AtomicU64) -> MutexGuard<'a, T> {
+    // Most of the time nobody else has it, and then there's nothing to
+    // time: don't pay for reading the clock.
+    if let Ok(guard) = mutex.try_lock() {
+        return guard;
+    }
+    let start = Instant::now();
+    let guard = mutex.lock().unwrap();
+    waited.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    guard
+}
+
+/// Every metric, in the Prometheus text format.
+pub fn render(hub: &Hub) -> String {
+    let metrics = hub.metrics();
+    let stats = hub.outbox_stats();
+    let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+    let mut out = String::new();
+
+    let gauges = [
+        (
+            "chat_connections_open",
+            "Connections open, logged in or not.",
+            metrics.open_connections(),
+        ),
+        (
+            "chat_users_online",
+            "Clients logged in.",
+            hub.active_connections() as u64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        metric(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    let counters = [
+        (
+            "chat_connections_total",
+            "Connections accepted or dialed.",
+            load(&metrics.connections),
+        ),
+        (
+            "chat_messages_received_total",
+            "Frames received from clients.",
+            load(&metrics.messages_in),
+        ),
+        (
+            "chat_messages_sent_total",
+            "Frames sent to clients.",
+            load(&metrics.messages_out),
+        ),
+        (
+            "chat_bytes_received_total",
+            "Bytes read from sockets.",
+            load(&metrics.bytes_in),
+        ),
+        (
+            "chat_bytes_sent_total",
+            "Bytes written to sockets.",
+            load(&metrics.bytes_out),
+        ),
+        (
+            "chat_write_errors_total",
+            "Socket writes that failed.",
+            load(&metrics.write_errors),
+        ),
+        (
+            "chat_outbox_dropped_total",
+            "Frames dropped from full outboxes.",
+            load(&stats.dropped),
+        ),
+        (
+            "chat_slow_disconnects_total",
+            "Clients disconnected for a full outbox.",
+            load(&stats.slow_disconnects),
+        ),
+    ];
+    for (name, help, value) in counters {
+        metric(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    let name = "chat_lock_wait_seconds_total";
+    metric(
+        &mut out,
+        name,
+        "counter",
+        "Time spent waiting for Hub locks.",
+    );
+    for (lock, waited) in [
+        ("clients", &metrics.clients_lock_wait),
+        ("rooms", &metrics.rooms_lock_wait),
+    ] {
+        let _ = writeln!(
+            out,
+            "{}{{lock=\"{}\"}} {}",
+            name,
+            lock,
+            seconds(load(waited))
+        );
+    }
+
+    let name = "chat_broadcast_duration_seconds";
+    metric(&mut out, name, "histogram", "Time to queue a broadcast.");
+    let histogram = &metrics.broadcast;
+    let mut total = 0;
+    for (bound, count) in BROADCAST_BUCKETS.iter().zip(&histogram.counts) {
+        total += load(count);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, total);
+    }
+    total += load(&histogram.counts[BROADCAST_BUCKETS.len()]);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, total);
+    let _ = writeln!(out, "{}_sum {}", name, seconds(load(&histogram.sum)));
+    let _ = writeln!(out, "{}_count {}", name, total);
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn seconds(nanos: u64) -> f64 {
+    nanos as f64 / 1e9
+}
+
+/// Answer scrapers on `listener` until `shutdown` is triggered, one at a
+/// time. Blocks, so it gets a thread of its own (see event_loop.rs).
+pub fn serve(listener: std::net::TcpListener, hub: Arc<Hub>, shutdown: Shutdown) -> io::Result<()> {
+    // mio tells us when someone's waiting, or when to stop; the accepting
+    // and answering are plain blocking std calls, on this thread only.
+    listener.set_nonblocking(true)?;
+    let mut ready = TcpListener::from_std(listener.try_clone()?);
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut ready, LISTENER, Interest::READABLE)?;
+    shutdown.wake_on_trigger(Arc::new(Waker::new(poll.registry(), SHUTDOWN)?));
+    let mut events = Events::with_capacity(4);
+    let mut timeout = None;
+    loop {
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        timeout = None;
+        if shutdown.is_requested() {
+            return Ok(());
+        }
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if let Err(e) = answer(stream, &hub) {
+                        warn!("Metrics request from {} failed: {}", peer, e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Metrics accept error: {}", e);
+                    timeout = Some(ACCEPT_RETRY);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Read one request and answer it. Anything but GET /metrics is turned away.
+fn answer(mut stream: TcpStream, hub: &Hub) -> io::Result<()> {
+    // Some systems hand out accepted sockets non-blocking, like their
+    // listener.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut seen = Vec::new();
+    let mut buf = [0u8; 1024];
+    let end = loop {
+        if let Some(end) = seen.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if seen.len() > MAX_REQUEST {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "");
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        seen.extend_from_slice(&buf[..n]);
+    };
+    let request = String::from_utf8_lossy(&seen[..end]);
+    let mut start = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    match (start.next(), start.next()) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            respond(&mut stream, "200 OK", &render(hub))
+        }
+        (Some("GET"), Some(_)) => respond(&mut stream, "404 Not Found", "try /metrics\n"),
+        (Some(_), Some(_)) => respond(&mut stream, "405 Method Not Allowed", "use GET\n"),
+        _ => respond(&mut stream, "400 Bad Request", "not an HTTP request\n"),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Moderation needs memory and a paper trail.
+//
+// Kicking someone is easy - close their connection. But they can reconnect a
+// second later, so a ban has to outlive both the connection and the server
+// process: it goes in a file, just like the user store. Bans come in two
+// flavours, because neither is enough on its own:
+//
+//   nick  - stops a name (for accounts, that's the person). Useless against
+//           a guest, who can just pick another one.
+//   ip    - stops a machine, whatever name it picks. Also stops everyone
+//           else behind the same NAT, so use it with care.
+//
+// Mutes are shorter-lived and only kept in memory, but they are keyed by
+// nickname rather than by connection, so reconnecting doesn't lift one.
+//
+// Power over other users should be accountable. Every moderation action is
+// appended to an audit log - who did what to whom, and when - which is the
+// first thing you want when someone asks "why was I banned?".
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModerationConfig {
+    /// The ban list. `None` keeps bans in memory only, so a restart lifts
+    /// them.
+    pub bans: Option<PathBuf>,
+    /// Where moderation actions are recorded, besides the server log.
+    pub log: Option<PathBuf>,
+    /// Accounts that are operators whenever they log in. Guests never are,
+    /// since anyone could pick their nickname.
+    pub operators: Vec<String>,
+}
+
+/// Who a ban applies to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ban {
+    Ip(IpAddr),
+    /// Stored lowercased, since nicknames are case-insensitive.
+    Nick(String),
+}
+
+impl Ban {
+    /// A ban on `target`: an IP address if it parses as one, otherwise a
+    /// nickname.
+    pub fn parse(target: &str) -> Ban {
+        match target.parse() {
+            Ok(ip) => Ban::Ip(ip),
+            Err(_) => Ban::Nick(target.to_ascii_lowercase()),
+        }
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ban::Ip(ip) => write!(f, "ip {}", ip),
+            Ban::Nick(nick) => write!(f, "nick {}", nick),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Moderation {
+    config: ModerationConfig,
+    // Each ban with the reason given for it.
+    bans: Mutex<BTreeMap<Ban, String>>,
+    // Muted nicknames (lowercased) and when the mute ends.
+    mutes: Mutex<HashMap<String, Instant>>,
+    audit: Option<Mutex<File>>,
+}
+
+impl Moderation {
+    /// Load the ban list and open the audit log, as configured. A missing
+    /// ban list is an empty one.
+    pub fn open(config: ModerationConfig) -> io::Result<Moderation> {
+        let bans = match &config.bans {
+            Some(path) => load_bans(path)?,
+            None => BTreeMap::new(),
+        };
+        let audit = match &config.log {
+            Some(path) => {
+                if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                    fs::create_dir_all(dir)?;
+                }
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+        Ok(Moderation {
+            config,
+            bans: Mutex::new(bans),
+            mutes: Mutex::new(HashMap::new()),
+            audit,
+        })
+    }
+
+    pub fn config(&self) -> &ModerationConfig {
+        &self.config
+    }
+
+    /// Whether the account `name` is a configured operator.
+    pub fn is_operator_account(&self, name: &str) -> bool {
+        self.config
+            .operators
+            .iter()
+            .any(|op| op.eq_ignore_ascii_case(name))
+    }
+
+    /// The ban that keeps out `ip`, if any.
+    pub fn ip_ban(&self, ip: IpAddr) -> Option<String> {
+        self.bans.lock().unwrap().get(&Ban::Ip(ip)).cloned()
+    }
+
+    /// The ban that keeps out the nickname `nick`, if any.
+    pub fn nick_ban(&self, nick: &str) -> Option<String> {
+        let ban = Ban::Nick(nick.to_ascii_lowercase());
+        self.bans.lock().unwrap().get(&ban).cloned()
+    }
+
+    /// Add a ban. False if it was already there.
+    pub fn ban(&self, ban: Ban, reason: &str) -> io::Result<bool> {
+        let mut bans = self.bans.lock().unwrap();
+        if bans.contains_key(&ban) {
+            return Ok(false);
+        }
+        bans.insert(ban, reason.to_string());
+        self.save(&bans)?;
+        Ok(true)
+    }
+
+    /// Lift a ban. False if there was none.
+    pub fn unban(&self, ban: &Ban) -> io::Result<bool> {
+        let mut bans = self.bans.lock().unwrap();
+        if bans.remove(ban).is_none() {
+            return Ok(false);
+        }
+        self.save(&bans)?;
+        Ok(true)
+    }
+
+    /// Every ban, with its reason.
+    pub fn bans(&self) -> Vec<(Ban, String)> {
+        let bans = self.bans.lock().unwrap();
+        bans.iter()
+            .map(|(ban, reason)| (ban.clone(), reason.clone()))
+            .collect()
+    }
+
+    pub fn mute(&self, nick: &str, time: Duration) {
+        let until = Instant::now() + time;
+        let mut mutes = self.mutes.lock().unwrap();
+        mutes.insert(nick.to_ascii_lowercase(), until);
+    }
+
+    /// Lift a mute. False if `nick` wasn't muted.
+    pub fn unmute(&self, nick: &str) -> bool {
+        let mut mutes = self.mutes.lock().unwrap();
+        mutes.remove(&nick.to_ascii_lowercase()).is_some()
+    }
+
+    /// How much longer `nick` is muted, if it is.
+    pub fn muted_for(&self, nick: &str) -> Option<Duration> {
+        let key = nick.to_ascii_lowercase();
+        let mut mutes = self.mutes.lock().unwrap();
+        let until = *mutes.get(&key)?;
+        let now = Instant::now();
+        if until > now {
+            return Some(until - now);
+        }
+        mutes.remove(&key);
+        None
+    }
+
+    /// Record a moderation action: `actor` did `action`.
+    pub fn audit(&self, actor: &str, action: &str) {
+        info!("[moderation] {} {}", actor, action);
+        let Some(file) = &self.audit else {
+            return;
+        };
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let line = format!(
+            "{}:{:02} {} {}\n",
+            protocol::utc_timestamp(secs),
+            secs % 60,
+            actor,
+            action
+        );
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Could not write to the moderation log: {}", e);
+        }
+    }
+
+    // Rewrite the ban list, the same way the user store is: to a temporary
+    // file first, then renamed into place.
+    fn save(&self, bans: &BTreeMap<Ban, String>) -> io::Result<()> {
+        let Some(path) = &self.config.bans else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+        for (ban, reason) in bans {
+            if reason.is_empty() {
+                contents.push_str(&format!("{}\n", ban));
+            } else {
+                contents.push_str(&format!("{} {}\n", ban, reason));
+            }
+        }
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+// One ban per line: "ip <address> [reason]" or "nick <name> [reason]".
+fn load_bans(path: &Path) -> io::Result<BTreeMap<Ban, String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut bans = BTreeMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(3, ' ');
+        let (kind, target) = (parts.next(), parts.next());
+        let reason = parts.next().unwrap_or("").trim().to_string();
+        let ban = match (kind, target) {
+            (Some("ip"), Some(ip)) => match ip.parse() {
+                Ok(ip) => Ban::Ip(ip),
+                Err(_) => {
+                    warn!("Skipping bad address in {}: {}", path.display(), ip);
+                    continue;
+                }
+            },
+            (Some("nick"), Some(nick)) => Ban::Nick(nick.to_ascii_lowercase()),
+            _ => {
+                warn!("Skipping malformed line in {}", path.display());
+                continue;
+            }
+        };
+        bans.insert(ban, reason);
+    }
+    Ok(bans)
+}
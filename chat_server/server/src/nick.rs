@@ -0,0 +1,68 @@
+use std::fmt;
+
+// ---------------------------------------------------------------------------
+// Nickname rules.
+//
+// Nicknames show up in every chat line and are how users address each other,
+// so they need to be short, unambiguous and impossible to confuse with the
+// server itself. Uniqueness is checked case-insensitively: "Alice" and
+// "alice" would look like the same person in a busy channel.
+// ---------------------------------------------------------------------------
+
+pub const MAX_NICK_LEN: usize = 16;
+
+// Names that would let a user impersonate the server or staff.
+const RESERVED: &[&str] = &["server", "system", "admin", "operator", "root", "nickserv"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NickError {
+    Empty,
+    TooLong,
+    /// Must start with a letter; the rest may be letters, digits, '_' or '-'.
+    InvalidChar(char),
+    Reserved,
+    Taken,
+}
+
+impl fmt::Display for NickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NickError::Empty => write!(f, "nickname cannot be empty"),
+            NickError::TooLong => {
+                write!(f, "nickname is longer than {} characters", MAX_NICK_LEN)
+            }
+            NickError::InvalidChar(c) => write!(f, "nickname cannot contain {:?}", c),
+            NickError::Reserved => write!(f, "that nickname is reserved"),
+            NickError::Taken => write!(f, "that nickname is already in use"),
+        }
+    }
+}
+
+impl std::error::Error for NickError {}
+
+/// Check a requested nickname against the naming rules. Does not check
+/// whether someone else already has it; that needs the client list.
+pub fn validate(nick: &str) -> Result<(), NickError> {
+    let mut chars = nick.chars();
+    let Some(first) = chars.next() else {
+        return Err(NickError::Empty);
+    };
+    if nick.chars().count() > MAX_NICK_LEN {
+        return Err(NickError::TooLong);
+    }
+    if !first.is_ascii_alphabetic() {
+        return Err(NickError::InvalidChar(first));
+    }
+    if let Some(bad) = chars.find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-')) {
+        return Err(NickError::InvalidChar(bad));
+    }
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(nick)) {
+        return Err(NickError::Reserved);
+    }
+    Ok(())
+}
+
+/// Whether two nicknames would be considered the same user.
+pub fn same(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::ClientId;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Bounded queues and back-pressure.
+//
+// Every client has an outbox: frames waiting for its worker to write them
+// to the socket. If a client stops reading (laptop lid closed, stuck
+// terminal), its socket buffer fills, the worker can't write, and the outbox
+// keeps growing with every broadcast. Unbounded, one stuck client could eat
+// all the server's memory.
+//
+// So the outbox has a capacity, and when it's full we have to pick who
+// loses:
+//
+//   DropOldest  - the slow client misses some old messages but stays
+//                 connected. Good for chatter nobody will scroll back to.
+//   Disconnect  - the slow client is told it couldn't keep up and dropped.
+//                 Nobody silently misses messages. (IRC calls this
+//                 "SendQ exceeded".)
+//
+// Either way the other clients never wait for the slow one.
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            other => Err(format!(
+                "unknown overflow policy {:?} (expected drop-oldest or disconnect)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverflowPolicy::DropOldest => write!(f, "drop-oldest"),
+            OverflowPolicy::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxConfig {
+    /// Frames a client may have waiting before the policy kicks in.
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            capacity: 1024,
+            policy: OverflowPolicy::Disconnect,
+        }
+    }
+}
+
+/// Server-wide counters, shared by every outbox.
+#[derive(Debug, Default)]
+pub struct OutboxStats {
+    /// Frames thrown away under DropOldest.
+    pub dropped: AtomicU64,
+    /// Clients disconnected under Disconnect.
+    pub slow_disconnects: AtomicU64,
+}
+
+/// What happened to a frame handed to `Outbox::push`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    /// Queued, but the oldest waiting frame was dropped to make room.
+    DroppedOldest,
+    /// Not queued: the outbox is full and the policy is Disconnect.
+    Overflowed,
+}
+
+/// A note for once the frame it rides with has been written to the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Receipt {
+    /// Tell `sender` that message `id` got there.
+    Message { id: u64, sender: ClientId },
+    /// `bytes` more of file transfer `id` got there (see transfer.rs).
+    Chunk { id: u64, bytes: usize },
+}
+
+#[derive(Debug)]
+pub struct Outbox {
+    // Each frame, and who wants to hear when it's written. A frame dropped
+    // to make room takes its receipt with it.
+    queue: Mutex<VecDeque<(Vec<u8>, Option<Receipt>)>>,
+    config: OutboxConfig,
+    stats: Arc<OutboxStats>,
+    // Frames this client lost, for the log when it leaves.
+    dropped: AtomicU64,
+}
+
+impl Outbox {
+    pub fn new(config: OutboxConfig, stats: Arc<OutboxStats>) -> Self {
+        Outbox {
+            queue: Mutex::new(VecDeque::new()),
+            config,
+            stats,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue encoded bytes, applying the overflow policy if full.
+    pub fn push(&self, bytes: Vec<u8>) -> Pushed {
+        self.push_with_receipt(bytes, None)
+    }
+
+    /// Like push(), with a receipt to hand back once the bytes are written.
+    pub fn push_with_receipt(&self, bytes: Vec<u8>, receipt: Option<Receipt>) -> Pushed {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() < self.config.capacity {
+            queue.push_back((bytes, receipt));
+            return Pushed::Queued;
+        }
+        match self.config.policy {
+            OverflowPolicy::DropOldest => {
+                queue.pop_front();
+                queue.push_back((bytes, receipt));
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                Pushed::DroppedOldest
+            }
+            OverflowPolicy::Disconnect => {
+                self.stats.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+                Pushed::Overflowed
+            }
+        }
+    }
+
+    /// Queue bytes even if the outbox is full. Only for the last words sent
+    /// to a client we're about to disconnect.
+    pub fn push_unbounded(&self, bytes: Vec<u8>) {
+        self.queue.lock().unwrap().push_back((bytes, None));
+    }
+
+    /// Take everything waiting.
+    pub fn take(&self) -> VecDeque<Vec<u8>> {
+        self.take_with_receipts().0
+    }
+
+    /// Take everything waiting, and the receipts to hand back once it has
+    /// all been written.
+    pub fn take_with_receipts(&self) -> (VecDeque<Vec<u8>>, Vec<Receipt>) {
+        let queue = std::mem::take(&mut *self.queue.lock().unwrap());
+        let mut receipts = Vec::new();
+        let frames = queue
+            .into_iter()
+            .map(|(bytes, receipt)| {
+                receipts.extend(receipt);
+                bytes
+            })
+            .collect();
+        (frames, receipts)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frames this client has lost to DropOldest.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::{Arc, Weak};
+
+use crate::hub::Hub;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Bots without sockets.
+//
+// A bot can be just another client: connect, log in, read events, send
+// lines (the client's --raw mode is there for that). But a bot that ships
+// with the server doesn't need the network at all. It can live in the same
+// process and hear about things as the Hub handles them. That's a plugin: a
+// value implementing the Plugin trait, whose callbacks the Hub calls when
+// someone logs in, says something, or leaves.
+//
+// Callbacks run on whichever thread is handling that client - usually an
+// event loop worker, in the middle of its turn. So they must be quick and
+// must never block (see event_loop.rs). Anything slow, like waiting for a
+// reminder to come due, belongs on a thread of the plugin's own. They're
+// called with no Hub locks held, so they may call straight back into the
+// Hub through the Bot they're given.
+//
+// The Bot holds the Hub weakly. The Hub owns its plugins, so a plugin that
+// kept a strong Arc<Hub> (in a reminder thread, say) would keep the whole
+// server alive in a cycle that is never freed.
+//
+// What a bot says isn't passed to the plugins, its own included, so two
+// bots can't set each other off forever.
+// ---------------------------------------------------------------------------
+
+/// In-process code that hears what happens in the chat and can answer.
+/// Register one with `ServerBuilder::plugin` (see server.rs).
+pub trait Plugin: Send + Sync {
+    /// The nickname the plugin speaks under. No client can log in with it,
+    /// and /msg to it reaches the plugin.
+    fn name(&self) -> &str;
+
+    /// `nick` logged in.
+    fn on_connect(&self, _bot: &Bot, _nick: &str) {}
+
+    /// Someone said something in a room, or sent the plugin a private
+    /// message.
+    fn on_message(&self, _bot: &Bot, _message: &Message) {}
+
+    /// `nick` left.
+    fn on_disconnect(&self, _bot: &Bot, _nick: &str) {}
+}
+
+/// A chat message, as a plugin sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The server's id for it.
+    pub id: u64,
+    /// The room it was said in, or `None` for a private message to the
+    /// plugin.
+    pub room: Option<String>,
+    pub from: String,
+    pub text: String,
+}
+
+/// A plugin's voice: speaks in the chat under the plugin's name. Cheap to
+/// clone, and fine to keep on another thread.
+#[derive(Clone)]
+pub struct Bot {
+    name: String,
+    hub: Weak<Hub>,
+}
+
+impl Bot {
+    pub(crate) fn new(name: &str, hub: Weak<Hub>) -> Self {
+        Bot {
+            name: name.to_string(),
+            hub,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Say `text` to everyone in `room`.
+    pub fn say(&self, room: &str, text: &str) -> Result<(), String> {
+        self.hub()?.bot_say(&self.name, room, text)
+    }
+
+    /// Send `nick` a private message.
+    pub fn tell(&self, nick: &str, text: &str) -> Result<(), String> {
+        self.hub()?.bot_tell(&self.name, nick, text)
+    }
+
+    /// Everyone logged in right now.
+    pub fn users(&self) -> Vec<String> {
+        self.hub().map(|hub| hub.nicks()).unwrap_or_default()
+    }
+
+    fn hub(&self) -> Result<Arc<Hub>, String> {
+        self.hub
+            .upgrade()
+            .ok_or_else(|| "the server has stopped".to_string())
+    }
+}
@@ -0,0 +1,175 @@
+use std::time::{Duration, Instant};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Token buckets.
+//
+// Picture a bucket that holds up to `burst` tokens and is topped up at
+// `rate` tokens a second. Every message a client sends costs one token; no
+// token, no message. A person typing never notices - they can even paste a
+// few lines at once, up to `burst` - but a script sending in a tight loop
+// empties the bucket and is then held to `rate` messages a second, however
+// fast it sends.
+//
+// We don't need a timer to refill the bucket. Each time a message arrives we
+// work out how long it's been since the last one and add that many tokens,
+// capped at `burst`.
+//
+// Dropping excess messages stops the flood reaching anyone else, but a
+// client that keeps hitting the limit is abusing the server, so it
+// escalates:
+//
+//   first message over the limit   -> dropped, with a warning
+//   `strikes` such bursts          -> muted for `mute`
+//   still at it after `max_mutes`  -> disconnected
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitConfig {
+    /// Messages per second a client may keep up indefinitely.
+    pub rate: f64,
+    /// Messages a client may send in one go after being quiet.
+    pub burst: u32,
+    /// Bursts over the limit before a client is muted.
+    pub strikes: u32,
+    /// How long a mute lasts.
+    pub mute: Duration,
+    /// Mutes a client may earn before it is disconnected instead.
+    pub max_mutes: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            rate: 5.0,
+            burst: 10,
+            strikes: 3,
+            mute: Duration::from_secs(30),
+            max_mutes: 2,
+        }
+    }
+}
+
+/// What to do with a message, according to the limiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit: drop it. `warn` is set for the first message of each
+    /// burst over the limit, so the client hears about it once, not once
+    /// per dropped message.
+    Drop {
+        warn: bool,
+    },
+    /// Over the limit once too often: drop it and mute the client for this
+    /// long.
+    Mute(Duration),
+    /// The client is muted: drop it quietly.
+    Muted,
+    /// Over the limit again after the last allowed mute.
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(rate: f64, burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            last: now,
+        }
+    }
+
+    /// Take a token if there is one.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// One client's bucket plus its record of abuse.
+#[derive(Debug)]
+pub struct Limiter {
+    config: RateLimitConfig,
+    bucket: TokenBucket,
+    // Currently dropping messages (so only the first gets a warning).
+    over: bool,
+    strikes: u32,
+    mutes: u32,
+    muted_until: Option<Instant>,
+    dropped: u64,
+}
+
+impl Limiter {
+    pub fn new(config: RateLimitConfig, now: Instant) -> Self {
+        Limiter {
+            config,
+            bucket: TokenBucket::new(config.rate, config.burst, now),
+            over: false,
+            strikes: 0,
+            mutes: 0,
+            muted_until: None,
+            dropped: 0,
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Judge one incoming message.
+    pub fn check(&mut self, now: Instant) -> Verdict {
+        let verdict = self.judge(now);
+        if verdict != Verdict::Allow {
+            self.dropped += 1;
+        }
+        verdict
+    }
+
+    /// Messages dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn judge(&mut self, now: Instant) -> Verdict {
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Verdict::Muted;
+            }
+            self.muted_until = None;
+        }
+        if self.bucket.try_take(now) {
+            self.over = false;
+            return Verdict::Allow;
+        }
+        if self.over {
+            return Verdict::Drop { warn: false };
+        }
+        self.over = true;
+        self.strikes += 1;
+        if self.strikes < self.config.strikes {
+            return Verdict::Drop { warn: true };
+        }
+        self.strikes = 0;
+        if self.mutes >= self.config.max_mutes {
+            return Verdict::Disconnect;
+        }
+        self.mutes += 1;
+        self.muted_until = Some(now + self.config.mute);
+        Verdict::Mute(self.config.mute)
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::ClientId;
+
+// ---------------------------------------------------------------------------
+// Rooms.
+//
+// A room is just a name and a set of member ids. There is no separate
+// "create" step: joining a room that doesn't exist creates it, and the last
+// member leaving removes it, so the map only ever holds rooms with people in
+// them.
+//
+// We store ClientIds rather than client handles so this module knows nothing
+// about sockets and can be tested on its own.
+// ---------------------------------------------------------------------------
+
+/// Everyone is put in this room after logging in, so a client that never
+/// types /join still sees the same traffic as before rooms existed.
+pub const DEFAULT_ROOM: &str = "#lobby";
+
+pub const MAX_ROOM_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomError {
+    /// Room names look like "#ops": a '#' then letters, digits, '_' or '-'.
+    InvalidName(String),
+    NotAMember(String),
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::InvalidName(name) => write!(
+                f,
+                "{:?} is not a valid room name (try #name, up to {} characters)",
+                name, MAX_ROOM_LEN
+            ),
+            RoomError::NotAMember(name) => write!(f, "you are not in {}", name),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+/// Check a room name and return its canonical (lowercase) form.
+pub fn normalize(name: &str) -> Result<String, RoomError> {
+    let invalid = || RoomError::InvalidName(name.to_string());
+    let rest = name.strip_prefix('#').ok_or_else(invalid)?;
+    if rest.is_empty() || name.len() > MAX_ROOM_LEN {
+        return Err(invalid());
+    }
+    if !rest
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(invalid());
+    }
+    Ok(name.to_ascii_lowercase())
+}
+
+#[derive(Debug, Default)]
+pub struct Rooms {
+    // BTreeMap/BTreeSet rather than HashMap so /list comes out sorted.
+    rooms: BTreeMap<String, BTreeSet<ClientId>>,
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        Rooms::default()
+    }
+
+    /// Add a client to a room. Returns true if the room was created.
+    /// `room` must already be normalized.
+    pub fn join(&mut self, room: &str, id: ClientId) -> bool {
+        let created = !self.rooms.contains_key(room);
+        self.rooms.entry(room.to_string()).or_default().insert(id);
+        created
+    }
+
+    /// Remove a client from a room. Returns true if the room is now gone.
+    pub fn part(&mut self, room: &str, id: ClientId) -> Result<bool, RoomError> {
+        let members = self
+            .rooms
+            .get_mut(room)
+            .filter(|m| m.contains(&id))
+            .ok_or_else(|| RoomError::NotAMember(room.to_string()))?;
+        members.remove(&id);
+        if members.is_empty() {
+            self.rooms.remove(room);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Take a client out of every room, e.g. when it disconnects. Returns
+    /// the rooms it was in and which of them were removed as a result.
+    pub fn remove_client(&mut self, id: ClientId) -> (Vec<String>, Vec<String>) {
+        let joined = self.rooms_of(id);
+        let mut emptied = Vec::new();
+        for room in &joined {
+            if let Ok(true) = self.part(room, id) {
+                emptied.push(room.clone());
+            }
+        }
+        (joined, emptied)
+    }
+
+    pub fn is_member(&self, room: &str, id: ClientId) -> bool {
+        self.rooms.get(room).is_some_and(|m| m.contains(&id))
+    }
+
+    pub fn members(&self, room: &str) -> BTreeSet<ClientId> {
+        self.rooms.get(room).cloned().unwrap_or_default()
+    }
+
+    /// Everyone who shares at least one room with `id` (including `id`).
+    pub fn neighbours(&self, id: ClientId) -> BTreeSet<ClientId> {
+        self.rooms
+            .values()
+            .filter(|m| m.contains(&id))
+            .flat_map(|m| m.iter().copied())
+            .collect()
+    }
+
+    pub fn rooms_of(&self, id: ClientId) -> Vec<String> {
+        self.rooms
+            .iter()
+            .filter(|(_, m)| m.contains(&id))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Every room with its member count, sorted by name.
+    pub fn list(&self) -> Vec<(String, usize)> {
+        self.rooms
+            .iter()
+            .map(|(name, m)| (name.clone(), m.len()))
+            .collect()
+    }
+}
@@ -0,0 +1,242 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::config;
+use crate::event_loop::{self, Listener};
+use crate::hub::Hub;
+use crate::plugin::Plugin;
+use crate::settings::Settings;
+use crate::shutdown::Shutdown;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: A server you can embed.
+//
+// Running a server takes a few steps in the right order: bind the
+// listeners, build the Hub, start the event loop. main.rs used to do them
+// by hand, and anything else that wanted a server - a test, a program with
+// a chat built in - had to copy it. The builder does them once:
+//
+//   let server = Server::builder()
+//       .bind(addr)
+//       .plugin(Echo)
+//       .build()?;              // binds the sockets, builds the Hub
+//   server.run()?;              // serves until shut down
+//
+// build() binds straight away, so a server asked for port 0 knows which
+// free port the OS gave it before anyone connects: local_addr() is ready
+// to hand to a client. That's what tests want, with no guessing at which
+// ports are free. spawn() serves on a thread of its own instead of
+// blocking, and hands back a handle that shuts the server down again.
+// ---------------------------------------------------------------------------
+
+/// Sets up a Server. Start with `Server::builder()`.
+#[derive(Default)]
+pub struct ServerBuilder {
+    settings: Settings,
+    chat: Vec<SocketAddr>,
+    websocket: Vec<SocketAddr>,
+    metrics: Vec<SocketAddr>,
+    workers: Option<usize>,
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl ServerBuilder {
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Listen for chat clients on `addr`; call again for more. Port 0
+    /// takes any free port. With none, the server listens on a free port
+    /// on 127.0.0.1.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.chat.push(addr);
+        self
+    }
+
+    /// Also take WebSocket clients (browsers) on `addr`.
+    pub fn websocket(mut self, addr: SocketAddr) -> Self {
+        self.websocket.push(addr);
+        self
+    }
+
+    /// Serve Prometheus metrics over HTTP on `addr` (see metrics.rs).
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.metrics.push(addr);
+        self
+    }
+
+    /// How many event loop threads to run. Defaults to one per core.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Run `plugin` in the server (see plugin.rs).
+    pub fn plugin(mut self, plugin: impl Plugin + 'static) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Bind every listener and build the Hub. Fails if an address can't be
+    /// bound, or the Hub can't open its files.
+    pub fn build(self) -> io::Result<Server> {
+        let chat = match self.chat.is_empty() {
+            true => vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+            false => self.chat,
+        };
+        let mut listeners = Vec::new();
+        let mut chat_addrs = Vec::new();
+        for addr in chat {
+            let listener = bind(addr)?;
+            chat_addrs.push(listener.local_addr()?);
+            listeners.push(Listener::Chat(listener));
+        }
+        let mut websocket_addrs = Vec::new();
+        for addr in self.websocket {
+            let listener = bind(addr)?;
+            websocket_addrs.push(listener.local_addr()?);
+            listeners.push(Listener::WebSocket(listener));
+        }
+        let mut metrics_addrs = Vec::new();
+        for addr in self.metrics {
+            let listener = bind(addr)?;
+            metrics_addrs.push(listener.local_addr()?);
+            listeners.push(Listener::Metrics(listener));
+        }
+        let workers = self.workers.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(2)
+                .min(config::MAX_WORKERS)
+        });
+        Ok(Server {
+            hub: Hub::with_plugins(self.settings, self.plugins)?,
+            listeners,
+            chat_addrs,
+            websocket_addrs,
+            metrics_addrs,
+            workers,
+            shutdown: Shutdown::new(),
+        })
+    }
+}
+
+/// A chat server, bound and ready to run.
+pub struct Server {
+    hub: Arc<Hub>,
+    listeners: Vec<Listener>,
+    chat_addrs: Vec<SocketAddr>,
+    websocket_addrs: Vec<SocketAddr>,
+    metrics_addrs: Vec<SocketAddr>,
+    workers: usize,
+    shutdown: Shutdown,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Where chat clients connect: the first address bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.chat_addrs[0]
+    }
+
+    /// Every address chat clients can connect to, with the ports the OS
+    /// picked filled in.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.chat_addrs
+    }
+
+    /// Every address taking WebSocket clients.
+    pub fn websocket_addrs(&self) -> &[SocketAddr] {
+        &self.websocket_addrs
+    }
+
+    /// Every address serving metrics.
+    pub fn metrics_addrs(&self) -> &[SocketAddr] {
+        &self.metrics_addrs
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn hub(&self) -> &Arc<Hub> {
+        &self.hub
+    }
+
+    /// The switch that stops the server, e.g. from a signal handler.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Serve until the shutdown switch is flipped (see shutdown.rs).
+    pub fn run(self) -> io::Result<()> {
+        event_loop::serve_until(self.listeners, self.hub, self.workers, self.shutdown)
+    }
+
+    /// Serve on a thread of its own.
+    pub fn spawn(self) -> io::Result<RunningServer> {
+        let addr = self.local_addr();
+        let hub = Arc::clone(&self.hub);
+        let shutdown = self.shutdown();
+        let thread = thread::Builder::new()
+            .name("server".to_string())
+            .spawn(move || self.run())?;
+        Ok(RunningServer {
+            addr,
+            hub,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A server running on its own thread. Dropping it shuts the server down
+/// and waits for it, like stop() without the result.
+pub struct RunningServer {
+    addr: SocketAddr,
+    hub: Arc<Hub>,
+    shutdown: Shutdown,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl RunningServer {
+    /// Where chat clients connect.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn hub(&self) -> &Arc<Hub> {
+        &self.hub
+    }
+
+    /// Shut the server down gracefully and wait until it has.
+    pub fn stop(mut self) -> io::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.shutdown.trigger();
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("the server thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn bind(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    event_loop::listen(addr)
+        .map_err(|e| io::Error::new(e.kind(), format!("could not listen on {}: {}", addr, e)))
+}
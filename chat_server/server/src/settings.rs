@@ -0,0 +1,35 @@
+use crate::accounts::AccountsConfig;
+use crate::federation::FederationConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::history::HistoryConfig;
+use crate::moderation::ModerationConfig;
+use crate::outbox::OutboxConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::tls::TlsSettings;
+use crate::transfer::TransferConfig;
+
+// ---------------------------------------------------------------------------
+// Server-wide knobs. The Hub owns one of these and everything else asks the
+// Hub, so there is exactly one place a setting can come from.
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub outbox: OutboxConfig,
+    pub history: HistoryConfig,
+    pub accounts: AccountsConfig,
+    pub moderation: ModerationConfig,
+    /// Ping framed clients and drop the ones that stop answering. `None`
+    /// means idle connections are never checked.
+    pub heartbeat: Option<HeartbeatConfig>,
+    /// Flood protection for incoming messages. `None` means no limit.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Serve TLS with this certificate instead of plain TCP.
+    pub tls: Option<TlsSettings>,
+    /// Shown to every client as it logs in.
+    pub motd: Option<String>,
+    /// Link up with other servers. `None` means this server stands alone.
+    pub federation: Option<FederationConfig>,
+    /// Let clients send each other files. `None` means they can't.
+    pub transfers: Option<TransferConfig>,
+}
@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mio::Waker;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Stopping on purpose.
+//
+// Killing the process works, in the sense that the kernel closes every
+// socket for us. But clients just see the connection die mid-sentence,
+// whatever was queued for them is gone, and the last few history lines may
+// still be sitting in the OS's buffers rather than on disk.
+//
+// A graceful shutdown runs in stages:
+//
+//   1. Stop accepting: close the listeners, so new clients are turned away
+//      instead of being half-served.
+//   2. Say goodbye: every client gets a Shutdown frame explaining why the
+//      connection is about to close.
+//   3. Drain: keep writing until every outbox is empty - but only for so
+//      long, because a client that stopped reading would otherwise keep us
+//      up forever.
+//   4. Clean up: join the worker threads, sync the history log, and only
+//      then return from main.
+//
+// The signal handler itself does none of that. It runs on its own thread at
+// an arbitrary moment, so all it does is flip a flag and wake the accept
+// loop, which sets the rest in motion from a known place.
+// ---------------------------------------------------------------------------
+
+/// A switch that tells a running server to stop. Clones share the switch.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    // Event loops to wake when the switch is flipped.
+    wakers: Mutex<Vec<Arc<Waker>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Ask the server to stop. Safe to call from any thread, any number of
+    /// times.
+    pub fn trigger(&self) {
+        self.inner.requested.store(true, Ordering::Release);
+        for waker in self.inner.wakers.lock().unwrap().iter() {
+            let _ = waker.wake();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::Acquire)
+    }
+
+    /// Wake `waker` when the switch is flipped - straight away if it
+    /// already has been.
+    pub(crate) fn wake_on_trigger(&self, waker: Arc<Waker>) {
+        self.inner.wakers.lock().unwrap().push(Arc::clone(&waker));
+        // Checked after registering, so a trigger() racing with us either
+        // sees our waker or is seen here.
+        if self.is_requested() {
+            let _ = waker.wake();
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: TLS on a non-blocking socket.
+//
+// TLS sits between TCP and our framing: the bytes on the wire are encrypted
+// records, and only after decrypting them do we get the frames we already
+// know how to decode. rustls is built for exactly our situation - it never
+// touches the socket itself. We hand it whatever ciphertext arrived
+// (read_tls), let it process that (process_new_packets), and then read the
+// plaintext out of it. Going the other way we give it plaintext and ask it
+// for the ciphertext to put on the wire (write_tls).
+//
+// That means the event loop keeps full control of the socket, and "the
+// socket would block" means the same thing it always did. The handshake is
+// just some extra reading and writing before the first plaintext shows up.
+//
+// The server needs a certificate and its private key, both PEM files. For
+// local development `self_signed` makes a throwaway pair; clients then have
+// to be told to trust that exact certificate (pinning), since no CA vouches
+// for it.
+// ---------------------------------------------------------------------------
+
+/// Where the server's certificate chain and private key live.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Load the certificate and key into a rustls config the workers can share.
+pub fn server_config(settings: &TlsSettings) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(&settings.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(&settings.cert, e))?;
+    if certs.is_empty() {
+        return Err(invalid(&settings.cert, "no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(&settings.key).map_err(|e| invalid(&settings.key, e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(config))
+}
+
+/// A fresh self-signed certificate for `names` (host names or IP
+/// addresses), as (certificate PEM, private key PEM).
+pub fn self_signed(names: &[&str]) -> io::Result<(String, String)> {
+    let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    Ok((certified.cert.pem(), certified.signing_key.serialize_pem()))
+}
+
+/// Write a self-signed certificate and key to the configured paths, unless
+/// both already exist. Returns true if new files were written.
+pub fn ensure_self_signed(settings: &TlsSettings, names: &[&str]) -> io::Result<bool> {
+    if settings.cert.exists() && settings.key.exists() {
+        return Ok(false);
+    }
+    let (cert, key) = self_signed(names)?;
+    for path in [&settings.cert, &settings.key] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    fs::write(&settings.cert, cert)?;
+    write_private(&settings.key, key.as_bytes())?;
+    Ok(true)
+}
+
+// Private keys shouldn't be readable by other users on the machine.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
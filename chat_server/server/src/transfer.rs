@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use crate::ClientId;
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: Passing files along without holding them.
+//
+// The server never keeps a file, not even part of one. It only keeps track
+// of each transfer - who offers what to whom, how big it says it is - and
+// hands every chunk straight on to the recipient:
+//
+//   alice: /offer bob 1234 <sha256> report.csv   -> both get a FileOffer
+//   bob:   /accept 7                              -> both get FileAccepted
+//   alice: Chunk 7 [bytes] Chunk 7 [bytes] ...    -> each one goes to bob
+//                                                 <- alice gets FileProgress
+//
+// Two things keep that honest. The size cap is checked against the offer,
+// and then against every chunk: whatever alice's client says, no more than
+// the size she offered gets through. And chunks are passed on with the same
+// outbox receipts as chat messages (see outbox.rs), so alice hears how much
+// has actually been written to bob's socket and can wait for him to catch
+// up, instead of filling his outbox with a file he can't read fast enough.
+//
+// Whether the bytes are the right ones is for bob's client to check, with
+// the SHA-256 hash from the offer.
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferConfig {
+    /// The largest file anyone may offer, in bytes.
+    pub max_size: u64,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig {
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// One file on its way from one client to another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub id: u64,
+    pub from: ClientId,
+    pub to: ClientId,
+    pub name: String,
+    pub size: u64,
+    pub accepted: bool,
+    /// Bytes passed on to the recipient's outbox.
+    pub forwarded: u64,
+    /// Bytes written to the recipient's socket.
+    pub delivered: u64,
+}
+
+/// Every transfer under way.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    last_id: u64,
+    transfers: HashMap<u64, Transfer>,
+}
+
+impl Transfers {
+    /// Note an offer of `name` from one client to another. Returns its id.
+    pub fn offer(&mut self, from: ClientId, to: ClientId, name: &str, size: u64) -> u64 {
+        self.last_id += 1;
+        let id = self.last_id;
+        self.transfers.insert(
+            id,
+            Transfer {
+                id,
+                from,
+                to,
+                name: name.to_string(),
+                size,
+                accepted: false,
+                forwarded: 0,
+                delivered: 0,
+            },
+        );
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Transfer> {
+        self.transfers.get(&id)
+    }
+
+    /// The recipient `by` takes transfer `id`.
+    pub fn accept(&mut self, id: u64, by: ClientId) -> Result<Transfer, String> {
+        match self.transfers.get_mut(&id) {
+            Some(transfer) if transfer.to == by && !transfer.accepted => {
+                transfer.accepted = true;
+                Ok(transfer.clone())
+            }
+            Some(transfer) if transfer.to == by => Err(format!("transfer {} is under way", id)),
+            _ => Err(no_such_transfer(id)),
+        }
+    }
+
+    /// Call off transfer `id`. Either side may.
+    pub fn cancel(&mut self, id: u64, by: ClientId) -> Result<Transfer, String> {
+        match self.transfers.get(&id) {
+            Some(transfer) if transfer.from == by || transfer.to == by => {
+                Ok(self.transfers.remove(&id).unwrap())
+            }
+            _ => Err(no_such_transfer(id)),
+        }
+    }
+
+    /// `by` sent `len` bytes of transfer `id`. Returns the recipient if they
+    /// should be passed on.
+    pub fn chunk(&mut self, id: u64, by: ClientId, len: usize) -> Result<ClientId, String> {
+        let transfer = match self.transfers.get_mut(&id) {
+            Some(transfer) if transfer.from == by => transfer,
+            _ => return Err(no_such_transfer(id)),
+        };
+        if !transfer.accepted {
+            return Err(format!("transfer {} hasn't been accepted", id));
+        }
+        if transfer.forwarded + len as u64 > transfer.size {
+            return Err(format!(
+                "transfer {} is bigger than the {} bytes offered",
+                id, transfer.size
+            ));
+        }
+        transfer.forwarded += len as u64;
+        Ok(transfer.to)
+    }
+
+    /// `bytes` more of transfer `id` reached the recipient. Returns the
+    /// transfer as it now stands; once it's all there, it's over and
+    /// forgotten.
+    pub fn delivered(&mut self, id: u64, bytes: usize) -> Option<Transfer> {
+        let transfer = self.transfers.get_mut(&id)?;
+        transfer.delivered += bytes as u64;
+        if transfer.delivered >= transfer.size {
+            return self.transfers.remove(&id);
+        }
+        Some(transfer.clone())
+    }
+
+    /// Drop every transfer `client` is part of, e.g. because it left.
+    pub fn remove_client(&mut self, client: ClientId) -> Vec<Transfer> {
+        let ids: Vec<u64> = self
+            .transfers
+            .values()
+            .filter(|t| t.from == client || t.to == client)
+            .map(|t| t.id)
+            .collect();
+        ids.iter()
+            .filter_map(|id| self.transfers.remove(id))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+}
+
+fn no_such_transfer(id: u64) -> String {
+    format!("no transfer {} of yours", id)
+}
+
+/// Whether `hash` looks like a SHA-256 hash in hex.
+pub fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
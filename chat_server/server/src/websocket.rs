@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+// ---------------------------------------------------------------------------
+// LEARNING NOTE: WebSockets, from the bottom up.
+//
+// Browsers can't open a raw TCP socket, but they can open a WebSocket: a
+// TCP connection that starts life as an HTTP request and then "upgrades".
+//
+//   GET /chat HTTP/1.1                  HTTP/1.1 101 Switching Protocols
+//   Upgrade: websocket          ->      Upgrade: websocket
+//   Connection: Upgrade                 Connection: Upgrade
+//   Sec-WebSocket-Key: <random>         Sec-WebSocket-Accept: <proof>
+//   Sec-WebSocket-Version: 13
+//
+// The proof is base64(SHA-1(key + a fixed GUID)). It isn't security - it
+// only shows the browser it reached a server that actually speaks
+// WebSocket, not some HTTP server that happened to answer.
+//
+// After that, both sides exchange frames, which look a lot like our own:
+//
+//   +-----+------+--------+------------+--------------+---------+
+//   | FIN | RSV  | opcode | MASK | len | ext. length  | mask key | payload
+//   | 1b  | 3b   | 4b     | 1b   | 7b  | 0, 2 or 8 B  | 0 or 4 B |
+//   +-----+------+--------+------+-----+--------------+----------+
+//
+// Three twists compared to our framing:
+//
+//   - Messages may be split across frames (FIN marks the last one).
+//   - Control frames (ping, pong, close) may arrive in the middle of a
+//     split message, and must be answered.
+//   - Everything a client sends is XOR-masked with a random key. That stops
+//     a malicious page from making the browser send bytes that a confused
+//     proxy in between would mistake for HTTP. Servers don't mask.
+//
+// This decoder works like the one in the protocol crate: feed it whatever
+// arrived, take out whole messages, and it never buffers more than one
+// message's worth.
+// ---------------------------------------------------------------------------
+
+/// Largest message we accept, the same limit as for our own frames.
+pub const MAX_MESSAGE: usize = protocol::MAX_FRAME_SIZE;
+
+// Largest HTTP upgrade request we'll wait for.
+const MAX_REQUEST: usize = 8 * 1024;
+
+// Fixed by RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Close codes we send.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+
+/// How far along an upgrade request is.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Handshake {
+    /// The request hasn't fully arrived yet.
+    Incomplete,
+    /// Send `response`; the connection is a WebSocket from here on. The
+    /// first `consumed` bytes were the request; anything after them is
+    /// already frames.
+    Accepted { response: Vec<u8>, consumed: usize },
+    /// Send `response` and hang up.
+    Rejected(Vec<u8>),
+}
+
+/// The Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key.
+pub fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(GUID.as_bytes());
+    STANDARD.encode(sha.finalize())
+}
+
+/// Look at what a new WebSocket connection has sent so far.
+pub fn parse_handshake(seen: &[u8]) -> Handshake {
+    let Some(end) = seen.windows(4).position(|w| w == b"\r\n\r\n") else {
+        if seen.len() > MAX_REQUEST {
+            return reject(
+                "431 Request Header Fields Too Large",
+                "",
+                "request too large",
+            );
+        }
+        return Handshake::Incomplete;
+    };
+    let consumed = end + 4;
+    let Ok(request) = std::str::from_utf8(&seen[..end]) else {
+        return reject("400 Bad Request", "", "not an HTTP request");
+    };
+    let mut lines = request.split("\r\n");
+    let mut start = lines.next().unwrap_or_default().split_whitespace();
+    match (start.next(), start.next(), start.next()) {
+        (Some("GET"), Some(_), Some("HTTP/1.1")) => {}
+        (Some(_), Some(_), Some(_)) => {
+            return reject("405 Method Not Allowed", "Allow: GET\r\n", "use GET");
+        }
+        _ => return reject("400 Bad Request", "", "not an HTTP request"),
+    }
+
+    // Header names are case-insensitive.
+    let headers: HashMap<String, &str> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect();
+    let has_token = |name: &str, token: &str| {
+        headers.get(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return reject(
+            "426 Upgrade Required",
+            "Upgrade: websocket\r\n",
+            "this address only speaks WebSocket",
+        );
+    }
+    if headers.get("sec-websocket-version") != Some(&"13") {
+        return reject(
+            "426 Upgrade Required",
+            "Sec-WebSocket-Version: 13\r\n",
+            "unsupported WebSocket version",
+        );
+    }
+    let key = match headers.get("sec-websocket-key") {
+        Some(key) if STANDARD.decode(key).is_ok_and(|k| k.len() == 16) => key,
+        _ => return reject("400 Bad Request", "", "missing or bad Sec-WebSocket-Key"),
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    Handshake::Accepted {
+        response: response.into_bytes(),
+        consumed,
+    }
+}
+
+fn reject(status: &str, headers: &str, body: &str) -> Handshake {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Connection: close\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}\n",
+        status,
+        headers,
+        body.len() + 1,
+        body
+    );
+    Handshake::Rejected(response.into_bytes())
+}
+
+/// A complete message from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The client is closing, with its close code if it gave one.
+    Close(Option<u16>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsError {
+    /// The client broke the protocol; the reason is for the close frame.
+    Protocol(&'static str),
+    TooBig,
+    BadUtf8,
+}
+
+impl WsError {
+    /// The close code that goes with this error.
+    pub fn close_code(&self) -> u16 {
+        match self {
+            WsError::Protocol(_) => 1002,
+            WsError::BadUtf8 => 1007,
+            WsError::TooBig => 1009,
+        }
+    }
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WsError::Protocol(why) => write!(f, "{}", why),
+            WsError::TooBig => write!(f, "message larger than {} bytes", MAX_MESSAGE),
+            WsError::BadUtf8 => write!(f, "text message is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for WsError {}
+
+/// Turns bytes from a client into messages.
+#[derive(Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    // The opcode and payload so far of a message split across frames.
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The next complete message, or `None` until more bytes arrive. Any
+    /// error means the connection should be closed.
+    pub fn next_message(&mut self) -> Result<Option<Message>, WsError> {
+        loop {
+            let Some((fin, opcode, payload)) = self.next_frame()? else {
+                return Ok(None);
+            };
+            match opcode {
+                PING => return Ok(Some(Message::Ping(payload))),
+                PONG => return Ok(Some(Message::Pong(payload))),
+                CLOSE => {
+                    let code =
+                        (payload.len() >= 2).then(|| u16::from_be_bytes([payload[0], payload[1]]));
+                    return Ok(Some(Message::Close(code)));
+                }
+                TEXT | BINARY => {
+                    if self.partial.is_some() {
+                        return Err(WsError::Protocol("new message before the last one ended"));
+                    }
+                    if fin {
+                        return finish(opcode, payload).map(Some);
+                    }
+                    self.partial = Some((opcode, payload));
+                }
+                CONTINUATION => {
+                    let Some((first, mut so_far)) = self.partial.take() else {
+                        return Err(WsError::Protocol("continuation without a message"));
+                    };
+                    if so_far.len() + payload.len() > MAX_MESSAGE {
+                        return Err(WsError::TooBig);
+                    }
+                    so_far.extend_from_slice(&payload);
+                    if fin {
+                        return finish(first, so_far).map(Some);
+                    }
+                    self.partial = Some((first, so_far));
+                }
+                _ => return Err(WsError::Protocol("unknown opcode")),
+            }
+        }
+    }
+
+    // One frame off the front of the buffer: (FIN, opcode, unmasked
+    // payload).
+    fn next_frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, WsError> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let (b0, b1) = (self.buf[0], self.buf[1]);
+        let fin = b0 & 0x80 != 0;
+        let opcode = b0 & 0x0F;
+        if b0 & 0x70 != 0 {
+            return Err(WsError::Protocol("reserved bits set"));
+        }
+        if b1 & 0x80 == 0 {
+            return Err(WsError::Protocol("client frames must be masked"));
+        }
+        let (len, mut at) = match b1 & 0x7F {
+            126 => {
+                let Some(bytes) = self.buf.get(2..4) else {
+                    return Ok(None);
+                };
+                (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4)
+            }
+            127 => {
+                let Some(bytes) = self.buf.get(2..10) else {
+                    return Ok(None);
+                };
+                (u64::from_be_bytes(bytes.try_into().unwrap()), 10)
+            }
+            n => (n as u64, 2),
+        };
+        if opcode >= CLOSE && (!fin || len > 125) {
+            return Err(WsError::Protocol("bad control frame"));
+        }
+        // Refuse before buffering, like the protocol crate's decoder.
+        if len > MAX_MESSAGE as u64 {
+            return Err(WsError::TooBig);
+        }
+        let len = len as usize;
+        if self.buf.len() < at + 4 + len {
+            return Ok(None);
+        }
+        let mask = [
+            self.buf[at],
+            self.buf[at + 1],
+            self.buf[at + 2],
+            self.buf[at + 3],
+        ];
+        at += 4;
+        let payload: Vec<u8> = self.buf[at..at + len]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        self.buf.drain(..at + len);
+        Ok(Some((fin, opcode, payload)))
+    }
+}
+
+fn finish(opcode: u8, payload: Vec<u8>) -> Result<Message, WsError> {
+    if opcode == BINARY {
+        return Ok(Message::Binary(payload));
+    }
+    String::from_utf8(payload)
+        .map(Message::Text)
+        .map_err(|_| WsError::BadUtf8)
+}
+
+// A single unmasked frame, as servers send them.
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    out.push(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => out.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            out.push(126);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            out.push(127);
+            out.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn text(msg: &str) -> Vec<u8> {
+    frame(TEXT, msg.as_bytes())
+}
+
+pub fn ping(payload: &[u8]) -> Vec<u8> {
+    frame(PING, payload)
+}
+
+pub fn pong(payload: &[u8]) -> Vec<u8> {
+    frame(PONG, payload)
+}
+
+pub fn close(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    // Control frames carry at most 125 bytes.
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    frame(CLOSE, &payload)
+}
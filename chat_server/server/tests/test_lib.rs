@@ -1 +1,1908 @@
+use protocol::{Decoder, Event, Frame, FrameReader, Framing, MessageType, Status};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use server::accounts::{AccountError, Accounts, AccountsConfig};
+use server::command::{self, Command};
+use server::config::Options;
+use server::event_loop::{self, Listener};
+use server::federation::{self, FederationConfig, Seen};
+use server::heartbeat::{Beat, Heartbeat, HeartbeatConfig};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{env, fs, process};
 
+use server::history::{History, HistoryConfig, Record};
+use server::hub::Hub;
+use server::moderation::{Ban, Moderation, ModerationConfig};
+use server::nick::{self, NickError};
+use server::outbox::{Outbox, OutboxConfig, OutboxStats, OverflowPolicy, Pushed};
+use server::plugin::{Bot, Message as PluginMessage, Plugin};
+use server::ratelimit::{Limiter, RateLimitConfig, Verdict};
+use server::room::{self, RoomError, Rooms};
+use server::server::{RunningServer, Server};
+use server::settings::Settings;
+use server::shutdown::Shutdown;
+use server::tls::{self, TlsSettings};
+use server::transfer::TransferConfig;
+use server::websocket;
+
+#[test]
+pub fn test_nick_validation() {
+    assert_eq!(nick::validate("alice_01"), Ok(()));
+    assert_eq!(nick::validate(""), Err(NickError::Empty));
+    assert_eq!(nick::validate("9lives"), Err(NickError::InvalidChar('9')));
+    assert_eq!(
+        nick::validate("bob smith"),
+        Err(NickError::InvalidChar(' '))
+    );
+    assert_eq!(
+        nick::validate("a".repeat(17).as_str()),
+        Err(NickError::TooLong)
+    );
+    assert_eq!(nick::validate("Server"), Err(NickError::Reserved));
+}
+
+#[test]
+pub fn test_nick_comparison_ignores_case() {
+    assert!(nick::same("Alice", "alice"));
+    assert!(!nick::same("alice", "alicia"));
+}
+
+#[test]
+pub fn test_parse_nick_command() {
+    assert_eq!(command::parse("hello"), None);
+    assert_eq!(
+        command::parse("/nick  carol "),
+        Some(Ok(Command::Nick("carol".to_string())))
+    );
+    assert!(matches!(command::parse("/nick"), Some(Err(_))));
+    assert!(matches!(command::parse("/dance"), Some(Err(_))));
+}
+
+#[test]
+pub fn test_parse_room_commands() {
+    assert_eq!(
+        command::parse("/join #ops"),
+        Some(Ok(Command::Join("#ops".to_string())))
+    );
+    assert_eq!(command::parse("/part"), Some(Ok(Command::Part(None))));
+    assert_eq!(command::parse("/list"), Some(Ok(Command::List)));
+}
+
+#[test]
+pub fn test_parse_msg_command() {
+    assert_eq!(
+        command::parse("/msg bob  see you at 5 "),
+        Some(Ok(Command::Msg {
+            to: "bob".to_string(),
+            text: "see you at 5".to_string()
+        }))
+    );
+    assert!(matches!(command::parse("/msg bob"), Some(Err(_))));
+    assert!(matches!(command::parse("/msg"), Some(Err(_))));
+}
+
+#[test]
+pub fn test_parse_account_commands() {
+    assert_eq!(
+        command::parse("/login alice correct horse"),
+        Some(Ok(Command::Login {
+            name: "alice".to_string(),
+            password: "correct horse".to_string()
+        }))
+    );
+    assert!(matches!(command::parse("/register alice"), Some(Err(_))));
+}
+
+#[test]
+pub fn test_parse_history_command() {
+    assert_eq!(
+        command::parse("/history 50"),
+        Some(Ok(Command::History(50)))
+    );
+    assert!(matches!(command::parse("/history"), Some(Err(_))));
+    assert!(matches!(command::parse("/history 0"), Some(Err(_))));
+    assert!(matches!(command::parse("/history lots"), Some(Err(_))));
+}
+
+#[test]
+pub fn test_parse_moderation_commands() {
+    assert_eq!(
+        command::parse("/kick troll spamming the lobby"),
+        Some(Ok(Command::Kick {
+            nick: "troll".to_string(),
+            reason: "spamming the lobby".to_string()
+        }))
+    );
+    assert_eq!(
+        command::parse("/ban 10.0.0.7"),
+        Some(Ok(Command::Ban {
+            target: "10.0.0.7".to_string(),
+            reason: String::new()
+        }))
+    );
+    assert_eq!(command::parse("/ban"), Some(Ok(Command::Bans)));
+    assert_eq!(
+        command::parse("/mute troll 10m"),
+        Some(Ok(Command::Mute {
+            nick: "troll".to_string(),
+            time: Duration::from_secs(600)
+        }))
+    );
+    assert!(matches!(command::parse("/mute troll"), Some(Err(_))));
+    assert!(matches!(
+        command::parse("/mute troll forever"),
+        Some(Err(_))
+    ));
+    assert!(matches!(command::parse("/op"), Some(Err(_))));
+
+    assert_eq!(command::parse_duration("90"), Some(Duration::from_secs(90)));
+    assert_eq!(
+        command::parse_duration("2h"),
+        Some(Duration::from_secs(7200))
+    );
+    assert_eq!(command::parse_duration("0s"), None);
+    assert_eq!(command::parse_duration("5w"), None);
+}
+
+#[test]
+pub fn test_room_names() {
+    assert_eq!(room::normalize("#Ops"), Ok("#ops".to_string()));
+    assert!(room::normalize("ops").is_err());
+    assert!(room::normalize("#").is_err());
+    assert!(room::normalize("#no spaces").is_err());
+}
+
+#[test]
+pub fn test_room_lifecycle() {
+    let mut rooms = Rooms::new();
+    assert!(rooms.join("#ops", 1));
+    assert!(!rooms.join("#ops", 2));
+    assert_eq!(rooms.list(), vec![("#ops".to_string(), 2)]);
+
+    assert_eq!(rooms.part("#ops", 1), Ok(false));
+    assert_eq!(
+        rooms.part("#ops", 1),
+        Err(RoomError::NotAMember("#ops".to_string()))
+    );
+
+    // The last member leaving removes the room.
+    assert_eq!(rooms.part("#ops", 2), Ok(true));
+    assert!(rooms.list().is_empty());
+}
+
+#[test]
+pub fn test_remove_client_leaves_every_room() {
+    let mut rooms = Rooms::new();
+    rooms.join("#a", 1);
+    rooms.join("#b", 1);
+    rooms.join("#b", 2);
+
+    assert_eq!(
+        rooms.neighbours(1).into_iter().collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    let (left, emptied) = rooms.remove_client(1);
+    assert_eq!(left, vec!["#a".to_string(), "#b".to_string()]);
+    assert_eq!(emptied, vec!["#a".to_string()]);
+    assert_eq!(rooms.members("#b").into_iter().collect::<Vec<_>>(), vec![2]);
+}
+
+#[test]
+pub fn test_outbox_drop_oldest() {
+    let stats = Arc::new(OutboxStats::default());
+    let config = OutboxConfig {
+        capacity: 2,
+        policy: OverflowPolicy::DropOldest,
+    };
+    let outbox = Outbox::new(config, Arc::clone(&stats));
+
+    assert_eq!(outbox.push(b"1".to_vec()), Pushed::Queued);
+    assert_eq!(outbox.push(b"2".to_vec()), Pushed::Queued);
+    assert_eq!(outbox.push(b"3".to_vec()), Pushed::DroppedOldest);
+
+    assert_eq!(outbox.take(), vec![b"2".to_vec(), b"3".to_vec()]);
+    assert_eq!(outbox.dropped(), 1);
+    assert_eq!(stats.dropped.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+pub fn test_outbox_disconnect_policy() {
+    let stats = Arc::new(OutboxStats::default());
+    let config = OutboxConfig {
+        capacity: 1,
+        policy: OverflowPolicy::Disconnect,
+    };
+    let outbox = Outbox::new(config, Arc::clone(&stats));
+
+    assert_eq!(outbox.push(b"1".to_vec()), Pushed::Queued);
+    assert_eq!(outbox.push(b"2".to_vec()), Pushed::Overflowed);
+    assert_eq!(outbox.len(), 1);
+    assert_eq!(stats.slow_disconnects.load(Ordering::Relaxed), 1);
+    assert_eq!("drop-oldest".parse(), Ok(OverflowPolicy::DropOldest));
+}
+
+#[test]
+pub fn test_history_record_escaping() {
+    let record = Record {
+        time: 1_700_000_000,
+        room: "#lobby".to_string(),
+        sender: "alice".to_string(),
+        text: "tab\there\nnew line \\o/".to_string(),
+    };
+    let line = record.encode();
+    assert_eq!(line.matches('\n').count(), 1);
+    assert_eq!(
+        Record::decode(line.trim_end_matches('\n')),
+        Some(record.clone())
+    );
+    assert_eq!(Record::decode("not a record"), None);
+    assert!(record
+        .render()
+        .starts_with("[2023-11-14 22:13] #lobby [alice]:"));
+}
+
+#[test]
+pub fn test_history_rotation_and_replay() {
+    let dir = env::temp_dir().join(format!("chat-history-test-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let config = HistoryConfig {
+        dir: Some(dir.clone()),
+        segment_bytes: 64,
+        max_segments: 3,
+        replay: 5,
+    };
+
+    let mut history = History::open(&dir, config.clone()).unwrap();
+    for n in 0..20 {
+        let room = if n % 2 == 0 { "#even" } else { "#odd" };
+        history
+            .append(&Record::now(room, "bob", &format!("message {}", n)))
+            .unwrap();
+    }
+    // Old segments were deleted as new ones were started.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+    // Reopening carries on from the same log.
+    drop(history);
+    let history = History::open(&dir, config).unwrap();
+    let texts: Vec<String> = history
+        .recent("#even", 2)
+        .unwrap()
+        .into_iter()
+        .map(|r| r.text)
+        .collect();
+    assert_eq!(texts, ["message 16", "message 18"]);
+    assert!(history.recent("#nowhere", 5).unwrap().is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+pub fn test_tls_login_and_chat() {
+    let dir = env::temp_dir().join(format!("chat-tls-test-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let tls_settings = TlsSettings {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+    };
+    assert!(tls::ensure_self_signed(&tls_settings, &["localhost"]).unwrap());
+    // A second call leaves the existing pair alone.
+    assert!(!tls::ensure_self_signed(&tls_settings, &["localhost"]).unwrap());
+
+    let settings = Settings {
+        tls: Some(tls_settings.clone()),
+        ..Settings::default()
+    };
+    let hub = Arc::new(Hub::with_settings(settings).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || event_loop::serve(vec![listener], hub, 1));
+
+    // Trust the self-signed certificate as if it were a CA.
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(&tls_settings.cert).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    );
+    let connect = |nick: &str| {
+        let session =
+            rustls::ClientConnection::new(Arc::clone(&config), "localhost".try_into().unwrap())
+                .unwrap();
+        let mut stream = rustls::StreamOwned::new(session, TcpStream::connect(addr).unwrap());
+        protocol::client_handshake(&mut stream).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        let reply = reader.read_frame().unwrap().unwrap();
+        assert_eq!(reply.kind, MessageType::Nick);
+        reader
+    };
+
+    let mut alice = connect("alice");
+    let mut bob = connect("bob");
+    protocol::write_frame(alice.get_mut(), Framing::Framed, &Frame::text("secret")).unwrap();
+    let heard = loop {
+        let frame = bob.read_frame().unwrap().unwrap();
+        if frame.kind == MessageType::Event {
+            break frame.to_event().unwrap();
+        }
+    };
+    assert_eq!(
+        heard,
+        Event::Chat {
+            id: Some(1),
+            room: "#lobby".to_string(),
+            from: "alice".to_string(),
+            text: "secret".to_string(),
+            time: None,
+        }
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+pub fn test_accounts_login_and_lockout() {
+    let file = env::temp_dir().join(format!("chat-accounts-test-{}.txt", process::id()));
+    let _ = fs::remove_file(&file);
+    let config = AccountsConfig {
+        file: Some(file.clone()),
+        registration: false,
+        max_failures: 2,
+        lockout: Duration::from_secs(60),
+        ..AccountsConfig::default()
+    };
+    let accounts = Accounts::open(&file, config.clone()).unwrap();
+
+    assert!(matches!(
+        accounts.add("alice", "short"),
+        Err(AccountError::WeakPassword)
+    ));
+    accounts.add("Alice", "correct horse").unwrap();
+    assert!(matches!(
+        accounts.add("alice", "another one"),
+        Err(AccountError::Exists)
+    ));
+    assert!(matches!(
+        accounts.register("bob", "battery staple"),
+        Err(AccountError::RegistrationClosed)
+    ));
+
+    // Names are case-insensitive and come back as registered.
+    assert_eq!(accounts.verify("alice", "correct horse").unwrap(), "Alice");
+    // The file holds a salted hash, never the password.
+    let stored = fs::read_to_string(&file).unwrap();
+    assert!(stored.starts_with("Alice:$argon2id$"));
+    assert!(!stored.contains("correct horse"));
+
+    // A second store on the same file (the admin CLI) sees the account.
+    assert!(Accounts::open(&file, config)
+        .unwrap()
+        .exists("ALICE")
+        .unwrap());
+
+    assert!(matches!(
+        accounts.verify("alice", "wrong"),
+        Err(AccountError::BadCredentials)
+    ));
+    assert!(matches!(
+        accounts.verify("alice", "wrong"),
+        Err(AccountError::BadCredentials)
+    ));
+    // Locked now, even with the right password.
+    assert!(matches!(
+        accounts.verify("alice", "correct horse"),
+        Err(AccountError::Locked(_))
+    ));
+
+    accounts.remove("alice").unwrap();
+    assert!(matches!(
+        accounts.remove("alice"),
+        Err(AccountError::NotFound)
+    ));
+    fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn test_rate_limit_escalates() {
+    let config = RateLimitConfig {
+        rate: 1.0,
+        burst: 3,
+        strikes: 2,
+        mute: Duration::from_secs(10),
+        max_mutes: 1,
+    };
+    let start = Instant::now();
+    let mut limiter = Limiter::new(config, start);
+
+    // A full bucket lets a burst through, then the flood is dropped with
+    // one warning.
+    for _ in 0..3 {
+        assert_eq!(limiter.check(start), Verdict::Allow);
+    }
+    assert_eq!(limiter.check(start), Verdict::Drop { warn: true });
+    assert_eq!(limiter.check(start), Verdict::Drop { warn: false });
+
+    // A second later there's a token again.
+    let later = start + Duration::from_secs(1);
+    assert_eq!(limiter.check(later), Verdict::Allow);
+
+    // The second burst over the limit earns a mute.
+    assert_eq!(limiter.check(later), Verdict::Mute(config.mute));
+    assert_eq!(limiter.check(later), Verdict::Muted);
+
+    // After the mute, the bucket has refilled; keep flooding and it's out.
+    let unmuted = later + config.mute;
+    for _ in 0..3 {
+        assert_eq!(limiter.check(unmuted), Verdict::Allow);
+    }
+    assert_eq!(limiter.check(unmuted), Verdict::Drop { warn: true });
+    assert_eq!(
+        limiter.check(unmuted + Duration::from_secs(1)),
+        Verdict::Allow
+    );
+    assert_eq!(
+        limiter.check(unmuted + Duration::from_secs(1)),
+        Verdict::Disconnect
+    );
+    assert_eq!(limiter.dropped(), 6);
+}
+
+#[test]
+fn test_heartbeat_counts_missed_pings() {
+    let mut heartbeat = Heartbeat::new(&HeartbeatConfig {
+        interval: Duration::from_secs(15),
+        missed: 2,
+    });
+    assert_eq!(heartbeat.beat(), Beat::Ping(1));
+    heartbeat.heard();
+    assert_eq!(heartbeat.beat(), Beat::Ping(2));
+    assert_eq!(heartbeat.beat(), Beat::Ping(3));
+    assert_eq!(heartbeat.beat(), Beat::Dead);
+}
+
+#[test]
+pub fn test_silent_client_is_evicted() {
+    let settings = Settings {
+        heartbeat: Some(HeartbeatConfig {
+            interval: Duration::from_millis(100),
+            missed: 1,
+        }),
+        ..Settings::default()
+    };
+    let hub = Arc::new(Hub::with_settings(settings).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn({
+        let hub = Arc::clone(&hub);
+        move || event_loop::serve(vec![listener], hub, 1)
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    protocol::client_handshake(&mut stream).unwrap();
+    protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick("sleepy")).unwrap();
+    let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+    let next_ping = |reader: &mut FrameReader<TcpStream>| loop {
+        let frame = reader.read_frame().unwrap().expect("still connected");
+        if frame.kind == MessageType::Ping {
+            break frame;
+        }
+    };
+
+    // Answering keeps the connection alive past the first deadline...
+    for _ in 0..3 {
+        let ping = next_ping(&mut reader);
+        protocol::write_frame(reader.get_mut(), Framing::Framed, &Frame::pong(&ping)).unwrap();
+    }
+    assert_eq!(hub.active_connections(), 1);
+
+    // ...and going quiet gets us dropped, and cleaned up.
+    next_ping(&mut reader);
+    loop {
+        match reader.read_frame() {
+            Ok(Some(frame)) => assert_eq!(frame.kind, MessageType::Ping),
+            Ok(None) => break,
+            Err(e) => panic!("expected the server to hang up, got {}", e),
+        }
+    }
+    assert_eq!(hub.active_connections(), 0);
+}
+
+#[test]
+pub fn test_config_layering() {
+    let file = Options::from_toml(
+        r#"
+        bind = ["::1", "127.0.0.1:9000"]
+        port = 7000
+        motd = "from the file"
+        history_dir = "off"
+        overflow = "disconnect"
+        rate = 0
+        "#,
+    )
+    .unwrap();
+    let flags = Options {
+        motd: Some("from a flag".to_string()),
+        ..Options::default()
+    };
+    let options = flags.or(file);
+
+    assert_eq!(
+        options.listen_addrs().unwrap(),
+        [
+            "[::1]:7000".parse::<SocketAddr>().unwrap(),
+            "127.0.0.1:9000".parse().unwrap()
+        ]
+    );
+    let settings = options.settings().unwrap();
+    assert_eq!(settings.motd.as_deref(), Some("from a flag"));
+    assert_eq!(settings.history.dir, None);
+    assert_eq!(settings.outbox.policy, OverflowPolicy::Disconnect);
+    assert!(settings.rate_limit.is_none());
+    assert!(settings.heartbeat.is_some());
+
+    // Only one half of a certificate is a mistake, not "TLS off".
+    let half = Options {
+        tls_cert: Some("cert.pem".into()),
+        ..Options::default()
+    };
+    assert!(half.settings().is_err());
+    assert!(Options::from_toml("prot = 80").is_err());
+}
+
+#[test]
+pub fn test_motd_greets_new_logins() {
+    let settings = Settings {
+        motd: Some("Welcome!\nBe nice.".to_string()),
+        ..Settings::default()
+    };
+    let hub = Arc::new(Hub::with_settings(settings).unwrap());
+    let listener = event_loop::listen("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || event_loop::serve(vec![listener], hub, 1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    // An older client gets the message of the day as plain notices.
+    protocol::client_handshake_with_version(&mut stream, 1).unwrap();
+    protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick("alice")).unwrap();
+    let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+    assert_eq!(
+        reader.read_frame().unwrap().unwrap().kind,
+        MessageType::Nick
+    );
+    let notices: Vec<String> = (0..2)
+        .map(|_| {
+            let frame = reader.read_frame().unwrap().unwrap();
+            assert_eq!(frame.kind, MessageType::Notice);
+            frame.as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(notices, ["Welcome!", "Be nice."]);
+}
+
+#[test]
+pub fn test_graceful_shutdown() {
+    let hub = Arc::new(Hub::new());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shutdown = Shutdown::new();
+    let server = thread::spawn({
+        let hub = Arc::clone(&hub);
+        let shutdown = shutdown.clone();
+        move || event_loop::serve_until(vec![Listener::Chat(listener)], hub, 2, shutdown)
+    });
+
+    let login = |nick: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        protocol::client_handshake(&mut stream).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        assert_eq!(
+            reader.read_frame().unwrap().unwrap().kind,
+            MessageType::Nick
+        );
+        reader
+    };
+    let mut readers = [login("alice"), login("bob")];
+    while hub.active_connections() < 2 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    shutdown.trigger();
+    // Everyone hears why, and then the server hangs up.
+    for reader in &mut readers {
+        let goodbye = loop {
+            let frame = reader.read_frame().unwrap().expect("a goodbye first");
+            if frame.kind == MessageType::Shutdown {
+                break frame;
+            }
+        };
+        assert_eq!(goodbye.as_str(), Ok("server shutting down"));
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+    server.join().unwrap().unwrap();
+    assert_eq!(hub.active_connections(), 0);
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+pub fn test_ban_list_persists_and_is_audited() {
+    let dir = env::temp_dir().join(format!("chat-moderation-test-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let config = ModerationConfig {
+        bans: Some(dir.join("bans.txt")),
+        log: Some(dir.join("moderation.log")),
+        operators: vec!["Boss".to_string()],
+    };
+    let moderation = Moderation::open(config.clone()).unwrap();
+    assert!(moderation.is_operator_account("boss"));
+    assert!(moderation.ban(Ban::parse("Troll"), "spam").unwrap());
+    assert!(!moderation.ban(Ban::parse("troll"), "again").unwrap());
+    assert!(moderation.ban(Ban::parse("10.0.0.7"), "").unwrap());
+    moderation.audit("boss", "banned nick troll (spam)");
+
+    // A restart keeps the bans.
+    let reopened = Moderation::open(config).unwrap();
+    assert_eq!(reopened.nick_ban("TROLL").as_deref(), Some("spam"));
+    assert_eq!(
+        reopened.ip_ban("10.0.0.7".parse().unwrap()).as_deref(),
+        Some("")
+    );
+    assert!(reopened.unban(&Ban::parse("10.0.0.7")).unwrap());
+    assert!(reopened.ip_ban("10.0.0.7".parse().unwrap()).is_none());
+
+    let log = fs::read_to_string(dir.join("moderation.log")).unwrap();
+    assert!(log.trim_end().ends_with("boss banned nick troll (spam)"));
+
+    moderation.mute("troll", Duration::from_secs(60));
+    assert!(moderation.muted_for("Troll").is_some());
+    assert!(moderation.unmute("troll"));
+    assert!(moderation.muted_for("troll").is_none());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+pub fn test_operator_mutes_and_kicks() {
+    let file = env::temp_dir().join(format!("chat-operators-test-{}.txt", process::id()));
+    let _ = fs::remove_file(&file);
+    Accounts::open(&file, AccountsConfig::default())
+        .unwrap()
+        .add("boss", "correct horse")
+        .unwrap();
+    let settings = Settings {
+        accounts: AccountsConfig {
+            file: Some(file.clone()),
+            guests: true,
+            ..AccountsConfig::default()
+        },
+        moderation: ModerationConfig {
+            operators: vec!["boss".to_string()],
+            ..ModerationConfig::default()
+        },
+        ..Settings::default()
+    };
+    let hub = Arc::new(Hub::with_settings(settings).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || event_loop::serve(vec![listener], hub, 1));
+
+    let login = |first: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        protocol::client_handshake_with_version(&mut stream, 1).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(first)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        assert_eq!(
+            reader.read_frame().unwrap().unwrap().kind,
+            MessageType::Nick
+        );
+        reader
+    };
+    let say = |reader: &mut FrameReader<TcpStream>, text: &str| {
+        protocol::write_frame(reader.get_mut(), Framing::Framed, &Frame::text(text)).unwrap();
+    };
+    // The next frame of `kind`, skipping anything else.
+    let next = |reader: &mut FrameReader<TcpStream>, kind: MessageType| loop {
+        let frame = reader.read_frame().unwrap().expect("still connected");
+        if frame.kind == kind {
+            break frame.as_str().unwrap().to_string();
+        }
+    };
+
+    let mut boss = login("/login boss correct horse");
+    assert_eq!(next(&mut boss, MessageType::Notice), "You are an operator");
+    let mut troll = login("troll");
+    assert_eq!(next(&mut boss, MessageType::Notice), "troll is online");
+
+    // Guests are not operators.
+    say(&mut troll, "/kick boss");
+    assert_eq!(
+        next(&mut troll, MessageType::Error),
+        "only operators can do that"
+    );
+
+    say(&mut boss, "/mute troll 1m");
+    assert_eq!(
+        next(&mut troll, MessageType::Error),
+        "you are muted for 60s by boss"
+    );
+    say(&mut troll, "can anyone hear me?");
+    assert!(next(&mut troll, MessageType::Error).starts_with("you are muted for another"));
+
+    say(&mut boss, "/kick troll enough");
+    assert_eq!(
+        next(&mut troll, MessageType::Error),
+        "you were kicked by boss (enough)"
+    );
+    assert!(troll.read_frame().unwrap().is_none());
+    // Everyone in the room hears about it, the operator included.
+    assert_eq!(next(&mut boss, MessageType::Notice), "Muted troll for 60s");
+    assert_eq!(
+        next(&mut boss, MessageType::Notice),
+        "troll was kicked by boss (enough)"
+    );
+
+    fs::remove_file(&file).unwrap();
+}
+
+#[test]
+pub fn test_websocket_accept_key() {
+    // The example from RFC 6455.
+    assert_eq!(
+        websocket::accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+    // Anything but a GET upgrade is turned away with a status line.
+    let refused = websocket::parse_handshake(b"POST / HTTP/1.1\r\nHost: x\r\n\r\n");
+    assert!(
+        matches!(refused, websocket::Handshake::Rejected(response) if response.starts_with(b"HTTP/1.1 405"))
+    );
+    assert!(matches!(
+        websocket::parse_handshake(b"GET / HTTP/1.1\r\n"),
+        websocket::Handshake::Incomplete
+    ));
+}
+
+// A client frame: always masked, as browsers send them.
+fn ws_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+// The next frame from the server, as (opcode, payload).
+fn ws_read(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head[1] & 0x80, 0, "servers don't mask");
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        127 => panic!("no message should be that long"),
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0] & 0x0f, payload)
+}
+
+#[test]
+pub fn test_websocket_client_chats_with_tcp_client() {
+    let hub = Arc::new(Hub::new());
+    let chat = TcpListener::bind("127.0.0.1:0").unwrap();
+    let gateway = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (chat_addr, ws_addr) = (chat.local_addr().unwrap(), gateway.local_addr().unwrap());
+    thread::spawn(move || {
+        let listeners = vec![Listener::Chat(chat), Listener::WebSocket(gateway)];
+        event_loop::serve_until(listeners, hub, 2, Shutdown::new())
+    });
+
+    let mut browser = TcpStream::connect(ws_addr).unwrap();
+    browser
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    browser
+        .write_all(
+            b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        browser.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    // Past the upgrade, a browser is a line client: a prompt, then a nick.
+    assert_eq!(ws_read(&mut browser).0, 0x1);
+    browser.write_all(&ws_frame(0x1, b"alice")).unwrap();
+    let mut tcp = TcpStream::connect(chat_addr).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    protocol::client_handshake(&mut tcp).unwrap();
+    protocol::write_frame(&mut tcp, Framing::Framed, &Frame::nick("bob")).unwrap();
+    let mut bob = FrameReader::new(tcp, Decoder::new(Framing::Framed));
+    assert_eq!(bob.read_frame().unwrap().unwrap().kind, MessageType::Nick);
+
+    // One text message is one line, both ways.
+    browser
+        .write_all(&ws_frame(0x1, b"hello from a browser"))
+        .unwrap();
+    // Depending on who got in first, bob may hear alice arrive before that.
+    let heard = loop {
+        let frame = bob.read_frame().unwrap().expect("still connected");
+        if let Ok(event @ Event::Chat { .. }) = frame.to_event() {
+            break event;
+        }
+    };
+    assert!(
+        matches!(&heard, Event::Chat { from, text, .. } if from == "alice" && text == "hello from a browser"),
+        "{:?}",
+        heard
+    );
+    protocol::write_frame(bob.get_mut(), Framing::Framed, &Frame::text("hi alice")).unwrap();
+    loop {
+        let (opcode, payload) = ws_read(&mut browser);
+        assert_eq!(opcode, 0x1);
+        let text = String::from_utf8(payload).unwrap();
+        assert!(!text.ends_with('\n'));
+        if text.contains("hi alice") {
+            break;
+        }
+    }
+
+    browser.write_all(&ws_frame(0x9, b"are you there")).unwrap();
+    assert_eq!(ws_read(&mut browser), (0xa, b"are you there".to_vec()));
+
+    // A close is answered with a close, and then the connection goes.
+    browser
+        .write_all(&ws_frame(0x8, &websocket::CLOSE_NORMAL.to_be_bytes()))
+        .unwrap();
+    let (opcode, payload) = ws_read(&mut browser);
+    assert_eq!(opcode, 0x8);
+    assert_eq!(payload[..2], websocket::CLOSE_NORMAL.to_be_bytes());
+    assert_eq!(browser.read(&mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+pub fn test_events_for_new_clients_text_for_old() {
+    let hub = Arc::new(Hub::new());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || event_loop::serve(vec![listener], hub, 1));
+
+    let login = |nick: &str, version: u8| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        protocol::client_handshake_with_version(&mut stream, version).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        assert_eq!(
+            reader.read_frame().unwrap().unwrap().kind,
+            MessageType::Nick
+        );
+        reader
+    };
+    let mut new = login("alice", protocol::VERSION);
+    let mut old = login("bob", 1);
+    let mut carol = login("carol", protocol::VERSION);
+    // Everyone hears who arrives.
+    for nick in ["bob", "carol"] {
+        assert_eq!(
+            new.read_frame().unwrap().unwrap().to_event(),
+            Ok(Event::Presence {
+                nick: nick.to_string(),
+                status: Status::Online,
+                message: None
+            })
+        );
+    }
+
+    for text in ["/nick caroline", "/join #rust", "/msg alice psst", "hi"] {
+        protocol::write_frame(carol.get_mut(), Framing::Framed, &Frame::text(text)).unwrap();
+    }
+    let events: Vec<Event> = (0..2)
+        .map(|_| new.read_frame().unwrap().unwrap().to_event().unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        [
+            Event::Nick {
+                old: "carol".to_string(),
+                new: "caroline".to_string()
+            },
+            Event::Direct {
+                id: Some(1),
+                from: "caroline".to_string(),
+                text: "psst".to_string()
+            },
+        ]
+    );
+    let json = Frame::event(&events[0]);
+    assert_eq!(
+        json.as_str(),
+        Ok(r#"{"type":"nick","old":"carol","new":"caroline"}"#)
+    );
+
+    // The same rename reaches an older client as the text it always got.
+    let frame = old.read_frame().unwrap().unwrap();
+    assert_eq!(frame.as_str(), Ok("carol is online"));
+    let frame = old.read_frame().unwrap().unwrap();
+    assert_eq!(frame.kind, MessageType::Notice);
+    assert_eq!(frame.as_str(), Ok("carol is now known as caroline"));
+    // Errors, too, come as events to those who take them.
+    protocol::write_frame(
+        new.get_mut(),
+        Framing::Framed,
+        &Frame::text("/msg nobody x"),
+    )
+    .unwrap();
+    assert!(matches!(
+        new.read_frame().unwrap().unwrap().to_event(),
+        Ok(Event::Error { text }) if text.starts_with("no such user")
+    ));
+}
+
+#[test]
+pub fn test_presence_who_and_away() {
+    assert_eq!(command::parse("/who"), Some(Ok(Command::Who)));
+    assert_eq!(
+        command::parse("/away  out to lunch"),
+        Some(Ok(Command::Away(Some("out to lunch".to_string()))))
+    );
+    assert_eq!(command::parse("/away"), Some(Ok(Command::Away(None))));
+    assert_eq!(command::format_duration(Duration::from_secs(42)), "42s");
+    assert_eq!(command::format_duration(Duration::from_secs(3900)), "1h 5m");
+    assert_eq!(
+        command::format_duration(Duration::from_secs(90_000)),
+        "1d 1h"
+    );
+
+    let hub = Arc::new(Hub::new());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || event_loop::serve(vec![listener], hub, 1));
+    let login = |nick: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        protocol::client_handshake(&mut stream).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        assert_eq!(
+            reader.read_frame().unwrap().unwrap().kind,
+            MessageType::Nick
+        );
+        reader
+    };
+    let say = |reader: &mut FrameReader<TcpStream>, text: &str| {
+        protocol::write_frame(reader.get_mut(), Framing::Framed, &Frame::text(text)).unwrap();
+    };
+    let next = |reader: &mut FrameReader<TcpStream>| {
+        reader
+            .read_frame()
+            .unwrap()
+            .expect("still connected")
+            .to_event()
+            .unwrap()
+    };
+    let presence = |nick: &str, status, message: Option<&str>| Event::Presence {
+        nick: nick.to_string(),
+        status,
+        message: message.map(str::to_string),
+    };
+
+    let mut alice = login("alice");
+    let mut bob = login("bob");
+    assert_eq!(next(&mut alice), presence("bob", Status::Online, None));
+
+    say(&mut bob, "/away lunch");
+    assert_eq!(next(&mut bob), Event::notice("You are marked as away"));
+    assert_eq!(
+        next(&mut alice),
+        presence("bob", Status::Away, Some("lunch"))
+    );
+    // A private message still arrives, but the sender hears why no answer
+    // may come.
+    say(&mut alice, "/msg bob are you there?");
+    assert_eq!(next(&mut alice), Event::Ack { seq: 1, id: 1 });
+    assert_eq!(
+        next(&mut alice),
+        presence("bob", Status::Away, Some("lunch"))
+    );
+
+    say(&mut alice, "/who");
+    let who: Vec<Event> = (0..3).map(|_| next(&mut alice)).collect();
+    assert_eq!(who[0], Event::notice("2 users online:"));
+    assert_eq!(who[1], Event::notice("alice - idle 0s, connected 0s"));
+    assert_eq!(
+        who[2],
+        Event::notice("bob - idle 0s, connected 0s, away: lunch")
+    );
+
+    drop(bob);
+    assert_eq!(next(&mut alice), presence("bob", Status::Offline, None));
+}
+
+#[test]
+pub fn test_acks_and_receipts() {
+    assert_eq!(
+        command::parse("/receipts on"),
+        Some(Ok(Command::Receipts(true)))
+    );
+    assert_eq!(
+        command::parse("/receipts off"),
+        Some(Ok(Command::Receipts(false)))
+    );
+    assert!(matches!(command::parse("/receipts maybe"), Some(Err(_))));
+
+    let hub = Arc::new(Hub::new());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || event_loop::serve(vec![listener], hub, 1));
+    let login = |nick: &str, version: u8| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        protocol::client_handshake_with_version(&mut stream, version).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        assert_eq!(
+            reader.read_frame().unwrap().unwrap().kind,
+            MessageType::Nick
+        );
+        reader
+    };
+    let say = |reader: &mut FrameReader<TcpStream>, text: &str| {
+        protocol::write_frame(reader.get_mut(), Framing::Framed, &Frame::text(text)).unwrap();
+    };
+    let next = |reader: &mut FrameReader<TcpStream>| {
+        let frame = reader.read_frame().unwrap().expect("still connected");
+        frame
+            .to_event()
+            .or_else(|_| Event::from_legacy(&frame).ok_or(()))
+    };
+
+    let mut alice = login("alice", protocol::VERSION);
+    let mut bob = login("bob", protocol::VERSION);
+    let mut old = login("old", 1);
+    for _ in 0..2 {
+        assert!(matches!(next(&mut alice), Ok(Event::Presence { .. })));
+    }
+    assert!(matches!(next(&mut bob), Ok(Event::Presence { .. })));
+
+    // Every line counts, commands too, so "hello" is alice's second.
+    say(&mut alice, "/receipts on");
+    assert_eq!(next(&mut alice), Ok(Event::notice("Delivery receipts on")));
+    say(&mut alice, "hello");
+    assert_eq!(next(&mut alice), Ok(Event::Ack { seq: 2, id: 1 }));
+    let mut receipts: Vec<Event> = (0..2).map(|_| next(&mut alice).unwrap()).collect();
+    receipts.sort_by_key(|e| e.to_string());
+    assert_eq!(
+        receipts,
+        [
+            Event::Receipt {
+                id: 1,
+                to: "bob".to_string()
+            },
+            Event::Receipt {
+                id: 1,
+                to: "old".to_string()
+            },
+        ]
+    );
+    assert_eq!(
+        next(&mut bob),
+        Ok(Event::Chat {
+            id: Some(1),
+            room: "#lobby".to_string(),
+            from: "alice".to_string(),
+            text: "hello".to_string(),
+            time: None,
+        })
+    );
+
+    // Private messages get the next id, and a receipt of their own.
+    say(&mut alice, "/msg bob psst");
+    assert_eq!(next(&mut alice), Ok(Event::Ack { seq: 3, id: 2 }));
+    assert_eq!(
+        next(&mut alice),
+        Ok(Event::Receipt {
+            id: 2,
+            to: "bob".to_string()
+        })
+    );
+
+    // Older clients can't tell acks apart, so they don't get them.
+    say(&mut old, "/receipts on");
+    let refusal = loop {
+        let frame = old.read_frame().unwrap().unwrap();
+        if frame.kind == MessageType::Error {
+            break frame;
+        }
+    };
+    assert!(refusal.as_str().unwrap().starts_with("receipts need"));
+}
+
+#[test]
+pub fn test_file_transfer_is_relayed_in_chunks() {
+    let sha = "ab".repeat(32);
+    assert_eq!(
+        command::parse(&format!("/offer bob 5 {} notes.txt", sha)),
+        Some(Ok(Command::Offer {
+            to: "bob".to_string(),
+            size: 5,
+            sha256: sha.clone(),
+            name: "notes.txt".to_string(),
+        }))
+    );
+    assert!(matches!(
+        command::parse(&format!("/offer bob 5 {} ../passwd", sha)),
+        Some(Err(_))
+    ));
+    assert!(matches!(
+        command::parse("/offer bob 5 nothex notes.txt"),
+        Some(Err(_))
+    ));
+    assert_eq!(command::parse("/accept 3"), Some(Ok(Command::Accept(3))));
+    assert_eq!(command::parse("/reject 3"), Some(Ok(Command::Cancel(3))));
+
+    let settings = Settings {
+        transfers: Some(TransferConfig { max_size: 8 }),
+        ..Settings::default()
+    };
+    let hub = Arc::new(Hub::with_settings(settings).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || event_loop::serve(vec![listener], hub, 1));
+    let login = |nick: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        protocol::client_handshake(&mut stream).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        assert_eq!(
+            reader.read_frame().unwrap().unwrap().kind,
+            MessageType::Nick
+        );
+        reader
+    };
+    let send = |reader: &mut FrameReader<TcpStream>, frame: &Frame| {
+        protocol::write_frame(reader.get_mut(), Framing::Framed, frame).unwrap();
+    };
+    // The next frame that isn't presence or an ack.
+    let next = |reader: &mut FrameReader<TcpStream>| loop {
+        let frame = reader.read_frame().unwrap().expect("still connected");
+        match frame.to_event() {
+            Ok(Event::Presence { .. } | Event::Ack { .. }) => {}
+            Ok(event) => return Some(event),
+            Err(_) => return Event::from_legacy(&frame),
+        }
+    };
+
+    let mut alice = login("alice");
+    let mut bob = login("bob");
+
+    // Too big for this server.
+    send(
+        &mut alice,
+        &Frame::text(&format!("/offer bob 9 {} big.bin", sha)),
+    );
+    assert!(matches!(next(&mut alice), Some(Event::Error { .. })));
+
+    send(
+        &mut alice,
+        &Frame::text(&format!("/offer bob 5 {} notes.txt", sha)),
+    );
+    let offer = Event::FileOffer {
+        id: 1,
+        from: "alice".to_string(),
+        to: "bob".to_string(),
+        name: "notes.txt".to_string(),
+        size: 5,
+        sha256: sha.clone(),
+    };
+    assert_eq!(next(&mut alice), Some(offer.clone()));
+    assert_eq!(next(&mut bob), Some(offer));
+
+    // Sending before bob says yes calls the whole thing off.
+    send(&mut alice, &Frame::chunk(1, b"he"));
+    assert!(matches!(next(&mut alice), Some(Event::Error { .. })));
+    assert!(matches!(
+        next(&mut alice),
+        Some(Event::FileCancelled { id: 1, .. })
+    ));
+    assert!(matches!(
+        next(&mut bob),
+        Some(Event::FileCancelled { id: 1, .. })
+    ));
+    send(
+        &mut alice,
+        &Frame::text(&format!("/offer bob 5 {} notes.txt", sha)),
+    );
+    assert!(matches!(
+        next(&mut alice),
+        Some(Event::FileOffer { id: 2, .. })
+    ));
+    assert!(matches!(
+        next(&mut bob),
+        Some(Event::FileOffer { id: 2, .. })
+    ));
+
+    send(&mut bob, &Frame::text("/accept 2"));
+    assert_eq!(next(&mut bob), Some(Event::FileAccepted { id: 2 }));
+    assert_eq!(next(&mut alice), Some(Event::FileAccepted { id: 2 }));
+
+    // The bytes arrive as sent, and alice hears how far they got.
+    send(&mut alice, &Frame::chunk(2, b"hel"));
+    send(&mut alice, &Frame::chunk(2, b"lo"));
+    let mut received = Vec::new();
+    while received.len() < 5 {
+        let frame = bob.read_frame().unwrap().unwrap();
+        if let Some((id, bytes)) = frame.chunk_parts() {
+            assert_eq!(id, 2);
+            received.extend_from_slice(bytes);
+        }
+    }
+    assert_eq!(received, b"hello");
+    assert_eq!(
+        next(&mut alice),
+        Some(Event::FileProgress { id: 2, bytes: 3 })
+    );
+    assert_eq!(
+        next(&mut alice),
+        Some(Event::FileProgress { id: 2, bytes: 5 })
+    );
+
+    // A finished transfer is forgotten; a refused one is called off for both.
+    send(&mut alice, &Frame::chunk(2, b"!"));
+    send(
+        &mut alice,
+        &Frame::text(&format!("/offer bob 1 {} more.txt", sha)),
+    );
+    assert!(matches!(
+        next(&mut alice),
+        Some(Event::FileOffer { id: 3, .. })
+    ));
+    assert!(matches!(
+        next(&mut bob),
+        Some(Event::FileOffer { id: 3, .. })
+    ));
+    send(&mut bob, &Frame::text("/reject 3"));
+    let cancelled = Event::FileCancelled {
+        id: 3,
+        reason: "bob said no".to_string(),
+    };
+    assert_eq!(next(&mut bob), Some(cancelled.clone()));
+    assert_eq!(next(&mut alice), Some(cancelled));
+}
+
+#[test]
+pub fn test_seen_cache_forgets_the_oldest() {
+    let mut seen = Seen::new(2);
+    assert!(seen.insert("east", 1));
+    assert!(!seen.insert("east", 1));
+    assert!(seen.insert("west", 1));
+    assert!(seen.insert("east", 2));
+    // Only two fit, so the first has been forgotten.
+    assert!(seen.insert("east", 1));
+    assert!(!seen.insert("east", 2));
+
+    assert_eq!(federation::qualify("bob", "west"), "bob@west");
+    assert_eq!(federation::home("bob@west"), Some("west"));
+    assert_eq!(federation::home("bob"), None);
+}
+
+#[test]
+pub fn test_federation_relays_chat_and_presence() {
+    assert_eq!(
+        command::parse("/peer west s3cret"),
+        Some(Ok(Command::Peer {
+            name: "west".to_string(),
+            secret: Some("s3cret".to_string())
+        }))
+    );
+    assert!(matches!(command::parse("/peer"), Some(Err(_))));
+
+    let start = |name: &str, peers: Vec<SocketAddr>| {
+        let settings = Settings {
+            federation: Some(FederationConfig {
+                name: name.to_string(),
+                peers,
+                secret: Some("s3cret".to_string()),
+            }),
+            ..Settings::default()
+        };
+        let hub = Arc::new(Hub::with_settings(settings).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn({
+            let hub = Arc::clone(&hub);
+            move || event_loop::serve(vec![listener], hub, 1)
+        });
+        (hub, addr)
+    };
+    let login = |addr: SocketAddr, nick: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        protocol::client_handshake(&mut stream).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        let answer = reader.read_frame().unwrap().unwrap();
+        (reader, answer)
+    };
+    let send = |reader: &mut FrameReader<TcpStream>, frame: &Frame| {
+        protocol::write_frame(reader.get_mut(), Framing::Framed, frame).unwrap();
+    };
+    let next = |reader: &mut FrameReader<TcpStream>| {
+        reader
+            .read_frame()
+            .unwrap()
+            .expect("still connected")
+            .to_event()
+            .unwrap()
+    };
+    let presence = |nick: &str, status| Event::Presence {
+        nick: nick.to_string(),
+        status,
+        message: None,
+    };
+    let chat = |from: &str, text: &str| Event::Chat {
+        id: None,
+        room: "#lobby".to_string(),
+        from: from.to_string(),
+        text: text.to_string(),
+        time: None,
+    };
+
+    // West dials east: one link, carrying traffic both ways.
+    let (east, east_addr) = start("east", Vec::new());
+    let (mut alice, _) = login(east_addr, "alice");
+    let (west, west_addr) = start("west", vec![east_addr]);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !(east.federation().unwrap().is_linked("west")
+        && west.federation().unwrap().is_linked("east"))
+    {
+        assert!(Instant::now() < deadline, "the servers never linked");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let (mut bob, _) = login(west_addr, "bob");
+    assert_eq!(next(&mut alice), presence("bob@west", Status::Online));
+    send(&mut bob, &Frame::text("hi from the west"));
+    assert_eq!(next(&mut bob), Event::Ack { seq: 1, id: 1 });
+    assert_eq!(next(&mut alice), chat("bob@west", "hi from the west"));
+    // West heard about alice when the link came up.
+    send(&mut bob, &Frame::text("/who"));
+    let who: Vec<Event> = (0..3).map(|_| next(&mut bob)).collect();
+    assert_eq!(who[0], Event::notice("2 users online:"));
+    assert_eq!(who[2], Event::notice("alice@east - on another server"));
+
+    // Peers need the secret.
+    let (_, refusal) = login(east_addr, "/peer south wrong");
+    assert_eq!(
+        refusal.to_event().unwrap(),
+        Event::error("wrong peer secret")
+    );
+
+    // A third server, played by hand, sends the same message twice. It is
+    // delivered once here, and passed on once to west.
+    let (mut north, answer) = login(east_addr, "/peer north s3cret");
+    assert_eq!(answer.as_str(), Ok("east"));
+    let mut snapshot: Vec<String> = (0..2)
+        .map(|_| match next(&mut north) {
+            Event::Relay { origin, event, .. } => format!("{} via {}", event, origin),
+            other => panic!("expected a relay, got {:?}", other),
+        })
+        .collect();
+    snapshot.sort();
+    assert_eq!(
+        snapshot,
+        [
+            "alice@east is online via east",
+            "bob@west is online via east"
+        ]
+    );
+    let relay = |id: u64, event: Event| {
+        Frame::event(&Event::Relay {
+            origin: "north".to_string(),
+            id,
+            event: Box::new(event),
+        })
+    };
+    send(
+        &mut north,
+        &relay(1, presence("carol@north", Status::Online)),
+    );
+    send(&mut north, &relay(2, chat("carol@north", "hello?")));
+    send(&mut north, &relay(2, chat("carol@north", "hello?")));
+    send(&mut north, &relay(3, chat("carol@north", "anyone?")));
+    for reader in [&mut alice, &mut bob] {
+        assert_eq!(next(reader), presence("carol@north", Status::Online));
+        assert_eq!(next(reader), chat("carol@north", "hello?"));
+        assert_eq!(next(reader), chat("carol@north", "anyone?"));
+    }
+
+    // When a link drops, everyone learned through it goes with it.
+    drop(north);
+    assert_eq!(next(&mut alice), presence("carol@north", Status::Offline));
+    assert_eq!(next(&mut bob), presence("carol@north", Status::Offline));
+}
+
+// Keeps a log of what it heard, answers "!ping" in rooms and echoes private
+// messages back in capitals.
+struct Pinger(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl Plugin for Pinger {
+    fn name(&self) -> &str {
+        "pinger"
+    }
+
+    fn on_connect(&self, _bot: &Bot, nick: &str) {
+        self.0.lock().unwrap().push(format!("+{}", nick));
+    }
+
+    fn on_message(&self, bot: &Bot, message: &PluginMessage) {
+        match &message.room {
+            Some(room) if message.text == "!ping" => bot.say(room, "pong").unwrap(),
+            Some(_) => {}
+            None => bot
+                .tell(&message.from, &message.text.to_uppercase())
+                .unwrap(),
+        }
+    }
+
+    fn on_disconnect(&self, _bot: &Bot, nick: &str) {
+        self.0.lock().unwrap().push(format!("-{}", nick));
+    }
+}
+
+#[test]
+pub fn test_plugins_hear_and_answer() {
+    let heard = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let running = Server::builder()
+        .workers(1)
+        .plugin(Pinger(Arc::clone(&heard)))
+        .build()
+        .unwrap()
+        .spawn()
+        .unwrap();
+    let addr = running.local_addr();
+    assert_ne!(addr.port(), 0);
+    let connect = |nick: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        protocol::client_handshake(&mut stream).unwrap();
+        protocol::write_frame(&mut stream, Framing::Framed, &Frame::nick(nick)).unwrap();
+        let mut reader = FrameReader::new(stream, Decoder::new(Framing::Framed));
+        let frame = reader.read_frame().unwrap().unwrap();
+        (reader, frame)
+    };
+    let next = |reader: &mut FrameReader<TcpStream>| loop {
+        let frame = reader.read_frame().unwrap().expect("still connected");
+        match frame.to_event() {
+            Ok(Event::Presence { .. } | Event::Ack { .. }) => {}
+            Ok(event) => return Some(event),
+            Err(_) => return Event::from_legacy(&frame),
+        }
+    };
+
+    // The plugin's name is taken.
+    let (_, frame) = connect("pinger");
+    assert!(matches!(frame.to_event(), Ok(Event::Error { .. })));
+
+    let (mut alice, frame) = connect("alice");
+    assert_eq!(frame.kind, MessageType::Nick);
+    assert_eq!(running.hub().nicks(), vec!["alice".to_string()]);
+
+    // Our own line isn't echoed back, but the answer to it is.
+    protocol::write_frame(alice.get_mut(), Framing::Framed, &Frame::text("!ping")).unwrap();
+    assert!(matches!(
+        next(&mut alice),
+        Some(Event::Chat { room, from, text, .. })
+            if room == room::DEFAULT_ROOM && from == "pinger" && text == "pong"
+    ));
+
+    protocol::write_frame(
+        alice.get_mut(),
+        Framing::Framed,
+        &Frame::text("/msg pinger hello"),
+    )
+    .unwrap();
+    assert!(matches!(
+        next(&mut alice),
+        Some(Event::Direct { from, text, .. }) if from == "pinger" && text == "HELLO"
+    ));
+
+    drop(alice);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while heard.lock().unwrap().len() < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(*heard.lock().unwrap(), vec!["+alice", "-alice"]);
+    running.stop().unwrap();
+}
+
+// ---------------------------------------------------------------------------
+// A harness for the concurrency tests: a server on a free port, and clients
+// that follow a script, as many at once as the test likes. Each client
+// reads with a timeout, so a lost message fails the test rather than
+// hanging it.
+// ---------------------------------------------------------------------------
+
+fn start_server(workers: usize) -> RunningServer {
+    Server::builder()
+        .workers(workers)
+        .build()
+        .unwrap()
+        .spawn()
+        .unwrap()
+}
+
+struct TestClient {
+    nick: String,
+    reader: FrameReader<TcpStream>,
+}
+
+impl TestClient {
+    // Connected and through the handshake, but not logged in.
+    fn connect(addr: SocketAddr) -> TestClient {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        protocol::client_handshake(&mut stream).unwrap();
+        TestClient {
+            nick: String::new(),
+            reader: FrameReader::new(stream, Decoder::new(Framing::Framed)),
+        }
+    }
+
+    fn login(addr: SocketAddr, nick: &str) -> TestClient {
+        let mut client = TestClient::connect(addr);
+        client.send_frame(&Frame::nick(nick));
+        let frame = client.reader.read_frame().unwrap().expect("logged in");
+        assert_eq!(frame.kind, MessageType::Nick, "{} wasn't let in", nick);
+        client.nick = nick.to_string();
+        client
+    }
+
+    fn send(&mut self, line: &str) {
+        self.send_frame(&Frame::text(line));
+    }
+
+    fn send_frame(&mut self, frame: &Frame) {
+        protocol::write_frame(self.reader.get_mut(), Framing::Framed, frame).unwrap();
+    }
+
+    // A second handle on the socket, for writing from another thread.
+    fn writer(&self) -> TcpStream {
+        self.reader.get_ref().try_clone().unwrap()
+    }
+
+    // The next event, or None once the server hangs up.
+    fn next(&mut self) -> Option<Event> {
+        let frame = self.reader.read_frame().unwrap()?;
+        Some(frame.to_event().unwrap_or_else(|_| {
+            Event::from_legacy(&frame).unwrap_or_else(|| Event::notice(frame.as_str().unwrap()))
+        }))
+    }
+
+    // The next event that isn't presence or an ack.
+    fn next_news(&mut self) -> Event {
+        loop {
+            match self.next().expect("still connected") {
+                Event::Presence { .. } | Event::Ack { .. } => {}
+                event => return event,
+            }
+        }
+    }
+
+    fn expect_error(&mut self) -> String {
+        match self.next_news() {
+            Event::Error { text } => text,
+            other => panic!("{} expected an error, got {:?}", self.nick, other),
+        }
+    }
+
+    // Read until the server hangs up.
+    fn expect_closed(&mut self) {
+        loop {
+            match self.reader.read_frame() {
+                Ok(Some(_)) => {}
+                Ok(None) => return,
+                // A reset counts as hung up too.
+                Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => return,
+                Err(e) => panic!("{} is still connected: {}", self.nick, e),
+            }
+        }
+    }
+}
+
+// Wait for something the server does in its own time.
+fn eventually(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+pub fn test_concurrent_senders_keep_order_and_skip_themselves() {
+    const CLIENTS: usize = 8;
+    const LINES: usize = 50;
+    let server = start_server(4);
+    let addr = server.local_addr();
+    // Everyone is in before anyone talks, so everyone hears everything.
+    let clients: Vec<TestClient> = (0..CLIENTS)
+        .map(|n| TestClient::login(addr, &format!("user{}", n)))
+        .collect();
+    let start = Arc::new(std::sync::Barrier::new(CLIENTS));
+
+    let threads: Vec<_> = clients
+        .into_iter()
+        .map(|mut client| {
+            let start = Arc::clone(&start);
+            thread::spawn(move || {
+                let mut writer = client.writer();
+                let nick = client.nick.clone();
+                start.wait();
+                let sender = thread::spawn(move || {
+                    for n in 0..LINES {
+                        let line = Frame::text(&format!("{} {}", nick, n));
+                        protocol::write_frame(&mut writer, Framing::Framed, &line).unwrap();
+                    }
+                });
+
+                // The next line we expect from each of the others.
+                let mut expected = std::collections::HashMap::new();
+                let (mut heard, mut acked) = (0, 0);
+                while heard < (CLIENTS - 1) * LINES || acked < LINES {
+                    match client.next().expect("still connected") {
+                        Event::Chat { from, text, .. } => {
+                            assert_ne!(from, client.nick, "got our own line back");
+                            let (who, n) = text.split_once(' ').unwrap();
+                            assert_eq!(who, from);
+                            let next = expected.entry(from.clone()).or_insert(0);
+                            assert_eq!(n.parse::<usize>().unwrap(), *next, "from {}", from);
+                            *next += 1;
+                            heard += 1;
+                        }
+                        Event::Ack { seq, .. } => {
+                            acked += 1;
+                            assert_eq!(seq, acked as u64);
+                        }
+                        Event::Presence { .. } => {}
+                        other => panic!("unexpected {:?}", other),
+                    }
+                }
+                sender.join().unwrap();
+                assert_eq!(expected.len(), CLIENTS - 1);
+                client
+            })
+        })
+        .collect();
+    let clients: Vec<TestClient> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    assert_eq!(server.hub().active_connections(), CLIENTS);
+    drop(clients);
+    eventually("everyone to be gone", || server.hub().nicks().is_empty());
+    server.stop().unwrap();
+}
+
+#[test]
+pub fn test_disconnects_are_cleaned_up() {
+    let server = start_server(2);
+    let addr = server.local_addr();
+    let mut stayer = TestClient::login(addr, "stayer");
+    let leavers: Vec<TestClient> = (0..6)
+        .map(|n| {
+            let mut client = TestClient::login(addr, &format!("leaver{}", n));
+            client.send("/join #doomed");
+            assert!(matches!(client.next_news(), Event::Notice { .. }));
+            client
+        })
+        .collect();
+    assert_eq!(server.hub().active_connections(), 7);
+
+    // Half vanish mid-conversation; half stop sending and wait for the
+    // server to hang up.
+    for (n, mut client) in leavers.into_iter().enumerate() {
+        if n % 2 == 0 {
+            client
+                .reader
+                .get_ref()
+                .shutdown(std::net::Shutdown::Write)
+                .unwrap();
+            client.expect_closed();
+        } else {
+            client.send("last words");
+            drop(client);
+        }
+    }
+    eventually("the leavers to be forgotten", || {
+        server.hub().nicks() == vec!["stayer".to_string()]
+    });
+    assert_eq!(server.hub().active_connections(), 1);
+
+    // The room went with its last member.
+    stayer.send("/list");
+    loop {
+        match stayer.next_news() {
+            Event::Notice { text } if text.contains(room::DEFAULT_ROOM) => {
+                assert!(!text.contains("#doomed"), "{}", text);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    // Their names are free again, and chat still flows to whoever's left.
+    let mut back = TestClient::login(addr, "leaver0");
+    back.send("I'm back");
+    loop {
+        if let Event::Chat { from, text, .. } = stayer.next_news() {
+            assert_eq!((from.as_str(), text.as_str()), ("leaver0", "I'm back"));
+            break;
+        }
+    }
+    server.stop().unwrap();
+}
+
+#[test]
+pub fn test_error_paths_leave_the_server_serving() {
+    let server = start_server(2);
+    let addr = server.local_addr();
+    let mut alice = TestClient::login(addr, "alice");
+
+    // Talking before logging in.
+    let mut anon = TestClient::connect(addr);
+    anon.send("hello?");
+    anon.expect_error();
+    // A bad name, then one that's taken (whatever the case), then a good one.
+    anon.send_frame(&Frame::nick("no spaces"));
+    anon.expect_error();
+    anon.send_frame(&Frame::nick("ALICE"));
+    anon.expect_error();
+    anon.send_frame(&Frame::nick("bob"));
+    assert_eq!(
+        anon.reader.read_frame().unwrap().unwrap().kind,
+        MessageType::Nick
+    );
+    let mut bob = anon;
+    bob.nick = "bob".to_string();
+
+    // Commands that can't be carried out.
+    bob.send("/frobnicate");
+    bob.expect_error();
+    bob.send("/msg ghost boo");
+    bob.expect_error();
+    bob.send("/join no-hash");
+    bob.expect_error();
+    bob.send("/kick alice");
+    bob.expect_error();
+
+    // Garbage on the wire: a frame too big to be real. The server says why
+    // and hangs up on that client only.
+    let mut mallory = TestClient::login(addr, "mallory");
+    let huge = (protocol::MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+    mallory.reader.get_mut().write_all(&huge).unwrap();
+    mallory.expect_error();
+    mallory.expect_closed();
+
+    // None of it got in the way of the people behaving.
+    bob.send("still here");
+    loop {
+        if let Event::Chat { from, text, .. } = alice.next_news() {
+            assert_eq!((from.as_str(), text.as_str()), ("bob", "still here"));
+            break;
+        }
+    }
+    eventually("mallory to be dropped", || server.hub().nicks().len() == 2);
+    server.stop().unwrap();
+}
+
+#[test]
+pub fn test_metrics_endpoint() {
+    let server = Server::builder()
+        .workers(1)
+        .metrics(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .build()
+        .unwrap();
+    let scrape_addr = server.metrics_addrs()[0];
+    let server = server.spawn().unwrap();
+    let get = |request: &str| {
+        let mut stream = TcpStream::connect(scrape_addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    // The value of one sample, by its full name.
+    let sample = |body: &str, name: &str| -> f64 {
+        body.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no {} in\n{}", name, body))
+            .parse()
+            .unwrap()
+    };
+
+    let mut alice = TestClient::login(server.local_addr(), "alice");
+    let mut bob = TestClient::login(server.local_addr(), "bob");
+    for n in 0..3 {
+        alice.send(&format!("hello {}", n));
+    }
+    for _ in 0..3 {
+        assert!(matches!(bob.next_news(), Event::Chat { .. }));
+    }
+
+    let response = get("GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n");
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+    assert!(body.contains("# TYPE chat_broadcast_duration_seconds histogram"));
+    assert_eq!(sample(body, "chat_users_online"), 2.0);
+    assert_eq!(sample(body, "chat_connections_open"), 2.0);
+    // Two nicknames and three lines.
+    assert!(sample(body, "chat_messages_received_total") >= 5.0);
+    assert!(sample(body, "chat_messages_sent_total") >= 3.0);
+    assert!(sample(body, "chat_bytes_received_total") > 0.0);
+    assert!(sample(body, "chat_bytes_sent_total") > 0.0);
+    assert_eq!(sample(body, "chat_write_errors_total"), 0.0);
+    let broadcasts = sample(body, "chat_broadcast_duration_seconds_count");
+    assert!(broadcasts >= 3.0);
+    assert_eq!(
+        sample(body, "chat_broadcast_duration_seconds_bucket{le=\"+Inf\"}"),
+        broadcasts
+    );
+    assert!(sample(body, "chat_lock_wait_seconds_total{lock=\"clients\"}") >= 0.0);
+
+    assert!(get("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+    assert!(get("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+
+    drop((alice, bob));
+    eventually("the connections to close", || {
+        server.hub().metrics().open_connections() == 0
+    });
+    server.stop().unwrap();
+    // The endpoint went with the server.
+    assert!(TcpStream::connect(scrape_addr).is_err());
+}